@@ -7,9 +7,21 @@ fn main() {
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
 
+    // pack everything under `ramdisk/` into a tar archive that the bootloader loads next to
+    // the kernel, the kernel unpacks it into an in-memory filesystem at boot
+    let ramdisk_dir = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("ramdisk");
+    let ramdisk_path = out_dir.join("ramdisk.tar");
+    let mut archive = tar::Builder::new(std::fs::File::create(&ramdisk_path).unwrap());
+    // store symlinks as symlinks instead of copying whatever they point to
+    archive.follow_symlinks(false);
+    archive.append_dir_all(".", &ramdisk_dir).unwrap();
+    archive.finish().unwrap();
+    println!("cargo:rerun-if-changed={}", ramdisk_dir.display());
+
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
+        .set_ramdisk(&ramdisk_path)
         .create_disk_image(&uefi_path)
         .unwrap();
 
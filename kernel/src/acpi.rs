@@ -0,0 +1,96 @@
+use alloc::vec::Vec;
+
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::memory::phys_to_virt;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Size of the header every system description table starts with.
+pub const SDT_HEADER_SIZE: usize = 36;
+
+/// Physical addresses of every table the RSDT or XSDT points to.
+static TABLES: Once<Vec<PhysAddr>> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    BadSignature,
+    BadChecksum,
+}
+
+/// Finds the system description tables through the RSDP at physical `rsdp_address`. Returns
+/// how many there are.
+pub fn init(rsdp_address: u64) -> Result<usize, AcpiError> {
+    let rsdp = unsafe { physical_slice(PhysAddr::new(rsdp_address), 36) };
+    if &rsdp[0..8] != RSDP_SIGNATURE {
+        return Err(AcpiError::BadSignature);
+    }
+    // the first 20 bytes are the ACPI 1.0 structure and have their own checksum
+    if !checksum_ok(&rsdp[..20]) {
+        return Err(AcpiError::BadChecksum);
+    }
+
+    // ACPI 2.0 and later point to the XSDT with 64 bit entries, older ones only to the RSDT
+    let revision = rsdp[15];
+    let (root, entry_size) = match revision {
+        0 => (u32::from_le_bytes(rsdp[16..20].try_into().unwrap()) as u64, 4),
+        _ => {
+            let length = u32::from_le_bytes(rsdp[20..24].try_into().unwrap()) as usize;
+            if !checksum_ok(unsafe { physical_slice(PhysAddr::new(rsdp_address), length) }) {
+                return Err(AcpiError::BadChecksum);
+            }
+            (u64::from_le_bytes(rsdp[24..32].try_into().unwrap()), 8)
+        }
+    };
+
+    let root = table_at(PhysAddr::new(root))?;
+    let expected: &[u8; 4] = if entry_size == 8 { b"XSDT" } else { b"RSDT" };
+    if &root[0..4] != expected {
+        return Err(AcpiError::BadSignature);
+    }
+    let tables = root[SDT_HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .map(|entry| match entry_size {
+            8 => u64::from_le_bytes(entry.try_into().unwrap()),
+            _ => u32::from_le_bytes(entry.try_into().unwrap()) as u64,
+        })
+        .map(PhysAddr::new)
+        .collect::<Vec<_>>();
+
+    Ok(TABLES.call_once(|| tables).len())
+}
+
+/// Returns the first table with the given signature, like `b"MCFG"`, header included.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    TABLES
+        .get()?
+        .iter()
+        .filter(|&&address| unsafe { physical_slice(address, 4) } == signature)
+        .find_map(|&address| table_at(address).ok())
+}
+
+/// Returns the table at `address` after checking its checksum.
+fn table_at(address: PhysAddr) -> Result<&'static [u8], AcpiError> {
+    let header = unsafe { physical_slice(address, SDT_HEADER_SIZE) };
+    let length = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    if length < SDT_HEADER_SIZE {
+        return Err(AcpiError::BadSignature);
+    }
+
+    let table = unsafe { physical_slice(address, length) };
+    match checksum_ok(table) {
+        true => Ok(table),
+        false => Err(AcpiError::BadChecksum),
+    }
+}
+
+/// All bytes of an ACPI structure add up to zero.
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// The firmware leaves the tables in memory the bootloader maps along with the rest of
+/// physical memory, so they can be read in place.
+unsafe fn physical_slice(address: PhysAddr, length: usize) -> &'static [u8] {
+    core::slice::from_raw_parts(phys_to_virt(address).as_ptr::<u8>(), length)
+}
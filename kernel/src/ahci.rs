@@ -0,0 +1,531 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::ManuallyDrop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::block::{self, BlockDevice, BlockError};
+use crate::memory::dma::{DmaBuffer, DmaConstraints, ScatterList, Segment};
+use crate::memory::PAGE_SIZE;
+use crate::pci::{PciDevice, PciDriver, PciError, PciMatch};
+
+const SECTOR_SIZE: usize = 512;
+/// Command slots used per port, however many the controller has.
+const MAX_SLOTS: usize = 4;
+/// Every slot has a bounce buffer of this many pages, for buffers the controller can't reach
+/// or that are too scattered. Transfers are split to fit it.
+const BOUNCE_PAGES: usize = 16;
+const TIMEOUT_MS: u64 = 5000;
+/// How often a port is polled before giving up when interrupts are off and the clock doesn't
+/// advance.
+const POLL_LIMIT: usize = 10_000_000;
+
+// generic host control registers
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0C;
+
+const CAP_SUPPORTS_64BIT: u32 = 1 << 31;
+const CAP_COMMAND_LIST_OVERRIDE: u32 = 1 << 24;
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+const GHC_INTERRUPT_ENABLE: u32 = 1 << 1;
+
+// port registers, offsets from the port's base
+const PORT_CLB: usize = 0x00;
+const PORT_CLBU: usize = 0x04;
+const PORT_FB: usize = 0x08;
+const PORT_FBU: usize = 0x0C;
+const PORT_IS: usize = 0x10;
+const PORT_IE: usize = 0x14;
+const PORT_CMD: usize = 0x18;
+const PORT_TFD: usize = 0x20;
+const PORT_SIG: usize = 0x24;
+const PORT_SSTS: usize = 0x28;
+const PORT_SERR: usize = 0x30;
+const PORT_CI: usize = 0x38;
+
+const CMD_START: u32 = 1 << 0;
+const CMD_COMMAND_LIST_OVERRIDE: u32 = 1 << 3;
+const CMD_FIS_RECEIVE_ENABLE: u32 = 1 << 4;
+const CMD_FIS_RECEIVE_RUNNING: u32 = 1 << 14;
+const CMD_LIST_RUNNING: u32 = 1 << 15;
+
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+/// Device to host register FIS, set device bits FIS and task file error interrupts.
+const PORT_INTERRUPTS: u32 = (1 << 0) | (1 << 3) | (1 << 30);
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+const FIS_REGISTER_H2D: u8 = 0x27;
+
+const COMMAND_READ_DMA_EXT: u8 = 0x25;
+const COMMAND_WRITE_DMA_EXT: u8 = 0x35;
+const COMMAND_FLUSH_EXT: u8 = 0xEA;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+// where things are in the first page of a port's memory
+const FIS_OFFSET: u64 = 0x400;
+const COMMAND_TABLE_OFFSET: u64 = 0x800;
+const COMMAND_TABLE_SIZE: u64 = 0x200;
+/// Physical region descriptors that fit in a command table after the FIS.
+const MAX_REGIONS: usize = (COMMAND_TABLE_SIZE as usize - 0x80) / 16;
+/// Largest region a descriptor can cover.
+const MAX_REGION_SIZE: u64 = 4 << 20;
+
+pub static DRIVER: AhciDriver = AhciDriver;
+
+/// Numbers the disks "sata0", "sata1" and so on across all controllers.
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+pub struct AhciDriver;
+
+impl PciDriver for AhciDriver {
+    fn name(&self) -> &'static str {
+        "ahci"
+    }
+
+    fn matches(&self) -> &'static [PciMatch] {
+        &[PciMatch::Class { class: 0x01, subclass: 0x06, prog_if: Some(0x01) }]
+    }
+
+    fn probe(&self, device: &Arc<PciDevice>) -> Result<(), PciError> {
+        device.enable();
+        let hba = device.map_bar(5)?;
+        let capabilities = read(hba, HBA_CAP);
+        write(hba, HBA_GHC, read(hba, HBA_GHC) | GHC_AHCI_ENABLE);
+
+        // one vector for the whole controller, the handler only acknowledges, waking up
+        // whoever waits for a command
+        let msi = device
+            .enable_msi(vec![Box::new(move |_| acknowledge(hba))])
+            .is_ok();
+
+        let implemented = read(hba, HBA_PI);
+        let mut found = 0;
+        for index in (0..32).filter(|index| implemented & (1 << index) != 0) {
+            let port = match AhciPort::new(hba, index, capabilities, msi) {
+                Some(port) => port,
+                None => continue,
+            };
+            let name = format!("sata{}", NEXT_INDEX.fetch_add(1, Ordering::Relaxed));
+            crate::log(&format!(
+                "{}: {} port {} on {}, {}, {} sectors",
+                name,
+                self.name(),
+                index,
+                device.address,
+                port.model,
+                port.sector_count
+            ));
+            if block::register(&name, Arc::new(port)).is_ok() {
+                found += 1;
+            }
+        }
+
+        if msi {
+            write(hba, HBA_GHC, read(hba, HBA_GHC) | GHC_INTERRUPT_ENABLE);
+        }
+        match found {
+            0 => Err(PciError::Unsupported),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn read(base: VirtAddr, register: usize) -> u32 {
+    unsafe { core::ptr::read_volatile((base + register).as_ptr::<u32>()) }
+}
+
+fn write(base: VirtAddr, register: usize, value: u32) {
+    unsafe { core::ptr::write_volatile((base + register).as_mut_ptr::<u32>(), value) }
+}
+
+/// Clears the interrupt status of every port and then of the controller.
+fn acknowledge(hba: VirtAddr) {
+    let pending = read(hba, HBA_IS);
+    for index in (0..32).filter(|index| pending & (1 << index) != 0) {
+        let port = port_base(hba, index);
+        write(port, PORT_IS, read(port, PORT_IS));
+    }
+    write(hba, HBA_IS, pending);
+}
+
+fn port_base(hba: VirtAddr, index: usize) -> VirtAddr {
+    hba + (0x100 + index * 0x80)
+}
+
+/// Polls until `done` returns true.
+fn poll(mut done: impl FnMut() -> bool) -> Result<(), BlockError> {
+    for _ in 0..POLL_LIMIT {
+        if done() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(BlockError::Io)
+}
+
+/// What a command transfers.
+enum Data<'a> {
+    None,
+    /// From the device into the buffer.
+    In(&'a mut [u8]),
+    /// From the buffer to the device.
+    Out(&'a [u8]),
+}
+
+/// A SATA disk on one port of an AHCI controller.
+pub struct AhciPort {
+    registers: VirtAddr,
+    capabilities: u32,
+    // command list, FIS receive area and command tables, then a bounce buffer for each slot,
+    // leaked if the port can't be stopped
+    memory: ManuallyDrop<DmaBuffer>,
+    constraints: DmaConstraints,
+    slots: usize,
+    // bit set for every slot that isn't in use
+    free_slots: Mutex<u32>,
+    // whether the controller interrupts when commands complete
+    interrupts: bool,
+    sector_count: u64,
+    pub model: String,
+}
+
+impl AhciPort {
+    /// Starts the port and identifies the disk behind it, returning none if there's no ATA
+    /// disk there or it doesn't work.
+    fn new(hba: VirtAddr, index: usize, capabilities: u32, interrupts: bool) -> Option<AhciPort> {
+        let registers = port_base(hba, index);
+        // a device has to be present with the link up, and be a disk rather than ATAPI
+        let status = read(registers, PORT_SSTS);
+        if status & 0xF != 3 || (status >> 8) & 0xF != 1 || read(registers, PORT_SIG) != SIGNATURE_ATA {
+            return None;
+        }
+
+        let constraints = DmaConstraints {
+            alignment: 2,
+            // controllers without 64 bit addressing ignore the upper halves
+            max_address: match capabilities & CAP_SUPPORTS_64BIT {
+                0 => u32::MAX as u64,
+                _ => u64::MAX,
+            },
+            max_segment: MAX_REGION_SIZE,
+            ..Default::default()
+        };
+        let slots = usize::min(((capabilities >> 8) & 0x1F) as usize + 1, MAX_SLOTS);
+        let length = (1 + slots * BOUNCE_PAGES) * PAGE_SIZE as usize;
+        let memory = DmaBuffer::allocate(length, &constraints).ok()?;
+
+        let mut port = AhciPort {
+            registers,
+            capabilities,
+            memory: ManuallyDrop::new(memory),
+            constraints,
+            slots,
+            free_slots: Mutex::new((1 << slots) - 1),
+            interrupts,
+            sector_count: 0,
+            model: String::new(),
+        };
+        if port.start().is_err() {
+            return None;
+        }
+
+        let identify = port.identify().ok()?;
+        let words: Vec<u16> = identify.chunks(2).map(|word| u16::from_le_bytes([word[0], word[1]])).collect();
+        // 48 bit addressing is needed for the DMA EXT commands
+        if words[83] & (1 << 10) == 0 {
+            return None;
+        }
+        port.sector_count = words[100..104].iter().rev().fold(0, |count, &word| (count << 16) | word as u64);
+        // the model string stores the first character of each pair in the high byte
+        let model: Vec<u8> = words[27..47].iter().flat_map(|word| word.to_be_bytes()).collect();
+        port.model = String::from_utf8_lossy(&model).trim().into();
+        Some(port)
+    }
+
+    fn read(&self, register: usize) -> u32 {
+        read(self.registers, register)
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        write(self.registers, register, value)
+    }
+
+    /// Stops the port from processing commands and receiving FISes.
+    fn stop(&self) -> Result<(), BlockError> {
+        self.write(PORT_CMD, self.read(PORT_CMD) & !CMD_START);
+        poll(|| self.read(PORT_CMD) & CMD_LIST_RUNNING == 0)?;
+        self.write(PORT_CMD, self.read(PORT_CMD) & !CMD_FIS_RECEIVE_ENABLE);
+        poll(|| self.read(PORT_CMD) & CMD_FIS_RECEIVE_RUNNING == 0)
+    }
+
+    /// Points the port at our memory and starts it, which also recovers it after an error.
+    fn start(&self) -> Result<(), BlockError> {
+        self.stop()?;
+
+        let command_list = self.memory.physical().as_u64();
+        let fis = command_list + FIS_OFFSET;
+        self.write(PORT_CLB, command_list as u32);
+        self.write(PORT_CLBU, (command_list >> 32) as u32);
+        self.write(PORT_FB, fis as u32);
+        self.write(PORT_FBU, (fis >> 32) as u32);
+        self.write(PORT_SERR, u32::MAX);
+        self.write(PORT_IS, u32::MAX);
+        self.write(PORT_CMD, self.read(PORT_CMD) | CMD_FIS_RECEIVE_ENABLE);
+
+        // a device stuck busy after an error is released with a command list override
+        let busy = || self.read(PORT_TFD) & (TFD_BSY | TFD_DRQ) != 0;
+        if busy() && self.capabilities & CAP_COMMAND_LIST_OVERRIDE != 0 {
+            self.write(PORT_CMD, self.read(PORT_CMD) | CMD_COMMAND_LIST_OVERRIDE);
+            poll(|| self.read(PORT_CMD) & CMD_COMMAND_LIST_OVERRIDE == 0)?;
+        }
+        poll(|| !busy())?;
+
+        self.write(PORT_IE, if self.interrupts { PORT_INTERRUPTS } else { 0 });
+        self.write(PORT_CMD, self.read(PORT_CMD) | CMD_START);
+        Ok(())
+    }
+
+    fn bounce_buffer(&self, slot: usize) -> PhysAddr {
+        self.memory.physical() + (1 + slot * BOUNCE_PAGES) as u64 * PAGE_SIZE
+    }
+
+    /// The bounce buffer of `slot`, which only whoever has the slot uses.
+    #[allow(clippy::mut_from_ref)]
+    fn bounce_slice(&self, slot: usize, length: usize) -> &mut [u8] {
+        let offset = (1 + slot * BOUNCE_PAGES) * PAGE_SIZE as usize;
+        let pointer = (self.memory.virtual_address() + offset).as_mut_ptr::<u8>();
+        unsafe { core::slice::from_raw_parts_mut(pointer, length) }
+    }
+
+    fn acquire_slot(&self) -> usize {
+        loop {
+            let acquired = interrupts::without_interrupts(|| {
+                let mut free = self.free_slots.lock();
+                let slot = free.trailing_zeros() as usize;
+                if slot < self.slots {
+                    *free &= !(1 << slot);
+                    Some(slot)
+                } else {
+                    None
+                }
+            });
+            match acquired {
+                Some(slot) => return slot,
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    fn release_slot(&self, slot: usize) {
+        interrupts::without_interrupts(|| *self.free_slots.lock() |= 1 << slot);
+    }
+
+    /// Runs one command, transferring `data` if there is some.
+    fn command(&self, command: u8, lba: u64, count: u16, data: Data) -> Result<(), BlockError> {
+        let slot = self.acquire_slot();
+        let result = self.run_command(slot, command, lba, count, data);
+        self.release_slot(slot);
+        result
+    }
+
+    /// Transfers straight to or from the caller's buffer if the controller can reach it with the
+    /// descriptors there are, through the slot's bounce buffer otherwise.
+    fn run_command(&self, slot: usize, command: u8, lba: u64, count: u16, data: Data) -> Result<(), BlockError> {
+        match data {
+            Data::None => self.issue(slot, command, lba, count, false, &[]),
+            Data::Out(buffer) => {
+                if let Ok(list) = ScatterList::new(buffer, &self.constraints) {
+                    if list.segments().len() <= MAX_REGIONS {
+                        return self.issue(slot, command, lba, count, true, list.segments());
+                    }
+                }
+                self.bounce_slice(slot, buffer.len()).copy_from_slice(buffer);
+                let bounce = Segment { address: self.bounce_buffer(slot), length: buffer.len() };
+                self.issue(slot, command, lba, count, true, &[bounce])
+            }
+            Data::In(buffer) => {
+                if let Ok(list) = ScatterList::new_mut(buffer, &self.constraints) {
+                    if list.segments().len() <= MAX_REGIONS {
+                        return self.issue(slot, command, lba, count, false, list.segments());
+                    }
+                }
+                let bounce = Segment { address: self.bounce_buffer(slot), length: buffer.len() };
+                self.issue(slot, command, lba, count, false, &[bounce])?;
+                buffer.copy_from_slice(self.bounce_slice(slot, buffer.len()));
+                Ok(())
+            }
+        }
+    }
+
+    /// Fills in the command table and header of `slot`, issues the command and waits for it.
+    fn issue(
+        &self,
+        slot: usize,
+        command: u8,
+        lba: u64,
+        count: u16,
+        is_write: bool,
+        regions: &[Segment],
+    ) -> Result<(), BlockError> {
+        let table_offset = COMMAND_TABLE_OFFSET + slot as u64 * COMMAND_TABLE_SIZE;
+        let table = self.memory.physical() + table_offset;
+        let table_pointer = (self.memory.virtual_address() + table_offset).as_mut_ptr::<u8>();
+        unsafe {
+            core::ptr::write_bytes(table_pointer, 0, COMMAND_TABLE_SIZE as usize);
+            // host to device register FIS
+            let fis = core::slice::from_raw_parts_mut(table_pointer, 20);
+            fis[0] = FIS_REGISTER_H2D;
+            // this FIS carries a command
+            fis[1] = 1 << 7;
+            fis[2] = command;
+            fis[4] = lba as u8;
+            fis[5] = (lba >> 8) as u8;
+            fis[6] = (lba >> 16) as u8;
+            // LBA addressing
+            fis[7] = 1 << 6;
+            fis[8] = (lba >> 24) as u8;
+            fis[9] = (lba >> 32) as u8;
+            fis[10] = (lba >> 40) as u8;
+            fis[12] = count as u8;
+            fis[13] = (count >> 8) as u8;
+
+            // physical region descriptors: address and byte count minus one
+            for (i, region) in regions.iter().enumerate() {
+                let prd = table_pointer.add(0x80 + 16 * i);
+                prd.cast::<u64>().write_volatile(region.address.as_u64());
+                prd.add(12).cast::<u32>().write_volatile(region.length as u32 - 1);
+            }
+
+            // command header: FIS length in dwords, direction and the number of descriptors
+            let header = (self.memory.virtual_address() + slot * 32).as_mut_ptr::<u32>();
+            let flags = 5 | if is_write { 1 << 6 } else { 0 } | (regions.len() as u32) << 16;
+            header.write_volatile(flags);
+            header.add(1).write_volatile(0);
+            header.add(2).write_volatile(table.as_u64() as u32);
+            header.add(3).write_volatile((table.as_u64() >> 32) as u32);
+        }
+
+        self.write(PORT_CI, 1 << slot);
+        if let Err(err) = self.wait(slot) {
+            // the port stops after an error and has to be restarted, abandoning whatever
+            // else was running
+            self.start()?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Waits for the command in `slot` to complete. Sleeps until an interrupt if the controller
+    /// has them and interrupts are enabled, syscalls run with them disabled so there we poll.
+    fn wait(&self, slot: usize) -> Result<(), BlockError> {
+        let done = || -> Option<Result<(), BlockError>> {
+            if self.read(PORT_TFD) & TFD_ERR != 0 {
+                return Some(Err(BlockError::Io));
+            }
+            match self.read(PORT_CI) & (1 << slot) {
+                0 => Some(Ok(())),
+                _ => None,
+            }
+        };
+
+        if !self.interrupts || !interrupts::are_enabled() {
+            for _ in 0..POLL_LIMIT {
+                if let Some(result) = done() {
+                    return result;
+                }
+                core::hint::spin_loop();
+            }
+            return Err(BlockError::Io);
+        }
+
+        let start = crate::time::uptime_ms();
+        loop {
+            // checking and halting have to be atomic, or the interrupt could arrive in between
+            // and leave us asleep until the next one
+            interrupts::disable();
+            if let Some(result) = done() {
+                interrupts::enable();
+                return result;
+            }
+            interrupts::enable_and_hlt();
+
+            if crate::time::uptime_ms() - start > TIMEOUT_MS {
+                return Err(BlockError::Io);
+            }
+        }
+    }
+
+    fn identify(&self) -> Result<[u8; SECTOR_SIZE], BlockError> {
+        let mut identify = [0; SECTOR_SIZE];
+        self.command(COMMAND_IDENTIFY, 0, 0, Data::In(&mut identify))?;
+        Ok(identify)
+    }
+
+    fn check_range(&self, sector: u64, len: usize) -> Result<(), BlockError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(BlockError::BadBufferSize);
+        }
+        match sector.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.sector_count => Ok(()),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+}
+
+impl Drop for AhciPort {
+    fn drop(&mut self) {
+        // the controller mustn't touch the memory once it's freed, if the port won't stop
+        // it's better to leak it
+        if self.stop().is_ok() {
+            unsafe { ManuallyDrop::drop(&mut self.memory) };
+        }
+    }
+}
+
+impl BlockDevice for AhciPort {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(sector, buf.len())?;
+        let chunk_size = BOUNCE_PAGES * PAGE_SIZE as usize;
+        for (i, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let lba = sector + (i * chunk_size / SECTOR_SIZE) as u64;
+            let count = (chunk.len() / SECTOR_SIZE) as u16;
+            self.command(COMMAND_READ_DMA_EXT, lba, count, Data::In(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_range(sector, buf.len())?;
+        let chunk_size = BOUNCE_PAGES * PAGE_SIZE as usize;
+        for (i, chunk) in buf.chunks(chunk_size).enumerate() {
+            let lba = sector + (i * chunk_size / SECTOR_SIZE) as u64;
+            let count = (chunk.len() / SECTOR_SIZE) as u16;
+            self.command(COMMAND_WRITE_DMA_EXT, lba, count, Data::Out(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.command(COMMAND_FLUSH_EXT, 0, 0, Data::None)
+    }
+}
@@ -1,6 +1,11 @@
 #[cfg(feature = "x64")]
 mod x64;
 
+#[cfg(feature = "x64")]
+pub use x64::apic::msi_message;
+#[cfg(feature = "x64")]
+pub use x64::interrupts::{allocate_vectors, free_vectors, set_handler, InterruptHandler};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum QemuExitCode {
@@ -13,23 +18,38 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
     x64::exit_qemu(exit_code);
 }
 
+/// What `init` goes through, in order: what's logged before each step, the step and what's
+/// logged once it's done.
 #[cfg(feature = "x64")]
-pub fn init() {
-    use crate::log;
+const INIT_STEPS: &[(&str, fn(), &str)] = {
+    use crate::device::{self, InitLevel};
 
-    log("Initializing GDT");
-    x64::gdt::init_gdt();
-    log("GDT initialized");
+    &[
+        ("Initializing GDT", x64::gdt::init_gdt, "GDT initialized"),
+        ("Initializing IDT", x64::interrupts::init_idt, "IDT initialized"),
+        ("Registering platform devices", x64::register_platform, "Platform devices registered"),
+        (
+            "Initializing interrupt controllers",
+            || device::init_level(InitLevel::InterruptController),
+            "Interrupt controllers initialized",
+        ),
+        ("Initializing clock", || device::init_level(InitLevel::Clock), "Clock initialized"),
+        ("Enabling interrupts", x86_64::instructions::interrupts::enable, "Interrupts enabled"),
+        ("Probing platform devices", || device::init_level(InitLevel::Device), "Platform devices probed"),
+    ]
+};
 
-    log("Initializing IDT");
-    x64::interrupts::init_idt();
-    log("IDT initialized");
+/// How many `step`s `init` takes.
+#[cfg(feature = "x64")]
+pub const INIT_STEP_COUNT: usize = INIT_STEPS.len();
 
-    log("Initializing PIC8529");
-    x64::interrupts::init_pic8529();
-    log("PIC8529 initialized");
+#[cfg(feature = "x64")]
+pub fn init() {
+    use crate::{log, step};
 
-    log("Enabling interrupts");
-    x86_64::instructions::interrupts::enable();
-    log("Interrupts enabled");
-}
\ No newline at end of file
+    for (before, init, after) in INIT_STEPS {
+        log(before);
+        init();
+        step(after);
+    }
+}
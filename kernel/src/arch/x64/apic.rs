@@ -0,0 +1,99 @@
+use alloc::sync::Arc;
+
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::device::platform::{PlatformDevice, PlatformDriver};
+use crate::device::{DeviceError, InitLevel};
+use crate::memory;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// register offsets
+const REG_ID: usize = 0x20;
+const REG_TASK_PRIORITY: usize = 0x80;
+const REG_EOI: usize = 0xB0;
+const REG_SPURIOUS: usize = 0xF0;
+const REG_LVT_LINT0: usize = 0x350;
+const REG_LVT_LINT1: usize = 0x360;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const DELIVERY_EXTINT: u32 = 0b111 << 8;
+const DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// Vector the local APIC reports spurious interrupts on, its low 4 bits have to be set.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Where MSI writes land, the local APICs pick them up from there.
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+/// The mapped registers of this CPU's local APIC.
+static LOCAL_APIC: Once<VirtAddr> = Once::new();
+
+pub static DRIVER: LocalApicDriver = LocalApicDriver;
+
+fn read(register: usize) -> u32 {
+    let base = LOCAL_APIC.get().expect("Local APIC is not initialized");
+    unsafe { core::ptr::read_volatile((*base + register).as_ptr::<u32>()) }
+}
+
+fn write(register: usize, value: u32) {
+    let base = LOCAL_APIC.get().expect("Local APIC is not initialized");
+    unsafe { core::ptr::write_volatile((*base + register).as_mut_ptr::<u32>(), value) }
+}
+
+/// Enables the local APIC so it accepts message signalled interrupts. The legacy PICs keep
+/// working through LINT0, which is set to pass their interrupts through as before.
+pub fn init() {
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { base_msr.read() };
+    unsafe { base_msr.write(base | APIC_BASE_ENABLE) };
+
+    let registers = memory::map_mmio(PhysAddr::new(base & 0x000F_FFFF_FFFF_F000), 4096)
+        .expect("Failed to map the local APIC");
+    LOCAL_APIC.call_once(|| registers);
+
+    write(REG_LVT_LINT0, DELIVERY_EXTINT);
+    write(REG_LVT_LINT1, DELIVERY_NMI);
+    write(REG_TASK_PRIORITY, 0);
+    write(REG_SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+pub fn id() -> u8 {
+    (read(REG_ID) >> 24) as u8
+}
+
+/// Tells the local APIC the interrupt it delivered last was handled. Interrupts coming from
+/// the PICs are acknowledged there instead.
+pub fn end_of_interrupt() {
+    write(REG_EOI, 0);
+}
+
+/// The address and data an MSI or MSI-X capable function has to write to raise `vector` on
+/// this CPU, edge triggered with fixed delivery.
+pub fn msi_message(vector: u8) -> (u64, u32) {
+    (MSI_ADDRESS_BASE | (id() as u64) << 12, vector as u32)
+}
+
+pub struct LocalApicDriver;
+
+impl PlatformDriver for LocalApicDriver {
+    fn name(&self) -> &'static str {
+        "local-apic"
+    }
+
+    fn kind(&self) -> &'static str {
+        "lapic"
+    }
+
+    fn level(&self) -> InitLevel {
+        InitLevel::InterruptController
+    }
+
+    fn probe(&self, _device: &Arc<PlatformDevice>) -> Result<(), DeviceError> {
+        init();
+        Ok(())
+    }
+}
@@ -0,0 +1,393 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use super::interrupts as irq;
+use crate::block::{self, BlockDevice, BlockError};
+use crate::device::platform::{PlatformDevice, PlatformDriver};
+use crate::device::DeviceError;
+
+const SECTOR_SIZE: usize = 512;
+/// Most sectors a single command transfers, so the count fits LBA28's sector count register.
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+/// Sectors addressable without the 48 bit commands.
+const LBA28_LIMIT: u64 = 1 << 28;
+const TIMEOUT_MS: u64 = 5000;
+/// How often the status is polled before giving up when interrupts are off and the clock
+/// doesn't advance.
+const POLL_LIMIT: usize = 10_000_000;
+
+// registers, offsets from the channel's I/O base
+const REG_DATA: u16 = 0;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+// status bits
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+const COMMAND_READ: u8 = 0x20;
+const COMMAND_READ_EXT: u8 = 0x24;
+const COMMAND_WRITE: u8 = 0x30;
+const COMMAND_WRITE_EXT: u8 = 0x34;
+const COMMAND_FLUSH: u8 = 0xE7;
+const COMMAND_FLUSH_EXT: u8 = 0xEA;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+/// The legacy I/O and control ports and interrupt lines of the primary and secondary
+/// channels.
+pub const CHANNELS: [(u16, u16, u8); 2] = [(0x1F0, 0x3F6, 14), (0x170, 0x376, 15)];
+
+pub static DRIVER: AtaDriver = AtaDriver;
+
+/// Set by a channel's interrupt handler, cleared before every command on that channel.
+static IRQ_RECEIVED: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+struct Channel {
+    index: usize,
+    io_base: u16,
+    control_base: u16,
+    // serializes commands to the two drives sharing the channel
+    lock: Mutex<()>,
+}
+
+/// A hard disk on one of the legacy IDE channels, driven with PIO transfers.
+pub struct AtaDrive {
+    channel: Arc<Channel>,
+    /// 0 for master, 1 for slave.
+    drive: u8,
+    lba48: bool,
+    sector_count: u64,
+    pub model: String,
+}
+
+impl Channel {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.io_base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.io_base + register).write(value) }
+    }
+
+    /// Reads the status without acknowledging an interrupt.
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::new(self.control_base).read() }
+    }
+
+    /// Drives need 400ns after being selected before their status means anything, reading
+    /// the alternate status takes about 100ns.
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    fn read_data(&self, buf: &mut [u8]) {
+        let mut port: Port<u16> = Port::new(self.io_base + REG_DATA);
+        for word in buf.chunks_mut(2) {
+            word.copy_from_slice(&unsafe { port.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_data(&self, buf: &[u8]) {
+        let mut port: Port<u16> = Port::new(self.io_base + REG_DATA);
+        for word in buf.chunks(2) {
+            unsafe { port.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+
+    fn check(status: u8) -> Result<u8, BlockError> {
+        match status & (STATUS_ERR | STATUS_DF) {
+            0 => Ok(status),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    /// Polls until the drive is no longer busy.
+    fn wait_ready(&self) -> Result<u8, BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alt_status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Io)
+    }
+
+    /// Polls until the drive wants data transferred.
+    fn wait_drq(&self) -> Result<(), BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = Channel::check(self.alt_status())?;
+            if status & (STATUS_BSY | STATUS_DRQ) == STATUS_DRQ {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Io)
+    }
+
+    /// Waits for the drive to raise its interrupt at the end of a command or data block and
+    /// returns the status. Syscalls run with interrupts disabled, so there we poll instead.
+    fn wait_irq(&self) -> Result<u8, BlockError> {
+        if !interrupts::are_enabled() {
+            self.wait_ready()?;
+            // reading the status register acknowledges the interrupt we didn't take
+            return Channel::check(self.read(REG_STATUS));
+        }
+
+        let start = crate::time::uptime_ms();
+        loop {
+            // checking and halting have to be atomic, or the interrupt could arrive in between
+            // and leave us asleep until the next one
+            interrupts::disable();
+            if IRQ_RECEIVED[self.index].swap(false, Ordering::AcqRel) {
+                interrupts::enable();
+                return Channel::check(self.alt_status());
+            }
+            interrupts::enable_and_hlt();
+
+            if crate::time::uptime_ms() - start > TIMEOUT_MS {
+                return Err(BlockError::Io);
+            }
+        }
+    }
+
+    /// Selects `drive` and loads the address registers for a command on `count` sectors at
+    /// `lba`, then issues `command`.
+    fn issue(&self, drive: u8, lba48: bool, lba: u64, count: usize, command: u8) -> Result<(), BlockError> {
+        self.wait_ready()?;
+        if lba48 {
+            self.write(REG_DRIVE, 0x40 | (drive << 4));
+            self.delay();
+            // the high bytes go first, the registers are two deep
+            self.write(REG_SECTOR_COUNT, (count >> 8) as u8);
+            self.write(REG_LBA_LOW, (lba >> 24) as u8);
+            self.write(REG_LBA_MID, (lba >> 32) as u8);
+            self.write(REG_LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            self.write(REG_DRIVE, 0xE0 | (drive << 4) | ((lba >> 24) & 0x0F) as u8);
+            self.delay();
+        }
+        // a count of 0 means 256 sectors in LBA28
+        self.write(REG_SECTOR_COUNT, count as u8);
+        self.write(REG_LBA_LOW, lba as u8);
+        self.write(REG_LBA_MID, (lba >> 8) as u8);
+        self.write(REG_LBA_HIGH, (lba >> 16) as u8);
+
+        IRQ_RECEIVED[self.index].store(false, Ordering::Release);
+        self.write(REG_COMMAND, command);
+        Ok(())
+    }
+
+    /// Sends IDENTIFY DEVICE to `drive`, returning none if there's no ATA disk there. ATAPI
+    /// and SATA devices abort the command and are skipped.
+    fn identify(&self, drive: u8) -> Option<[u16; 256]> {
+        self.write(REG_DRIVE, 0xA0 | (drive << 4));
+        self.delay();
+        self.write(REG_SECTOR_COUNT, 0);
+        self.write(REG_LBA_LOW, 0);
+        self.write(REG_LBA_MID, 0);
+        self.write(REG_LBA_HIGH, 0);
+        IRQ_RECEIVED[self.index].store(false, Ordering::Release);
+        self.write(REG_COMMAND, COMMAND_IDENTIFY);
+
+        if self.alt_status() == 0 {
+            return None;
+        }
+        self.wait_ready().ok()?;
+        if self.read(REG_LBA_MID) != 0 || self.read(REG_LBA_HIGH) != 0 {
+            return None;
+        }
+        self.wait_drq().ok()?;
+
+        let mut data = [0; SECTOR_SIZE];
+        self.read_data(&mut data);
+        // acknowledge the interrupt in case it isn't taken
+        self.read(REG_STATUS);
+
+        let mut words = [0; 256];
+        for (word, bytes) in words.iter_mut().zip(data.chunks(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Some(words)
+    }
+}
+
+impl AtaDrive {
+    fn new(channel: Arc<Channel>, drive: u8, identify: &[u16; 256]) -> AtaDrive {
+        let lba48 = identify[83] & (1 << 10) != 0;
+        let sector_count = match lba48 {
+            true => identify[100..104]
+                .iter()
+                .rev()
+                .fold(0, |count, &word| (count << 16) | word as u64),
+            false => (identify[61] as u64) << 16 | identify[60] as u64,
+        };
+        // the model string stores the first character of each pair in the high byte
+        let model: Vec<u8> = identify[27..47].iter().flat_map(|word| word.to_be_bytes()).collect();
+
+        AtaDrive {
+            channel,
+            drive,
+            lba48,
+            sector_count,
+            model: String::from_utf8_lossy(&model).trim().into(),
+        }
+    }
+
+    fn check_range(&self, sector: u64, len: usize) -> Result<(), BlockError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(BlockError::BadBufferSize);
+        }
+        match sector.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.sector_count => Ok(()),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+
+    /// Whether the request ending before `end` needs the 48 bit commands.
+    fn needs_lba48(&self, end: u64) -> Result<bool, BlockError> {
+        match end > LBA28_LIMIT {
+            true if self.lba48 => Ok(true),
+            true => Err(BlockError::OutOfRange),
+            false => Ok(false),
+        }
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(sector, buf.len())?;
+        let channel = &self.channel;
+        let _lock = channel.lock.lock();
+
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let lba = sector + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            let count = chunk.len() / SECTOR_SIZE;
+            let lba48 = self.needs_lba48(lba + count as u64)?;
+            let command = if lba48 { COMMAND_READ_EXT } else { COMMAND_READ };
+            channel.issue(self.drive, lba48, lba, count, command)?;
+
+            // the drive interrupts once each sector is ready to be read
+            for data in chunk.chunks_mut(SECTOR_SIZE) {
+                channel.wait_irq()?;
+                channel.wait_drq()?;
+                channel.read_data(data);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_range(sector, buf.len())?;
+        let channel = &self.channel;
+        let _lock = channel.lock.lock();
+
+        for (i, chunk) in buf.chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let lba = sector + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            let count = chunk.len() / SECTOR_SIZE;
+            let lba48 = self.needs_lba48(lba + count as u64)?;
+            let command = if lba48 { COMMAND_WRITE_EXT } else { COMMAND_WRITE };
+            channel.issue(self.drive, lba48, lba, count, command)?;
+
+            // the first sector is sent as soon as the drive asks, after that it interrupts
+            // once it has taken each one
+            for data in chunk.chunks(SECTOR_SIZE) {
+                channel.wait_drq()?;
+                channel.write_data(data);
+                channel.wait_irq()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let channel = &self.channel;
+        let _lock = channel.lock.lock();
+        let command = if self.lba48 { COMMAND_FLUSH_EXT } else { COMMAND_FLUSH };
+        channel.issue(self.drive, false, 0, 0, command)?;
+        channel.wait_irq()?;
+        Ok(())
+    }
+}
+
+/// Called from the interrupt handler of `channel`. Reading the status register tells the drive
+/// its interrupt was seen.
+pub fn handle_interrupt(channel: usize) {
+    let mut status: Port<u8> = Port::new(CHANNELS[channel].0 + REG_STATUS);
+    unsafe { status.read() };
+    IRQ_RECEIVED[channel].store(true, Ordering::Release);
+}
+
+/// Drives the disks on one of the legacy IDE channels, registering them as "ata0" to "ata3",
+/// the primary master first.
+pub struct AtaDriver;
+
+impl PlatformDriver for AtaDriver {
+    fn name(&self) -> &'static str {
+        "ata-pio"
+    }
+
+    fn kind(&self) -> &'static str {
+        "ata"
+    }
+
+    fn probe(&self, device: &Arc<PlatformDevice>) -> Result<(), DeviceError> {
+        // the interrupt handlers only know the two legacy channels
+        if device.index >= CHANNELS.len() {
+            return Err(DeviceError::Unsupported);
+        }
+        let channel = Arc::new(Channel {
+            index: device.index,
+            io_base: device.io_ports(0)?,
+            control_base: device.io_ports(1)?,
+            lock: Mutex::new(()),
+        });
+        // a floating bus reads as all ones, there's no controller on it
+        if channel.read(REG_STATUS) == 0xFF {
+            return Err(DeviceError::Unsupported);
+        }
+        irq::unmask_irq(device.irq()?);
+
+        for drive in 0..2 {
+            let identify = match channel.identify(drive) {
+                Some(identify) => identify,
+                None => continue,
+            };
+            let ata = AtaDrive::new(channel.clone(), drive, &identify);
+            if ata.sector_count == 0 {
+                continue;
+            }
+
+            let name = format!("ata{}", channel.index * 2 + drive as usize);
+            let model = ata.model.clone();
+            if block::register(&name, Arc::new(ata)).is_ok() {
+                crate::log(&format!("Found {}: {}", name, model));
+            }
+        }
+        Ok(())
+    }
+}
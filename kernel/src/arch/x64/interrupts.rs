@@ -1,13 +1,21 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
 use core::cell::OnceCell;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use pc_keyboard::{Keyboard, layouts, ScancodeSet1, HandleControl};
 use spin::Lazy;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::PrivilegeLevel;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 use pic8259::ChainedPics;
 
-use crate::{println, print};
+use crate::device::platform::{PlatformDevice, PlatformDriver};
+use crate::device::{DeviceError, InitLevel};
+use crate::println;
 
-use super::gdt;
+use super::{apic, ata, gdt, syscall};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -15,13 +23,41 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// The vectors handed out to drivers by `allocate_vectors`, everything between the PICs and
+/// the vectors the local APIC reserves for itself.
+pub const DYNAMIC_VECTORS_START: u8 = 0x30;
+pub const DYNAMIC_VECTORS_END: u8 = 0xEF;
+
+/// Called with the vector that fired.
+pub type InterruptHandler = Box<dyn Fn(u8) + Send + Sync>;
+
+/// Allocated dynamic vectors and their handlers, none for allocated vectors without one.
+///
+/// Only locked with interrupts disabled, since the handlers are called with it held.
+static DYNAMIC_HANDLERS: spin::Mutex<BTreeMap<u8, Option<InterruptHandler>>> =
+    spin::Mutex::new(BTreeMap::new());
+
 pub static KEYBOARD: spin::Mutex<OnceCell<Keyboard<layouts::Us104Key, ScancodeSet1>>> = spin::Mutex::new(OnceCell::new());
 
+/// Whether a shift key is down, which the keyboard doesn't tell for keys without a shifted
+/// character.
+static SHIFT_HELD: AtomicBool = AtomicBool::new(false);
+/// Whether an alt key is down, the keyboard doesn't tell at all.
+static ALT_HELD: AtomicBool = AtomicBool::new(false);
+
+pub static PIC_DRIVER: PicDriver = PicDriver;
+pub static KEYBOARD_DRIVER: KeyboardDriver = KeyboardDriver;
+
+/// The line of the second PIC into the first.
+const CASCADE_IRQ: u8 = 2;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
-    Keyboard
+    Keyboard,
+    PrimaryAta = PIC_2_OFFSET + 6,
+    SecondaryAta,
 }
 
 impl InterruptIndex {
@@ -34,16 +70,35 @@ impl InterruptIndex {
     }
 }
 
+/// Points every vector from `row * 16` to `row * 16 + 15` of each row at its own instance of
+/// `dynamic_interrupt_handler`.
+macro_rules! set_dynamic_handlers {
+    ($idt:ident, $($row:literal),*) => {$(
+        set_dynamic_handlers!(@row $idt, $row, 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+    )*};
+    (@row $idt:ident, $row:literal, $($column:literal)*) => {$(
+        $idt[$row * 16 + $column].set_handler_fn(dynamic_interrupt_handler::<{ $row * 16 + $column }>);
+    )*};
+}
+
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
+    // the system call vector is in the dynamic range and gets overwritten below
+    set_dynamic_handlers!(idt, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14);
     idt.breakpoint.set_handler_fn(breakpoint_handler);
     unsafe {
         idt.general_protection_fault.set_handler_fn(general_protection_handler);
         idt.double_fault.set_handler_fn(double_fault_handler)
             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt[syscall::SYSCALL_VECTOR as usize].set_handler_addr(syscall::entry_address())
+            .set_privilege_level(PrivilegeLevel::Ring3);
     };
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
     idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
     idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+    idt[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(primary_ata_interrupt_handler);
+    idt[InterruptIndex::SecondaryAta.as_usize()].set_handler_fn(secondary_ata_interrupt_handler);
     idt
 });
 
@@ -52,18 +107,90 @@ pub fn init_idt() {
 }
 
 pub fn init_pic8529() {
-    KEYBOARD.lock().get_or_init(|| {
-        Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore)
-    });
     unsafe {
         PICS.lock().initialize();
-        PICS.lock().write_masks(0xfc, 0xff);
+        // everything masked but the cascade, drivers unmask the lines of their devices
+        PICS.lock().write_masks(!(1 << CASCADE_IRQ), 0xff);
+    }
+}
+
+/// Lets legacy interrupt line `irq` through the PICs.
+pub fn unmask_irq(irq: u8) {
+    set_irq_masked(irq, false);
+}
+
+pub fn mask_irq(irq: u8) {
+    set_irq_masked(irq, true);
+}
+
+fn set_irq_masked(irq: u8, masked: bool) {
+    interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let mut masks = unsafe { pics.read_masks() };
+        let (pic, bit) = ((irq / 8) as usize, irq % 8);
+        match masked {
+            true => masks[pic] |= 1 << bit,
+            false => masks[pic] &= !(1 << bit),
+        }
+        unsafe { pics.write_masks(masks[0], masks[1]) };
+    });
+}
+
+/// The two chained 8259 PICs.
+pub struct PicDriver;
+
+impl PlatformDriver for PicDriver {
+    fn name(&self) -> &'static str {
+        "i8259"
+    }
+
+    fn kind(&self) -> &'static str {
+        "pic"
+    }
+
+    fn level(&self) -> InitLevel {
+        InitLevel::InterruptController
+    }
+
+    fn probe(&self, _device: &Arc<PlatformDevice>) -> Result<(), DeviceError> {
+        init_pic8529();
+        Ok(())
+    }
+}
+
+/// The PS/2 keyboard, which prints what's typed.
+pub struct KeyboardDriver;
+
+impl PlatformDriver for KeyboardDriver {
+    fn name(&self) -> &'static str {
+        "ps2-keyboard"
+    }
+
+    fn kind(&self) -> &'static str {
+        "keyboard"
+    }
+
+    fn probe(&self, device: &Arc<PlatformDevice>) -> Result<(), DeviceError> {
+        let irq = device.irq()?;
+        KEYBOARD.lock().get_or_init(|| {
+            Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore)
+        });
+        unmask_irq(irq);
+        Ok(())
+    }
+
+    fn remove(&self, device: &Arc<PlatformDevice>) -> Result<(), DeviceError> {
+        mask_irq(device.irq()?);
+        interrupts::without_interrupts(|| KEYBOARD.lock().take());
+        Ok(())
     }
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
+    crate::time::tick();
+
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
@@ -74,18 +201,47 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
     use x86_64::instructions::port::Port;
-    use pc_keyboard::DecodedKey;
+    use core::fmt::Write;
+    use pc_keyboard::{DecodedKey, KeyCode, KeyState};
+    use crate::display::{CONSOLES, LOG_CONSOLE};
 
     let mut kb_lock = KEYBOARD.lock();
-    let keyboard = kb_lock.get_mut().unwrap();
     let mut port = Port::new(0x60);
 
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
+    // the keyboard can still interrupt once right after its driver let go of it
+    if let Some(keyboard) = kb_lock.get_mut()
+        && let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+        match key_event.code {
+            KeyCode::LShift | KeyCode::RShift => {
+                SHIFT_HELD.store(key_event.state == KeyState::Down, Ordering::Relaxed);
+            }
+            KeyCode::LAlt | KeyCode::RAltGr => {
+                ALT_HELD.store(key_event.state == KeyState::Down, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+        if let Some(key) = keyboard.process_keyevent(key_event)
+            && let Some(consoles) = CONSOLES.lock().get_mut() {
+            let shift = SHIFT_HELD.load(Ordering::Relaxed);
+            let alt = ALT_HELD.load(Ordering::Relaxed);
             match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
+                // alt and a function key switch to that console
+                DecodedKey::RawKey(KeyCode::F1) if alt => consoles.switch(0),
+                DecodedKey::RawKey(KeyCode::F2) if alt => consoles.switch(1),
+                DecodedKey::RawKey(KeyCode::F3) if alt => consoles.switch(2),
+                DecodedKey::RawKey(KeyCode::F4) if alt => consoles.switch(3),
+                DecodedKey::RawKey(KeyCode::F5) if alt => consoles.switch(4),
+                DecodedKey::RawKey(KeyCode::F6) if alt => consoles.switch(5),
+                // shift and page up or down scroll the console through its history
+                DecodedKey::RawKey(KeyCode::PageUp) if shift => consoles.active_console().page_up(),
+                DecodedKey::RawKey(KeyCode::PageDown) if shift => consoles.active_console().page_down(),
+                DecodedKey::Unicode(character) => consoles.type_char(character),
+                // the log console is left to the log
+                DecodedKey::RawKey(_) if consoles.active() == LOG_CONSOLE => {}
+                DecodedKey::RawKey(key) => {
+                    let _ = write!(consoles.active_console(), "{:?}", key);
+                }
             }
         }
     }
@@ -96,6 +252,87 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     }
 }
 
+extern "x86-interrupt" fn primary_ata_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    ata::handle_interrupt(0);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::PrimaryAta.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn secondary_ata_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    ata::handle_interrupt(1);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SecondaryAta.as_u8());
+    }
+}
+
+/// Reserves `count` consecutive dynamic vectors, starting at a multiple of `count` rounded up
+/// to a power of two like multi-message MSI needs. Returns the first one.
+pub fn allocate_vectors(count: usize) -> Option<u8> {
+    if count == 0 {
+        return None;
+    }
+    let align = count.checked_next_power_of_two()?;
+    interrupts::without_interrupts(|| {
+        let mut handlers = DYNAMIC_HANDLERS.lock();
+        let start = (DYNAMIC_VECTORS_START as usize + align - 1) / align * align;
+        let first = (start..=DYNAMIC_VECTORS_END as usize + 1 - count)
+            .step_by(align)
+            .find(|&first| {
+                (first..first + count).all(|vector| {
+                    vector != syscall::SYSCALL_VECTOR as usize && !handlers.contains_key(&(vector as u8))
+                })
+            })? as u8;
+        for vector in first..first + count as u8 {
+            handlers.insert(vector, None);
+        }
+        Some(first)
+    })
+}
+
+/// Calls `handler` whenever `vector`, which must have been allocated, fires.
+pub fn set_handler(vector: u8, handler: InterruptHandler) {
+    interrupts::without_interrupts(|| {
+        if let Some(slot) = DYNAMIC_HANDLERS.lock().get_mut(&vector) {
+            *slot = Some(handler);
+        }
+    });
+}
+
+/// Gives back `count` vectors starting at `first`, along with their handlers.
+pub fn free_vectors(first: u8, count: usize) {
+    interrupts::without_interrupts(|| {
+        let mut handlers = DYNAMIC_HANDLERS.lock();
+        for vector in first..first + count as u8 {
+            handlers.remove(&vector);
+        }
+    });
+}
+
+extern "x86-interrupt" fn dynamic_interrupt_handler<const VECTOR: u8>(
+    _stack_frame: InterruptStackFrame)
+{
+    if let Some(Some(handler)) = DYNAMIC_HANDLERS.lock().get(&VECTOR) {
+        handler(VECTOR);
+    }
+
+    // dynamic vectors only ever come from message signalled interrupts through the local APIC
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    // spurious interrupts aren't acknowledged
+}
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("INTERRUPT: BREAKPOINT\n{:#?}", stack_frame);
@@ -105,6 +342,19 @@ extern "x86-interrupt" fn general_protection_handler(stack_frame: InterruptStack
     println!("INTERRUPT: GENERAL PROTECTION\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode)
+{
+    use x86_64::registers::control::Cr2;
+
+    let address = Cr2::read();
+    if let Err(err) = crate::memory::handle_page_fault(address, error_code) {
+        panic!("INTERRUPT: PAGE FAULT\nAddress: {:?}\nError: {:?} ({:?})\n{:#?}",
+            address, err, error_code, stack_frame);
+    }
+}
+
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _code: u64) -> ! {
     panic!("INTERRUPT: DOUBLE FAULT\n{:#?}", stack_frame);
 }
\ No newline at end of file
@@ -1,7 +1,15 @@
+pub mod apic;
+pub mod ata;
 pub mod interrupts;
 pub mod gdt;
+pub mod pit;
+pub mod rtc;
+pub mod syscall;
+
+use alloc::vec;
 
 use super::QemuExitCode;
+use crate::device::platform::{self, PlatformDevice, Resource};
 
 pub fn exit_qemu(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
@@ -11,3 +19,28 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
         port.write(exit_code as u32);
     }
 }
+
+/// Registers the drivers of the devices every PC has and the devices themselves, interrupt
+/// controllers first. They probe as `device::init_level` reaches their level.
+pub fn register_platform() {
+    platform::register_driver(&interrupts::PIC_DRIVER);
+    platform::register_driver(&apic::DRIVER);
+    platform::register_driver(&pit::DRIVER);
+    platform::register_driver(&rtc::DRIVER);
+    platform::register_driver(&crate::serial::DRIVER);
+    platform::register_driver(&interrupts::KEYBOARD_DRIVER);
+    platform::register_driver(&ata::DRIVER);
+
+    let ports = |start, count| Resource::IoPorts { start, count };
+    platform::register_device(PlatformDevice::new("pic", 0, vec![ports(0x20, 2), ports(0xA0, 2)]));
+    platform::register_device(PlatformDevice::new("lapic", 0, vec![]));
+    platform::register_device(PlatformDevice::new("pit", 0, vec![ports(0x40, 4), Resource::Irq(0)]));
+    platform::register_device(PlatformDevice::new("rtc", 0, vec![ports(0x70, 2)]));
+    platform::register_device(PlatformDevice::new("serial", 0, vec![ports(0x3F8, 8), Resource::Irq(4)]));
+    let keyboard = vec![ports(0x60, 1), ports(0x64, 1), Resource::Irq(1)];
+    platform::register_device(PlatformDevice::new("keyboard", 0, keyboard));
+    for (index, &(io_base, control_base, irq)) in ata::CHANNELS.iter().enumerate() {
+        let resources = vec![ports(io_base, 8), ports(control_base, 1), Resource::Irq(irq)];
+        platform::register_device(PlatformDevice::new("ata", index, resources));
+    }
+}
@@ -0,0 +1,49 @@
+use alloc::sync::Arc;
+
+use x86_64::instructions::port::Port;
+
+use super::interrupts;
+use crate::device::platform::{PlatformDevice, PlatformDriver};
+use crate::device::{DeviceError, InitLevel};
+
+/// Frequency of the PIT's input clock in Hz.
+const BASE_FREQUENCY: u32 = 1_193_182;
+
+pub static DRIVER: PitDriver = PitDriver;
+
+/// Programs channel 0 of the PIT to fire the timer interrupt `frequency` times per second.
+pub fn init_pit(frequency: u32) {
+    let divisor = (BASE_FREQUENCY / frequency).clamp(1, u16::MAX as u32) as u16;
+
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel0: Port<u8> = Port::new(0x40);
+    unsafe {
+        // channel 0, lobyte/hibyte access, mode 2 (rate generator), binary
+        command.write(0b0011_0100);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+}
+
+/// The PIT drives the timer interrupt the kernel keeps time with.
+pub struct PitDriver;
+
+impl PlatformDriver for PitDriver {
+    fn name(&self) -> &'static str {
+        "i8254"
+    }
+
+    fn kind(&self) -> &'static str {
+        "pit"
+    }
+
+    fn level(&self) -> InitLevel {
+        InitLevel::Clock
+    }
+
+    fn probe(&self, device: &Arc<PlatformDevice>) -> Result<(), DeviceError> {
+        init_pit(crate::time::TICK_HZ as u32);
+        interrupts::unmask_irq(device.irq()?);
+        Ok(())
+    }
+}
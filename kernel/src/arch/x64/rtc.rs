@@ -0,0 +1,120 @@
+use alloc::sync::Arc;
+
+use x86_64::instructions::port::Port;
+
+use crate::device::platform::{PlatformDevice, PlatformDriver};
+use crate::device::{DeviceError, InitLevel};
+use crate::time::DateTime;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+pub static DRIVER: RtcDriver = RtcDriver;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RtcTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    unsafe {
+        // keep NMIs disabled while poking at the CMOS
+        address.write(0x80 | register);
+        data.read()
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(0x0A) & 0x80 != 0
+}
+
+fn read_raw() -> RtcTime {
+    while update_in_progress() {}
+    RtcTime {
+        second: read_register(0x00),
+        minute: read_register(0x02),
+        hour: read_register(0x04),
+        day: read_register(0x07),
+        month: read_register(0x08),
+        year: read_register(0x09),
+        // most firmware puts the century here, the ACPI FADT would tell us for sure
+        century: read_register(0x32),
+    }
+}
+
+/// Reads the real time clock, returning seconds since the Unix epoch.
+pub fn read_unix_time() -> u64 {
+    // the clock can update between reading two registers, so read until we get the same
+    // value twice in a row
+    let mut time = read_raw();
+    loop {
+        let again = read_raw();
+        if again == time {
+            break;
+        }
+        time = again;
+    }
+
+    let status_b = read_register(0x0B);
+    let bcd = status_b & 0x04 == 0;
+    let hour_12 = status_b & 0x02 == 0;
+    let from_bcd = |value: u8| match bcd {
+        true => (value & 0x0F) + (value >> 4) * 10,
+        false => value,
+    };
+
+    let pm = time.hour & 0x80 != 0;
+    let mut hour = from_bcd(time.hour & 0x7F);
+    if hour_12 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = match from_bcd(time.century) {
+        century @ 19..=21 => century as u64,
+        _ => 20,
+    };
+    let year = century * 100 + from_bcd(time.year) as u64;
+
+    DateTime {
+        year,
+        month: from_bcd(time.month),
+        day: from_bcd(time.day),
+        hour,
+        minute: from_bcd(time.minute),
+        second: from_bcd(time.second),
+    }
+    .to_unix()
+}
+
+/// The real time clock, which the wall clock time is read from once at boot.
+pub struct RtcDriver;
+
+impl PlatformDriver for RtcDriver {
+    fn name(&self) -> &'static str {
+        "cmos-rtc"
+    }
+
+    fn kind(&self) -> &'static str {
+        "rtc"
+    }
+
+    fn level(&self) -> InitLevel {
+        InitLevel::Clock
+    }
+
+    fn probe(&self, _device: &Arc<PlatformDevice>) -> Result<(), DeviceError> {
+        crate::time::init(read_unix_time());
+        Ok(())
+    }
+}
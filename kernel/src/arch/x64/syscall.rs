@@ -0,0 +1,67 @@
+use core::arch::global_asm;
+
+use x86_64::VirtAddr;
+
+/// Interrupt vector used for system calls, reachable from ring 3.
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// Registers saved by `syscall_entry`, in the order they end up on the stack.
+///
+/// The system call number is passed in rax and the arguments in rdi, rsi, rdx, r10, r8 and r9
+/// like on Linux. The result is returned in rax.
+#[repr(C)]
+struct SyscallFrame {
+    rax: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+}
+
+// The CPU already pushed ss, rsp, rflags, cs and rip, so all we need to do is save the
+// caller-saved registers and hand a pointer to them to `syscall_dispatch`.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "push r11",
+    "push r10",
+    "push r9",
+    "push r8",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push rcx",
+    "push rax",
+    "mov rdi, rsp",
+    "cld",
+    "call syscall_dispatch",
+    "pop rax",
+    "pop rcx",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop r8",
+    "pop r9",
+    "pop r10",
+    "pop r11",
+    "iretq",
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    frame.rax = crate::syscall::dispatch(frame.rax, args) as u64;
+}
+
+/// Address of the assembly stub to install in the IDT for `SYSCALL_VECTOR`.
+pub fn entry_address() -> VirtAddr {
+    VirtAddr::from_ptr(syscall_entry as *const ())
+}
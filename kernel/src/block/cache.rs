@@ -0,0 +1,179 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::{BlockDevice, BlockError};
+
+/// Bytes the cache reads and writes at a time, unless sectors are bigger.
+const CACHE_BLOCK_SIZE: usize = 4096;
+
+struct CacheBlock {
+    data: Vec<u8>,
+    dirty: bool,
+    // when the block was last used, the key of its entry in `lru`
+    stamp: u64,
+}
+
+struct CacheState {
+    blocks: BTreeMap<u64, CacheBlock>,
+    // cache block numbers ordered from least to most recently used
+    lru: BTreeMap<u64, u64>,
+    next_stamp: u64,
+}
+
+/// A write-back cache in front of a block device. Writes stay in memory until they're
+/// evicted or the cache is flushed.
+pub struct BufferCache {
+    device: Arc<dyn BlockDevice>,
+    // sectors per cache block
+    block_sectors: u64,
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+impl BufferCache {
+    /// Caches up to `capacity` blocks of `device`.
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> BufferCache {
+        let block_sectors = usize::max(CACHE_BLOCK_SIZE / device.sector_size(), 1) as u64;
+        BufferCache {
+            device,
+            block_sectors,
+            capacity: usize::max(capacity, 1),
+            state: Mutex::new(CacheState {
+                blocks: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                next_stamp: 0,
+            }),
+        }
+    }
+
+    /// The first sector and number of sectors in cache block `index`, the last block of the
+    /// device may be short.
+    fn block_range(&self, index: u64) -> (u64, u64) {
+        let first = index * self.block_sectors;
+        (first, u64::min(self.block_sectors, self.device.sector_count() - first))
+    }
+
+    /// Makes sure cache block `index` is present and marks it as most recently used. Blocks
+    /// that are about to be completely overwritten don't need to be read in.
+    fn load<'a>(&self, state: &'a mut CacheState, index: u64, overwrite: bool) -> Result<&'a mut CacheBlock, BlockError> {
+        let stamp = state.next_stamp;
+        state.next_stamp += 1;
+
+        if let Some(block) = state.blocks.get_mut(&index) {
+            state.lru.remove(&block.stamp);
+            state.lru.insert(stamp, index);
+            block.stamp = stamp;
+            return Ok(state.blocks.get_mut(&index).unwrap());
+        }
+
+        if state.blocks.len() >= self.capacity {
+            self.evict(state)?;
+        }
+
+        let (first, count) = self.block_range(index);
+        let mut data = vec![0; count as usize * self.device.sector_size()];
+        if !overwrite {
+            self.device.read_sectors(first, &mut data)?;
+        }
+        state.lru.insert(stamp, index);
+        Ok(state.blocks.entry(index).or_insert(CacheBlock {
+            data,
+            dirty: false,
+            stamp,
+        }))
+    }
+
+    /// Drops the least recently used block, writing it back first if it's dirty.
+    fn evict(&self, state: &mut CacheState) -> Result<(), BlockError> {
+        let (&stamp, &index) = match state.lru.iter().next() {
+            Some(oldest) => oldest,
+            None => return Ok(()),
+        };
+
+        let block = &state.blocks[&index];
+        if block.dirty {
+            self.device.write_sectors(self.block_range(index).0, &block.data)?;
+        }
+        state.lru.remove(&stamp);
+        state.blocks.remove(&index);
+        Ok(())
+    }
+
+    /// Calls `f` with each cache block touched by a request for `buf_len` bytes starting
+    /// at `sector`, along with the byte range within the block and within the request.
+    fn for_each_block(
+        &self,
+        sector: u64,
+        buf_len: usize,
+        mut f: impl FnMut(u64, core::ops::Range<usize>, core::ops::Range<usize>) -> Result<(), BlockError>,
+    ) -> Result<(), BlockError> {
+        let sector_size = self.device.sector_size();
+        if buf_len % sector_size != 0 {
+            return Err(BlockError::BadBufferSize);
+        }
+        let sectors = (buf_len / sector_size) as u64;
+        if sector.checked_add(sectors).map_or(true, |end| end > self.device.sector_count()) {
+            return Err(BlockError::OutOfRange);
+        }
+
+        let mut done = 0;
+        while done < sectors {
+            let current = sector + done;
+            let index = current / self.block_sectors;
+            let within = current % self.block_sectors;
+            let count = u64::min(self.block_range(index).1 - within, sectors - done);
+
+            let block_bytes = within as usize * sector_size..(within + count) as usize * sector_size;
+            let buf_bytes = done as usize * sector_size..(done + count) as usize * sector_size;
+            f(index, block_bytes, buf_bytes)?;
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for BufferCache {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let mut state = self.state.lock();
+        self.for_each_block(sector, buf.len(), |index, block_bytes, buf_bytes| {
+            let block = self.load(&mut state, index, false)?;
+            buf[buf_bytes].copy_from_slice(&block.data[block_bytes]);
+            Ok(())
+        })
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let mut state = self.state.lock();
+        self.for_each_block(sector, buf.len(), |index, block_bytes, buf_bytes| {
+            let overwrite = block_bytes.start == 0 && block_bytes.end == self.block_range(index).1 as usize * self.sector_size();
+            let block = self.load(&mut state, index, overwrite)?;
+            block.data[block_bytes].copy_from_slice(&buf[buf_bytes]);
+            block.dirty = true;
+            Ok(())
+        })
+    }
+
+    /// Writes back every dirty block in order, then flushes the device.
+    fn flush(&self) -> Result<(), BlockError> {
+        let mut state = self.state.lock();
+        for (&index, block) in state.blocks.iter_mut() {
+            if block.dirty {
+                self.device.write_sectors(index * self.block_sectors, &block.data)?;
+                block.dirty = false;
+            }
+        }
+        self.device.flush()
+    }
+}
@@ -0,0 +1,141 @@
+pub mod cache;
+pub mod partition;
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use cache::BufferCache;
+
+/// How many 4 KiB blocks of each registered disk are kept in memory.
+const CACHE_BLOCKS: usize = 256;
+
+static DEVICES: Mutex<Vec<DeviceInfo>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the end of the device.
+    OutOfRange,
+    /// The buffer isn't a multiple of the sector size.
+    BadBufferSize,
+    ReadOnly,
+    /// The device reported an error or didn't respond.
+    Io,
+}
+
+/// A device addressed in fixed size sectors, like a disk or a partition on one.
+pub trait BlockDevice: Send + Sync {
+    /// Size of a sector in bytes.
+    fn sector_size(&self) -> usize;
+
+    fn sector_count(&self) -> u64;
+
+    /// Reads `buf.len() / sector_size()` sectors starting at `sector` into `buf`.
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf.len() / sector_size()` sectors starting at `sector` from `buf`.
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes sure everything written so far has reached the medium.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+impl dyn BlockDevice {
+    /// Reads `buf.len()` bytes starting at byte `offset`, which doesn't need to be aligned to
+    /// sectors.
+    pub fn read_bytes(&self, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let sector_size = self.sector_size();
+        let mut sector_buf = vec![0; sector_size];
+        let mut done = 0;
+
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let sector = position / sector_size as u64;
+            let within = (position % sector_size as u64) as usize;
+            let len = usize::min(sector_size - within, buf.len() - done);
+
+            self.read_sectors(sector, &mut sector_buf)?;
+            buf[done..done + len].copy_from_slice(&sector_buf[within..within + len]);
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `buf` starting at byte `offset`, reading back partially covered sectors first.
+    pub fn write_bytes(&self, offset: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let sector_size = self.sector_size();
+        let mut sector_buf = vec![0; sector_size];
+        let mut done = 0;
+
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let sector = position / sector_size as u64;
+            let within = (position % sector_size as u64) as usize;
+            let len = usize::min(sector_size - within, buf.len() - done);
+
+            if len != sector_size {
+                self.read_sectors(sector, &mut sector_buf)?;
+            }
+            sector_buf[within..within + len].copy_from_slice(&buf[done..done + len]);
+            self.write_sectors(sector, &sector_buf)?;
+            done += len;
+        }
+
+        Ok(())
+    }
+}
+
+/// Describes one registered block device, a whole disk or a partition on one.
+#[derive(Clone)]
+pub struct DeviceInfo {
+    /// Like "ata0" for a disk, or "ata0p1" for its first partition.
+    pub name: String,
+    pub device: Arc<dyn BlockDevice>,
+    /// Whether this is a whole disk with a partition table, in which case only its
+    /// partitions hold filesystems.
+    pub partitioned: bool,
+}
+
+/// Makes a disk available to the rest of the kernel under `name`. Every storage driver hands
+/// its disks to this, which puts a buffer cache in front of them and registers each
+/// partition found on them as its own device. Returns the cached disk.
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) -> Result<Arc<dyn BlockDevice>, BlockError> {
+    let disk: Arc<dyn BlockDevice> = Arc::new(BufferCache::new(device, CACHE_BLOCKS));
+    let partitions = partition::scan(&disk)?;
+
+    let mut devices = DEVICES.lock();
+    devices.push(DeviceInfo {
+        name: name.to_string(),
+        device: disk.clone(),
+        partitioned: !partitions.is_empty(),
+    });
+    for partition in partitions {
+        devices.push(DeviceInfo {
+            name: format!("{}p{}", name, partition.number),
+            device: Arc::new(partition),
+            partitioned: false,
+        });
+    }
+
+    Ok(disk)
+}
+
+/// Every registered disk and partition, in the order they were found.
+pub fn devices() -> Vec<DeviceInfo> {
+    DEVICES.lock().clone()
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|info| info.name == name)
+        .map(|info| info.device.clone())
+}
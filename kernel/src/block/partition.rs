@@ -0,0 +1,299 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{BlockDevice, BlockError};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+// MBR partition types
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+/// How many logical partitions we follow in an extended partition before giving up.
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// What kind of partition table an entry came from, and its type in that table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    Mbr { partition_type: u8 },
+    Gpt { type_guid: [u8; 16], name: String },
+}
+
+/// A range of sectors of another block device, usable as a block device of its own.
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    first_sector: u64,
+    sector_count: u64,
+    /// 1 based position in the partition table, logical MBR partitions start at 5.
+    pub number: usize,
+    pub kind: PartitionKind,
+}
+
+impl Partition {
+    fn check(&self, sector: u64, len: usize) -> Result<u64, BlockError> {
+        if len % self.device.sector_size() != 0 {
+            return Err(BlockError::BadBufferSize);
+        }
+        let sectors = (len / self.device.sector_size()) as u64;
+        match sector.checked_add(sectors) {
+            Some(end) if end <= self.sector_count => Ok(self.first_sector + sector),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+}
+
+impl BlockDevice for Partition {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let sector = self.check(sector, buf.len())?;
+        self.device.read_sectors(sector, buf)
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let sector = self.check(sector, buf.len())?;
+        self.device.write_sectors(sector, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
+
+/// Reads the partition table of `device`, GPT if there is one and MBR otherwise. A device
+/// without a recognizable table has no partitions.
+pub fn scan(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, BlockError> {
+    let mut sector = vec![0; device.sector_size()];
+    device.read_sectors(0, &mut sector)?;
+    let entries = match parse_mbr(device, &sector) {
+        Some(entries) => entries,
+        None => return Ok(Vec::new()),
+    };
+
+    // a protective MBR covering the disk means the real table is a GPT
+    if entries.iter().any(|entry| entry.partition_type == MBR_TYPE_GPT_PROTECTIVE) {
+        return scan_gpt(device);
+    }
+
+    // primary partitions are numbered by their slot, logical ones from 5 on
+    let mut partitions = Vec::new();
+    let mut logical = Vec::new();
+    for entry in entries {
+        if MBR_TYPES_EXTENDED.contains(&entry.partition_type) {
+            scan_extended(device, entry.first_sector, &mut logical)?;
+            continue;
+        }
+        partitions.push(entry);
+    }
+    for (i, mut entry) in logical.into_iter().enumerate() {
+        entry.slot = 4 + i;
+        partitions.push(entry);
+    }
+
+    Ok(partitions
+        .into_iter()
+        .map(|entry| Partition {
+            device: device.clone(),
+            first_sector: entry.first_sector,
+            sector_count: entry.sector_count,
+            number: entry.slot + 1,
+            kind: PartitionKind::Mbr {
+                partition_type: entry.partition_type,
+            },
+        })
+        .collect())
+}
+
+struct MbrEntry {
+    /// Index into the table the entry came from.
+    slot: usize,
+    partition_type: u8,
+    first_sector: u64,
+    sector_count: u64,
+}
+
+/// Parses the four entries of an MBR or extended boot record, with sectors relative to
+/// wherever the record says they're relative to. Returns none if the sector doesn't look
+/// like one, e.g. because it's the boot sector of an unpartitioned FAT volume.
+fn parse_mbr_entries(sector: &[u8]) -> Option<Vec<MbrEntry>> {
+    if sector[510..512] != MBR_SIGNATURE {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    let table = &sector[MBR_ENTRIES_OFFSET..MBR_ENTRIES_OFFSET + 4 * MBR_ENTRY_SIZE];
+    for (slot, raw) in table.chunks(MBR_ENTRY_SIZE).enumerate() {
+        let status = raw[0];
+        let partition_type = raw[4];
+        let first_sector = u32::from_le_bytes(raw[8..12].try_into().unwrap()) as u64;
+        let sector_count = u32::from_le_bytes(raw[12..16].try_into().unwrap()) as u64;
+
+        // only bootable and inactive are valid states
+        if status != 0x00 && status != 0x80 {
+            return None;
+        }
+        if partition_type == MBR_TYPE_EMPTY || sector_count == 0 {
+            continue;
+        }
+        entries.push(MbrEntry {
+            slot,
+            partition_type,
+            first_sector,
+            sector_count,
+        });
+    }
+    Some(entries)
+}
+
+fn fits(device: &Arc<dyn BlockDevice>, entry: &MbrEntry) -> bool {
+    entry.first_sector != 0 && entry.first_sector + entry.sector_count <= device.sector_count()
+}
+
+fn parse_mbr(device: &Arc<dyn BlockDevice>, sector: &[u8]) -> Option<Vec<MbrEntry>> {
+    let entries = parse_mbr_entries(sector)?;
+    // protective entries may claim more than the disk has
+    let valid = entries
+        .iter()
+        .all(|entry| entry.partition_type == MBR_TYPE_GPT_PROTECTIVE || fits(device, entry));
+    match valid && !entries.is_empty() {
+        true => Some(entries),
+        false => None,
+    }
+}
+
+/// Follows the chain of extended boot records in the extended partition starting at
+/// `extended_start`, adding each logical partition.
+fn scan_extended(
+    device: &Arc<dyn BlockDevice>,
+    extended_start: u64,
+    partitions: &mut Vec<MbrEntry>,
+) -> Result<(), BlockError> {
+    let mut sector = vec![0; device.sector_size()];
+    let mut ebr = extended_start;
+    for _ in 0..MAX_LOGICAL_PARTITIONS {
+        device.read_sectors(ebr, &mut sector)?;
+        let entries = match parse_mbr_entries(&sector) {
+            Some(entries) => entries,
+            None => return Ok(()),
+        };
+
+        // the logical partition is relative to this EBR, the link to the next EBR is
+        // relative to the start of the extended partition
+        let mut next = None;
+        for mut entry in entries {
+            if MBR_TYPES_EXTENDED.contains(&entry.partition_type) {
+                next = Some(extended_start + entry.first_sector);
+                continue;
+            }
+            entry.first_sector += ebr;
+            if fits(device, &entry) {
+                partitions.push(entry);
+            }
+        }
+        match next {
+            Some(next) if next > ebr && next < device.sector_count() => ebr = next,
+            _ => return Ok(()),
+        }
+    }
+    Ok(())
+}
+
+fn scan_gpt(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, BlockError> {
+    let sector_size = device.sector_size();
+    let mut header = vec![0; sector_size];
+    device.read_sectors(1, &mut header)?;
+
+    let u32_at = |buf: &[u8], offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+    let u64_at = |buf: &[u8], offset: usize| u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+
+    let header_size = u32_at(&header, 12) as usize;
+    if &header[0..8] != GPT_SIGNATURE || !(92..=sector_size).contains(&header_size) {
+        return Ok(Vec::new());
+    }
+    // the checksum is calculated with its own field zeroed
+    let checksum = u32_at(&header, 16);
+    let mut checked = header[..header_size].to_vec();
+    checked[16..20].fill(0);
+    if crc32(&checked) != checksum {
+        return Ok(Vec::new());
+    }
+
+    let entries_start = u64_at(&header, 72);
+    let entry_count = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    // entries are a multiple of 8 bytes and don't span sectors, which with at most 1024 of
+    // them keeps the table to 1024 sectors
+    if entry_size < 128 || entry_size > sector_size || entry_size % 8 != 0 || entry_count > 1024 {
+        return Ok(Vec::new());
+    }
+
+    let table_len = entry_count * entry_size;
+    let read_len = (table_len + sector_size - 1) / sector_size * sector_size;
+    let mut table = Vec::new();
+    // the disk is skipped if there isn't memory for its table
+    if table.try_reserve_exact(read_len).is_err() {
+        return Ok(Vec::new());
+    }
+    table.resize(read_len, 0);
+    device.read_sectors(entries_start, &mut table)?;
+    if crc32(&table[..table_len]) != u32_at(&header, 88) {
+        return Ok(Vec::new());
+    }
+
+    let mut partitions = Vec::new();
+    for (i, entry) in table[..table_len].chunks(entry_size).enumerate() {
+        let type_guid: [u8; 16] = entry[0..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let first_sector = u64_at(entry, 32);
+        // the last sector is inclusive
+        let last_sector = u64_at(entry, 40);
+        if last_sector < first_sector || last_sector >= device.sector_count() {
+            continue;
+        }
+
+        let name: Vec<u16> = entry[56..128]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+        partitions.push(Partition {
+            device: device.clone(),
+            first_sector,
+            sector_count: last_sector - first_sector + 1,
+            number: i + 1,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                name: String::from_utf16_lossy(&name),
+            },
+        });
+    }
+    Ok(partitions)
+}
+
+/// The CRC-32 used by GPT, the same one as zlib and Ethernet.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xEDB8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
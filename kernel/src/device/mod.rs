@@ -0,0 +1,256 @@
+pub mod platform;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use spin::Mutex;
+
+use crate::pci::PciError;
+
+/// Every device in the order it was registered, so parents come before their children.
+static DEVICES: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+static DRIVERS: Mutex<Vec<&'static dyn Driver>> = Mutex::new(Vec::new());
+/// The latest level `init_level` was called for, drivers of later levels don't probe yet.
+static LEVEL: Mutex<Option<InitLevel>> = Mutex::new(None);
+
+/// How the kernel found a device and talks to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    /// Devices at fixed places that can't be discovered, described by the architecture.
+    Platform,
+    Pci,
+    /// Virtio devices, whatever transport they're reached through.
+    Virtio,
+}
+
+/// When a driver gets to probe its devices, in order. Devices registered before their driver's
+/// level is reached wait for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InitLevel {
+    /// Interrupt controllers, which everything that interrupts needs.
+    InterruptController,
+    /// Timers and clocks, set up before interrupts are enabled.
+    Clock,
+    /// Everything else, with interrupts enabled.
+    Device,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    /// No device has that name.
+    NotFound,
+    /// The device lacks a resource or register the driver needs.
+    MissingResource,
+    /// The driver can't let go of the device.
+    Busy,
+    /// The driver doesn't support this particular device after all.
+    Unsupported,
+    OutOfMemory,
+    /// The device reported an error or didn't respond.
+    Failed,
+}
+
+impl From<PciError> for DeviceError {
+    fn from(err: PciError) -> DeviceError {
+        match err {
+            PciError::BadBar | PciError::MissingCapability => DeviceError::MissingResource,
+            PciError::OutOfMemory => DeviceError::OutOfMemory,
+            PciError::DeviceError => DeviceError::Failed,
+            PciError::Unsupported => DeviceError::Unsupported,
+        }
+    }
+}
+
+pub trait Device: Send + Sync {
+    /// Unique among all devices.
+    fn name(&self) -> String;
+
+    fn bus(&self) -> Bus;
+
+    /// Lets drivers get back at the bus specific type, see `downcast`.
+    fn as_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+}
+
+/// The bus specific type of `device`, if it is a `T`.
+pub fn downcast<T: Any + Send + Sync>(device: &Arc<dyn Device>) -> Option<Arc<T>> {
+    device.clone().as_any().downcast::<T>().ok()
+}
+
+pub trait Driver: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The bus of the devices this driver takes.
+    fn bus(&self) -> Bus;
+
+    fn level(&self) -> InitLevel {
+        InitLevel::Device
+    }
+
+    /// Whether the driver is able to take `device`, which is on the driver's bus.
+    fn matches(&self, device: &Arc<dyn Device>) -> bool;
+
+    /// Takes over `device`. Called once for every matching device that no other driver has
+    /// taken yet.
+    fn probe(&self, device: &Arc<dyn Device>) -> Result<(), DeviceError>;
+
+    /// Lets go of a device it took. Drivers that hand their devices out to the rest of the
+    /// kernel keep them.
+    fn remove(&self, _device: &Arc<dyn Device>) -> Result<(), DeviceError> {
+        Err(DeviceError::Busy)
+    }
+}
+
+struct Entry {
+    device: Arc<dyn Device>,
+    name: String,
+    parent: Option<String>,
+    driver: Option<&'static dyn Driver>,
+    /// Drivers that failed to probe the device, which aren't offered it again.
+    failed: Vec<&'static str>,
+}
+
+/// A registered device, for listing.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub bus: Bus,
+    /// The device this one was found on, like the PCI function of a virtio device.
+    pub parent: Option<String>,
+    /// The driver bound to the device, if any.
+    pub driver: Option<&'static str>,
+}
+
+/// Adds `device` to the registry and offers it to the drivers of its bus. `parent` is the name
+/// of the device it was found on.
+pub fn register_device(device: Arc<dyn Device>, parent: Option<String>) {
+    DEVICES.lock().push(Entry {
+        name: device.name(),
+        device: device.clone(),
+        parent,
+        driver: None,
+        failed: Vec::new(),
+    });
+    let drivers = DRIVERS.lock().clone();
+    bind(&device, &drivers);
+}
+
+/// Adds `driver` to the drivers tried on new devices and offers it every device of its bus
+/// that's still unclaimed, once its level is reached. The other drivers already had their
+/// chance at those.
+pub fn register_driver(driver: &'static dyn Driver) {
+    DRIVERS.lock().push(driver);
+    for device in unbound() {
+        bind(&device, &[driver]);
+    }
+}
+
+/// Lets the drivers of `level` and every level before it probe, in the order the devices
+/// were registered.
+pub fn init_level(level: InitLevel) {
+    *LEVEL.lock() = Some(level);
+    for device in unbound() {
+        let drivers = DRIVERS.lock().clone();
+        bind(&device, &drivers);
+    }
+}
+
+fn unbound() -> Vec<Arc<dyn Device>> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter(|entry| entry.driver.is_none())
+        .map(|entry| entry.device.clone())
+        .collect()
+}
+
+/// Offers `device` to each of `drivers` that's for its bus and allowed to probe until one
+/// takes it. A driver that failed to probe the device before isn't offered it again, probing
+/// can have side effects a retry would only repeat.
+///
+/// No lock is held while probing, drivers register the devices they find on the one they
+/// took.
+fn bind(device: &Arc<dyn Device>, drivers: &[&'static dyn Driver]) {
+    let level = *LEVEL.lock();
+    let name = device.name();
+
+    for &driver in drivers {
+        let ready = level.map_or(false, |level| driver.level() <= level);
+        if !ready || driver.bus() != device.bus() || !driver.matches(device) {
+            continue;
+        }
+        let failed = DEVICES
+            .lock()
+            .iter()
+            .find(|entry| entry.name == name)
+            .map_or(false, |entry| entry.failed.contains(&driver.name()));
+        if failed {
+            continue;
+        }
+
+        match driver.probe(device) {
+            Ok(()) => {
+                if let Some(entry) = DEVICES.lock().iter_mut().find(|entry| entry.name == name) {
+                    entry.driver = Some(driver);
+                }
+                return;
+            }
+            Err(err) => {
+                crate::log(&format!("{}: {} failed to probe: {:?}", name, driver.name(), err));
+                if let Some(entry) = DEVICES.lock().iter_mut().find(|entry| entry.name == name) {
+                    entry.failed.push(driver.name());
+                }
+            }
+        }
+    }
+}
+
+/// Removes the device called `name` and everything found on it, children first. Fails if a
+/// driver won't let go, leaving whatever it couldn't remove.
+pub fn remove_device(name: &str) -> Result<(), DeviceError> {
+    let children: Vec<String> = DEVICES
+        .lock()
+        .iter()
+        .filter(|entry| entry.parent.as_deref() == Some(name))
+        .map(|entry| entry.name.clone())
+        .collect();
+    for child in children {
+        remove_device(&child)?;
+    }
+
+    let (device, driver) = DEVICES
+        .lock()
+        .iter()
+        .find(|entry| entry.name == name)
+        .map(|entry| (entry.device.clone(), entry.driver))
+        .ok_or(DeviceError::NotFound)?;
+    if let Some(driver) = driver {
+        driver.remove(&device)?;
+    }
+    DEVICES.lock().retain(|entry| entry.name != name);
+    Ok(())
+}
+
+/// Every registered device, parents before their children.
+pub fn devices() -> Vec<DeviceInfo> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|entry| DeviceInfo {
+            name: entry.name.clone(),
+            bus: entry.device.bus(),
+            parent: entry.parent.clone(),
+            driver: entry.driver.map(|driver| driver.name()),
+        })
+        .collect()
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn Device>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|entry| entry.name == name)
+        .map(|entry| entry.device.clone())
+}
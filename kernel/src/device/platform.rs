@@ -0,0 +1,124 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use super::{Bus, Device, DeviceError, Driver, InitLevel};
+
+/// Something a platform device decodes or raises.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    IoPorts { start: u16, count: u16 },
+    /// A legacy interrupt line.
+    Irq(u8),
+}
+
+/// A device the architecture knows is there, like the PIC or the legacy IDE channels.
+pub struct PlatformDevice {
+    /// What the device is, drivers take devices by kind.
+    pub kind: &'static str,
+    /// Tells apart devices of the same kind.
+    pub index: usize,
+    pub resources: Vec<Resource>,
+}
+
+impl PlatformDevice {
+    pub fn new(kind: &'static str, index: usize, resources: Vec<Resource>) -> PlatformDevice {
+        PlatformDevice { kind, index, resources }
+    }
+
+    /// Start of the `n`th range of I/O ports.
+    pub fn io_ports(&self, n: usize) -> Result<u16, DeviceError> {
+        self.resources
+            .iter()
+            .filter_map(|resource| match *resource {
+                Resource::IoPorts { start, .. } => Some(start),
+                _ => None,
+            })
+            .nth(n)
+            .ok_or(DeviceError::MissingResource)
+    }
+
+    pub fn irq(&self) -> Result<u8, DeviceError> {
+        self.resources
+            .iter()
+            .find_map(|resource| match *resource {
+                Resource::Irq(irq) => Some(irq),
+                _ => None,
+            })
+            .ok_or(DeviceError::MissingResource)
+    }
+}
+
+impl Device for PlatformDevice {
+    fn name(&self) -> String {
+        format!("{}{}", self.kind, self.index)
+    }
+
+    fn bus(&self) -> Bus {
+        Bus::Platform
+    }
+
+    fn as_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+pub trait PlatformDriver: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The kind of device this driver takes.
+    fn kind(&self) -> &'static str;
+
+    fn level(&self) -> InitLevel {
+        InitLevel::Device
+    }
+
+    fn probe(&self, device: &Arc<PlatformDevice>) -> Result<(), DeviceError>;
+
+    fn remove(&self, _device: &Arc<PlatformDevice>) -> Result<(), DeviceError> {
+        Err(DeviceError::Busy)
+    }
+}
+
+/// Makes a platform driver a driver of the device model.
+struct Adapter(&'static dyn PlatformDriver);
+
+impl Driver for Adapter {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    fn bus(&self) -> Bus {
+        Bus::Platform
+    }
+
+    fn level(&self) -> InitLevel {
+        self.0.level()
+    }
+
+    fn matches(&self, device: &Arc<dyn Device>) -> bool {
+        super::downcast::<PlatformDevice>(device).map_or(false, |device| device.kind == self.0.kind())
+    }
+
+    fn probe(&self, device: &Arc<dyn Device>) -> Result<(), DeviceError> {
+        let device = super::downcast::<PlatformDevice>(device).ok_or(DeviceError::Unsupported)?;
+        self.0.probe(&device)
+    }
+
+    fn remove(&self, device: &Arc<dyn Device>) -> Result<(), DeviceError> {
+        let device = super::downcast::<PlatformDevice>(device).ok_or(DeviceError::Unsupported)?;
+        self.0.remove(&device)
+    }
+}
+
+pub fn register_driver(driver: &'static dyn PlatformDriver) {
+    // drivers are never unregistered, so the adapter lives as long as the kernel
+    super::register_driver(Box::leak(Box::new(Adapter(driver))));
+}
+
+pub fn register_device(device: PlatformDevice) {
+    super::register_device(Arc::new(device), None);
+}
@@ -0,0 +1,218 @@
+use super::Color;
+
+/// Most parameters a control sequence keeps, any after are dropped.
+const MAX_PARAMS: usize = 16;
+
+/// What a character written to the console turned out to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// A character to draw.
+    Print(char),
+    /// A C0 control character like a newline or backspace.
+    Control(char),
+    /// An escape sequence other than a control sequence, by its final character, like the '7'
+    /// of save cursor.
+    Escape(char),
+    /// A complete control sequence, one starting with "ESC [".
+    Csi(Csi),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    // parameters started, one more than MAX_PARAMS once some were dropped
+    count: usize,
+    /// Whether the sequence starts with '?', the private sequences like showing the cursor.
+    pub private: bool,
+    /// The character ending the sequence, which says what it does.
+    pub final_char: char,
+}
+
+impl Csi {
+    const fn new() -> Csi {
+        Csi {
+            params: [0; MAX_PARAMS],
+            count: 0,
+            private: false,
+            final_char: '\0',
+        }
+    }
+
+    pub fn params(&self) -> &[u16] {
+        &self.params[..usize::min(self.count, MAX_PARAMS)]
+    }
+
+    /// The `index`th parameter, or `default` if it's missing or 0, which mean the same.
+    pub fn param(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            Some(&param) if param != 0 => param,
+            _ => default,
+        }
+    }
+}
+
+enum State {
+    Ground,
+    Escape,
+    /// After the intermediate characters of an escape sequence, like the '(' of character set
+    /// selection, which isn't supported so the final character is dropped.
+    EscapeIntermediate,
+    Csi,
+    /// Inside a string like an operating system command, ignored until it ends.
+    String,
+}
+
+/// Splits the characters written to the console into text, control characters and escape
+/// sequences, following ECMA-48 like VT100 and its descendants.
+pub struct Parser {
+    state: State,
+    csi: Csi,
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            csi: Csi::new(),
+        }
+    }
+
+    /// Takes the next character, returning what to do once it ends something.
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        match self.state {
+            State::Ground => match c {
+                '\x1b' => {
+                    self.state = State::Escape;
+                    None
+                }
+                '\0'..='\x1f' | '\x7f' => Some(Action::Control(c)),
+                _ => Some(Action::Print(c)),
+            },
+            State::Escape => match c {
+                '[' => {
+                    self.csi = Csi::new();
+                    self.state = State::Csi;
+                    None
+                }
+                ']' | 'P' | 'X' | '^' | '_' => {
+                    self.state = State::String;
+                    None
+                }
+                ' '..='/' => {
+                    self.state = State::EscapeIntermediate;
+                    None
+                }
+                '\x1b' => None,
+                // control characters still work in the middle of a sequence
+                '\0'..='\x1f' => Some(Action::Control(c)),
+                _ => {
+                    self.state = State::Ground;
+                    Some(Action::Escape(c))
+                }
+            },
+            State::EscapeIntermediate => match c {
+                ' '..='/' => None,
+                '\0'..='\x1f' => Some(Action::Control(c)),
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+            State::Csi => match c {
+                '0'..='9' => {
+                    let csi = &mut self.csi;
+                    csi.count = usize::max(csi.count, 1);
+                    if csi.count <= MAX_PARAMS {
+                        let param = &mut csi.params[csi.count - 1];
+                        *param = param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                    }
+                    None
+                }
+                // subparameters are taken as parameters, which is what most programs send
+                ';' | ':' => {
+                    let csi = &mut self.csi;
+                    csi.count = usize::min(usize::max(csi.count, 1) + 1, MAX_PARAMS + 1);
+                    None
+                }
+                '<'..='?' => {
+                    self.csi.private |= c == '?';
+                    None
+                }
+                ' '..='/' => None,
+                '@'..='~' => {
+                    self.csi.final_char = c;
+                    self.state = State::Ground;
+                    Some(Action::Csi(self.csi))
+                }
+                '\x1b' => {
+                    self.state = State::Escape;
+                    None
+                }
+                '\0'..='\x1f' => Some(Action::Control(c)),
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+            State::String => {
+                match c {
+                    '\x07' => self.state = State::Ground,
+                    // the '\' of the string terminator ends up as an escape sequence that does nothing
+                    '\x1b' => self.state = State::Escape,
+                    _ => {}
+                }
+                None
+            }
+        }
+    }
+}
+
+/// One of the 256 indexed colors: the 16 standard ones, a 6x6x6 color cube and a ramp of grays.
+pub fn palette(index: u8) -> Color {
+    const STANDARD: [Color; 16] = [
+        Color(0, 0, 0),
+        Color(170, 0, 0),
+        Color(0, 170, 0),
+        Color(170, 85, 0),
+        Color(0, 0, 170),
+        Color(170, 0, 170),
+        Color(0, 170, 170),
+        Color(170, 170, 170),
+        Color(85, 85, 85),
+        Color(255, 85, 85),
+        Color(85, 255, 85),
+        Color(255, 255, 85),
+        Color(85, 85, 255),
+        Color(255, 85, 255),
+        Color(85, 255, 255),
+        Color(255, 255, 255),
+    ];
+    const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+    match index {
+        0..=15 => STANDARD[index as usize],
+        16..=231 => {
+            let index = index as usize - 16;
+            Color(CUBE_LEVELS[index / 36], CUBE_LEVELS[index / 6 % 6], CUBE_LEVELS[index % 6])
+        }
+        _ => {
+            let level = 8 + (index - 232) * 10;
+            Color(level, level, level)
+        }
+    }
+}
+
+/// Reads the color of an SGR 38 or 48 from the parameters after it, either "5;index" or
+/// "2;r;g;b". Returns the color if it's valid and how many parameters it used.
+pub fn extended_color(params: &[u16]) -> (Option<Color>, usize) {
+    match *params {
+        [5, index, ..] => (u8::try_from(index).ok().map(palette), 2),
+        [2, r, g, b, ..] => {
+            let component = |value: u16| value.min(255) as u8;
+            (Some(Color(component(r), component(g), component(b))), 4)
+        }
+        // a truncated color takes the rest of the sequence with it
+        [5, ..] | [2, ..] => (None, params.len()),
+        _ => (None, 0),
+    }
+}
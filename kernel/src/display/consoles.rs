@@ -0,0 +1,94 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use bootloader_api::info::FrameBuffer;
+
+use super::{Color, Display, TextDisplay};
+
+/// How many consoles there are, switched between with Alt+F1 and on.
+pub const CONSOLE_COUNT: usize = 6;
+/// The console the kernel logs to, on Alt+F1. Nothing else writes to it and it takes no input.
+pub const LOG_CONSOLE: usize = 0;
+/// The console `print!` writes to, where a shell would run.
+pub const PRINT_CONSOLE: usize = 1;
+/// Most characters typed into a console that nothing read yet, newer ones are dropped.
+const INPUT_CAPACITY: usize = 256;
+
+struct VirtualConsole {
+    text: TextDisplay,
+    // Characters typed while the console was shown, oldest first
+    input: VecDeque<char>,
+}
+
+/// Consoles that each keep their own text, cursor and input, only one of them on the screen at a
+/// time. The one on the screen has the display, the others keep writing to their text without
+/// drawing it until they get it back.
+pub struct VirtualConsoles {
+    consoles: Vec<VirtualConsole>,
+    active: usize,
+}
+
+impl VirtualConsoles {
+    /// The consoles, showing the log console.
+    pub fn new(fb: &'static mut FrameBuffer, clear_color: Color, text_color: Color) -> VirtualConsoles {
+        let display = Display::new(fb);
+        let (width, height) = (display.fb_info.width, display.fb_info.height);
+        let mut consoles: Vec<VirtualConsole> = (0..CONSOLE_COUNT)
+            .map(|_| VirtualConsole {
+                text: TextDisplay::new(width, height, clear_color, text_color),
+                input: VecDeque::new(),
+            })
+            .collect();
+        consoles[LOG_CONSOLE].text.attach(display);
+
+        VirtualConsoles {
+            consoles,
+            active: LOG_CONSOLE,
+        }
+    }
+
+    /// The console `index`, which has to be below `CONSOLE_COUNT`.
+    pub fn console(&mut self, index: usize) -> &mut TextDisplay {
+        &mut self.consoles[index].text
+    }
+
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    /// The console on the screen.
+    pub fn active_console(&mut self) -> &mut TextDisplay {
+        &mut self.consoles[self.active].text
+    }
+
+    /// Puts console `index` on the screen. Nothing happens for consoles that don't exist, or
+    /// while the console on the screen is hidden since something else is using the screen.
+    pub fn switch(&mut self, index: usize) {
+        if index >= self.consoles.len() || index == self.active || self.active_console().is_hidden() {
+            return;
+        }
+        if let Some(display) = self.active_console().detach() {
+            self.consoles[index].text.attach(display);
+        }
+        self.active = index;
+    }
+
+    /// Queues a character typed on the keyboard for the console on the screen and echoes it
+    /// there. The log console ignores it.
+    pub fn type_char(&mut self, c: char) {
+        if self.active == LOG_CONSOLE {
+            return;
+        }
+        let console = &mut self.consoles[self.active];
+        if console.input.len() < INPUT_CAPACITY {
+            console.input.push_back(c);
+        }
+        let mut buf = [0; 4];
+        console.text.write_text(c.encode_utf8(&mut buf));
+    }
+
+    /// The oldest character typed into console `index` that wasn't read yet.
+    pub fn read_char(&mut self, index: usize) -> Option<char> {
+        self.consoles.get_mut(index)?.input.pop_front()
+    }
+}
@@ -0,0 +1,315 @@
+use alloc::vec::Vec;
+
+use super::image::Image;
+use super::{Color, Display, Point, Rect};
+
+/// Shapes, drawn inside the clip rectangle and blended with what's under them by the alpha.
+/// Whatever falls outside the clip rectangle, or the screen, is left out.
+impl Display {
+    /// Limits the shapes drawn from now on to `clip`, or to the screen for None.
+    pub fn set_clip(&mut self, clip: Option<Rect>) {
+        let screen = Rect::new(Point(0, 0), Point(self.fb_info.width, self.fb_info.height));
+        self.clip = clip.map_or(screen, |clip| clip.intersection(&screen));
+    }
+
+    pub fn clip(&self) -> Rect {
+        self.clip
+    }
+
+    /// How opaque the shapes drawn from now on are, from 0 for invisible to 255 for hiding
+    /// what's under them.
+    pub fn set_alpha(&mut self, alpha: u8) {
+        self.alpha = alpha;
+    }
+
+    /// Draws the line from `from` to `to`, both ends included.
+    pub fn draw_line(&mut self, color: Color, from: Point, to: Point) {
+        let (mut x, mut y) = (coordinate(from.0), coordinate(from.1));
+        let (end_x, end_y) = (coordinate(to.0), coordinate(to.1));
+        let dx = (end_x - x).abs();
+        let dy = -(end_y - y).abs();
+        let step_x = if x < end_x { 1 } else { -1 };
+        let step_y = if y < end_y { 1 } else { -1 };
+
+        // Bresenham's, the error tracks how far off the ideal line the next pixel would be
+        let mut error = dx + dy;
+        loop {
+            self.span(color, y, x, x + 1);
+            if x == end_x && y == end_y {
+                break;
+            }
+            let error2 = 2 * error;
+            if error2 >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if error2 <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+
+        let (start_x, start_y) = (coordinate(from.0), coordinate(from.1));
+        let (left, right) = (isize::min(start_x, end_x), isize::max(start_x, end_x));
+        let (top, bottom) = (isize::min(start_y, end_y), isize::max(start_y, end_y));
+        self.mark_shape(left, top, right + 1, bottom + 1);
+    }
+
+    /// Draws the one pixel wide edge of the rectangle from `top_left` up to but not including
+    /// `bottom_right`, like `draw_rect` fills it.
+    pub fn draw_rect_outline(&mut self, color: Color, top_left: Point, bottom_right: Point) {
+        let (left, top) = (coordinate(top_left.0), coordinate(top_left.1));
+        let (right, bottom) = (coordinate(bottom_right.0), coordinate(bottom_right.1));
+        if left >= right || top >= bottom {
+            return;
+        }
+
+        self.span(color, top, left, right);
+        if bottom - 1 > top {
+            self.span(color, bottom - 1, left, right);
+        }
+        let (first, last) = self.visible_rows(top + 1, bottom - 1);
+        for y in first..last {
+            self.span(color, y, left, left + 1);
+            if right - 1 > left {
+                self.span(color, y, right - 1, right);
+            }
+        }
+        self.mark_shape(left, top, right, bottom);
+    }
+
+    /// Fills the rectangle from `top_left` up to but not including `bottom_right`. Unlike
+    /// `draw_rect` it's clipped and blended.
+    pub fn fill_rect(&mut self, color: Color, top_left: Point, bottom_right: Point) {
+        let (left, right) = (coordinate(top_left.0), coordinate(bottom_right.0));
+        let (top, bottom) = (coordinate(top_left.1), coordinate(bottom_right.1));
+        let (first, last) = self.visible_rows(top, bottom);
+        for y in first..last {
+            self.span(color, y, left, right);
+        }
+        self.mark_shape(left, top, right, bottom);
+    }
+
+    pub fn draw_circle(&mut self, color: Color, center: Point, radius: usize) {
+        self.draw_ellipse(color, center, radius, radius);
+    }
+
+    pub fn fill_circle(&mut self, color: Color, center: Point, radius: usize) {
+        self.fill_ellipse(color, center, radius, radius);
+    }
+
+    /// Draws the edge of the ellipse around `center` that's `radius_x` pixels to the left and
+    /// right of it and `radius_y` above and below.
+    pub fn draw_ellipse(&mut self, color: Color, center: Point, radius_x: usize, radius_y: usize) {
+        let (center_x, center_y) = (coordinate(center.0), coordinate(center.1));
+        let radius = coordinate(radius_y);
+        let (first, last) = self.visible_rows(center_y - radius, center_y + radius + 1);
+        for dy in first - center_y..last - center_y {
+            let width = coordinate(ellipse_width(radius_x, radius_y, dy.unsigned_abs()));
+            // the edge on this row reaches in to where the row further out ends, so that it
+            // stays connected where it's steep
+            let inner = if dy.abs() == radius {
+                0
+            } else {
+                let outer = coordinate(ellipse_width(radius_x, radius_y, dy.unsigned_abs() + 1));
+                isize::min(outer + 1, width)
+            };
+
+            let y = center_y + dy;
+            if inner == 0 {
+                self.span(color, y, center_x - width, center_x + width + 1);
+            } else {
+                self.span(color, y, center_x - width, center_x - inner + 1);
+                self.span(color, y, center_x + inner, center_x + width + 1);
+            }
+        }
+        self.mark_ellipse(center, radius_x, radius_y);
+    }
+
+    pub fn fill_ellipse(&mut self, color: Color, center: Point, radius_x: usize, radius_y: usize) {
+        let (center_x, center_y) = (coordinate(center.0), coordinate(center.1));
+        let radius = coordinate(radius_y);
+        let (first, last) = self.visible_rows(center_y - radius, center_y + radius + 1);
+        for dy in first - center_y..last - center_y {
+            let width = coordinate(ellipse_width(radius_x, radius_y, dy.unsigned_abs()));
+            self.span(color, center_y + dy, center_x - width, center_x + width + 1);
+        }
+        self.mark_ellipse(center, radius_x, radius_y);
+    }
+
+    /// Draws the lines from each of `points` to the next, and from the last back to the first.
+    pub fn draw_polygon(&mut self, color: Color, points: &[Point]) {
+        for (i, &from) in points.iter().enumerate() {
+            let to = points[(i + 1) % points.len()];
+            self.draw_line(color, from, to);
+        }
+    }
+
+    /// Fills the polygon with corners `points`, which can be concave or cross itself. Parts
+    /// an odd number of edges away from the outside are filled.
+    pub fn fill_polygon(&mut self, color: Color, points: &[Point]) {
+        if points.len() < 3 {
+            return;
+        }
+        let top = points.iter().map(|point| point.1).min().unwrap_or(0);
+        let bottom = points.iter().map(|point| point.1).max().unwrap_or(0);
+        let left = points.iter().map(|point| point.0).min().unwrap_or(0);
+        let right = points.iter().map(|point| point.0).max().unwrap_or(0);
+
+        let first = usize::max(top, self.clip.top_left.1);
+        let last = usize::min(bottom, self.clip.bottom_right.1);
+        let mut crossings = Vec::new();
+        for y in first..last {
+            // each row is sampled through the middle of its pixels, in doubled coordinates so
+            // that stays whole, which keeps it off the corners
+            let sample = 2 * y as isize + 1;
+            crossings.clear();
+            for (i, a) in points.iter().enumerate() {
+                let b = points[(i + 1) % points.len()];
+                let (a_y, b_y) = (2 * coordinate(a.1), 2 * coordinate(b.1));
+                if (a_y < sample) != (b_y < sample) {
+                    // the product of two coordinates doesn't fit in an isize, the result does
+                    let (a_x, b_x) = (coordinate(a.0) as i128, coordinate(b.0) as i128);
+                    let (a_y, b_y, sample) = (a_y as i128, b_y as i128, sample as i128);
+                    crossings.push((a_x + (sample - a_y) * (b_x - a_x) / (b_y - a_y)) as isize);
+                }
+            }
+            crossings.sort_unstable();
+            for pair in crossings.chunks_exact(2) {
+                self.span(color, y as isize, pair[0], pair[1]);
+            }
+        }
+        self.mark_shape(coordinate(left), coordinate(top), coordinate(right) + 1, coordinate(bottom) + 1);
+    }
+
+    /// Draws `image` stretched or shrunk to fill `dest`, each pixel taking the color of the one
+    /// nearest to it in the image. Pixels of the image that aren't opaque are blended by their
+    /// own alpha on top of the alpha.
+    pub fn draw_image(&mut self, image: &Image, dest: Rect) {
+        let area = dest.intersection(&self.clip);
+        if area.is_empty() {
+            return;
+        }
+        let dest_width = dest.bottom_right.0 - dest.top_left.0;
+        let dest_height = dest.bottom_right.1 - dest.top_left.1;
+
+        for y in area.top_left.1..area.bottom_right.1 {
+            let row = (y - dest.top_left.1) * image.height / dest_height * image.width;
+            for x in area.top_left.0..area.bottom_right.0 {
+                let index = row + (x - dest.top_left.0) * image.width / dest_width;
+                let color = image.pixels[index];
+                let alpha = image.alpha.as_ref().map_or(u8::MAX, |alpha| alpha[index]);
+                match (alpha as u16 * self.alpha as u16 / 255) as u8 {
+                    0 => {}
+                    u8::MAX => self.fill_span(color, Point(x, y), 1),
+                    alpha => self.blend_pixel(color, Point(x, y), alpha),
+                }
+            }
+        }
+        self.mark_dirty(area);
+    }
+
+    /// Fills the pixels of row `y` from `start` up to but not including `end`, or whatever of
+    /// them is inside the clip rectangle.
+    fn span(&mut self, color: Color, y: isize, start: isize, end: isize) {
+        let clip = self.clip;
+        if y < clip.top_left.1 as isize || y >= clip.bottom_right.1 as isize {
+            return;
+        }
+        let start = isize::max(start, clip.top_left.0 as isize);
+        let end = isize::min(end, clip.bottom_right.0 as isize);
+        if start >= end {
+            return;
+        }
+
+        let (y, start, end) = (y as usize, start as usize, end as usize);
+        if self.alpha == u8::MAX {
+            self.fill_span(color, Point(start, y), end - start);
+        } else {
+            for x in start..end {
+                self.blend_pixel(color, Point(x, y), self.alpha);
+            }
+        }
+    }
+
+    /// Mixes `color` into the pixel at `position`, `alpha` parts of it to 255.
+    fn blend_pixel(&mut self, color: Color, position: Point, alpha: u8) {
+        let bytes_per_pixel = self.fb_info.bytes_per_pixel;
+        let offset = self.pixel_pos_to_real(position);
+        let mut pixel = [0; 4];
+        pixel[..bytes_per_pixel].copy_from_slice(&self.buffer()[offset..offset + bytes_per_pixel]);
+        let under = self.decode_pixel(&pixel[..bytes_per_pixel]);
+
+        let alpha = alpha as u16;
+        let mix = |over: u8, under: u8| ((over as u16 * alpha + under as u16 * (255 - alpha)) / 255) as u8;
+        let mixed = Color(mix(color.0, under.0), mix(color.1, under.1), mix(color.2, under.2));
+        self.encode_pixel(mixed, &mut pixel[..bytes_per_pixel]);
+        self.buffer()[offset..offset + bytes_per_pixel].copy_from_slice(&pixel[..bytes_per_pixel]);
+    }
+
+    fn mark_ellipse(&mut self, center: Point, radius_x: usize, radius_y: usize) {
+        let (center_x, center_y) = (coordinate(center.0), coordinate(center.1));
+        let (radius_x, radius_y) = (coordinate(radius_x), coordinate(radius_y));
+        self.mark_shape(
+            center_x.saturating_sub(radius_x),
+            center_y.saturating_sub(radius_y),
+            center_x.saturating_add(radius_x).saturating_add(1),
+            center_y.saturating_add(radius_y).saturating_add(1),
+        );
+    }
+
+    /// The rows from `top` up to but not including `bottom` that are inside the clip rectangle,
+    /// so that shapes far bigger than the screen don't walk every row they cover.
+    fn visible_rows(&self, top: isize, bottom: isize) -> (isize, isize) {
+        let first = isize::max(top, self.clip.top_left.1 as isize);
+        let last = isize::min(bottom, self.clip.bottom_right.1 as isize);
+        (first, isize::max(first, last))
+    }
+
+    /// Marks the part of a shape's bounding box inside the clip rectangle dirty.
+    fn mark_shape(&mut self, left: isize, top: isize, right: isize, bottom: isize) {
+        let clamp = |value: isize| usize::try_from(value).unwrap_or(0);
+        let bounds = Rect::new(Point(clamp(left), clamp(top)), Point(clamp(right), clamp(bottom)));
+        let clip = self.clip;
+        self.mark_dirty(bounds.intersection(&clip));
+    }
+}
+
+/// `value` as a signed coordinate. Anything past u32::MAX is far off any screen, clamping it
+/// there keeps sums and doubles of coordinates from overflowing.
+fn coordinate(value: usize) -> isize {
+    usize::min(value, u32::MAX as usize) as isize
+}
+
+/// How far the ellipse with radii `radius_x` and `radius_y` reaches to either side of its
+/// center on the row `dy` above or below it.
+fn ellipse_width(radius_x: usize, radius_y: usize, dy: usize) -> usize {
+    if dy > radius_y {
+        return 0;
+    }
+    if radius_y == 0 {
+        return radius_x;
+    }
+    // the largest dx with (dx / rx)^2 + (dy / ry)^2 <= 1, radii past u32::MAX are clamped,
+    // they're far off any screen and the product of four of them then fits in a u128
+    let clamp = |value: usize| usize::min(value, u32::MAX as usize) as u128;
+    let (radius_x, radius_y, dy) = (clamp(radius_x), clamp(radius_y), clamp(dy));
+    let squared = radius_x * radius_x * (radius_y * radius_y - dy * dy) / (radius_y * radius_y);
+    // at most radius_x squared, which fits
+    integer_sqrt(squared as u64) as usize
+}
+
+/// The largest number whose square is at most `n`.
+fn integer_sqrt(n: u64) -> u64 {
+    if n < 2 {
+        return n;
+    }
+    // Newton's method from above, it only goes down until it's there
+    let mut x = n;
+    let mut next = (x + n / x) / 2;
+    while next < x {
+        x = next;
+        next = (x + n / x) / 2;
+    }
+    x
+}
@@ -0,0 +1,66 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use super::Color;
+
+/// Most glyphs kept, the cache starts over once it's full.
+const CAPACITY: usize = 512;
+
+/// A character rendered in a pair of colors, already in the framebuffer's pixel format so that
+/// drawing it is a copy per row.
+pub struct Glyph {
+    pub width: usize,
+    pub height: usize,
+    /// `height` rows of `width` pixels.
+    pub pixels: Vec<u8>,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    c: char,
+    bold: bool,
+    text_color: u32,
+    clear_color: u32,
+}
+
+/// Glyphs the console drew recently. Consoles draw few characters in few colors, so nearly
+/// every character is already here.
+pub struct GlyphCache {
+    glyphs: BTreeMap<Key, Glyph>,
+}
+
+impl GlyphCache {
+    pub fn new() -> GlyphCache {
+        GlyphCache { glyphs: BTreeMap::new() }
+    }
+
+    /// The glyph of `c` in these colors, rendered by `render` if it isn't cached yet.
+    pub fn get(
+        &mut self,
+        c: char,
+        bold: bool,
+        text_color: Color,
+        clear_color: Color,
+        render: impl FnOnce() -> Glyph,
+    ) -> &Glyph {
+        let key = Key {
+            c,
+            bold,
+            text_color: pack(text_color),
+            clear_color: pack(clear_color),
+        };
+        if self.glyphs.len() >= CAPACITY && !self.glyphs.contains_key(&key) {
+            self.glyphs.clear();
+        }
+        self.glyphs.entry(key).or_insert_with(render)
+    }
+
+    /// Forgets every glyph, for when the font changes.
+    pub fn clear(&mut self) {
+        self.glyphs.clear();
+    }
+}
+
+fn pack(color: Color) -> u32 {
+    (color.0 as u32) << 16 | (color.1 as u32) << 8 | color.2 as u32
+}
@@ -0,0 +1,143 @@
+use super::{Image, ImageError};
+use crate::display::Color;
+
+/// The file header, before the info header.
+const FILE_HEADER_SIZE: usize = 14;
+/// The smallest info header, BITMAPINFOHEADER. V4 and V5 headers are bigger but start the same.
+const INFO_HEADER_SIZE: usize = 40;
+
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+const BI_ALPHABITFIELDS: u32 = 6;
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, ImageError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ImageError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, ImageError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ImageError::Truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// A channel of a 32 bit pixel as given by a bitfield mask.
+#[derive(Clone, Copy)]
+struct Mask {
+    shift: u32,
+    bits: u32,
+    mask: u32,
+}
+
+impl Mask {
+    fn new(mask: u32) -> Mask {
+        let shift = if mask == 0 { 0 } else { mask.trailing_zeros() };
+        let bits = (mask >> shift).count_ones();
+        Mask { shift, bits, mask }
+    }
+
+    /// The channel of `pixel`, scaled to 8 bits.
+    fn get(&self, pixel: u32) -> u8 {
+        if self.bits == 0 {
+            return 0;
+        }
+        let value = (pixel & self.mask) >> self.shift;
+        if self.bits >= 8 {
+            (value >> (self.bits - 8)) as u8
+        } else {
+            // spread the few bits over the whole range, so the largest value is 255
+            (value * 255 / ((1 << self.bits) - 1)) as u8
+        }
+    }
+}
+
+/// Decodes an uncompressed BMP with 1, 4, 8, 24 or 32 bits per pixel.
+pub fn decode(data: &[u8]) -> Result<Image, ImageError> {
+    let pixels_offset = u32_at(data, 10)? as usize;
+    let header_size = u32_at(data, FILE_HEADER_SIZE)? as usize;
+    if header_size < INFO_HEADER_SIZE {
+        // the old OS/2 headers
+        return Err(ImageError::Unsupported);
+    }
+    let info = FILE_HEADER_SIZE;
+    let width = u32_at(data, info + 4)? as i32;
+    let height = u32_at(data, info + 8)? as i32;
+    let bits_per_pixel = u16_at(data, info + 14)?;
+    let compression = u32_at(data, info + 16)?;
+    let palette_size = u32_at(data, info + 32)? as usize;
+    if width <= 0 || height == 0 {
+        return Err(ImageError::BadHeader);
+    }
+    // rows go from the bottom up, unless the height is negative
+    let top_down = height < 0;
+    let (width, height) = (width as usize, height.unsigned_abs() as usize);
+
+    let (red, green, blue, alpha) = match (bits_per_pixel, compression) {
+        (1 | 4 | 8 | 24, BI_RGB) => (Mask::new(0), Mask::new(0), Mask::new(0), Mask::new(0)),
+        (32, BI_RGB) => (Mask::new(0xFF0000), Mask::new(0xFF00), Mask::new(0xFF), Mask::new(0)),
+        (32, BI_BITFIELDS | BI_ALPHABITFIELDS) => {
+            // the masks follow the 40 byte header, or are part of the bigger ones
+            let masks = info + INFO_HEADER_SIZE;
+            let alpha = match header_size > INFO_HEADER_SIZE || compression == BI_ALPHABITFIELDS {
+                true => u32_at(data, masks + 12)?,
+                false => 0,
+            };
+            (
+                Mask::new(u32_at(data, masks)?),
+                Mask::new(u32_at(data, masks + 4)?),
+                Mask::new(u32_at(data, masks + 8)?),
+                Mask::new(alpha),
+            )
+        }
+        _ => return Err(ImageError::Unsupported),
+    };
+
+    let palette = if bits_per_pixel <= 8 {
+        let entries = if palette_size == 0 { 1 << bits_per_pixel } else { palette_size };
+        let start = FILE_HEADER_SIZE + header_size;
+        let palette = data.get(start..start + entries * 4).ok_or(ImageError::Truncated)?;
+        Some(palette)
+    } else {
+        None
+    };
+
+    let mut image = Image::new(width, height, alpha.bits != 0)?;
+    // rows are padded to a multiple of 4 bytes
+    let stride = (width * bits_per_pixel as usize + 31) / 32 * 4;
+    for y in 0..height {
+        let row_start = match top_down {
+            true => pixels_offset + y * stride,
+            false => pixels_offset + (height - 1 - y) * stride,
+        };
+        let row = data.get(row_start..row_start + stride).ok_or(ImageError::Truncated)?;
+        for x in 0..width {
+            let index = y * width + x;
+            image.pixels[index] = match bits_per_pixel {
+                1 | 4 | 8 => {
+                    let bits = bits_per_pixel as usize;
+                    // the leftmost pixel is in the highest bits of a byte
+                    let bit = x * bits;
+                    let shift = 8 - bits - bit % 8;
+                    let entry = (row[bit / 8] >> shift) as usize & ((1 << bits) - 1);
+                    let palette = palette.unwrap_or_default();
+                    let color = palette.get(entry * 4..entry * 4 + 3).ok_or(ImageError::BadHeader)?;
+                    Color(color[2], color[1], color[0])
+                }
+                24 => Color(row[x * 3 + 2], row[x * 3 + 1], row[x * 3]),
+                _ => {
+                    let pixel = u32::from_le_bytes([row[x * 4], row[x * 4 + 1], row[x * 4 + 2], row[x * 4 + 3]]);
+                    if let Some(alphas) = &mut image.alpha {
+                        alphas[index] = alpha.get(pixel);
+                    }
+                    Color(red.get(pixel), green.get(pixel), blue.get(pixel))
+                }
+            };
+        }
+    }
+
+    // plenty of writers leave the alpha at 0 even with a mask for it
+    if let Some(alphas) = &image.alpha && alphas.iter().all(|&alpha| alpha == 0) {
+        image.alpha = None;
+    }
+    image.drop_opaque_alpha();
+    Ok(image)
+}
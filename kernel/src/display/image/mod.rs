@@ -0,0 +1,76 @@
+mod bmp;
+mod qoi;
+
+use alloc::vec::Vec;
+
+use super::Color;
+
+/// Most pixels an image can have, so a bad header can't ask for all of memory.
+const MAX_PIXELS: usize = 4096 * 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+    /// The data ends before the image does.
+    Truncated,
+    /// The header doesn't describe an image that makes sense.
+    BadHeader,
+    /// A valid image using a feature that isn't supported, like compressed BMPs.
+    Unsupported,
+    /// The image is bigger than `MAX_PIXELS` or there isn't the memory for it.
+    TooLarge,
+}
+
+/// A decoded image.
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    /// The pixels a row after the other, from the top.
+    pub pixels: Vec<Color>,
+    /// How opaque each pixel is, from 0 for not at all to 255, None if all of them are fully.
+    pub alpha: Option<Vec<u8>>,
+}
+
+impl Image {
+    /// Decodes a BMP or QOI image, telling which it is from its signature.
+    pub fn decode(data: &[u8]) -> Result<Image, ImageError> {
+        if data.starts_with(b"BM") {
+            bmp::decode(data)
+        } else if data.starts_with(b"qoif") {
+            qoi::decode(data)
+        } else {
+            Err(ImageError::Unsupported)
+        }
+    }
+
+    /// An image of `width` by `height` black pixels, with room for alpha if `alpha`.
+    fn new(width: usize, height: usize, alpha: bool) -> Result<Image, ImageError> {
+        let count = width.checked_mul(height).ok_or(ImageError::TooLarge)?;
+        if width == 0 || height == 0 {
+            return Err(ImageError::BadHeader);
+        }
+        if count > MAX_PIXELS {
+            return Err(ImageError::TooLarge);
+        }
+
+        let mut pixels = Vec::new();
+        pixels.try_reserve_exact(count).map_err(|_| ImageError::TooLarge)?;
+        pixels.resize(count, Color(0, 0, 0));
+        let alpha = match alpha {
+            true => {
+                let mut alpha = Vec::new();
+                alpha.try_reserve_exact(count).map_err(|_| ImageError::TooLarge)?;
+                alpha.resize(count, u8::MAX);
+                Some(alpha)
+            }
+            false => None,
+        };
+        Ok(Image { width, height, pixels, alpha })
+    }
+
+    /// Drops the alpha if every pixel is opaque after all, which is quicker to draw.
+    fn drop_opaque_alpha(&mut self) {
+        if let Some(alpha) = &self.alpha && alpha.iter().all(|&alpha| alpha == u8::MAX) {
+            self.alpha = None;
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use super::{Image, ImageError};
+use crate::display::Color;
+
+const HEADER_SIZE: usize = 14;
+
+const OP_RGB: u8 = 0xFE;
+const OP_RGBA: u8 = 0xFF;
+// the ops below are told apart by their top two bits, 0xC0 is a run of the last pixel
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const OP_MASK: u8 = 0xC0;
+
+/// Decodes a QOI image, see https://qoiformat.org/qoi-specification.pdf.
+pub fn decode(data: &[u8]) -> Result<Image, ImageError> {
+    if data.len() < HEADER_SIZE {
+        return Err(ImageError::Truncated);
+    }
+    let width = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    let height = u32::from_be_bytes([data[8], data[9], data[10], data[11]]) as usize;
+    let channels = data[12];
+    if channels != 3 && channels != 4 {
+        return Err(ImageError::BadHeader);
+    }
+    let mut image = Image::new(width, height, channels == 4)?;
+
+    // pixels seen before, by a hash of their color, which the index op refers back to
+    let mut seen = [[0u8; 4]; 64];
+    let mut pixel = [0, 0, 0, 255];
+    let mut offset = HEADER_SIZE;
+    let mut next = 0;
+    let byte = |offset: usize| data.get(offset).copied().ok_or(ImageError::Truncated);
+
+    while next < image.pixels.len() {
+        let op = byte(offset)?;
+        offset += 1;
+        let mut run = 1;
+
+        if op == OP_RGB {
+            pixel[..3].copy_from_slice(data.get(offset..offset + 3).ok_or(ImageError::Truncated)?);
+            offset += 3;
+        } else if op == OP_RGBA {
+            pixel.copy_from_slice(data.get(offset..offset + 4).ok_or(ImageError::Truncated)?);
+            offset += 4;
+        } else {
+            match op & OP_MASK {
+                OP_INDEX => pixel = seen[(op & 0x3F) as usize],
+                OP_DIFF => {
+                    // each channel differs by -2 to 1 from the last pixel
+                    pixel[0] = pixel[0].wrapping_add((op >> 4) & 0x03).wrapping_sub(2);
+                    pixel[1] = pixel[1].wrapping_add((op >> 2) & 0x03).wrapping_sub(2);
+                    pixel[2] = pixel[2].wrapping_add(op & 0x03).wrapping_sub(2);
+                }
+                OP_LUMA => {
+                    // green differs by -32 to 31, red and blue by up to 8 more or less than it
+                    let green = (op & 0x3F).wrapping_sub(32);
+                    let rest = byte(offset)?;
+                    offset += 1;
+                    pixel[0] = pixel[0].wrapping_add(green).wrapping_add(rest >> 4).wrapping_sub(8);
+                    pixel[1] = pixel[1].wrapping_add(green);
+                    pixel[2] = pixel[2].wrapping_add(green).wrapping_add(rest & 0x0F).wrapping_sub(8);
+                }
+                // a run, of up to 62 since 63 and 64 would be OP_RGB and OP_RGBA
+                _ => run = (op & 0x3F) as usize + 1,
+            }
+        }
+
+        let [r, g, b, a] = pixel.map(|channel| channel as usize);
+        seen[(r * 3 + g * 5 + b * 7 + a * 11) % 64] = pixel;
+
+        let end = usize::min(next + run, image.pixels.len());
+        image.pixels[next..end].fill(Color(pixel[0], pixel[1], pixel[2]));
+        if let Some(alpha) = &mut image.alpha {
+            alpha[next..end].fill(pixel[3]);
+        }
+        next = end;
+    }
+
+    image.drop_opaque_alpha();
+    Ok(image)
+}
@@ -0,0 +1,1161 @@
+mod ansi;
+mod consoles;
+mod draw;
+mod glyph;
+pub mod image;
+pub mod splash;
+
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::collections::TryReserveError;
+use alloc::vec::Vec;
+use core::{cell::OnceCell, fmt};
+
+use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
+use noto_sans_mono_bitmap::{get_raster, get_raster_width, FontWeight, RasterHeight};
+use pixel::Bitmask;
+use spin::Mutex;
+
+use ansi::{Action, Csi, Parser};
+use glyph::{Glyph, GlyphCache};
+
+pub use consoles::{VirtualConsoles, LOG_CONSOLE, PRINT_CONSOLE};
+pub use pixel::Color;
+
+/// (x, y) coordinate
+#[derive(Clone, Copy)]
+pub struct Point(pub usize, pub usize);
+
+/// The pixels from `top_left` up to but not including `bottom_right`.
+#[derive(Clone, Copy)]
+pub struct Rect {
+    pub top_left: Point,
+    pub bottom_right: Point,
+}
+
+impl Rect {
+    pub fn new(top_left: Point, bottom_right: Point) -> Rect {
+        Rect { top_left, bottom_right }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.top_left.0 >= self.bottom_right.0 || self.top_left.1 >= self.bottom_right.1
+    }
+
+    /// The smallest rectangle holding both.
+    pub fn union(&self, other: &Rect) -> Rect {
+        Rect {
+            top_left: Point(
+                usize::min(self.top_left.0, other.top_left.0),
+                usize::min(self.top_left.1, other.top_left.1),
+            ),
+            bottom_right: Point(
+                usize::max(self.bottom_right.0, other.bottom_right.0),
+                usize::max(self.bottom_right.1, other.bottom_right.1),
+            ),
+        }
+    }
+
+    /// The part both cover, empty if they don't overlap.
+    pub fn intersection(&self, other: &Rect) -> Rect {
+        Rect {
+            top_left: Point(
+                usize::max(self.top_left.0, other.top_left.0),
+                usize::max(self.top_left.1, other.top_left.1),
+            ),
+            bottom_right: Point(
+                usize::min(self.bottom_right.0, other.bottom_right.0),
+                usize::min(self.bottom_right.1, other.bottom_right.1),
+            ),
+        }
+    }
+
+    /// Whether the two overlap or share an edge.
+    pub fn touches(&self, other: &Rect) -> bool {
+        self.top_left.0 <= other.bottom_right.0
+            && other.top_left.0 <= self.bottom_right.0
+            && self.top_left.1 <= other.bottom_right.1
+            && other.top_left.1 <= self.bottom_right.1
+    }
+}
+
+pub static CONSOLES: Mutex<OnceCell<VirtualConsoles>> = Mutex::new(OnceCell::new());
+
+/// Lines of history the console keeps unless told otherwise.
+const DEFAULT_SCROLLBACK: usize = 500;
+/// Most dirty rectangles a back buffer tracks before they're merged into one.
+const MAX_DIRTY_RECTS: usize = 16;
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::display::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::display::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! clearscrn {
+    () => {
+        $crate::display::_clearscrn()
+    };
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        CONSOLES
+        .lock()
+        .get_mut()
+        .expect("Uninitialized CONSOLES")
+        .console(PRINT_CONSOLE)
+        .write_fmt(args)
+        .unwrap();
+    });
+}
+
+#[doc(hidden)]
+pub fn _clearscrn() {
+    CONSOLES
+        .lock()
+        .get_mut()
+        .expect("Uninitialized CONSOLES")
+        .console(PRINT_CONSOLE)
+        .clear();
+}
+
+/// Writes lines of text to the console for a second, scrolling all the while, and returns
+/// how many characters a second it managed. Needs the clock and interrupts to be running, and
+/// `PRINT_CONSOLE` on the screen, otherwise nothing is drawn.
+#[cfg(feature = "console-bench")]
+pub fn benchmark() -> u64 {
+    use crate::time::uptime_ms;
+
+    const LINE: &str = "The quick brown \x1b[1;32mfox\x1b[0m jumps over the lazy dog 0123456789\n";
+    // the escape sequences aren't drawn
+    const DRAWN: u64 = LINE.len() as u64 - 11;
+
+    let start = uptime_ms();
+    let mut chars = 0;
+    while uptime_ms() - start < 1000 {
+        _print(format_args!("{}", LINE));
+        chars += DRAWN;
+    }
+    chars * 1000 / (uptime_ms() - start)
+}
+
+/// Clears the console over and over for a second and returns how many times a second it
+/// managed, which is mostly filling and presenting the whole framebuffer.
+#[cfg(feature = "console-bench")]
+pub fn benchmark_fill() -> u64 {
+    use crate::time::uptime_ms;
+    use x86_64::instructions::interrupts;
+
+    let start = uptime_ms();
+    let mut frames = 0;
+    while uptime_ms() - start < 1000 {
+        interrupts::without_interrupts(_clearscrn);
+        frames += 1;
+    }
+    frames * 1000 / (uptime_ms() - start)
+}
+
+pub struct Display {
+    fb: &'static mut FrameBuffer,
+    fb_info: FrameBufferInfo,
+    // A copy of the framebuffer in normal memory that drawing goes to, if enabled
+    back_buffer: Option<Vec<u8>>,
+    // The parts of the back buffer that changed since they were last presented
+    dirty: Vec<Rect>,
+    // Where shapes are drawn, see `set_clip`
+    clip: Rect,
+    // How opaque shapes are drawn
+    alpha: u8,
+    // The layout of pixels, for framebuffers with PixelFormat::Unknown
+    bitmask: Option<Bitmask>,
+}
+
+impl Display {
+    pub fn new(fb: &'static mut FrameBuffer) -> Display {
+        let fb_info = fb.info();
+
+        if fb_info.bytes_per_pixel > 4 {
+            panic!("framebuffer pixels are wider than 4 bytes");
+        }
+        let bitmask = match fb_info.pixel_format {
+            PixelFormat::Rgb | PixelFormat::Bgr | PixelFormat::U8 => None,
+            // firmware with unusual GOP modes only tells where each channel starts
+            PixelFormat::Unknown { red_position, green_position, blue_position } => {
+                let positions = [red_position, green_position, blue_position];
+                match Bitmask::new(positions, fb_info.bytes_per_pixel) {
+                    Some(bitmask) => Some(bitmask),
+                    None => panic!("framebuffer pixel format {:?} doesn't fit its pixels", fb_info.pixel_format),
+                }
+            }
+            _ => panic!("unknown pixel format for framebuffer"),
+        };
+
+        Display {
+            fb,
+            fb_info,
+            back_buffer: None,
+            dirty: Vec::new(),
+            clip: Rect::new(Point(0, 0), Point(fb_info.width, fb_info.height)),
+            alpha: u8::MAX,
+            bitmask,
+        }
+    }
+
+    /// Draws into a buffer in normal memory from now on, which only reaches the screen on
+    /// `present`. Video memory is slow, especially to read, and the screen never shows anything
+    /// half drawn.
+    pub fn enable_back_buffer(&mut self) -> Result<(), TryReserveError> {
+        if self.back_buffer.is_some() {
+            return Ok(());
+        }
+        // the buffer is as big as the framebuffer, so running out of memory is a real prospect
+        let mut back_buffer = Vec::new();
+        back_buffer.try_reserve_exact(self.fb.buffer().len())?;
+        back_buffer.extend_from_slice(self.fb.buffer());
+        self.back_buffer = Some(back_buffer);
+        Ok(())
+    }
+
+    /// Goes back to drawing straight to the screen, presenting whatever wasn't yet.
+    pub fn disable_back_buffer(&mut self) {
+        self.present();
+        self.back_buffer = None;
+    }
+
+    /// Copies the parts of the back buffer that changed to the screen, a row of each at a time.
+    /// Does nothing without a back buffer.
+    pub fn present(&mut self) {
+        let back_buffer = match &self.back_buffer {
+            Some(back_buffer) => back_buffer,
+            None => return,
+        };
+
+        let bytes_per_pixel = self.fb_info.bytes_per_pixel;
+        let stride = self.fb_info.stride * bytes_per_pixel;
+        let fb = self.fb.buffer_mut();
+        for rect in self.dirty.drain(..) {
+            for y in rect.top_left.1..rect.bottom_right.1 {
+                let start = y * stride + rect.top_left.0 * bytes_per_pixel;
+                let end = y * stride + rect.bottom_right.0 * bytes_per_pixel;
+                fb[start..end].copy_from_slice(&back_buffer[start..end]);
+            }
+        }
+    }
+
+    /// Where drawing goes, the back buffer if there is one.
+    fn buffer(&mut self) -> &mut [u8] {
+        match &mut self.back_buffer {
+            Some(back_buffer) => back_buffer,
+            None => self.fb.buffer_mut(),
+        }
+    }
+
+    /// Notes that `rect` of the back buffer changed and has to be presented.
+    fn mark_dirty(&mut self, rect: Rect) {
+        if self.back_buffer.is_none() || rect.is_empty() {
+            return;
+        }
+
+        // text is drawn a cell after the other, merging neighbors keeps that a single rectangle
+        if let Some(dirty) = self.dirty.iter_mut().find(|dirty| dirty.touches(&rect)) {
+            *dirty = dirty.union(&rect);
+        } else if self.dirty.len() < MAX_DIRTY_RECTS {
+            self.dirty.push(rect);
+        } else {
+            let all = self.dirty.drain(..).fold(rect, |all, dirty| all.union(&dirty));
+            self.dirty.push(all);
+        }
+    }
+
+    fn pixel_pos_to_real(&self, position: Point) -> usize {
+        let stride = self.fb_info.stride * self.fb_info.bytes_per_pixel;
+        stride * position.1 + position.0 * self.fb_info.bytes_per_pixel
+    }
+
+    /// This function is unsafe as it does not do bounds checking to determine
+    /// if the given pixel coordinates are within the bounds of the framebuffer.
+    pub unsafe fn draw_pixel(&mut self, color: Color, position: Point) {
+        let bytes_per_pixel = self.fb_info.bytes_per_pixel;
+        let mut pixel = [0; 4];
+        self.encode_pixel(color, &mut pixel[..bytes_per_pixel]);
+        let offset = self.pixel_pos_to_real(position);
+        self.buffer()[offset..offset + bytes_per_pixel].copy_from_slice(&pixel[..bytes_per_pixel]);
+        self.mark_dirty(Rect::new(position, Point(position.0 + 1, position.1 + 1)));
+    }
+
+    /// Fills `length` pixels of a row, starting at `start`, with `color`. It's up to the caller
+    /// to make sure the span is inside the framebuffer.
+    fn fill_span(&mut self, color: Color, start: Point, length: usize) {
+        let bytes_per_pixel = self.fb_info.bytes_per_pixel;
+        let mut pixel = [0; 4];
+        self.encode_pixel(color, &mut pixel[..bytes_per_pixel]);
+        let offset = self.pixel_pos_to_real(start);
+        let span = &mut self.buffer()[offset..offset + length * bytes_per_pixel];
+
+        match bytes_per_pixel {
+            1 => span.fill(pixel[0]),
+            4 => {
+                // rows of 4 byte pixels are word aligned in any framebuffer there is, but check
+                let (head, words, tail) = unsafe { span.align_to_mut::<u32>() };
+                if head.is_empty() && tail.is_empty() {
+                    words.fill(u32::from_ne_bytes(pixel));
+                } else {
+                    repeat_pixel(span, &pixel[..bytes_per_pixel]);
+                }
+            }
+            _ => repeat_pixel(span, &pixel[..bytes_per_pixel]),
+        }
+    }
+
+    /// Copies an image `width` pixels wide, its rows one after the other in `pixels`, with its
+    /// top left corner at `position`.
+    pub fn blit(&mut self, pixels: &[Color], width: usize, position: Point) {
+        if width == 0 {
+            return;
+        }
+        let height = pixels.len() / width;
+        if position.0 + width > self.fb_info.width || position.1 + height > self.fb_info.height {
+            panic!("Image would exceed framebuffer bounds");
+        }
+
+        let bytes_per_pixel = self.fb_info.bytes_per_pixel;
+        let mut row = vec![0; width * bytes_per_pixel];
+        for (y, colors) in pixels.chunks_exact(width).enumerate() {
+            for (pixel, &color) in row.chunks_exact_mut(bytes_per_pixel).zip(colors) {
+                self.encode_pixel(color, pixel);
+            }
+            let start = self.pixel_pos_to_real(Point(position.0, position.1 + y));
+            self.buffer()[start..start + row.len()].copy_from_slice(&row);
+        }
+        self.mark_dirty(Rect::new(position, Point(position.0 + width, position.1 + height)));
+    }
+
+    pub fn clear(&mut self, color: Color) {
+        self.draw_rect(
+            color,
+            Point(0, 0),
+            Point(self.fb_info.width, self.fb_info.height),
+        );
+    }
+
+    pub fn draw_rect(&mut self, color: Color, top_left: Point, bottom_right: Point) {
+        if top_left.0 > bottom_right.0 {
+            panic!("Top left coordinate is further right than the bottom right coordinate");
+        }
+        if top_left.1 > bottom_right.1 {
+            panic!("Top left coordinate is further down than the bottom right coordinate");
+        }
+        if usize::max(top_left.0, bottom_right.0) > self.fb_info.width {
+            panic!("X coordinate is larger than framebuffer");
+        }
+        if usize::max(top_left.1, bottom_right.1) > self.fb_info.height {
+            panic!("Y coordinate is larger than framebuffer");
+        }
+
+        let length = bottom_right.0 - top_left.0;
+        for y in top_left.1..bottom_right.1 {
+            self.fill_span(color, Point(top_left.0, y), length);
+        }
+        self.mark_dirty(Rect::new(top_left, bottom_right));
+    }
+
+    /// Draws `c` in `color` over `background`, blending the two at the edges of the glyph.
+    pub fn putc(
+        &mut self,
+        c: char,
+        color: Color,
+        background: Color,
+        height: RasterHeight,
+        weight: FontWeight,
+        position: Point,
+    ) {
+        let glyph = self.render_glyph(c, color, background, height, weight);
+        self.draw_glyph(&glyph, position);
+    }
+
+    /// Renders `c` in `color` over `background` into the framebuffer's pixel format, ready to be
+    /// drawn any number of times with `draw_glyph`.
+    pub fn render_glyph(
+        &self,
+        c: char,
+        color: Color,
+        background: Color,
+        height: RasterHeight,
+        weight: FontWeight,
+    ) -> Glyph {
+        let raster = match get_raster(c, weight, height) {
+            Some(raster) => raster,
+            None => get_raster('?', weight, height).unwrap(),
+        };
+
+        let bytes_per_pixel = self.fb_info.bytes_per_pixel;
+        let mut pixels = vec![0; raster.width() * raster.height() * bytes_per_pixel];
+        let rows = raster.raster().iter().flat_map(|row| row.iter());
+        for (pixel, &intensity) in pixels.chunks_exact_mut(bytes_per_pixel).zip(rows) {
+            let blend = |fg: u8, bg: u8| {
+                ((fg as u16 * intensity as u16 + bg as u16 * (255 - intensity as u16)) / 255) as u8
+            };
+            let color = Color(
+                blend(color.0, background.0),
+                blend(color.1, background.1),
+                blend(color.2, background.2),
+            );
+            self.encode_pixel(color, pixel);
+        }
+
+        Glyph {
+            width: raster.width(),
+            height: raster.height(),
+            pixels,
+        }
+    }
+
+    /// Draws a glyph made by `render_glyph` with its top left corner at `position`.
+    pub fn draw_glyph(&mut self, glyph: &Glyph, position: Point) {
+        if position.0 + glyph.width > self.fb_info.width || position.1 + glyph.height > self.fb_info.height {
+            panic!("Printing character would exceed framebuffer bounds")
+        }
+
+        let row_len = glyph.width * self.fb_info.bytes_per_pixel;
+        for (row, pixels) in glyph.pixels.chunks_exact(row_len).enumerate() {
+            let start = self.pixel_pos_to_real(Point(position.0, position.1 + row));
+            self.buffer()[start..start + row_len].copy_from_slice(pixels);
+        }
+        let bottom_right = Point(position.0 + glyph.width, position.1 + glyph.height);
+        self.mark_dirty(Rect::new(position, bottom_right));
+    }
+
+    /// Writes `color` as the bytes of one pixel in the framebuffer's format. The padding byte
+    /// of 4 byte RGB and BGR is left alone, so it stays whatever `pixel` was, 0 everywhere here.
+    /// Bitmask layouts write the whole pixel, with 0 in the bits no channel uses.
+    fn encode_pixel(&self, color: Color, pixel: &mut [u8]) {
+        match self.fb_info.pixel_format {
+            PixelFormat::Rgb => pixel[..3].copy_from_slice(&[color.0, color.1, color.2]),
+            PixelFormat::Bgr => pixel[..3].copy_from_slice(&[color.2, color.1, color.0]),
+            PixelFormat::U8 => pixel[0] = ((color.0 as u16 + color.1 as u16 + color.2 as u16) / 3) as u8,
+            // `new` turns down every other format, and has the bitmask of Unknown ready
+            _ => match &self.bitmask {
+                Some(bitmask) => bitmask.encode(color, pixel),
+                None => unreachable!(),
+            },
+        }
+    }
+
+    /// Reads the color of a pixel in the framebuffer's format.
+    fn decode_pixel(&self, pixel: &[u8]) -> Color {
+        match self.fb_info.pixel_format {
+            PixelFormat::Rgb => Color(pixel[0], pixel[1], pixel[2]),
+            PixelFormat::Bgr => Color(pixel[2], pixel[1], pixel[0]),
+            PixelFormat::U8 => Color(pixel[0], pixel[0], pixel[0]),
+            _ => match &self.bitmask {
+                Some(bitmask) => bitmask.decode(pixel),
+                None => unreachable!(),
+            },
+        }
+    }
+
+    /// Copies a rectangular chunk from from src to dst using the given width and height.
+    /// This function is unsafe as it does not do bound checks to make sure the rectangles
+    /// aren't exceeding the size of the framebuffer.
+    pub unsafe fn copy_rect(&mut self, src: Point, dst: Point, width: usize, height: usize) {
+        // convert from (x, y) coordinates to real pixel position coordinates
+        for y in 0..height {
+            // we copy one horizontal line at a time
+            let src_line_start = self.pixel_pos_to_real(Point(src.0, src.1 + y));
+            let src_line_end = self.pixel_pos_to_real(Point(src.0 + width, src.1 + y));
+            let dst_line_start = self.pixel_pos_to_real(Point(dst.0, dst.1 + y));
+
+            self.buffer()
+                .copy_within(src_line_start..src_line_end, dst_line_start);
+        }
+        self.mark_dirty(Rect::new(dst, Point(dst.0 + width, dst.1 + height)));
+    }
+}
+
+/// Fills `span` with copies of `pixel` by writing it once, then doubling what's written with
+/// each copy.
+fn repeat_pixel(span: &mut [u8], pixel: &[u8]) {
+    if span.len() < pixel.len() {
+        return;
+    }
+    span[..pixel.len()].copy_from_slice(pixel);
+    let mut filled = pixel.len();
+    while filled < span.len() {
+        let count = usize::min(filled, span.len() - filled);
+        span.copy_within(..count, filled);
+        filled += count;
+    }
+}
+
+/// A character on the console and how it's drawn.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    c: char,
+    text_color: Color,
+    clear_color: Color,
+    bold: bool,
+}
+
+/// The cursor and text attributes as saved by save cursor.
+#[derive(Clone, Copy)]
+struct SavedCursor {
+    cursor: Point,
+    text_color: Color,
+    clear_color: Color,
+    bold: bool,
+}
+
+/// A console on the framebuffer, understanding the VT100/ANSI escape sequences programs use for
+/// colors and moving the cursor around.
+pub struct TextDisplay {
+    // x, y coordinates of column and row, in chars
+    pub cursor: Point,
+    // Width in terms of how many chars fit on a line
+    width: usize,
+    // Height in terms of how many lines fit in the framebuffer
+    height: usize,
+    // The background color
+    clear_color: Color,
+    // The text color
+    text_color: Color,
+    // The colors a reset of the text attributes goes back to
+    default_clear_color: Color,
+    default_text_color: Color,
+    bold: bool,
+    saved: SavedCursor,
+    // The first and last line that scroll when a new line goes past the bottom
+    scroll_top: usize,
+    scroll_bottom: usize,
+    font_height: RasterHeight,
+    // The weight of text that isn't bold
+    font_weight: FontWeight,
+    // The text on screen, a line at a time, so it can be drawn again
+    screen: Vec<Vec<Cell>>,
+    // Lines that scrolled off the top of the screen, oldest first
+    history: VecDeque<Vec<Cell>>,
+    // The most lines of history kept
+    scrollback: usize,
+    // How many lines back into the history the view is, 0 when it shows the screen
+    view_offset: usize,
+    // What the framebuffer shows, so only cells that changed are drawn, None where it's unknown
+    drawn: Vec<Vec<Option<Cell>>>,
+    // Lines of the view that changed since they were last drawn
+    dirty: Vec<bool>,
+    glyphs: GlyphCache,
+    parser: Parser,
+    // Whether something else has the screen, the text is still kept but not drawn
+    hidden: bool,
+    // Size of the screen in pixels, which the console lays its grid out for even without one
+    pixel_width: usize,
+    pixel_height: usize,
+    // Where the console draws, None while another console has the screen
+    display: Option<Display>,
+}
+
+impl TextDisplay {
+    /// A console for a screen `pixel_width` by `pixel_height` pixels big, which keeps its text
+    /// without drawing it until it's given a display with `attach`.
+    pub fn new(pixel_width: usize, pixel_height: usize, clear_color: Color, text_color: Color) -> TextDisplay {
+        let cursor = Point(0, 0);
+        let font_height = RasterHeight::Size20;
+        let font_weight = FontWeight::Regular;
+        let width = pixel_width / get_raster_width(font_weight, font_height);
+        let height = pixel_height / font_height.val();
+
+        TextDisplay {
+            cursor,
+            width,
+            height,
+            clear_color,
+            text_color,
+            default_clear_color: clear_color,
+            default_text_color: text_color,
+            bold: false,
+            saved: SavedCursor {
+                cursor,
+                text_color,
+                clear_color,
+                bold: false,
+            },
+            scroll_top: 0,
+            scroll_bottom: height - 1,
+            font_height,
+            font_weight,
+            screen: vec![vec![Self::blank_cell(text_color, clear_color); width]; height],
+            history: VecDeque::new(),
+            scrollback: DEFAULT_SCROLLBACK,
+            view_offset: 0,
+            drawn: vec![vec![None; width]; height],
+            dirty: vec![true; height],
+            glyphs: GlyphCache::new(),
+            parser: Parser::new(),
+            hidden: false,
+            pixel_width,
+            pixel_height,
+            display: None,
+        }
+    }
+
+    /// Switches to another size or weight of the font. The grid is laid out again for the new
+    /// cell size and the text redrawn in it, lines that no longer fit going into the history.
+    pub fn set_font(&mut self, height: RasterHeight, weight: FontWeight) {
+        self.font_height = height;
+        self.font_weight = weight;
+        self.width = self.pixel_width / self.raster_width();
+        self.height = self.pixel_height / self.raster_height();
+
+        let blank = self.blank();
+        while self.screen.len() > self.height {
+            let line = self.screen.remove(0);
+            self.push_history(line);
+            self.cursor.1 = self.cursor.1.saturating_sub(1);
+        }
+        self.screen.resize(self.height, Vec::new());
+        for line in self.screen.iter_mut() {
+            line.resize(self.width, blank);
+        }
+
+        self.scroll_top = 0;
+        self.scroll_bottom = self.height - 1;
+        self.cursor = Point(usize::min(self.cursor.0, self.width - 1), self.cursor.1);
+        self.saved.cursor = self.cursor;
+        self.view_offset = 0;
+        self.glyphs.clear();
+        // the old cells don't line up with the new ones, so everything is drawn again
+        let clear_color = self.clear_color;
+        if let Some(display) = self.visible_display() {
+            display.clear(clear_color);
+        }
+        self.drawn = vec![vec![None; self.width]; self.height];
+        self.dirty = vec![true; self.height];
+        self.render();
+    }
+
+    /// Sets how many lines that scrolled off the screen are kept, dropping the oldest ones
+    /// beyond that.
+    pub fn set_scrollback(&mut self, lines: usize) {
+        self.scrollback = lines;
+        while self.history.len() > lines {
+            self.history.pop_front();
+        }
+        if self.view_offset > lines {
+            self.move_view(lines);
+        }
+    }
+
+    /// Scrolls the view back through the history by half a screen.
+    pub fn page_up(&mut self) {
+        self.move_view(usize::min(self.view_offset + self.height / 2, self.history.len()));
+    }
+
+    /// Scrolls the view forward towards the screen by half a screen.
+    pub fn page_down(&mut self) {
+        self.move_view(self.view_offset.saturating_sub(self.height / 2));
+    }
+
+    /// Goes back to showing the screen if the view is in the history, anything written shows
+    /// up there.
+    fn show_screen(&mut self) {
+        self.move_view(0);
+    }
+
+    fn move_view(&mut self, offset: usize) {
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.dirty.fill(true);
+            self.render();
+        }
+    }
+
+    /// Draws everything again, for when something else drew over the console.
+    pub fn redraw(&mut self) {
+        for line in self.drawn.iter_mut() {
+            line.fill(None);
+        }
+        self.dirty.fill(true);
+        self.render();
+    }
+
+    /// Stops drawing the console, so something else can have the screen through `display`.
+    /// Text written in the meantime is kept and shows once the console is shown again.
+    pub fn hide(&mut self) {
+        self.hidden = true;
+    }
+
+    /// Takes the screen back from whatever had it since `hide`, drawing the console over it.
+    pub fn show(&mut self) {
+        if !self.hidden {
+            return;
+        }
+        self.hidden = false;
+        self.repaint();
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.hidden
+    }
+
+    /// Gives the console the screen, drawing it over whatever was there unless it's hidden.
+    pub fn attach(&mut self, display: Display) {
+        self.display = Some(display);
+        self.repaint();
+    }
+
+    /// Takes the screen away from the console, which keeps its text without drawing it.
+    pub fn detach(&mut self) -> Option<Display> {
+        self.display.take()
+    }
+
+    /// The display under the console, to draw on while it's hidden, None if it has none.
+    pub fn display(&mut self) -> Option<&mut Display> {
+        self.display.as_mut()
+    }
+
+    /// The display, if the console has one and isn't hidden.
+    fn visible_display(&mut self) -> Option<&mut Display> {
+        match self.hidden {
+            true => None,
+            false => self.display.as_mut(),
+        }
+    }
+
+    /// Clears the screen and draws the whole console on it.
+    fn repaint(&mut self) {
+        let clear_color = self.clear_color;
+        if let Some(display) = self.visible_display() {
+            display.clear(clear_color);
+            self.redraw();
+        }
+    }
+
+    /// Draws into a back buffer in normal memory, presented once a write is done, if
+    /// `enabled`, or straight to the framebuffer otherwise. The back buffer belongs to the
+    /// display, so it goes wherever the display goes.
+    pub fn set_double_buffered(&mut self, enabled: bool) -> Result<(), TryReserveError> {
+        match &mut self.display {
+            Some(display) if enabled => display.enable_back_buffer(),
+            Some(display) => {
+                display.disable_back_buffer();
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Draws the cells of the view that changed since they were last drawn.
+    ///
+    /// Only ever the screen changes while the view is on the screen, so the dirty lines of the
+    /// screen and the view are the same.
+    fn render(&mut self) {
+        if self.visible_display().is_none() {
+            return;
+        }
+        let first = self.history.len() - self.view_offset;
+        let blank = self.blank();
+        for line in 0..self.height {
+            if !self.dirty[line] {
+                continue;
+            }
+            self.dirty[line] = false;
+
+            let index = first + line;
+            for column in 0..self.width {
+                let cell = match self.history.get(index) {
+                    // history from before the font changed can be narrower than the screen
+                    Some(cells) => cells.get(column).copied().unwrap_or(blank),
+                    None => self.screen[index - self.history.len()][column],
+                };
+                if self.drawn[line][column] != Some(cell) {
+                    self.draw_cell(line, column, cell);
+                    self.drawn[line][column] = Some(cell);
+                }
+            }
+        }
+        if let Some(display) = self.visible_display() {
+            display.present();
+        }
+    }
+
+    fn push_history(&mut self, line: Vec<Cell>) {
+        if self.scrollback == 0 {
+            return;
+        }
+        if self.history.len() == self.scrollback {
+            self.history.pop_front();
+        }
+        self.history.push_back(line);
+    }
+
+    const fn blank_cell(text_color: Color, clear_color: Color) -> Cell {
+        Cell {
+            c: ' ',
+            text_color,
+            clear_color,
+            bold: false,
+        }
+    }
+
+    /// An empty cell in the current colors, what erasing leaves behind.
+    fn blank(&self) -> Cell {
+        Self::blank_cell(self.text_color, self.clear_color)
+    }
+
+    fn draw_cell(&mut self, line: usize, column: usize, cell: Cell) {
+        let weight = if cell.bold { FontWeight::Bold } else { self.font_weight };
+        let height = self.font_height;
+        let position = Point(column * self.raster_width(), line * self.raster_height());
+
+        let display = match &mut self.display {
+            Some(display) => display,
+            None => return,
+        };
+        let glyph = self.glyphs.get(cell.c, cell.bold, cell.text_color, cell.clear_color, || {
+            display.render_glyph(cell.c, cell.text_color, cell.clear_color, height, weight)
+        });
+        display.draw_glyph(glyph, position);
+    }
+
+    // every weight of the font is as wide, so bold text fits the same cells
+    fn raster_width(&self) -> usize {
+        get_raster_width(self.font_weight, self.font_height)
+    }
+
+    fn raster_height(&self) -> usize {
+        self.font_height.val()
+    }
+
+    pub fn move_cursor(&mut self, position: Point) {
+        if position.0 > self.width || position.1 > self.height {
+            panic!("Attempted to move cursor outside of framebuffer bounds");
+        }
+        self.cursor = position;
+    }
+
+    pub fn increment_cursor_pos(&mut self) {
+        if self.cursor.0 == self.width-1 {
+            self.cursor_new_line();
+        }
+        else {
+            self.cursor.0 = (self.cursor.0 + 1) % self.width;
+        }
+    }
+
+    pub fn cursor_new_line(&mut self) {
+        self.cursor.0 = 0;
+        self.line_feed();
+    }
+
+    /// Moves the cursor down a line, scrolling if it's on the last line of the scroll region.
+    fn line_feed(&mut self) {
+        if self.cursor.1 == self.scroll_bottom {
+            self.scroll_down();
+        } else if self.cursor.1 + 1 < self.height {
+            self.cursor.1 += 1;
+        }
+    }
+
+    /// Moves the cursor up a line, scrolling back if it's on the first line of the scroll region.
+    fn reverse_line_feed(&mut self) {
+        if self.cursor.1 == self.scroll_top {
+            self.scroll_up(1);
+        } else if self.cursor.1 > 0 {
+            self.cursor.1 -= 1;
+        }
+    }
+
+    pub fn set_clear_color(&mut self, color: Color) {
+        self.clear_color = color;
+    }
+
+    pub fn set_text_color(&mut self, color: Color) {
+        self.text_color = color;
+    }
+
+    pub fn clear(&mut self) {
+        self.view_offset = 0;
+        let blank = self.blank();
+        for line in self.screen.iter_mut() {
+            line.fill(blank);
+        }
+        let clear_color = self.clear_color;
+        let display = match self.visible_display() {
+            Some(display) => display,
+            None => {
+                self.dirty.fill(true);
+                return;
+            }
+        };
+        display.clear(clear_color);
+        display.present();
+        for line in self.drawn.iter_mut() {
+            line.fill(Some(blank));
+        }
+        self.dirty.fill(false);
+    }
+
+    pub fn clear_line(&mut self, line: usize) {
+        if line >= self.height {
+            panic!("Line index is greater than the maximum allowed index!");
+        }
+        self.clear_cells(line, 0, self.width);
+    }
+
+    /// Clears the cells of `line` from column `start` up to but not including `end`.
+    fn clear_cells(&mut self, line: usize, start: usize, end: usize) {
+        let blank = self.blank();
+        self.screen[line][start..end].fill(blank);
+        self.dirty[line] = true;
+    }
+
+    /// Writes `text` to the text model, then draws what changed.
+    pub fn write_text(&mut self, text: &str) {
+        self.show_screen();
+        for c in text.chars() {
+            match self.parser.advance(c) {
+                Some(Action::Print(c)) => self.put_char(c),
+                Some(Action::Control(c)) => self.control(c),
+                Some(Action::Escape(c)) => self.escape(c),
+                Some(Action::Csi(csi)) => self.control_sequence(&csi),
+                None => {}
+            }
+        }
+        self.render();
+    }
+
+    fn put_char(&mut self, c: char) {
+        let Point(column, line) = self.cursor;
+        let cell = Cell {
+            c,
+            text_color: self.text_color,
+            clear_color: self.clear_color,
+            bold: self.bold,
+        };
+        self.screen[line][column] = cell;
+        self.dirty[line] = true;
+        self.increment_cursor_pos();
+    }
+
+    fn control(&mut self, c: char) {
+        match c {
+            // a newline also goes back to the start of the line, there's no terminal driver
+            // to turn "\n" into "\r\n"
+            '\n' | '\x0b' | '\x0c' => self.cursor_new_line(),
+            '\r' => self.cursor.0 = 0,
+            '\x08' => self.cursor.0 = self.cursor.0.saturating_sub(1),
+            '\t' => self.cursor.0 = usize::min((self.cursor.0 / 8 + 1) * 8, self.width - 1),
+            _ => {}
+        }
+    }
+
+    fn escape(&mut self, c: char) {
+        match c {
+            '7' => self.save_cursor(),
+            '8' => self.restore_cursor(),
+            // index, next line and reverse index
+            'D' => self.line_feed(),
+            'E' => self.cursor_new_line(),
+            'M' => self.reverse_line_feed(),
+            'c' => self.reset(),
+            _ => {}
+        }
+    }
+
+    fn control_sequence(&mut self, csi: &Csi) {
+        // nothing private, like hiding the cursor, is supported
+        if csi.private {
+            return;
+        }
+
+        let n = csi.param(0, 1) as usize;
+        let last_column = self.width - 1;
+        let last_line = self.height - 1;
+        match csi.final_char {
+            'A' => self.cursor.1 = self.cursor.1.saturating_sub(n),
+            'B' => self.cursor.1 = usize::min(self.cursor.1 + n, last_line),
+            'C' => self.cursor.0 = usize::min(self.cursor.0 + n, last_column),
+            'D' => self.cursor.0 = self.cursor.0.saturating_sub(n),
+            'G' => self.cursor.0 = usize::min(n - 1, last_column),
+            'd' => self.cursor.1 = usize::min(n - 1, last_line),
+            'H' | 'f' => {
+                let line = csi.param(0, 1) as usize - 1;
+                let column = csi.param(1, 1) as usize - 1;
+                self.cursor = Point(usize::min(column, last_column), usize::min(line, last_line));
+            }
+            'J' => self.erase_in_display(csi.param(0, 0)),
+            'K' => self.erase_in_line(csi.param(0, 0)),
+            'S' => self.scroll_down_lines(n),
+            'T' => self.scroll_up(n),
+            'm' => self.select_graphic_rendition(csi.params()),
+            'r' => {
+                let top = csi.param(0, 1) as usize - 1;
+                let bottom = csi.param(1, self.height as u16) as usize - 1;
+                if top < bottom && bottom < self.height {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                    self.cursor = Point(0, 0);
+                }
+            }
+            's' => self.save_cursor(),
+            'u' => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    fn erase_in_display(&mut self, mode: u16) {
+        let Point(column, line) = self.cursor;
+        match mode {
+            0 => {
+                self.clear_cells(line, column, self.width);
+                for line in line + 1..self.height {
+                    self.clear_line(line);
+                }
+            }
+            1 => {
+                for line in 0..line {
+                    self.clear_line(line);
+                }
+                self.clear_cells(line, 0, column + 1);
+            }
+            2 => {
+                for line in 0..self.height {
+                    self.clear_line(line);
+                }
+            }
+            3 => {
+                self.history.clear();
+                for line in 0..self.height {
+                    self.clear_line(line);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn erase_in_line(&mut self, mode: u16) {
+        let Point(column, line) = self.cursor;
+        match mode {
+            0 => self.clear_cells(line, column, self.width),
+            1 => self.clear_cells(line, 0, column + 1),
+            2 => self.clear_line(line),
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.reset_attributes();
+        }
+
+        let mut i = 0;
+        while i < params.len() {
+            match params[i] {
+                0 => self.reset_attributes(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                param @ 30..=37 => self.set_text_color(ansi::palette(param as u8 - 30)),
+                param @ 90..=97 => self.set_text_color(ansi::palette(param as u8 - 90 + 8)),
+                39 => self.set_text_color(self.default_text_color),
+                param @ 40..=47 => self.set_clear_color(ansi::palette(param as u8 - 40)),
+                param @ 100..=107 => self.set_clear_color(ansi::palette(param as u8 - 100 + 8)),
+                49 => self.set_clear_color(self.default_clear_color),
+                param @ (38 | 48) => {
+                    let (color, used) = ansi::extended_color(&params[i + 1..]);
+                    if let Some(color) = color {
+                        if param == 38 {
+                            self.set_text_color(color);
+                        } else {
+                            self.set_clear_color(color);
+                        }
+                    }
+                    i += used;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    fn reset_attributes(&mut self) {
+        self.text_color = self.default_text_color;
+        self.clear_color = self.default_clear_color;
+        self.bold = false;
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = SavedCursor {
+            cursor: self.cursor,
+            text_color: self.text_color,
+            clear_color: self.clear_color,
+            bold: self.bold,
+        };
+    }
+
+    fn restore_cursor(&mut self) {
+        let saved = self.saved;
+        self.cursor = saved.cursor;
+        self.text_color = saved.text_color;
+        self.clear_color = saved.clear_color;
+        self.bold = saved.bold;
+    }
+
+    /// Puts the console back the way it started and clears it.
+    fn reset(&mut self) {
+        self.reset_attributes();
+        self.scroll_top = 0;
+        self.scroll_bottom = self.height - 1;
+        self.cursor = Point(0, 0);
+        self.save_cursor();
+        self.clear();
+    }
+
+    pub fn scroll_down(&mut self) {
+        self.scroll_down_lines(1);
+    }
+
+    /// Moves the text of the scroll region up by `lines`, blank lines coming in at the bottom.
+    /// Lines scrolling off the top of the screen go into the history.
+    fn scroll_down_lines(&mut self, lines: usize) {
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let lines = usize::min(lines, bottom - top + 1);
+        if top == 0 {
+            for line in 0..lines {
+                let cells = self.screen[line].clone();
+                self.push_history(cells);
+            }
+        }
+        self.screen[top..=bottom].rotate_left(lines);
+        self.dirty[top..=bottom].fill(true);
+        for line in bottom + 1 - lines..=bottom {
+            self.clear_line(line);
+        }
+    }
+
+    /// Moves the text of the scroll region down by `lines`, blank lines coming in at the top.
+    fn scroll_up(&mut self, lines: usize) {
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let lines = usize::min(lines, bottom - top + 1);
+        self.screen[top..=bottom].rotate_right(lines);
+        self.dirty[top..=bottom].fill(true);
+        for line in top..top + lines {
+            self.clear_line(line);
+        }
+    }
+}
+
+impl fmt::Write for TextDisplay {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_text(s);
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use noto_sans_mono_bitmap::{get_raster_width, FontWeight, RasterHeight};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::image::{Image, ImageError};
+use super::{Color, Display, Point, Rect, CONSOLES};
+
+const LOGO: &[u8] = include_bytes!("../../assets/bee.qoi");
+const TITLE: &str = "BeeOS";
+
+const BACKGROUND: Color = Color(24, 20, 12);
+const TITLE_COLOR: Color = Color(255, 220, 0);
+const BAR_COLOR: Color = Color(255, 190, 0);
+const BAR_OUTLINE: Color = Color(120, 90, 20);
+const TITLE_HEIGHT: RasterHeight = RasterHeight::Size32;
+const BAR_HEIGHT: usize = 12;
+/// Space between the logo, the title and the bar.
+const GAP: usize = 24;
+
+/// The progress bar, while the splash is showing.
+struct Progress {
+    steps: usize,
+    done: usize,
+    // Where the bar is, outline included
+    bar: Rect,
+}
+
+static SPLASH: Mutex<Option<Progress>> = Mutex::new(None);
+
+/// Hides the console on the screen behind the bee and a progress bar that fills up over `steps`
+/// calls to `advance`. Does nothing before the consoles are up.
+pub fn show(steps: usize) -> Result<(), ImageError> {
+    let logo = Image::decode(LOGO)?;
+
+    interrupts::without_interrupts(|| {
+        let mut splash = SPLASH.lock();
+        let mut lock = CONSOLES.lock();
+        let console = match lock.get_mut() {
+            Some(consoles) => consoles.active_console(),
+            None => return,
+        };
+        console.hide();
+        let display = match console.display() {
+            Some(display) => display,
+            None => return,
+        };
+        let bar = draw(display, &logo);
+        let progress = Progress { steps, done: 0, bar };
+        draw_bar(display, &progress);
+        display.present();
+        *splash = Some(progress);
+    });
+    Ok(())
+}
+
+/// Moves the progress bar along a step, not going past full if there are more steps than
+/// `show` was told.
+pub fn advance() {
+    interrupts::without_interrupts(|| {
+        let mut splash = SPLASH.lock();
+        let progress = match splash.as_mut() {
+            Some(progress) => progress,
+            None => return,
+        };
+        progress.done = usize::min(progress.done + 1, progress.steps);
+
+        let mut lock = CONSOLES.lock();
+        if let Some(display) = lock.get_mut().and_then(|consoles| consoles.active_console().display()) {
+            draw_bar(display, progress);
+            display.present();
+        }
+    });
+}
+
+/// Takes the splash away and shows the console, with everything logged while booting.
+pub fn finish() {
+    interrupts::without_interrupts(|| {
+        if SPLASH.lock().take().is_none() {
+            return;
+        }
+        if let Some(consoles) = CONSOLES.lock().get_mut() {
+            consoles.active_console().show();
+        }
+    });
+}
+
+/// Draws the background, the logo and the title, the logo scaled up as far as it goes in a
+/// third of the screen. Returns where the bar goes.
+fn draw(display: &mut Display, logo: &Image) -> Rect {
+    let (width, height) = (display.fb_info.width, display.fb_info.height);
+    display.set_clip(None);
+    display.set_alpha(u8::MAX);
+    display.clear(BACKGROUND);
+
+    let scale = usize::max(usize::min(width / 3 / logo.width, height / 3 / logo.height), 1);
+    let (logo_width, logo_height) = (logo.width * scale, logo.height * scale);
+    let title_width = get_raster_width(FontWeight::Bold, TITLE_HEIGHT) * TITLE.len();
+    let total = logo_height + GAP + TITLE_HEIGHT.val() + GAP + BAR_HEIGHT;
+    let top = height.saturating_sub(total) / 2;
+
+    let logo_left = width.saturating_sub(logo_width) / 2;
+    let logo_rect = Rect::new(Point(logo_left, top), Point(logo_left + logo_width, top + logo_height));
+    display.draw_image(logo, logo_rect);
+
+    // the title could be too wide for a tiny screen, it's left out then
+    let title_top = top + logo_height + GAP;
+    if title_width <= width && title_top + TITLE_HEIGHT.val() <= height {
+        let mut x = (width - title_width) / 2;
+        for c in TITLE.chars() {
+            display.putc(c, TITLE_COLOR, BACKGROUND, TITLE_HEIGHT, FontWeight::Bold, Point(x, title_top));
+            x += get_raster_width(FontWeight::Bold, TITLE_HEIGHT);
+        }
+    }
+
+    let bar_width = usize::max(logo_width, title_width);
+    let bar_left = width.saturating_sub(bar_width) / 2;
+    let bar_top = title_top + TITLE_HEIGHT.val() + GAP;
+    Rect::new(Point(bar_left, bar_top), Point(bar_left + bar_width, bar_top + BAR_HEIGHT))
+}
+
+fn draw_bar(display: &mut Display, progress: &Progress) {
+    let bar = progress.bar;
+    display.draw_rect_outline(BAR_OUTLINE, bar.top_left, bar.bottom_right);
+
+    // inside the outline, with a pixel of space around the fill
+    let left = bar.top_left.0 + 2;
+    let right = bar.bottom_right.0.saturating_sub(2);
+    let (top, bottom) = (bar.top_left.1 + 2, bar.bottom_right.1.saturating_sub(2));
+    if left >= right || top >= bottom {
+        return;
+    }
+    let filled = match progress.steps {
+        0 => right - left,
+        steps => (right - left) * progress.done / steps,
+    };
+    display.fill_rect(BAR_COLOR, Point(left, top), Point(left + filled, bottom));
+    display.fill_rect(BACKGROUND, Point(left + filled, top), Point(right, bottom));
+}
@@ -0,0 +1,102 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{FileType, FsError};
+
+/// Size of the fixed part of a directory entry, before the name.
+pub const HEADER_SIZE: usize = 8;
+
+// file type codes stored in directory entries
+const TYPE_UNKNOWN: u8 = 0;
+const TYPE_FILE: u8 = 1;
+const TYPE_DIRECTORY: u8 = 2;
+const TYPE_CHAR_DEVICE: u8 = 3;
+const TYPE_BLOCK_DEVICE: u8 = 4;
+const TYPE_SYMLINK: u8 = 7;
+
+/// A directory entry as found in a directory block.
+pub struct RawEntry {
+    /// Byte offset of the entry within the directory.
+    pub offset: u64,
+    pub inode: u32,
+    pub rec_len: u16,
+    /// Only filled in on volumes with the file type feature.
+    pub file_type: Option<FileType>,
+    pub name: String,
+}
+
+/// Walks the entries of a directory's contents, skipping unused ones, and fails on entries
+/// that don't fit in their block.
+pub fn entries(data: &[u8], block_size: u64) -> Result<Vec<RawEntry>, FsError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + HEADER_SIZE <= data.len() {
+        let entry = &data[offset..];
+        let inode = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+        let rec_len = u16::from_le_bytes([entry[4], entry[5]]);
+        let name_len = entry[6] as usize;
+
+        // entries never cross a block boundary
+        let block_end = (offset as u64 / block_size + 1) * block_size;
+        if (rec_len as usize) < HEADER_SIZE
+            || rec_len % 4 != 0
+            || (offset + rec_len as usize) as u64 > block_end
+            || HEADER_SIZE + name_len > rec_len as usize
+        {
+            return Err(FsError::Io);
+        }
+
+        if inode != 0 {
+            let name = String::from_utf8_lossy(&entry[HEADER_SIZE..HEADER_SIZE + name_len]).into_owned();
+            entries.push(RawEntry {
+                offset: offset as u64,
+                inode,
+                rec_len,
+                file_type: decode_type(entry[7]),
+                name,
+            });
+        }
+        offset += rec_len as usize;
+    }
+    Ok(entries)
+}
+
+fn decode_type(file_type: u8) -> Option<FileType> {
+    match file_type {
+        TYPE_UNKNOWN => None,
+        TYPE_FILE => Some(FileType::File),
+        TYPE_DIRECTORY => Some(FileType::Directory),
+        TYPE_CHAR_DEVICE => Some(FileType::CharDevice),
+        TYPE_BLOCK_DEVICE => Some(FileType::BlockDevice),
+        TYPE_SYMLINK => Some(FileType::Symlink),
+        // fifos and sockets show up as files, like they do in inodes
+        _ => Some(FileType::File),
+    }
+}
+
+#[cfg(feature = "ext2-write")]
+pub fn encode_type(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::File => TYPE_FILE,
+        FileType::Directory => TYPE_DIRECTORY,
+        FileType::Symlink => TYPE_SYMLINK,
+        FileType::CharDevice => TYPE_CHAR_DEVICE,
+        FileType::BlockDevice => TYPE_BLOCK_DEVICE,
+    }
+}
+
+/// Space a directory entry with a name of `name_len` bytes needs.
+#[cfg(feature = "ext2-write")]
+pub fn entry_size(name_len: usize) -> usize {
+    (HEADER_SIZE + name_len + 3) & !3
+}
+
+/// Encodes a directory entry header followed by the name into `buf`.
+#[cfg(feature = "ext2-write")]
+pub fn write_entry(buf: &mut [u8], inode: u32, rec_len: u16, file_type: u8, name: &str) {
+    buf[0..4].copy_from_slice(&inode.to_le_bytes());
+    buf[4..6].copy_from_slice(&rec_len.to_le_bytes());
+    buf[6] = name.len() as u8;
+    buf[7] = file_type;
+    buf[HEADER_SIZE..HEADER_SIZE + name.len()].copy_from_slice(name.as_bytes());
+}
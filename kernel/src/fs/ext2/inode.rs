@@ -0,0 +1,436 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use spin::Mutex;
+
+use super::dir::{self, RawEntry};
+use super::{DiskInode, Volume, VolumeState};
+use crate::fs::{DirEntry, FsError, Inode, Metadata};
+#[cfg(feature = "ext2-write")]
+use super::{FileType, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG};
+#[cfg(feature = "ext2-write")]
+use crate::time;
+
+/// An inode of an ext2 volume, caching the on-disk inode.
+pub(super) struct Ext2Inode {
+    volume: Arc<Volume>,
+    ino: u32,
+    node: Mutex<Node>,
+}
+
+struct Node {
+    disk: DiskInode,
+    // set once the last link is gone and the inode has been freed
+    removed: bool,
+}
+
+impl Ext2Inode {
+    /// Returns the inode `ino`, reusing the one already handed out if it's still alive.
+    pub(super) fn get(volume: &Arc<Volume>, state: &mut VolumeState, ino: u32, disk: DiskInode) -> Arc<Ext2Inode> {
+        if let Some(inode) = state.inodes.get(&ino).and_then(|inode| inode.upgrade()) {
+            return inode;
+        }
+
+        let inode = Arc::new(Ext2Inode {
+            volume: volume.clone(),
+            ino,
+            node: Mutex::new(Node {
+                disk,
+                removed: false,
+            }),
+        });
+        state.inodes.insert(ino, Arc::downgrade(&inode));
+        inode
+    }
+
+    fn load(&self, state: &mut VolumeState, ino: u32) -> Result<Arc<Ext2Inode>, FsError> {
+        if let Some(inode) = state.inodes.get(&ino).and_then(|inode| inode.upgrade()) {
+            return Ok(inode);
+        }
+        let disk = self.volume.read_inode(state, ino)?;
+        Ok(Self::get(&self.volume, state, ino, disk))
+    }
+
+    fn entries(&self) -> Result<Vec<RawEntry>, FsError> {
+        let node = self.node.lock();
+        let data = self.volume.read_all(&node.disk)?;
+        dir::entries(&data, self.volume.block_size())
+    }
+
+    fn find(&self, name: &str) -> Result<RawEntry, FsError> {
+        self.entries()?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or(FsError::NotFound)
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Metadata {
+        let disk = &self.node.lock().disk;
+        Metadata {
+            inode: self.ino as u64,
+            file_type: disk.file_type(),
+            size: disk.size,
+            mode: (disk.mode & 0o7777) as u32,
+            links: disk.links as u32,
+            atime: disk.atime as u64,
+            mtime: disk.mtime as u64,
+            ctime: disk.ctime as u64,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let _state = self.volume.state.lock();
+        let node = self.node.lock();
+        self.volume.read_data(&node.disk, offset, buf)
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        let _state = self.volume.state.lock();
+        let node = self.node.lock();
+        let disk = &node.disk;
+        let target = match disk.is_fast_symlink() {
+            true => disk
+                .block
+                .iter()
+                .flat_map(|block| block.to_le_bytes())
+                .take(disk.size as usize)
+                .collect(),
+            false => self.volume.read_all(disk)?,
+        };
+        String::from_utf8(target).map_err(|_| FsError::Io)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let mut state = self.volume.state.lock();
+        let entry = self.find(name)?;
+        Ok(self.load(&mut state, entry.inode)?)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut state = self.volume.state.lock();
+        let mut entries = Vec::new();
+        for entry in self.entries()? {
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            // the type in the entry is optional, the inode always knows
+            let file_type = match entry.file_type {
+                Some(file_type) => file_type,
+                None => self.load(&mut state, entry.inode)?.node.lock().disk.file_type(),
+            };
+            entries.push(DirEntry {
+                name: entry.name,
+                inode: entry.inode as u64,
+                file_type,
+            });
+        }
+        Ok(entries)
+    }
+
+    #[cfg(feature = "ext2-write")]
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.volume.writable {
+            return Err(FsError::ReadOnly);
+        }
+        let mut state = self.volume.state.lock();
+        let mut node = self.node.lock();
+        if node.removed {
+            return Err(FsError::NotFound);
+        }
+        if node.disk.mode & S_IFMT == S_IFDIR {
+            return Err(FsError::IsDirectory);
+        }
+
+        let end = offset.checked_add(buf.len() as u64).ok_or(FsError::NoSpace)?;
+        if end > self.volume.max_file_size() {
+            return Err(FsError::NoSpace);
+        }
+        let written = self.volume.write_data(&mut state, self.ino, &mut node.disk, offset, buf);
+
+        // whatever made it to disk counts, even if we ran out of space part way
+        let now = time::now() as u32;
+        node.disk.size = u64::max(node.disk.size, offset + *written.as_ref().unwrap_or(&0) as u64);
+        node.disk.mtime = now;
+        node.disk.ctime = now;
+        self.volume.write_inode(&state, self.ino, &node.disk)?;
+        written
+    }
+
+    #[cfg(feature = "ext2-write")]
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        if !self.volume.writable {
+            return Err(FsError::ReadOnly);
+        }
+        let mut state = self.volume.state.lock();
+        let mut node = self.node.lock();
+        if node.removed {
+            return Err(FsError::NotFound);
+        }
+        if node.disk.mode & S_IFMT == S_IFDIR {
+            return Err(FsError::IsDirectory);
+        }
+        if size > self.volume.max_file_size() {
+            return Err(FsError::NoSpace);
+        }
+
+        // growing just moves the size, the new space is a hole
+        if size < node.disk.size {
+            self.volume.free_data(&mut state, &mut node.disk, size)?;
+        }
+        let now = time::now() as u32;
+        node.disk.size = size;
+        node.disk.mtime = now;
+        node.disk.ctime = now;
+        self.volume.write_inode(&state, self.ino, &node.disk)
+    }
+
+    #[cfg(feature = "ext2-write")]
+    fn create(&self, name: &str, file_type: FileType, mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        let mode = mode as u16 & 0o7777;
+        match file_type {
+            FileType::File => self.insert(name, S_IFREG | mode, |_, _, _, _| Ok(())),
+            FileType::Directory => self.insert(name, S_IFDIR | mode, |volume, state, ino, child| {
+                volume.init_directory(state, ino, child, self.ino)
+            }),
+            _ => Err(FsError::Unsupported),
+        }
+    }
+
+    #[cfg(feature = "ext2-write")]
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.insert(name, S_IFLNK | 0o777, |volume, state, ino, child| {
+            volume.init_symlink(state, ino, child, target.as_bytes())
+        })
+    }
+
+    #[cfg(feature = "ext2-write")]
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.remove(name, |child| match child.file_type() {
+            FileType::Directory => Err(FsError::IsDirectory),
+            _ => Ok(()),
+        })
+    }
+
+    #[cfg(feature = "ext2-write")]
+    fn rmdir(&self, name: &str) -> Result<(), FsError> {
+        self.remove(name, |child| match child.file_type() {
+            FileType::Directory => Ok(()),
+            _ => Err(FsError::NotDirectory),
+        })
+    }
+
+    #[cfg(feature = "ext2-write")]
+    fn rename(&self, old_name: &str, new_parent: &Arc<dyn Inode>, new_name: &str) -> Result<(), FsError> {
+        let new_parent = new_parent
+            .as_any()
+            .downcast_ref::<Ext2Inode>()
+            .filter(|parent| Arc::ptr_eq(&parent.volume, &self.volume))
+            .ok_or(FsError::CrossDevice)?;
+        if !self.volume.writable {
+            return Err(FsError::ReadOnly);
+        }
+        check_name(new_name)?;
+        let mut state = self.volume.state.lock();
+
+        let source_entry = self.find(old_name)?;
+        let source = self.load(&mut state, source_entry.inode)?;
+        let source_type = source.node.lock().disk.file_type();
+
+        if let Ok(target_entry) = new_parent.find(new_name) {
+            // both names already refer to the same inode
+            if target_entry.inode == source_entry.inode {
+                return Ok(());
+            }
+            let target = self.load(&mut state, target_entry.inode)?;
+            let target_type = target.node.lock().disk.file_type();
+            match (source_type, target_type) {
+                (FileType::Directory, FileType::Directory) => {}
+                (FileType::Directory, _) => return Err(FsError::NotDirectory),
+                (_, FileType::Directory) => return Err(FsError::IsDirectory),
+                _ => {}
+            }
+            target.check_removable()?;
+            // the target's entry is reused, so nothing is lost if there's no room for a new one,
+            // and its link only goes once the source is in its place
+            new_parent.set_entry(&mut state, &target_entry, source.ino, source_type)?;
+            self.remove_entry(&mut state, old_name)?;
+            new_parent.drop_link(&mut state, &target)?;
+        } else {
+            new_parent.add_entry(&mut state, new_name, source.ino, source_type)?;
+            self.remove_entry(&mut state, old_name)?;
+        }
+
+        let now = time::now() as u32;
+        // a moved directory's `..` has to point at its new parent
+        if source_type == FileType::Directory && self.ino != new_parent.ino {
+            source.set_dotdot(new_parent.ino)?;
+            self.change_links(&state, -1)?;
+            new_parent.change_links(&state, 1)?;
+        }
+        let mut node = source.node.lock();
+        node.disk.ctime = now;
+        self.volume.write_inode(&state, source.ino, &node.disk)
+    }
+}
+
+/// Checks that `name` fits in a directory entry.
+#[cfg(feature = "ext2-write")]
+fn check_name(name: &str) -> Result<(), FsError> {
+    match name.is_empty() || name.len() > 255 || name.contains('\0') {
+        true => Err(FsError::InvalidArgument),
+        false => Ok(()),
+    }
+}
+
+#[cfg(feature = "ext2-write")]
+impl Ext2Inode {
+    /// Creates a new inode with `mode` and links it into this directory as `name`, `init`
+    /// fills in the contents before the entry becomes visible.
+    fn insert(
+        &self,
+        name: &str,
+        mode: u16,
+        init: impl FnOnce(&Volume, &mut VolumeState, u32, &mut DiskInode) -> Result<(), FsError>,
+    ) -> Result<Arc<dyn Inode>, FsError> {
+        if !self.volume.writable {
+            return Err(FsError::ReadOnly);
+        }
+        check_name(name)?;
+        let mut state = self.volume.state.lock();
+        if self.node.lock().removed {
+            return Err(FsError::NotFound);
+        }
+        if self.find(name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let is_dir = mode & S_IFMT == S_IFDIR;
+        let ino = self.volume.allocate_inode(&mut state, self.ino, is_dir)?;
+        let mut disk = DiskInode::new(mode, time::now() as u32);
+        let created = init(&self.volume, &mut state, ino, &mut disk)
+            .and_then(|()| self.volume.clear_inode(&state, ino))
+            .and_then(|()| self.volume.write_inode(&state, ino, &disk))
+            .and_then(|()| self.add_entry(&mut state, name, ino, disk.file_type()));
+        if let Err(err) = created {
+            // give back everything `init` allocated
+            self.volume.free_data(&mut state, &mut disk, 0)?;
+            self.volume.free_inode(&mut state, ino, is_dir)?;
+            return Err(err);
+        }
+
+        if is_dir {
+            // the new directory's `..` links back to us
+            self.change_links(&state, 1)?;
+        }
+        Ok(Self::get(&self.volume, &mut state, ino, disk))
+    }
+
+    /// Shared by `unlink` and `rmdir`, removes the child `name` after checking it with `check`.
+    fn remove(&self, name: &str, check: impl FnOnce(&DiskInode) -> Result<(), FsError>) -> Result<(), FsError> {
+        if !self.volume.writable {
+            return Err(FsError::ReadOnly);
+        }
+        let mut state = self.volume.state.lock();
+        let entry = self.find(name)?;
+        let child = self.load(&mut state, entry.inode)?;
+        check(&child.node.lock().disk)?;
+        self.remove_child(&mut state, name, &child)
+    }
+
+    /// Removes the entry `name` that refers to `child`, freeing the child once its last link
+    /// is gone. Directories have to be empty.
+    fn remove_child(&self, state: &mut VolumeState, name: &str, child: &Ext2Inode) -> Result<(), FsError> {
+        child.check_removable()?;
+        self.remove_entry(state, name)?;
+        self.drop_link(state, child)
+    }
+
+    /// Fails with `NotEmpty` if this is a directory with anything in it.
+    fn check_removable(&self) -> Result<(), FsError> {
+        let is_dir = self.node.lock().disk.mode & S_IFMT == S_IFDIR;
+        match is_dir && self.entries()?.iter().any(|entry| entry.name != "." && entry.name != "..") {
+            true => Err(FsError::NotEmpty),
+            false => Ok(()),
+        }
+    }
+
+    /// Takes away the link of an entry of this directory that no longer refers to `child`,
+    /// freeing the child once its last link is gone.
+    fn drop_link(&self, state: &mut VolumeState, child: &Ext2Inode) -> Result<(), FsError> {
+        let mut node = child.node.lock();
+        let is_dir = node.disk.mode & S_IFMT == S_IFDIR;
+        let now = time::now() as u32;
+        node.disk.ctime = now;
+        // a directory's own `.` entry goes away with it
+        node.disk.links = match is_dir {
+            true => 0,
+            false => node.disk.links.saturating_sub(1),
+        };
+        if node.disk.links == 0 {
+            self.volume.free_data(state, &mut node.disk, 0)?;
+            node.disk.size = 0;
+            node.disk.dtime = now;
+            self.volume.write_inode(state, child.ino, &node.disk)?;
+            self.volume.free_inode(state, child.ino, is_dir)?;
+            node.removed = true;
+            state.inodes.remove(&child.ino);
+        } else {
+            self.volume.write_inode(state, child.ino, &node.disk)?;
+        }
+        drop(node);
+
+        if is_dir {
+            // the child's `..` no longer links to us
+            self.change_links(state, -1)?;
+        }
+        Ok(())
+    }
+
+    fn add_entry(&self, state: &mut VolumeState, name: &str, ino: u32, file_type: FileType) -> Result<(), FsError> {
+        let mut node = self.node.lock();
+        self.volume.add_entry(state, self.ino, &mut node.disk, name, ino, file_type)?;
+        self.touch(state, &mut node.disk)
+    }
+
+    fn set_entry(&self, state: &mut VolumeState, entry: &RawEntry, ino: u32, file_type: FileType) -> Result<(), FsError> {
+        let mut node = self.node.lock();
+        self.volume.set_entry(&node.disk, entry.offset, ino, file_type)?;
+        self.touch(state, &mut node.disk)
+    }
+
+    fn remove_entry(&self, state: &mut VolumeState, name: &str) -> Result<(), FsError> {
+        let mut node = self.node.lock();
+        self.volume.remove_entry(&mut node.disk, name)?;
+        self.touch(state, &mut node.disk)
+    }
+
+    /// Marks a directory as modified, dropping any hash index since it isn't kept up to date.
+    fn touch(&self, state: &VolumeState, disk: &mut DiskInode) -> Result<(), FsError> {
+        let now = time::now() as u32;
+        disk.mtime = now;
+        disk.ctime = now;
+        disk.flags &= !super::INDEX_FLAG;
+        self.volume.write_inode(state, self.ino, disk)
+    }
+
+    fn change_links(&self, state: &VolumeState, delta: i16) -> Result<(), FsError> {
+        let mut node = self.node.lock();
+        node.disk.links = node.disk.links.saturating_add_signed(delta);
+        self.volume.write_inode(state, self.ino, &node.disk)
+    }
+
+    /// Points the `..` entry of this directory at `parent`.
+    fn set_dotdot(&self, parent: u32) -> Result<(), FsError> {
+        let entry = self.find("..")?;
+        let node = self.node.lock();
+        self.volume.write_file(&node.disk, entry.offset, &parent.to_le_bytes())
+    }
+}
@@ -0,0 +1,429 @@
+//! The second extended filesystem. Read-only unless built with the `ext2-write` feature.
+
+mod dir;
+mod inode;
+#[cfg(feature = "ext2-write")]
+mod write;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use self::inode::Ext2Inode;
+use super::{FileSystem, FileType, FsError, Inode};
+use crate::block::BlockDevice;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u32 = 2;
+
+/// Pointers in an inode to data blocks, the last three point at indirect blocks.
+const DIRECT_BLOCKS: usize = 12;
+const BLOCK_POINTERS: usize = 15;
+
+// incompatible features, anything else we can't read at all
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+// read-only compatible features, anything else we can't safely write
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+/// Inode flag of directories with a hash index, which we don't maintain.
+#[cfg(feature = "ext2-write")]
+const INDEX_FLAG: u32 = 0x1000;
+
+// file type bits of the mode
+const S_IFMT: u16 = 0xF000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xA000;
+const S_IFCHR: u16 = 0x2000;
+const S_IFBLK: u16 = 0x6000;
+
+/// Fields of the superblock we care about.
+struct Superblock {
+    inode_count: u32,
+    block_count: u32,
+    free_blocks: u32,
+    free_inodes: u32,
+    first_data_block: u32,
+    block_size: u64,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    revision: u32,
+    first_inode: u32,
+    inode_size: u64,
+    feature_incompat: u32,
+    feature_ro_compat: u32,
+}
+
+impl Superblock {
+    /// Parses the superblock of a volume on a device of `device_size` bytes.
+    fn parse(raw: &[u8], device_size: u64) -> Result<Superblock, FsError> {
+        let u16_at = |offset: usize| u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_le_bytes(raw[offset..offset + 4].try_into().unwrap());
+
+        if u16_at(56) != MAGIC {
+            return Err(FsError::InvalidArgument);
+        }
+        let revision = u32_at(76);
+        // revision 0 has fixed inode sizes and no feature flags
+        let (first_inode, inode_size, feature_incompat, feature_ro_compat) = match revision {
+            0 => (11, 128, 0, 0),
+            _ => (u32_at(84), u16_at(88) as u64, u32_at(96), u32_at(100)),
+        };
+
+        let log_block_size = u32_at(24);
+        let superblock = Superblock {
+            inode_count: u32_at(0),
+            block_count: u32_at(4),
+            free_blocks: u32_at(12),
+            free_inodes: u32_at(16),
+            first_data_block: u32_at(20),
+            block_size: 1024u64.checked_shl(log_block_size).unwrap_or(0),
+            blocks_per_group: u32_at(32),
+            inodes_per_group: u32_at(40),
+            revision,
+            first_inode,
+            inode_size,
+            feature_incompat,
+            feature_ro_compat,
+        };
+
+        if !(1024..=65536).contains(&superblock.block_size)
+            || superblock.first_data_block >= superblock.block_count
+            || superblock.block_count as u64 * superblock.block_size > device_size
+            // a group's bitmaps are a block each
+            || superblock.blocks_per_group == 0
+            || superblock.blocks_per_group as u64 > superblock.block_size * 8
+            || superblock.inodes_per_group == 0
+            || superblock.inodes_per_group as u64 > superblock.block_size * 8
+            || superblock.inode_size < 128
+            || !superblock.inode_size.is_power_of_two()
+            || superblock.inode_size > superblock.block_size
+            // compression, journal recovery, meta block groups, extents and so on
+            || superblock.feature_incompat & !(INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG) != 0
+        {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(superblock)
+    }
+
+    fn group_count(&self) -> Result<u32, FsError> {
+        self.block_count
+            .checked_sub(self.first_data_block)
+            .and_then(|blocks| blocks.checked_add(self.blocks_per_group - 1))
+            .map(|blocks| blocks / self.blocks_per_group)
+            .ok_or(FsError::InvalidArgument)
+    }
+}
+
+/// A block group descriptor.
+#[derive(Clone)]
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    used_dirs: u16,
+}
+
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+
+impl Group {
+    fn parse(raw: &[u8]) -> Group {
+        let u16_at = |offset: usize| u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_le_bytes(raw[offset..offset + 4].try_into().unwrap());
+        Group {
+            block_bitmap: u32_at(0),
+            inode_bitmap: u32_at(4),
+            inode_table: u32_at(8),
+            free_blocks: u16_at(12),
+            free_inodes: u16_at(14),
+            used_dirs: u16_at(16),
+        }
+    }
+}
+
+/// The fields of an on-disk inode we use. Everything else is left untouched on disk.
+#[derive(Clone)]
+struct DiskInode {
+    mode: u16,
+    uid: u16,
+    size: u64,
+    atime: u32,
+    ctime: u32,
+    mtime: u32,
+    dtime: u32,
+    gid: u16,
+    links: u16,
+    // allocated space in 512 byte units, indirect blocks included
+    sectors: u32,
+    flags: u32,
+    block: [u32; BLOCK_POINTERS],
+}
+
+impl DiskInode {
+    fn parse(raw: &[u8]) -> DiskInode {
+        let u16_at = |offset: usize| u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+        let u32_at = |offset: usize| u32::from_le_bytes(raw[offset..offset + 4].try_into().unwrap());
+
+        let mode = u16_at(0);
+        // the upper half of the size is only used by regular files
+        let size_high = match mode & S_IFMT {
+            S_IFREG => u32_at(108) as u64,
+            _ => 0,
+        };
+        DiskInode {
+            mode,
+            uid: u16_at(2),
+            size: u32_at(4) as u64 | size_high << 32,
+            atime: u32_at(8),
+            ctime: u32_at(12),
+            mtime: u32_at(16),
+            dtime: u32_at(20),
+            gid: u16_at(24),
+            links: u16_at(26),
+            sectors: u32_at(28),
+            flags: u32_at(32),
+            block: core::array::from_fn(|i| u32_at(40 + i * 4)),
+        }
+    }
+
+    #[cfg(feature = "ext2-write")]
+    fn store(&self, raw: &mut [u8]) {
+        raw[0..2].copy_from_slice(&self.mode.to_le_bytes());
+        raw[2..4].copy_from_slice(&self.uid.to_le_bytes());
+        raw[4..8].copy_from_slice(&(self.size as u32).to_le_bytes());
+        raw[8..12].copy_from_slice(&self.atime.to_le_bytes());
+        raw[12..16].copy_from_slice(&self.ctime.to_le_bytes());
+        raw[16..20].copy_from_slice(&self.mtime.to_le_bytes());
+        raw[20..24].copy_from_slice(&self.dtime.to_le_bytes());
+        raw[24..26].copy_from_slice(&self.gid.to_le_bytes());
+        raw[26..28].copy_from_slice(&self.links.to_le_bytes());
+        raw[28..32].copy_from_slice(&self.sectors.to_le_bytes());
+        raw[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        for (i, block) in self.block.iter().enumerate() {
+            raw[40 + i * 4..44 + i * 4].copy_from_slice(&block.to_le_bytes());
+        }
+        if self.mode & S_IFMT == S_IFREG {
+            raw[108..112].copy_from_slice(&((self.size >> 32) as u32).to_le_bytes());
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        match self.mode & S_IFMT {
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            // fifos and sockets show up as empty files
+            _ => FileType::File,
+        }
+    }
+
+    /// Short symlink targets are stored in the block pointers instead of a data block.
+    fn is_fast_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK && self.size < 60 && self.sectors == 0
+    }
+}
+
+struct VolumeState {
+    groups: Vec<Group>,
+    free_blocks: u32,
+    free_inodes: u32,
+    // inodes handed out, keyed by inode number
+    inodes: BTreeMap<u32, Weak<Ext2Inode>>,
+}
+
+/// The parts of a mounted ext2 volume shared by all of its inodes.
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    superblock: Superblock,
+    // whether the volume only uses features we know how to keep consistent
+    writable: bool,
+    // serializes every operation that touches the volume
+    state: Mutex<VolumeState>,
+}
+
+impl Volume {
+    fn block_size(&self) -> u64 {
+        self.superblock.block_size
+    }
+
+    fn read_block(&self, block: u32, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        if block == 0 || block >= self.superblock.block_count {
+            return Err(FsError::Io);
+        }
+        Ok(self.device.read_bytes(block as u64 * self.block_size() + offset, buf)?)
+    }
+
+    fn read_u32(&self, block: u32, index: u64) -> Result<u32, FsError> {
+        let mut buf = [0; 4];
+        self.read_block(block, index * 4, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Returns the device offset of inode number `ino`.
+    fn inode_offset(&self, state: &VolumeState, ino: u32) -> Result<u64, FsError> {
+        if ino == 0 || ino > self.superblock.inode_count {
+            return Err(FsError::Io);
+        }
+        let group = (ino - 1) / self.superblock.inodes_per_group;
+        let index = (ino - 1) % self.superblock.inodes_per_group;
+        let table = state.groups.get(group as usize).ok_or(FsError::Io)?.inode_table;
+        Ok(table as u64 * self.block_size() + index as u64 * self.superblock.inode_size)
+    }
+
+    fn read_inode(&self, state: &VolumeState, ino: u32) -> Result<DiskInode, FsError> {
+        let mut raw = [0; 128];
+        self.device.read_bytes(self.inode_offset(state, ino)?, &mut raw)?;
+        Ok(DiskInode::parse(&raw))
+    }
+
+    /// Splits a file block index into the block pointer of the inode it starts from and
+    /// the index into each level of indirect blocks below that.
+    fn block_path(&self, index: u64) -> Option<(usize, Vec<u64>)> {
+        let per_block = self.block_size() / 4;
+        if index < DIRECT_BLOCKS as u64 {
+            return Some((index as usize, Vec::new()));
+        }
+
+        let mut index = index - DIRECT_BLOCKS as u64;
+        let mut span = per_block;
+        for depth in 1..=3 {
+            if index < span {
+                let mut path = Vec::with_capacity(depth);
+                for level in (0..depth).rev() {
+                    path.push(index / per_block.pow(level as u32) % per_block);
+                }
+                return Some((DIRECT_BLOCKS + depth - 1, path));
+            }
+            index -= span;
+            span *= per_block;
+        }
+        None
+    }
+
+    /// Returns the device block holding block `index` of a file, zero for holes.
+    fn map_block(&self, inode: &DiskInode, index: u64) -> Result<u32, FsError> {
+        let (slot, path) = self.block_path(index).ok_or(FsError::InvalidArgument)?;
+        let mut block = inode.block[slot];
+        for index in path {
+            if block == 0 {
+                break;
+            }
+            block = self.read_u32(block, index)?;
+        }
+        Ok(block)
+    }
+
+    /// Reads file contents, holes read as zeros.
+    fn read_data(&self, inode: &DiskInode, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = usize::min(buf.len(), (inode.size - offset) as usize);
+        let block_size = self.block_size();
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = position % block_size;
+            let chunk = usize::min((block_size - within) as usize, len - done);
+            match self.map_block(inode, position / block_size)? {
+                0 => buf[done..done + chunk].fill(0),
+                block => self.read_block(block, within, &mut buf[done..done + chunk])?,
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    /// Reads a whole directory or symlink.
+    fn read_all(&self, inode: &DiskInode) -> Result<Vec<u8>, FsError> {
+        let size = usize::try_from(inode.size).map_err(|_| FsError::Io)?;
+        let mut data = vec![0; size];
+        self.read_data(inode, 0, &mut data)?;
+        Ok(data)
+    }
+}
+
+/// An ext2 volume on a block device.
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+    root: Arc<Ext2Inode>,
+}
+
+impl Ext2Fs {
+    /// Mounts the ext2 volume on `device`, failing with `InvalidArgument` if there isn't one.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Ext2Fs, FsError> {
+        let mut raw = [0; 1024];
+        device.read_bytes(SUPERBLOCK_OFFSET, &mut raw)?;
+        let device_size = device.sector_count() * device.sector_size() as u64;
+        let superblock = Superblock::parse(&raw, device_size)?;
+
+        // the descriptor table starts in the block after the superblock, a corrupt superblock
+        // can still make it bigger than the heap
+        let group_count = superblock.group_count()? as usize;
+        let mut table = Vec::new();
+        table
+            .try_reserve_exact(group_count * GROUP_DESCRIPTOR_SIZE)
+            .map_err(|_| FsError::NoSpace)?;
+        table.resize(group_count * GROUP_DESCRIPTOR_SIZE, 0);
+        device.read_bytes((superblock.first_data_block as u64 + 1) * superblock.block_size, &mut table)?;
+        let mut groups = Vec::new();
+        groups.try_reserve_exact(group_count).map_err(|_| FsError::NoSpace)?;
+        groups.extend(table.chunks(GROUP_DESCRIPTOR_SIZE).map(Group::parse));
+
+        // directory entry lengths can't describe a whole 64 KiB block
+        let writable = cfg!(feature = "ext2-write")
+            && superblock.feature_ro_compat & !(RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE) == 0
+            && superblock.block_size < 65536;
+        let volume = Arc::new(Volume {
+            device,
+            writable,
+            state: Mutex::new(VolumeState {
+                groups,
+                free_blocks: superblock.free_blocks,
+                free_inodes: superblock.free_inodes,
+                inodes: BTreeMap::new(),
+            }),
+            superblock,
+        });
+
+        let root = {
+            let mut state = volume.state.lock();
+            let inode = volume.read_inode(&state, ROOT_INODE)?;
+            if inode.file_type() != FileType::Directory {
+                return Err(FsError::Io);
+            }
+            Ext2Inode::get(&volume, &mut state, ROOT_INODE, inode)
+        };
+        Ok(Ext2Fs { volume, root })
+    }
+
+    /// Whether writes are allowed, which needs the `ext2-write` feature and a volume
+    /// without features we don't know how to maintain.
+    pub fn is_writable(&self) -> bool {
+        self.volume.writable
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(self.volume.device.flush()?)
+    }
+}
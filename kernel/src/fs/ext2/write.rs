@@ -0,0 +1,512 @@
+//! Allocation and the other on-disk updates behind writes.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::dir::{self, HEADER_SIZE};
+use super::{
+    DiskInode, FileType, FsError, Volume, VolumeState, BLOCK_POINTERS, DIRECT_BLOCKS, GROUP_DESCRIPTOR_SIZE,
+    INCOMPAT_FILETYPE, RO_COMPAT_LARGE_FILE, SUPERBLOCK_OFFSET,
+};
+
+impl DiskInode {
+    pub(super) fn new(mode: u16, now: u32) -> DiskInode {
+        DiskInode {
+            mode,
+            uid: 0,
+            size: 0,
+            atime: now,
+            ctime: now,
+            mtime: now,
+            dtime: 0,
+            gid: 0,
+            links: 1,
+            sectors: 0,
+            flags: 0,
+            block: [0; BLOCK_POINTERS],
+        }
+    }
+}
+
+impl Volume {
+    /// The largest file size the volume can store.
+    pub(super) fn max_file_size(&self) -> u64 {
+        // without the large file feature sizes have to fit in a signed 32 bit integer
+        let limit = match self.superblock.feature_ro_compat & RO_COMPAT_LARGE_FILE {
+            0 => i32::MAX as u64,
+            _ => u64::MAX,
+        };
+        let per_block = self.block_size() / 4;
+        let blocks = DIRECT_BLOCKS as u64 + per_block + per_block.pow(2) + per_block.pow(3);
+        u64::min(limit, blocks * self.block_size())
+    }
+
+    fn sectors_per_block(&self) -> u32 {
+        (self.block_size() / 512) as u32
+    }
+
+    fn write_block(&self, block: u32, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        if block == 0 || block >= self.superblock.block_count {
+            return Err(FsError::Io);
+        }
+        Ok(self.device.write_bytes(block as u64 * self.block_size() + offset, buf)?)
+    }
+
+    fn write_u32(&self, block: u32, index: u64, value: u32) -> Result<(), FsError> {
+        self.write_block(block, index * 4, &value.to_le_bytes())
+    }
+
+    pub(super) fn write_inode(&self, state: &VolumeState, ino: u32, inode: &DiskInode) -> Result<(), FsError> {
+        let offset = self.inode_offset(state, ino)?;
+        let mut raw = [0; 128];
+        self.device.read_bytes(offset, &mut raw)?;
+        inode.store(&mut raw);
+        Ok(self.device.write_bytes(offset, &raw)?)
+    }
+
+    /// Zeroes the on-disk inode so nothing from its previous life survives.
+    pub(super) fn clear_inode(&self, state: &VolumeState, ino: u32) -> Result<(), FsError> {
+        let offset = self.inode_offset(state, ino)?;
+        Ok(self.device.write_bytes(offset, &vec![0; self.superblock.inode_size as usize])?)
+    }
+
+    fn write_group(&self, state: &VolumeState, index: usize) -> Result<(), FsError> {
+        let group = &state.groups[index];
+        let mut raw = [0; 6];
+        raw[0..2].copy_from_slice(&group.free_blocks.to_le_bytes());
+        raw[2..4].copy_from_slice(&group.free_inodes.to_le_bytes());
+        raw[4..6].copy_from_slice(&group.used_dirs.to_le_bytes());
+
+        let table = (self.superblock.first_data_block as u64 + 1) * self.block_size();
+        Ok(self
+            .device
+            .write_bytes(table + (index * GROUP_DESCRIPTOR_SIZE) as u64 + 12, &raw)?)
+    }
+
+    /// Writes the free block and inode counts to the superblock.
+    fn write_counts(&self, state: &VolumeState) -> Result<(), FsError> {
+        let mut raw = [0; 8];
+        raw[0..4].copy_from_slice(&state.free_blocks.to_le_bytes());
+        raw[4..8].copy_from_slice(&state.free_inodes.to_le_bytes());
+        Ok(self.device.write_bytes(SUPERBLOCK_OFFSET + 12, &raw)?)
+    }
+
+    /// Sets the first clear bit from `start` to `count` in a bitmap block, returning its index.
+    fn claim_bit(&self, bitmap: u32, start: u32, count: u32) -> Result<Option<u32>, FsError> {
+        let mut bits = vec![0; self.block_size() as usize];
+        self.read_block(bitmap, 0, &mut bits)?;
+
+        for index in start..count {
+            let byte = bits[index as usize / 8];
+            let mask = 1 << (index % 8);
+            if byte & mask == 0 {
+                self.write_block(bitmap, index as u64 / 8, &[byte | mask])?;
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Clears a bit in a bitmap block, returning whether it was set.
+    fn release_bit(&self, bitmap: u32, index: u32) -> Result<bool, FsError> {
+        let mut byte = [0];
+        self.read_block(bitmap, index as u64 / 8, &mut byte)?;
+        let mask = 1 << (index % 8);
+        if byte[0] & mask == 0 {
+            return Ok(false);
+        }
+        self.write_block(bitmap, index as u64 / 8, &[byte[0] & !mask])?;
+        Ok(true)
+    }
+
+    fn group_of_inode(&self, ino: u32) -> usize {
+        ((ino - 1) / self.superblock.inodes_per_group) as usize
+    }
+
+    /// Allocates a zeroed block, preferably in the block group of inode `ino`.
+    fn allocate_block(&self, state: &mut VolumeState, ino: u32) -> Result<u32, FsError> {
+        let superblock = &self.superblock;
+        let group_count = state.groups.len();
+        let goal = self.group_of_inode(ino);
+
+        for group in (0..group_count).map(|i| (goal + i) % group_count) {
+            if state.groups[group].free_blocks == 0 {
+                continue;
+            }
+            let first = superblock.first_data_block + group as u32 * superblock.blocks_per_group;
+            let count = u32::min(superblock.blocks_per_group, superblock.block_count - first);
+            let bit = match self.claim_bit(state.groups[group].block_bitmap, 0, count)? {
+                Some(bit) => bit,
+                None => continue,
+            };
+
+            state.groups[group].free_blocks -= 1;
+            state.free_blocks = state.free_blocks.saturating_sub(1);
+            self.write_group(state, group)?;
+            self.write_counts(state)?;
+
+            let block = first + bit;
+            self.write_block(block, 0, &vec![0; self.block_size() as usize])?;
+            return Ok(block);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&self, state: &mut VolumeState, block: u32) -> Result<(), FsError> {
+        let index = block - self.superblock.first_data_block;
+        let group = (index / self.superblock.blocks_per_group) as usize;
+        let bitmap = state.groups.get(group).ok_or(FsError::Io)?.block_bitmap;
+        if self.release_bit(bitmap, index % self.superblock.blocks_per_group)? {
+            state.groups[group].free_blocks += 1;
+            state.free_blocks += 1;
+            self.write_group(state, group)?;
+            self.write_counts(state)?;
+        }
+        Ok(())
+    }
+
+    /// Allocates an inode number, preferably in the block group of `parent`.
+    pub(super) fn allocate_inode(&self, state: &mut VolumeState, parent: u32, is_dir: bool) -> Result<u32, FsError> {
+        let superblock = &self.superblock;
+        let group_count = state.groups.len();
+        let goal = self.group_of_inode(parent);
+
+        for group in (0..group_count).map(|i| (goal + i) % group_count) {
+            if state.groups[group].free_inodes == 0 {
+                continue;
+            }
+            // the first few inodes are reserved for the filesystem itself
+            let first = group as u32 * superblock.inodes_per_group + 1;
+            let start = superblock.first_inode.saturating_sub(first);
+            let bit = match self.claim_bit(state.groups[group].inode_bitmap, start, superblock.inodes_per_group)? {
+                Some(bit) => bit,
+                None => continue,
+            };
+
+            state.groups[group].free_inodes -= 1;
+            if is_dir {
+                state.groups[group].used_dirs += 1;
+            }
+            state.free_inodes = state.free_inodes.saturating_sub(1);
+            self.write_group(state, group)?;
+            self.write_counts(state)?;
+            return Ok(first + bit);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    pub(super) fn free_inode(&self, state: &mut VolumeState, ino: u32, is_dir: bool) -> Result<(), FsError> {
+        let group = self.group_of_inode(ino);
+        let bitmap = state.groups.get(group).ok_or(FsError::Io)?.inode_bitmap;
+        if self.release_bit(bitmap, (ino - 1) % self.superblock.inodes_per_group)? {
+            state.groups[group].free_inodes += 1;
+            if is_dir {
+                state.groups[group].used_dirs = state.groups[group].used_dirs.saturating_sub(1);
+            }
+            state.free_inodes += 1;
+            self.write_group(state, group)?;
+            self.write_counts(state)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the device block holding block `index` of inode `ino`, allocating it and any
+    /// indirect blocks on the way if needed.
+    fn map_block_alloc(&self, state: &mut VolumeState, ino: u32, inode: &mut DiskInode, index: u64) -> Result<u32, FsError> {
+        let (slot, path) = self.block_path(index).ok_or(FsError::NoSpace)?;
+        if inode.block[slot] == 0 {
+            inode.block[slot] = self.allocate_block(state, ino)?;
+            inode.sectors += self.sectors_per_block();
+        }
+
+        let mut block = inode.block[slot];
+        for index in path {
+            block = match self.read_u32(block, index)? {
+                0 => {
+                    let new = self.allocate_block(state, ino)?;
+                    inode.sectors += self.sectors_per_block();
+                    self.write_u32(block, index, new)?;
+                    new
+                }
+                next => next,
+            };
+        }
+        Ok(block)
+    }
+
+    /// Writes file contents, allocating blocks as needed. Returns how much was written, which
+    /// is less than asked for if the volume filled up part way.
+    pub(super) fn write_data(
+        &self,
+        state: &mut VolumeState,
+        ino: u32,
+        inode: &mut DiskInode,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<usize, FsError> {
+        let block_size = self.block_size();
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let within = position % block_size;
+            let chunk = usize::min((block_size - within) as usize, buf.len() - done);
+            let block = match self.map_block_alloc(state, ino, inode, position / block_size) {
+                Ok(block) => block,
+                Err(FsError::NoSpace) if done > 0 => break,
+                Err(err) => return Err(err),
+            };
+            self.write_block(block, within, &buf[done..done + chunk])?;
+            done += chunk;
+        }
+        Ok(done)
+    }
+
+    /// Overwrites part of a file that's already allocated.
+    pub(super) fn write_file(&self, inode: &DiskInode, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        let block_size = self.block_size();
+        match self.map_block(inode, offset / block_size)? {
+            0 => Err(FsError::Io),
+            block => self.write_block(block, offset % block_size, buf),
+        }
+    }
+
+    /// Frees every block past the first `size` bytes of a file.
+    pub(super) fn free_data(&self, state: &mut VolumeState, inode: &mut DiskInode, size: u64) -> Result<(), FsError> {
+        if inode.is_fast_symlink() {
+            return Ok(());
+        }
+        let block_size = self.block_size();
+        let keep = (size + block_size - 1) / block_size;
+
+        // zero the rest of a partial last block so growing the file again reads zeros
+        let within = size % block_size;
+        if within != 0 {
+            let block = self.map_block(inode, size / block_size)?;
+            if block != 0 {
+                self.write_block(block, within, &vec![0; (block_size - within) as usize])?;
+            }
+        }
+
+        let per_block = block_size / 4;
+        for slot in 0..BLOCK_POINTERS {
+            // how deep the tree below the pointer is, and the first file block it covers
+            let (depth, base) = match slot {
+                12 => (1, 12),
+                13 => (2, 12 + per_block),
+                14 => (3, 12 + per_block + per_block.pow(2)),
+                _ => (0, slot as u64),
+            };
+            let block = inode.block[slot];
+            if block != 0 && self.free_tree(state, inode, block, depth, base, keep)? {
+                inode.block[slot] = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Frees the file blocks from `keep` onwards below `block`, an indirect block `depth`
+    /// levels above the data whose first file block is `base`. Returns whether `block` itself
+    /// was freed.
+    fn free_tree(
+        &self,
+        state: &mut VolumeState,
+        inode: &mut DiskInode,
+        block: u32,
+        depth: u32,
+        base: u64,
+        keep: u64,
+    ) -> Result<bool, FsError> {
+        if depth > 0 {
+            let per_block = self.block_size() / 4;
+            let span = per_block.pow(depth - 1);
+            let mut raw = vec![0; self.block_size() as usize];
+            self.read_block(block, 0, &mut raw)?;
+            let pointers: Vec<u32> = raw
+                .chunks_exact(4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect();
+
+            for (index, &child) in pointers.iter().enumerate() {
+                let child_base = base + index as u64 * span;
+                if child == 0 || child_base + span <= keep {
+                    continue;
+                }
+                // pointers in a block that's being freed don't need clearing
+                if self.free_tree(state, inode, child, depth - 1, child_base, keep)? && base < keep {
+                    self.write_u32(block, index as u64, 0)?;
+                }
+            }
+        }
+
+        if base < keep {
+            return Ok(false);
+        }
+        self.free_block(state, block)?;
+        inode.sectors = inode.sectors.saturating_sub(self.sectors_per_block());
+        Ok(true)
+    }
+
+    /// The type code to store in directory entries, if the volume has them.
+    fn entry_type(&self, file_type: FileType) -> u8 {
+        match self.superblock.feature_incompat & INCOMPAT_FILETYPE {
+            0 => 0,
+            _ => dir::encode_type(file_type),
+        }
+    }
+
+    /// Gives a new directory its first block with the `.` and `..` entries.
+    pub(super) fn init_directory(
+        &self,
+        state: &mut VolumeState,
+        ino: u32,
+        inode: &mut DiskInode,
+        parent: u32,
+    ) -> Result<(), FsError> {
+        let block_size = self.block_size() as usize;
+        let block = self.map_block_alloc(state, ino, inode, 0)?;
+
+        let entry_type = self.entry_type(FileType::Directory);
+        let mut data = vec![0; block_size];
+        dir::write_entry(&mut data, ino, 12, entry_type, ".");
+        dir::write_entry(&mut data[12..], parent, (block_size - 12) as u16, entry_type, "..");
+        self.write_block(block, 0, &data)?;
+
+        inode.size = block_size as u64;
+        // one link from the parent and one from `.`
+        inode.links = 2;
+        Ok(())
+    }
+
+    /// Stores a symlink target, in the block pointers if it's short enough.
+    pub(super) fn init_symlink(
+        &self,
+        state: &mut VolumeState,
+        ino: u32,
+        inode: &mut DiskInode,
+        target: &[u8],
+    ) -> Result<(), FsError> {
+        if target.len() as u64 > self.block_size() {
+            return Err(FsError::InvalidArgument);
+        }
+
+        if target.len() < BLOCK_POINTERS * 4 {
+            for (block, bytes) in inode.block.iter_mut().zip(target.chunks(4)) {
+                let mut raw = [0; 4];
+                raw[..bytes.len()].copy_from_slice(bytes);
+                *block = u32::from_le_bytes(raw);
+            }
+        } else if self.write_data(state, ino, inode, 0, target)? != target.len() {
+            return Err(FsError::NoSpace);
+        }
+        inode.size = target.len() as u64;
+        Ok(())
+    }
+
+    /// Adds an entry for `name` to the directory `dir_ino`, splitting the first entry with
+    /// enough slack or appending a block.
+    pub(super) fn add_entry(
+        &self,
+        state: &mut VolumeState,
+        dir_ino: u32,
+        dir: &mut DiskInode,
+        name: &str,
+        ino: u32,
+        file_type: FileType,
+    ) -> Result<(), FsError> {
+        let block_size = self.block_size() as usize;
+        let needed = dir::entry_size(name.len());
+        let entry_type = self.entry_type(file_type);
+        let mut data = vec![0; block_size];
+
+        for index in 0..dir.size / block_size as u64 {
+            let block = match self.map_block(dir, index)? {
+                0 => return Err(FsError::Io),
+                block => block,
+            };
+            self.read_block(block, 0, &mut data)?;
+
+            let mut offset = 0;
+            while offset + HEADER_SIZE <= block_size {
+                let inode = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+                let rec_len = u16::from_le_bytes([data[offset + 4], data[offset + 5]]) as usize;
+                if rec_len < HEADER_SIZE || offset + rec_len > block_size {
+                    return Err(FsError::Io);
+                }
+
+                let used = match inode {
+                    0 => 0,
+                    _ => dir::entry_size(data[offset + 6] as usize),
+                };
+                if used > rec_len {
+                    return Err(FsError::Io);
+                }
+                if rec_len - used >= needed {
+                    if used != 0 {
+                        data[offset + 4..offset + 6].copy_from_slice(&(used as u16).to_le_bytes());
+                    }
+                    dir::write_entry(&mut data[offset + used..], ino, (rec_len - used) as u16, entry_type, name);
+                    return self.write_block(block, 0, &data);
+                }
+                offset += rec_len;
+            }
+        }
+
+        let index = dir.size / block_size as u64;
+        let block = self.map_block_alloc(state, dir_ino, dir, index)?;
+        data.fill(0);
+        dir::write_entry(&mut data, ino, block_size as u16, entry_type, name);
+        self.write_block(block, 0, &data)?;
+        dir.size += block_size as u64;
+        Ok(())
+    }
+
+    /// Points the entry at `offset` of a directory at inode `ino`, keeping its name and length.
+    pub(super) fn set_entry(&self, dir: &DiskInode, offset: u64, ino: u32, file_type: FileType) -> Result<(), FsError> {
+        self.write_file(dir, offset, &ino.to_le_bytes())?;
+        // without the file type feature the byte is the top of the name length
+        match self.superblock.feature_incompat & INCOMPAT_FILETYPE {
+            0 => Ok(()),
+            _ => self.write_file(dir, offset + 7, &[dir::encode_type(file_type)]),
+        }
+    }
+
+    /// Removes the entry for `name` by merging it into the entry before it.
+    pub(super) fn remove_entry(&self, dir: &DiskInode, name: &str) -> Result<(), FsError> {
+        let block_size = self.block_size() as usize;
+        let mut data = vec![0; block_size];
+
+        for index in 0..dir.size / block_size as u64 {
+            let block = match self.map_block(dir, index)? {
+                0 => return Err(FsError::Io),
+                block => block,
+            };
+            self.read_block(block, 0, &mut data)?;
+
+            let mut offset = 0;
+            let mut previous = None;
+            while offset + HEADER_SIZE <= block_size {
+                let inode = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+                let rec_len = u16::from_le_bytes([data[offset + 4], data[offset + 5]]) as usize;
+                let name_len = data[offset + 6] as usize;
+                if rec_len < HEADER_SIZE + name_len || offset + rec_len > block_size {
+                    return Err(FsError::Io);
+                }
+
+                if inode != 0 && &data[offset + HEADER_SIZE..offset + HEADER_SIZE + name_len] == name.as_bytes() {
+                    match previous {
+                        Some(previous) => {
+                            let merged = (offset - previous + rec_len) as u16;
+                            data[previous + 4..previous + 6].copy_from_slice(&merged.to_le_bytes());
+                        }
+                        // the first entry of a block can't be merged away, only marked unused
+                        None => data[offset..offset + 4].fill(0),
+                    }
+                    return self.write_block(block, 0, &data);
+                }
+                previous = Some(offset);
+                offset += rec_len;
+            }
+        }
+        Err(FsError::NotFound)
+    }
+}
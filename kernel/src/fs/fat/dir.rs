@@ -0,0 +1,412 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::time::DateTime;
+
+pub const ENTRY_SIZE: usize = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// First name byte of a deleted entry.
+pub const DELETED: u8 = 0xE5;
+
+// flags in the reserved byte used by Windows NT to store all lowercase short names
+const NT_LOWERCASE_BASE: u8 = 0x08;
+const NT_LOWERCASE_EXT: u8 = 0x10;
+
+/// Characters stored in each long name entry.
+const LFN_CHARS: usize = 13;
+/// Byte offsets of the UTF-16 characters within a long name entry.
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// A file or directory entry, assembled from a short entry and the long name entries before it.
+#[derive(Debug, Clone)]
+pub struct DirItem {
+    pub name: String,
+    pub short_name: [u8; 11],
+    pub attr: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    pub ctime: u64,
+    pub mtime: u64,
+    pub atime: u64,
+    /// Device offset of the short entry.
+    pub entry_offset: u64,
+    /// Device offsets of every slot used by this item, long name entries included.
+    pub slots: Vec<u64>,
+}
+
+impl DirItem {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    /// FAT names are case insensitive, and both the long and short name refer to the item.
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || short_name_string(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+/// The raw contents of a directory together with where each slot lives on the device.
+pub struct DirData {
+    pub slots: Vec<u64>,
+    pub bytes: Vec<u8>,
+}
+
+impl DirData {
+    pub fn entry(&self, index: usize) -> &[u8] {
+        &self.bytes[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE]
+    }
+
+    /// Parses every live entry, skipping `.`, `..`, volume labels and deleted entries.
+    pub fn items(&self) -> Vec<DirItem> {
+        let mut items = Vec::new();
+        // long name characters collected so far, with the checksum they belong to
+        let mut long_name: Vec<u16> = Vec::new();
+        let mut long_checksum: Option<u8> = None;
+        let mut long_slots: Vec<u64> = Vec::new();
+
+        for index in 0..self.slots.len() {
+            let entry = self.entry(index);
+            match entry[0] {
+                // nothing follows the first never used entry
+                0x00 => break,
+                DELETED => {
+                    long_checksum = None;
+                    continue;
+                }
+                _ => {}
+            }
+
+            if entry[11] & 0x3F == ATTR_LONG_NAME {
+                let sequence = entry[0] & 0x1F;
+                if entry[0] & 0x40 != 0 {
+                    long_name = alloc::vec![0xFFFF; sequence as usize * LFN_CHARS];
+                    long_checksum = Some(entry[13]);
+                    long_slots.clear();
+                }
+                if sequence == 0 || long_checksum != Some(entry[13]) || long_name.len() < sequence as usize * LFN_CHARS {
+                    long_checksum = None;
+                    continue;
+                }
+
+                let start = (sequence as usize - 1) * LFN_CHARS;
+                for (i, &offset) in LFN_OFFSETS.iter().enumerate() {
+                    long_name[start + i] = u16::from_le_bytes([entry[offset], entry[offset + 1]]);
+                }
+                long_slots.push(self.slots[index]);
+                continue;
+            }
+
+            let mut short_name = [0; 11];
+            short_name.copy_from_slice(&entry[..11]);
+            let attr = entry[11];
+            let checksum = long_checksum.take();
+            if attr & ATTR_VOLUME_ID != 0 || short_name[0] == b'.' {
+                continue;
+            }
+
+            let name = match checksum {
+                Some(checksum) if checksum == short_name_checksum(&short_name) => {
+                    let end = long_name
+                        .iter()
+                        .position(|&c| c == 0 || c == 0xFFFF)
+                        .unwrap_or(long_name.len());
+                    String::from_utf16_lossy(&long_name[..end])
+                }
+                _ => short_name_string(&short_name, entry[12]),
+            };
+            let mut slots = match checksum {
+                Some(_) => core::mem::take(&mut long_slots),
+                None => Vec::new(),
+            };
+            slots.push(self.slots[index]);
+
+            let first_cluster = (u16::from_le_bytes([entry[20], entry[21]]) as u32) << 16
+                | u16::from_le_bytes([entry[26], entry[27]]) as u32;
+            items.push(DirItem {
+                name,
+                short_name,
+                attr,
+                first_cluster,
+                size: u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]]),
+                ctime: fat_to_unix(u16::from_le_bytes([entry[16], entry[17]]), u16::from_le_bytes([entry[14], entry[15]])),
+                mtime: fat_to_unix(u16::from_le_bytes([entry[24], entry[25]]), u16::from_le_bytes([entry[22], entry[23]])),
+                atime: fat_to_unix(u16::from_le_bytes([entry[18], entry[19]]), 0),
+                entry_offset: self.slots[index],
+                slots,
+            });
+        }
+
+        items
+    }
+
+    /// Returns the index of the first run of `count` free slots, if there is one.
+    pub fn find_free(&self, count: usize) -> Option<usize> {
+        let mut run = 0;
+        for index in 0..self.slots.len() {
+            match self.entry(index)[0] {
+                // everything from the end marker onwards is free
+                0x00 if self.slots.len() - index + run >= count => return Some(index - run),
+                0x00 => return None,
+                DELETED => run += 1,
+                _ => run = 0,
+            }
+            if run == count {
+                return Some(index + 1 - run);
+            }
+        }
+        None
+    }
+}
+
+/// Builds the display name of a short entry, honouring the NT lowercase flags.
+fn short_name_string(short_name: &[u8; 11], nt_flags: u8) -> String {
+    let convert = |bytes: &[u8], lowercase: bool| -> String {
+        bytes
+            .iter()
+            .map(|&b| if b == 0x05 { DELETED } else { b })
+            .map(|b| match lowercase {
+                true => b.to_ascii_lowercase() as char,
+                false => b as char,
+            })
+            .collect::<String>()
+            .trim_end()
+            .into()
+    };
+
+    let base = convert(&short_name[..8], nt_flags & NT_LOWERCASE_BASE != 0);
+    let ext = convert(&short_name[8..], nt_flags & NT_LOWERCASE_EXT != 0);
+    match ext.is_empty() {
+        true => base,
+        false => base + "." + &ext,
+    }
+}
+
+pub fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &b| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b))
+}
+
+/// Checks whether `name` can be stored in a directory at all.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.encode_utf16().count() <= 255
+        && !name.ends_with('.')
+        && !name.chars().any(|c| c.is_control() || "\\/:*?\"<>|".contains(c))
+}
+
+fn is_short_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// Checks whether `name` can be stored as a short entry only. Returns the short name and the NT
+/// flags needed to restore its case.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || !base.chars().chain(ext.chars()).all(is_short_name_char) {
+        return None;
+    }
+
+    // each part has to be entirely upper or entirely lower case to be representable
+    let case_flag = |part: &str, flag: u8| -> Option<u8> {
+        if !part.chars().any(|c| c.is_ascii_lowercase()) {
+            Some(0)
+        } else if !part.chars().any(|c| c.is_ascii_uppercase()) {
+            Some(flag)
+        } else {
+            None
+        }
+    };
+    let flags = case_flag(base, NT_LOWERCASE_BASE)? | case_flag(ext, NT_LOWERCASE_EXT)?;
+
+    let mut short_name = [b' '; 11];
+    for (i, b) in base.bytes().enumerate() {
+        short_name[i] = b.to_ascii_uppercase();
+    }
+    for (i, b) in ext.bytes().enumerate() {
+        short_name[8 + i] = b.to_ascii_uppercase();
+    }
+    Some((short_name, flags))
+}
+
+/// Derives a unique `BASE~N.EXT` short name for a long name.
+fn generate_short_name(name: &str, taken: &[[u8; 11]]) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) if !base.is_empty() => (base, ext),
+        _ => (name, ""),
+    };
+    let clean = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match is_short_name_char(c) {
+                true => c.to_ascii_uppercase() as u8,
+                false => b'_',
+            })
+            .collect()
+    };
+    let base = clean(base);
+    let ext = clean(ext);
+
+    for n in 1..1_000_000u32 {
+        let mut tail = [0u8; 8];
+        let mut tail_len = 0;
+        let mut value = n;
+        loop {
+            tail[tail_len] = b'0' + (value % 10) as u8;
+            tail_len += 1;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+
+        let mut short_name = [b' '; 11];
+        let base_len = usize::min(base.len(), 8 - tail_len - 1);
+        short_name[..base_len].copy_from_slice(&base[..base_len]);
+        short_name[base_len] = b'~';
+        for i in 0..tail_len {
+            short_name[base_len + 1 + i] = tail[tail_len - 1 - i];
+        }
+        let ext_len = usize::min(ext.len(), 3);
+        short_name[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+
+        if !taken.contains(&short_name) {
+            return Some(short_name);
+        }
+    }
+    None
+}
+
+/// Fields of a short entry other than its name.
+pub struct EntryInfo {
+    pub attr: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    pub ctime: u64,
+    pub mtime: u64,
+    pub atime: u64,
+}
+
+/// Encodes the entries for an item called `name`: any long name entries followed by the short
+/// entry. `taken` are the short names already in the directory.
+pub fn build_entries(name: &str, info: &EntryInfo, taken: &[[u8; 11]]) -> Option<Vec<[u8; ENTRY_SIZE]>> {
+    let (short_name, nt_flags, needs_long_name) = match exact_short_name(name) {
+        Some((short_name, flags)) if !taken.contains(&short_name) => (short_name, flags, false),
+        _ => (generate_short_name(name, taken)?, 0, true),
+    };
+
+    let mut entries = Vec::new();
+    if needs_long_name {
+        let utf16: Vec<u16> = name.encode_utf16().collect();
+        // long names are limited to 255 UTF-16 characters
+        if utf16.len() > 255 {
+            return None;
+        }
+        let count = (utf16.len() + LFN_CHARS - 1) / LFN_CHARS;
+        let checksum = short_name_checksum(&short_name);
+
+        for sequence in (1..=count).rev() {
+            let mut entry = [0u8; ENTRY_SIZE];
+            entry[0] = sequence as u8 | if sequence == count { 0x40 } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            for (i, &offset) in LFN_OFFSETS.iter().enumerate() {
+                let position = (sequence - 1) * LFN_CHARS + i;
+                // the name is terminated by a null and padded with 0xFFFF
+                let c = match position.cmp(&utf16.len()) {
+                    core::cmp::Ordering::Less => utf16[position],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xFFFF,
+                };
+                entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            entries.push(entry);
+        }
+    }
+
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[..11].copy_from_slice(&short_name);
+    entry[12] = nt_flags;
+    write_entry_info(&mut entry, info);
+    entries.push(entry);
+
+    Some(entries)
+}
+
+/// Stores everything but the name in a short entry.
+pub fn write_entry_info(entry: &mut [u8], info: &EntryInfo) {
+    let (cdate, ctime) = unix_to_fat(info.ctime);
+    let (mdate, mtime) = unix_to_fat(info.mtime);
+    let (adate, _) = unix_to_fat(info.atime);
+
+    entry[11] = info.attr;
+    entry[14..16].copy_from_slice(&ctime.to_le_bytes());
+    entry[16..18].copy_from_slice(&cdate.to_le_bytes());
+    entry[18..20].copy_from_slice(&adate.to_le_bytes());
+    entry[20..22].copy_from_slice(&((info.first_cluster >> 16) as u16).to_le_bytes());
+    entry[22..24].copy_from_slice(&mtime.to_le_bytes());
+    entry[24..26].copy_from_slice(&mdate.to_le_bytes());
+    entry[26..28].copy_from_slice(&(info.first_cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&info.size.to_le_bytes());
+}
+
+/// Builds a `.` or `..` entry for a new directory.
+pub fn dot_entry(name: &[u8], first_cluster: u32, time: u64) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[..11].fill(b' ');
+    entry[..name.len()].copy_from_slice(name);
+    write_entry_info(
+        &mut entry,
+        &EntryInfo {
+            attr: ATTR_DIRECTORY,
+            first_cluster,
+            size: 0,
+            ctime: time,
+            mtime: time,
+            atime: time,
+        },
+    );
+    entry
+}
+
+/// Converts a FAT date and time to a Unix timestamp. FAT has no notion of time zones, the
+/// time is taken to be UTC.
+fn fat_to_unix(date: u16, time: u16) -> u64 {
+    if date == 0 {
+        return 0;
+    }
+    DateTime {
+        year: 1980 + (date >> 9) as u64,
+        month: ((date >> 5) & 0x0F).clamp(1, 12) as u8,
+        day: (date & 0x1F).max(1) as u8,
+        hour: (time >> 11) as u8,
+        minute: ((time >> 5) & 0x3F) as u8,
+        second: ((time & 0x1F) * 2) as u8,
+    }
+    .to_unix()
+}
+
+/// Converts a Unix timestamp to a FAT `(date, time)` pair, clamped to the range FAT can store.
+fn unix_to_fat(unix_time: u64) -> (u16, u16) {
+    let time = DateTime::from_unix(unix_time);
+    if time.year < 1980 {
+        // the earliest date FAT can represent, 1980-01-01
+        return ((1 << 5) | 1, 0);
+    }
+    let year = u64::min(time.year - 1980, 127) as u16;
+
+    let date = (year << 9) | ((time.month as u16) << 5) | time.day as u16;
+    let time = ((time.hour as u16) << 11) | ((time.minute as u16) << 5) | (time.second as u16 / 2);
+    (date, time)
+}
@@ -0,0 +1,543 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use spin::Mutex;
+
+use super::dir::{self, DirItem, EntryInfo, ENTRY_SIZE};
+use super::{DirLocation, FatType, Volume, VolumeState, END_OF_CHAIN};
+use crate::fs::{DirEntry, FileType, FsError, Inode, Metadata};
+use crate::time;
+
+/// A file or directory on a FAT volume, mirroring its directory entry.
+pub(super) struct FatInode {
+    volume: Arc<Volume>,
+    is_dir: bool,
+    node: Mutex<Node>,
+}
+
+struct Node {
+    // device offset of the short directory entry, none for the root directory
+    entry: Option<u64>,
+    attr: u8,
+    first_cluster: u32,
+    size: u32,
+    ctime: u64,
+    mtime: u64,
+    atime: u64,
+    // set once the entry is deleted, its clusters are gone with it
+    removed: bool,
+}
+
+impl Node {
+    fn from_item(item: &DirItem) -> Node {
+        Node {
+            entry: Some(item.entry_offset),
+            attr: item.attr,
+            first_cluster: item.first_cluster,
+            size: item.size,
+            ctime: item.ctime,
+            mtime: item.mtime,
+            atime: item.atime,
+            removed: false,
+        }
+    }
+
+    fn info(&self) -> EntryInfo {
+        EntryInfo {
+            attr: self.attr,
+            first_cluster: self.first_cluster,
+            size: self.size,
+            ctime: self.ctime,
+            mtime: self.mtime,
+            atime: self.atime,
+        }
+    }
+
+    /// The cluster `..` entries of subdirectories point at, zero stands for the root.
+    fn parent_cluster(&self) -> u32 {
+        match self.entry {
+            Some(_) => self.first_cluster,
+            None => 0,
+        }
+    }
+}
+
+fn item_type(item: &DirItem) -> FileType {
+    match item.is_dir() {
+        true => FileType::Directory,
+        false => FileType::File,
+    }
+}
+
+/// Checks whether `source` may replace `target` in a rename.
+fn check_replace(volume: &Volume, source: &DirItem, target: &DirItem) -> Result<(), FsError> {
+    match (source.is_dir(), target.is_dir()) {
+        (true, true) if !volume.read_dir_data(DirLocation::Clusters(target.first_cluster))?.items().is_empty() => {
+            Err(FsError::NotEmpty)
+        }
+        (true, true) => Ok(()),
+        (true, false) => Err(FsError::NotDirectory),
+        (false, true) => Err(FsError::IsDirectory),
+        (false, false) => Ok(()),
+    }
+}
+
+impl FatInode {
+    pub(super) fn new_root(volume: &Arc<Volume>) -> Arc<FatInode> {
+        let first_cluster = match volume.geometry.fat_type {
+            FatType::Fat32 => volume.geometry.root_cluster,
+            _ => 0,
+        };
+        Arc::new(FatInode {
+            volume: volume.clone(),
+            is_dir: true,
+            node: Mutex::new(Node {
+                entry: None,
+                attr: dir::ATTR_DIRECTORY,
+                first_cluster,
+                size: 0,
+                ctime: 0,
+                mtime: 0,
+                atime: 0,
+                removed: false,
+            }),
+        })
+    }
+
+    fn location(&self) -> Result<DirLocation, FsError> {
+        let node = self.node.lock();
+        match node.first_cluster {
+            // the clusters of a removed directory may already belong to something else
+            _ if node.removed => Err(FsError::NotFound),
+            0 => Ok(DirLocation::FixedRoot),
+            cluster => Ok(DirLocation::Clusters(cluster)),
+        }
+    }
+
+    fn items(&self) -> Result<Vec<DirItem>, FsError> {
+        Ok(self.volume.read_dir_data(self.location()?)?.items())
+    }
+
+    fn find(&self, name: &str) -> Result<DirItem, FsError> {
+        self.items()?
+            .into_iter()
+            .find(|item| item.matches(name))
+            .ok_or(FsError::NotFound)
+    }
+
+    /// Returns the inode for `item`, reusing the one already handed out if it's still alive.
+    fn child(&self, state: &mut VolumeState, item: &DirItem) -> Arc<FatInode> {
+        if let Some(inode) = state.inodes.get(&item.entry_offset).and_then(|inode| inode.upgrade()) {
+            return inode;
+        }
+
+        let inode = Arc::new(FatInode {
+            volume: self.volume.clone(),
+            is_dir: item.is_dir(),
+            node: Mutex::new(Node::from_item(item)),
+        });
+        state.inodes.insert(item.entry_offset, Arc::downgrade(&inode));
+        inode
+    }
+
+    /// Writes the cached fields back to the directory entry.
+    fn write_entry(&self, node: &Node) -> Result<(), FsError> {
+        let offset = match node.entry {
+            Some(offset) if !node.removed => offset,
+            _ => return Ok(()),
+        };
+
+        let mut entry = [0; ENTRY_SIZE];
+        self.volume.read(offset, &mut entry)?;
+        dir::write_entry_info(&mut entry, &node.info());
+        self.volume.write(offset, &entry)
+    }
+
+    /// Marks the contents as modified.
+    fn touch(&self) -> Result<(), FsError> {
+        let mut node = self.node.lock();
+        node.mtime = time::now();
+        self.write_entry(&node)
+    }
+
+    /// Splits the byte range `offset..offset + len` of a file into pieces that are contiguous
+    /// on the device, as `(device offset, length)` pairs.
+    fn extents(&self, clusters: &[u32], offset: u64, len: usize) -> Result<Vec<(u64, usize)>, FsError> {
+        let geometry = &self.volume.geometry;
+        let mut extents = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let cluster = *clusters
+                .get((position / geometry.cluster_size) as usize)
+                .ok_or(FsError::Io)?;
+            let within = position % geometry.cluster_size;
+            let size = usize::min((geometry.cluster_size - within) as usize, len - done);
+
+            extents.push((geometry.cluster_offset(cluster) + within, size));
+            done += size;
+        }
+        Ok(extents)
+    }
+
+    /// Allocates clusters until the chain covers `size` bytes.
+    fn grow(&self, state: &mut VolumeState, node: &mut Node, clusters: &mut Vec<u32>, size: u64) -> Result<(), FsError> {
+        let cluster_size = self.volume.geometry.cluster_size;
+        while (clusters.len() as u64) * cluster_size < size {
+            let cluster = self.volume.allocate_cluster(state, clusters.last().copied(), false)?;
+            if node.first_cluster == 0 {
+                node.first_cluster = cluster;
+            }
+            clusters.push(cluster);
+        }
+        Ok(())
+    }
+
+    /// Zeroes the byte range `start..end` of a file.
+    fn zero(&self, clusters: &[u32], start: u64, end: u64) -> Result<(), FsError> {
+        for (offset, len) in self.extents(clusters, start, (end - start) as usize)? {
+            self.volume.write(offset, &vec![0; len])?;
+        }
+        Ok(())
+    }
+
+    /// Shared by `unlink` and `rmdir`, removes the child `name` after checking it with `check`.
+    fn remove(&self, name: &str, check: impl FnOnce(&DirItem) -> Result<(), FsError>) -> Result<(), FsError> {
+        let mut state = self.volume.state.lock();
+        let item = self.find(name)?;
+        check(&item)?;
+
+        self.remove_item(&mut state, &item)?;
+        self.touch()
+    }
+
+    /// Deletes the entries of `item` and frees its clusters.
+    fn remove_item(&self, state: &mut VolumeState, item: &DirItem) -> Result<(), FsError> {
+        if item.first_cluster != 0 {
+            let clusters = self.volume.chain(item.first_cluster)?;
+            self.volume.free_clusters(state, &clusters)?;
+        }
+        self.volume.delete_entries(&item.slots)?;
+
+        if let Some(inode) = state.inodes.remove(&item.entry_offset).and_then(|inode| inode.upgrade()) {
+            let mut node = inode.node.lock();
+            node.removed = true;
+            node.first_cluster = 0;
+            node.size = 0;
+        }
+        Ok(())
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        let node = self.node.lock();
+        let (file_type, mode) = match (self.is_dir, node.attr & dir::ATTR_READ_ONLY != 0) {
+            (true, _) => (FileType::Directory, 0o755),
+            (false, true) => (FileType::File, 0o444),
+            (false, false) => (FileType::File, 0o644),
+        };
+
+        Metadata {
+            // entries are 32 byte aligned, so this is unique, the root gets 1
+            inode: node.entry.map_or(1, |offset| offset / ENTRY_SIZE as u64),
+            file_type,
+            size: node.size as u64,
+            mode,
+            links: 1,
+            atime: node.atime,
+            mtime: node.mtime,
+            ctime: node.ctime,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if self.is_dir {
+            return Err(FsError::IsDirectory);
+        }
+        let _state = self.volume.state.lock();
+        let node = self.node.lock();
+
+        let size = node.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = usize::min(buf.len(), (size - offset) as usize);
+        let clusters = self.volume.chain(node.first_cluster)?;
+
+        let mut done = 0;
+        for (device_offset, extent_len) in self.extents(&clusters, offset, len)? {
+            self.volume.read(device_offset, &mut buf[done..done + extent_len])?;
+            done += extent_len;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        if self.is_dir {
+            return Err(FsError::IsDirectory);
+        }
+        let mut state = self.volume.state.lock();
+        let mut node = self.node.lock();
+        if node.removed {
+            return Err(FsError::NotFound);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // file sizes are stored in 32 bits
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .filter(|&end| end <= u32::MAX as u64)
+            .ok_or(FsError::NoSpace)?;
+        let mut clusters = self.volume.chain(node.first_cluster)?;
+        let grown = self.grow(&mut state, &mut node, &mut clusters, end);
+        if let Err(err) = grown {
+            // keep whatever was allocated reachable from the entry
+            self.write_entry(&node)?;
+            return Err(err);
+        }
+
+        // writing past the end leaves a hole of zeros
+        if offset > node.size as u64 {
+            self.zero(&clusters, node.size as u64, offset)?;
+        }
+        let mut done = 0;
+        for (device_offset, len) in self.extents(&clusters, offset, buf.len())? {
+            self.volume.write(device_offset, &buf[done..done + len])?;
+            done += len;
+        }
+
+        node.size = u32::max(node.size, end as u32);
+        node.mtime = time::now();
+        node.attr |= dir::ATTR_ARCHIVE;
+        self.write_entry(&node)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        if self.is_dir {
+            return Err(FsError::IsDirectory);
+        }
+        let mut state = self.volume.state.lock();
+        let mut node = self.node.lock();
+        if node.removed {
+            return Err(FsError::NotFound);
+        }
+        let size: u32 = size.try_into().map_err(|_| FsError::NoSpace)?;
+
+        let mut clusters = self.volume.chain(node.first_cluster)?;
+        if size < node.size {
+            let cluster_size = self.volume.geometry.cluster_size;
+            let keep = ((size as u64 + cluster_size - 1) / cluster_size) as usize;
+            // a chain shorter than the size says is a corrupt FAT, which is left alone
+            if clusters.len() < keep {
+                return Err(FsError::Io);
+            }
+            match keep {
+                0 => node.first_cluster = 0,
+                _ => self.volume.set_fat_entry(clusters[keep - 1], END_OF_CHAIN)?,
+            }
+            self.volume.free_clusters(&mut state, &clusters[keep..])?;
+        } else if size > node.size {
+            let grown = self.grow(&mut state, &mut node, &mut clusters, size as u64);
+            if let Err(err) = grown {
+                self.write_entry(&node)?;
+                return Err(err);
+            }
+            self.zero(&clusters, node.size as u64, size as u64)?;
+        }
+
+        node.size = size;
+        node.mtime = time::now();
+        node.attr |= dir::ATTR_ARCHIVE;
+        self.write_entry(&node)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let mut state = self.volume.state.lock();
+        let item = self.find(name)?;
+        Ok(self.child(&mut state, &item))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let _state = self.volume.state.lock();
+        Ok(self
+            .items()?
+            .iter()
+            .map(|item| DirEntry {
+                name: item.name.clone(),
+                inode: item.entry_offset / ENTRY_SIZE as u64,
+                file_type: item_type(item),
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, file_type: FileType, mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        if !dir::is_valid_name(name) {
+            return Err(FsError::InvalidArgument);
+        }
+        let mut state = self.volume.state.lock();
+        let items = self.items()?;
+        if items.iter().any(|item| item.matches(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let now = time::now();
+        let mut info = EntryInfo {
+            attr: 0,
+            first_cluster: 0,
+            size: 0,
+            ctime: now,
+            mtime: now,
+            atime: now,
+        };
+        match file_type {
+            FileType::File => info.attr = dir::ATTR_ARCHIVE,
+            FileType::Directory => {
+                // a directory starts out with a cluster holding its `.` and `..` entries
+                let cluster = self.volume.allocate_cluster(&mut state, None, true)?;
+                let offset = self.volume.geometry.cluster_offset(cluster);
+                let parent_cluster = self.node.lock().parent_cluster();
+                self.volume.write(offset, &dir::dot_entry(b".", cluster, now))?;
+                self.volume
+                    .write(offset + ENTRY_SIZE as u64, &dir::dot_entry(b"..", parent_cluster, now))?;
+                info.attr = dir::ATTR_DIRECTORY;
+                info.first_cluster = cluster;
+            }
+            _ => return Err(FsError::Unsupported),
+        }
+        // FAT only knows about write permission
+        if mode & 0o222 == 0 {
+            info.attr |= dir::ATTR_READ_ONLY;
+        }
+
+        let taken: Vec<[u8; 11]> = items.iter().map(|item| item.short_name).collect();
+        let inserted = dir::build_entries(name, &info, &taken)
+            .ok_or(FsError::InvalidArgument)
+            .and_then(|entries| self.volume.insert_entries(&mut state, self.location()?, &entries));
+        let slots = match inserted {
+            Ok(slots) => slots,
+            Err(err) => {
+                if info.first_cluster != 0 {
+                    self.volume.free_clusters(&mut state, &[info.first_cluster])?;
+                }
+                return Err(err);
+            }
+        };
+
+        let entry_offset = *slots.last().unwrap();
+        let inode = Arc::new(FatInode {
+            volume: self.volume.clone(),
+            is_dir: file_type == FileType::Directory,
+            node: Mutex::new(Node {
+                entry: Some(entry_offset),
+                attr: info.attr,
+                first_cluster: info.first_cluster,
+                size: 0,
+                ctime: now,
+                mtime: now,
+                atime: now,
+                removed: false,
+            }),
+        });
+        state.inodes.insert(entry_offset, Arc::downgrade(&inode));
+        self.touch()?;
+        Ok(inode)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.remove(name, |item| match item.is_dir() {
+            true => Err(FsError::IsDirectory),
+            false => Ok(()),
+        })
+    }
+
+    fn rmdir(&self, name: &str) -> Result<(), FsError> {
+        let volume = self.volume.clone();
+        self.remove(name, |item| {
+            if !item.is_dir() {
+                return Err(FsError::NotDirectory);
+            }
+            match volume.read_dir_data(DirLocation::Clusters(item.first_cluster))?.items().is_empty() {
+                true => Ok(()),
+                false => Err(FsError::NotEmpty),
+            }
+        })
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &Arc<dyn Inode>, new_name: &str) -> Result<(), FsError> {
+        let new_parent = new_parent
+            .as_any()
+            .downcast_ref::<FatInode>()
+            .filter(|parent| Arc::ptr_eq(&parent.volume, &self.volume))
+            .ok_or(FsError::CrossDevice)?;
+        if !dir::is_valid_name(new_name) {
+            return Err(FsError::InvalidArgument);
+        }
+        let mut state = self.volume.state.lock();
+        let same_dir = core::ptr::eq(self, new_parent);
+
+        let source = self.find(old_name)?;
+        let target_items = new_parent.items()?;
+        // in the same directory the target may be the source itself, with different case
+        let target = target_items
+            .iter()
+            .find(|item| item.matches(new_name) && item.entry_offset != source.entry_offset);
+        if let Some(target) = target {
+            check_replace(&self.volume, &source, target)?;
+        }
+
+        let info = EntryInfo {
+            attr: source.attr,
+            first_cluster: source.first_cluster,
+            size: source.size,
+            ctime: source.ctime,
+            mtime: source.mtime,
+            atime: source.atime,
+        };
+        let taken: Vec<[u8; 11]> = target_items
+            .iter()
+            .filter(|item| Some(item.entry_offset) != target.map(|target| target.entry_offset))
+            .map(|item| item.short_name)
+            .collect();
+        let entries = dir::build_entries(new_name, &info, &taken).ok_or(FsError::InvalidArgument)?;
+        let slots = self
+            .volume
+            .insert_entries(&mut state, new_parent.location()?, &entries)?;
+        // the target only goes once the new entries are in, so it's still there if they don't fit
+        if let Some(target) = target {
+            new_parent.remove_item(&mut state, target)?;
+        }
+        self.volume.delete_entries(&source.slots)?;
+        let entry_offset = *slots.last().unwrap();
+
+        // a moved directory's `..` has to point at its new parent
+        if source.is_dir() && !same_dir {
+            let parent_cluster = new_parent.node.lock().parent_cluster();
+            let offset = self.volume.geometry.cluster_offset(source.first_cluster) + ENTRY_SIZE as u64;
+            self.volume.write(offset + 20, &((parent_cluster >> 16) as u16).to_le_bytes())?;
+            self.volume.write(offset + 26, &(parent_cluster as u16).to_le_bytes())?;
+        }
+
+        if let Some(inode) = state.inodes.remove(&source.entry_offset) {
+            if let Some(inode) = inode.upgrade() {
+                inode.node.lock().entry = Some(entry_offset);
+            }
+            state.inodes.insert(entry_offset, inode);
+        }
+
+        self.touch()?;
+        if !same_dir {
+            new_parent.touch()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,468 @@
+//! FAT12, FAT16 and FAT32 with long file names.
+
+mod dir;
+mod inode;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use self::dir::{DirData, ENTRY_SIZE};
+use self::inode::FatInode;
+use super::{FileSystem, FsError, Inode};
+use crate::block::{BlockDevice, BlockError};
+
+// lowest FAT entry values that mark a bad cluster and the end of a chain, as FAT32 values
+const BAD_CLUSTER: u32 = 0x0FFF_FFF7;
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// Layout of the volume, everything in bytes from the start of the device.
+struct Geometry {
+    fat_type: FatType,
+    cluster_size: u64,
+    fat_start: u64,
+    fat_size: u64,
+    fat_count: u64,
+    // the fixed size root directory of FAT12 and FAT16
+    root_dir_start: u64,
+    root_dir_size: u64,
+    data_start: u64,
+    cluster_count: u32,
+    // first cluster of the root directory on FAT32
+    root_cluster: u32,
+    fs_info: Option<u64>,
+}
+
+impl Geometry {
+    /// Parses the BIOS parameter block in the boot sector.
+    fn parse(boot: &[u8]) -> Result<Geometry, FsError> {
+        let u16_at = |offset: usize| u16::from_le_bytes([boot[offset], boot[offset + 1]]) as u64;
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([boot[offset], boot[offset + 1], boot[offset + 2], boot[offset + 3]]) as u64
+        };
+
+        let sector_size = u16_at(11);
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = u16_at(14);
+        let fat_count = boot[16] as u64;
+        let root_entries = u16_at(17);
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            count => count,
+        };
+        let fat_sectors = match u16_at(22) {
+            0 => u32_at(36),
+            count => count,
+        };
+
+        if boot[510..512] != [0x55, 0xAA]
+            || !sector_size.is_power_of_two()
+            || !(512..=4096).contains(&sector_size)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || fat_sectors == 0
+        {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let root_dir_sectors = (root_entries * ENTRY_SIZE as u64 + sector_size - 1) / sector_size;
+        let data_sector = reserved_sectors + fat_count * fat_sectors + root_dir_sectors;
+        let cluster_count = total_sectors
+            .checked_sub(data_sector)
+            .ok_or(FsError::InvalidArgument)?
+            / sectors_per_cluster;
+        // sectors_per_cluster can't be 0 here, it isn't a power of two
+        if cluster_count == 0 {
+            return Err(FsError::InvalidArgument);
+        }
+
+        // the type is decided by the number of clusters alone
+        let fat_type = match cluster_count {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let (root_cluster, fs_info) = match fat_type {
+            FatType::Fat32 => (u32_at(44) as u32, Some(u16_at(48) * sector_size)),
+            _ => (0, None),
+        };
+        // a FAT32 volume keeps its root directory in the data area
+        if fat_type == FatType::Fat32 && root_entries != 0 || fat_type != FatType::Fat32 && root_entries == 0 {
+            return Err(FsError::InvalidArgument);
+        }
+
+        Ok(Geometry {
+            fat_type,
+            cluster_size: sectors_per_cluster * sector_size,
+            fat_start: reserved_sectors * sector_size,
+            fat_size: fat_sectors * sector_size,
+            fat_count,
+            root_dir_start: (reserved_sectors + fat_count * fat_sectors) * sector_size,
+            root_dir_size: root_entries * ENTRY_SIZE as u64,
+            data_start: data_sector * sector_size,
+            cluster_count: cluster_count as u32,
+            root_cluster,
+            // sectors 0 and 0xFFFF both mean there's no FSInfo sector
+            fs_info: fs_info.filter(|&offset| offset != 0 && offset != 0xFFFF * sector_size),
+        })
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+}
+
+/// Where the entries of a directory are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DirLocation {
+    /// The root directory of FAT12 and FAT16, outside of the data area.
+    FixedRoot,
+    Clusters(u32),
+}
+
+struct VolumeState {
+    // where to start looking for a free cluster
+    next_free: u32,
+    free_count: Option<u32>,
+    // inodes handed out, keyed by the device offset of their directory entry
+    inodes: BTreeMap<u64, Weak<FatInode>>,
+}
+
+/// The parts of a mounted FAT volume shared by all of its inodes.
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    geometry: Geometry,
+    // serializes every operation that touches the volume
+    state: Mutex<VolumeState>,
+}
+
+impl From<BlockError> for FsError {
+    fn from(_: BlockError) -> FsError {
+        FsError::Io
+    }
+}
+
+impl Volume {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        Ok(self.device.read_bytes(offset, buf)?)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        Ok(self.device.write_bytes(offset, buf)?)
+    }
+
+    /// Reads the FAT entry for `cluster`, widened so end of chain and bad cluster markers
+    /// compare like their FAT32 counterparts.
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let geometry = &self.geometry;
+        match geometry.fat_type {
+            FatType::Fat12 => {
+                let mut buf = [0; 2];
+                self.read(geometry.fat_start + cluster as u64 * 3 / 2, &mut buf)?;
+                let value = u16::from_le_bytes(buf) as u32;
+                // odd clusters use the high 12 bits
+                let value = match cluster % 2 {
+                    0 => value & 0xFFF,
+                    _ => value >> 4,
+                };
+                Ok(match value >= 0xFF7 {
+                    true => value | 0x0FFF_F000,
+                    false => value,
+                })
+            }
+            FatType::Fat16 => {
+                let mut buf = [0; 2];
+                self.read(geometry.fat_start + cluster as u64 * 2, &mut buf)?;
+                let value = u16::from_le_bytes(buf) as u32;
+                Ok(match value >= 0xFFF7 {
+                    true => value | 0x0FFF_0000,
+                    false => value,
+                })
+            }
+            FatType::Fat32 => {
+                let mut buf = [0; 4];
+                self.read(geometry.fat_start + cluster as u64 * 4, &mut buf)?;
+                // the top four bits are reserved
+                Ok(u32::from_le_bytes(buf) & 0x0FFF_FFFF)
+            }
+        }
+    }
+
+    /// Sets the FAT entry for `cluster` in every copy of the FAT.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let geometry = &self.geometry;
+        for copy in 0..geometry.fat_count {
+            let fat_start = geometry.fat_start + copy * geometry.fat_size;
+            match geometry.fat_type {
+                FatType::Fat12 => {
+                    let offset = fat_start + cluster as u64 * 3 / 2;
+                    let mut buf = [0; 2];
+                    self.read(offset, &mut buf)?;
+                    let old = u16::from_le_bytes(buf);
+                    let value = (value & 0xFFF) as u16;
+                    let new = match cluster % 2 {
+                        0 => (old & 0xF000) | value,
+                        _ => (old & 0x000F) | (value << 4),
+                    };
+                    self.write(offset, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => {
+                    self.write(fat_start + cluster as u64 * 2, &(value as u16).to_le_bytes())?;
+                }
+                FatType::Fat32 => {
+                    let offset = fat_start + cluster as u64 * 4;
+                    let mut buf = [0; 4];
+                    self.read(offset, &mut buf)?;
+                    let new = (u32::from_le_bytes(buf) & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                    self.write(offset, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Follows the chain starting at `first`, returning every cluster in it.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 {
+            // a chain longer than the volume must loop back on itself
+            if !self.geometry.is_valid_cluster(cluster) || clusters.len() > self.geometry.cluster_count as usize {
+                return Err(FsError::Io);
+            }
+            clusters.push(cluster);
+
+            cluster = match self.fat_entry(cluster)? {
+                next if next >= END_OF_CHAIN => 0,
+                BAD_CLUSTER | 0 => return Err(FsError::Io),
+                next => next,
+            };
+        }
+        Ok(clusters)
+    }
+
+    /// Allocates a cluster and appends it to the chain ending in `last`, if there is one.
+    fn allocate_cluster(&self, state: &mut VolumeState, last: Option<u32>, zero: bool) -> Result<u32, FsError> {
+        let count = self.geometry.cluster_count;
+        let start = match self.geometry.is_valid_cluster(state.next_free) {
+            true => state.next_free,
+            false => 2,
+        };
+
+        let mut cluster = start;
+        loop {
+            if self.fat_entry(cluster)? == 0 {
+                break;
+            }
+            cluster = match cluster + 1 {
+                next if next >= count + 2 => 2,
+                next => next,
+            };
+            if cluster == start {
+                return Err(FsError::NoSpace);
+            }
+        }
+
+        if zero {
+            self.write(self.geometry.cluster_offset(cluster), &vec![0; self.geometry.cluster_size as usize])?;
+        }
+        self.set_fat_entry(cluster, END_OF_CHAIN)?;
+        if let Some(last) = last {
+            self.set_fat_entry(last, cluster)?;
+        }
+
+        state.next_free = cluster + 1;
+        state.free_count = state.free_count.map(|free| free.saturating_sub(1));
+        Ok(cluster)
+    }
+
+    /// Frees every cluster in `clusters`.
+    fn free_clusters(&self, state: &mut VolumeState, clusters: &[u32]) -> Result<(), FsError> {
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        state.free_count = state.free_count.map(|free| free + clusters.len() as u32);
+        Ok(())
+    }
+
+    /// Reads every entry slot of a directory.
+    fn read_dir_data(&self, location: DirLocation) -> Result<DirData, FsError> {
+        let geometry = &self.geometry;
+        let extents = match location {
+            DirLocation::FixedRoot => vec![(geometry.root_dir_start, geometry.root_dir_size)],
+            DirLocation::Clusters(first) => self
+                .chain(first)?
+                .into_iter()
+                .map(|cluster| (geometry.cluster_offset(cluster), geometry.cluster_size))
+                .collect(),
+        };
+
+        let total = extents.iter().map(|&(_, size)| size as usize).sum();
+        let mut data = DirData {
+            slots: Vec::with_capacity(total / ENTRY_SIZE),
+            bytes: vec![0; total],
+        };
+        let mut done = 0;
+        for (offset, size) in extents {
+            let size = size as usize;
+            self.read(offset, &mut data.bytes[done..done + size])?;
+            data.slots
+                .extend((0..size / ENTRY_SIZE).map(|slot| offset + (slot * ENTRY_SIZE) as u64));
+            done += size;
+        }
+        Ok(data)
+    }
+
+    /// Writes `entries` into consecutive free slots of a directory, growing it if needed.
+    /// Returns the offset of each written entry.
+    fn insert_entries(
+        &self,
+        state: &mut VolumeState,
+        location: DirLocation,
+        entries: &[[u8; ENTRY_SIZE]],
+    ) -> Result<Vec<u64>, FsError> {
+        let mut data = self.read_dir_data(location)?;
+        let index = loop {
+            if let Some(index) = data.find_free(entries.len()) {
+                break index;
+            }
+            let first = match location {
+                DirLocation::FixedRoot => return Err(FsError::NoSpace),
+                DirLocation::Clusters(first) => first,
+            };
+            let last = *self.chain(first)?.last().ok_or(FsError::Io)?;
+            self.allocate_cluster(state, Some(last), true)?;
+            data = self.read_dir_data(location)?;
+        };
+
+        let offsets = data.slots[index..index + entries.len()].to_vec();
+        for (entry, &offset) in entries.iter().zip(&offsets) {
+            self.write(offset, entry)?;
+        }
+        Ok(offsets)
+    }
+
+    /// Marks the slots of a removed entry as deleted.
+    fn delete_entries(&self, slots: &[u64]) -> Result<(), FsError> {
+        for &offset in slots {
+            self.write(offset, &[dir::DELETED])?;
+        }
+        Ok(())
+    }
+
+    /// Counts the free clusters by scanning the whole FAT.
+    fn count_free(&self) -> Result<u32, FsError> {
+        let mut free = 0;
+        for cluster in 2..self.geometry.cluster_count + 2 {
+            if self.fat_entry(cluster)? == 0 {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+
+    /// Stores the free cluster count and allocation hint in the FSInfo sector of FAT32
+    /// volumes, so other systems don't have to scan the FAT.
+    fn write_fs_info(&self, state: &VolumeState) -> Result<(), FsError> {
+        let offset = match self.geometry.fs_info {
+            Some(offset) => offset,
+            None => return Ok(()),
+        };
+
+        let mut signature = [0; 4];
+        self.read(offset, &mut signature)?;
+        if u32::from_le_bytes(signature) != FS_INFO_LEAD_SIGNATURE {
+            return Ok(());
+        }
+
+        let mut info = [0; 8];
+        info[..4].copy_from_slice(&state.free_count.unwrap_or(u32::MAX).to_le_bytes());
+        info[4..].copy_from_slice(&state.next_free.to_le_bytes());
+        self.write(offset + 488, &info)
+    }
+}
+
+/// A FAT volume on a block device.
+pub struct FatFs {
+    volume: Arc<Volume>,
+    root: Arc<FatInode>,
+}
+
+impl FatFs {
+    /// Mounts the FAT volume on `device`, failing with `InvalidArgument` if there isn't one.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<FatFs, FsError> {
+        let mut boot = vec![0; usize::max(device.sector_size(), 512)];
+        device.read_sectors(0, &mut boot)?;
+        let geometry = Geometry::parse(&boot)?;
+
+        // start allocating where the last system to use the volume stopped
+        let mut next_free = 2;
+        let mut free_count = None;
+        if let Some(offset) = geometry.fs_info {
+            let mut info = [0; 496];
+            device.read_bytes(offset, &mut info)?;
+            let u32_at = |offset: usize| u32::from_le_bytes(info[offset..offset + 4].try_into().unwrap());
+            if u32_at(0) == FS_INFO_LEAD_SIGNATURE && u32_at(484) == FS_INFO_STRUCT_SIGNATURE {
+                next_free = u32_at(492);
+                free_count = Some(u32_at(488)).filter(|&free| free <= geometry.cluster_count);
+            }
+        }
+
+        let volume = Arc::new(Volume {
+            device,
+            geometry,
+            state: Mutex::new(VolumeState {
+                next_free,
+                free_count,
+                inodes: BTreeMap::new(),
+            }),
+        });
+        if volume.geometry.fat_type == FatType::Fat32 && free_count.is_none() {
+            let free = volume.count_free()?;
+            volume.state.lock().free_count = Some(free);
+        }
+
+        Ok(FatFs {
+            root: FatInode::new_root(&volume),
+            volume,
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.volume.geometry.fat_type
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "fat"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        let state = self.volume.state.lock();
+        self.volume.write_fs_info(&state)?;
+        Ok(self.volume.device.flush()?)
+    }
+}
@@ -0,0 +1,130 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use bitflags::bitflags;
+use spin::Mutex;
+
+use super::vfs::Dentry;
+use super::{DirEntry, FileType, FsError, Metadata};
+
+bitflags! {
+    pub struct OpenFlags: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        /// Create the file if it doesn't exist.
+        const CREATE = 1 << 2;
+        /// Together with `CREATE`, fail if the file already exists.
+        const EXCLUSIVE = 1 << 3;
+        /// Cut the file down to zero bytes when opening it for writing.
+        const TRUNCATE = 1 << 4;
+        /// Every write goes to the end of the file.
+        const APPEND = 1 << 5;
+        /// Fail unless the path is a directory.
+        const DIRECTORY = 1 << 6;
+        /// Open a symlink at the end of the path instead of its target.
+        const NO_FOLLOW = 1 << 7;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// An open file, a dentry together with the current offset into it.
+pub struct File {
+    dentry: Arc<Dentry>,
+    flags: OpenFlags,
+    offset: Mutex<u64>,
+}
+
+impl File {
+    pub(super) fn new(dentry: Arc<Dentry>, flags: OpenFlags) -> File {
+        File {
+            dentry,
+            flags,
+            offset: Mutex::new(0),
+        }
+    }
+
+    pub fn dentry(&self) -> &Arc<Dentry> {
+        &self.dentry
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.dentry.metadata()
+    }
+
+    /// Reads from the current offset, advancing it by the number of bytes read.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut offset = self.offset.lock();
+        let read = self.read_at(*offset, buf)?;
+        *offset += read as u64;
+        Ok(read)
+    }
+
+    /// Writes at the current offset, or the end of the file if opened with `APPEND`, and
+    /// advances the offset past the written bytes.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        let mut offset = self.offset.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            *offset = self.metadata().size;
+        }
+        let written = self.write_at(*offset, buf)?;
+        *offset += written as u64;
+        Ok(written)
+    }
+
+    /// Reads at `offset` without touching the current offset.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FsError::PermissionDenied);
+        }
+        if self.dentry.file_type() == FileType::Directory {
+            return Err(FsError::IsDirectory);
+        }
+        self.dentry.inode().read_at(offset, buf)
+    }
+
+    /// Writes at `offset` without touching the current offset.
+    pub fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::PermissionDenied);
+        }
+        self.dentry.inode().write_at(offset, buf)
+    }
+
+    /// Moves the current offset, returning the new one.
+    pub fn seek(&self, pos: SeekFrom) -> Result<u64, FsError> {
+        let mut offset = self.offset.lock();
+        let new_offset = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.metadata().size.checked_add_signed(delta),
+        };
+
+        *offset = new_offset.ok_or(FsError::InvalidArgument)?;
+        Ok(*offset)
+    }
+
+    pub fn truncate(&self, size: u64) -> Result<(), FsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::PermissionDenied);
+        }
+        self.dentry.inode().truncate(size)
+    }
+
+    /// Lists the directory this file refers to.
+    pub fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        if self.dentry.file_type() != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        self.dentry.inode().read_dir()
+    }
+}
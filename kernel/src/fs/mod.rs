@@ -0,0 +1,196 @@
+pub mod ext2;
+pub mod fat;
+pub mod file;
+pub mod ramdisk;
+pub mod tar;
+pub mod tmpfs;
+pub mod vfs;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use crate::block::{self, BlockDevice};
+use ext2::Ext2Fs;
+use fat::FatFs;
+use ramdisk::{RamdiskFs, RAMDISK};
+use tmpfs::TmpFs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotDirectory,
+    IsDirectory,
+    AlreadyExists,
+    NotEmpty,
+    /// The filesystem or file can't be modified.
+    ReadOnly,
+    /// The file wasn't opened for the attempted access.
+    PermissionDenied,
+    InvalidArgument,
+    /// Too many symlinks were followed while resolving a path.
+    TooManyLinks,
+    /// The operation would move a file between two filesystems.
+    CrossDevice,
+    /// A mount point is in the way of the operation.
+    Busy,
+    NoSpace,
+    /// The underlying device failed or returned garbage.
+    Io,
+    /// The filesystem doesn't implement the operation.
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    /// Inode number, unique within the filesystem.
+    pub inode: u64,
+    pub file_type: FileType,
+    pub size: u64,
+    /// Permission bits.
+    pub mode: u32,
+    pub links: u32,
+    /// Access, modification and status change times in seconds since the Unix epoch.
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType,
+}
+
+/// A mountable filesystem.
+pub trait FileSystem: Send + Sync {
+    /// Short name of the filesystem type, like "tmpfs".
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Writes back anything that's cached in memory.
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// A file, directory or other object in a filesystem.
+///
+/// Every operation has a default that fails, filesystems only implement what makes sense
+/// for them. The VFS checks file types before calling in, so e.g. `lookup` is only ever called
+/// on directories. Names passed to directory operations are single path components, never
+/// `.` or `..`, which the VFS handles itself.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Lets filesystems recover their concrete inode type, e.g. for `rename`.
+    fn as_any(&self) -> &dyn Any;
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        Err(FsError::InvalidArgument)
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// Creates a new file or directory called `name` in this directory.
+    fn create(&self, _name: &str, _file_type: FileType, _mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Removes the non-directory entry `name`.
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Removes the empty directory `name`.
+    fn rmdir(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Moves the entry `old_name` to `new_name` in `new_parent`, which the VFS guarantees is
+    /// a directory on the same filesystem. An existing target is replaced if it's compatible.
+    fn rename(&self, _old_name: &str, _new_parent: &Arc<dyn Inode>, _new_name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+/// Sets up the initial mount table. Unless a disk filesystem was already mounted as the root,
+/// the root is a tmpfs. A fresh tmpfs always goes on `/tmp` and the ramdisk, if one was loaded,
+/// on `/initrd`. Every registered block device holding a filesystem we recognize is mounted on
+/// `/mnt/<device name>`.
+pub fn init() -> Result<(), FsError> {
+    if vfs::root().is_err() {
+        vfs::mount("/", Arc::new(TmpFs::new()))?;
+    }
+
+    mount_on_new_dir("/tmp", 0o1777, Arc::new(TmpFs::new()))?;
+    if let Some(ramdisk) = RAMDISK.get() {
+        mount_on_new_dir("/initrd", 0o755, Arc::new(RamdiskFs::new(ramdisk)))?;
+    }
+
+    for info in block::devices().into_iter().filter(|info| !info.partitioned) {
+        if let Some(fs) = probe(info.device) {
+            match vfs::create_dir("/mnt", 0o755) {
+                Ok(()) | Err(FsError::AlreadyExists) => {}
+                Err(err) => return Err(err),
+            }
+            mount_on_new_dir(&format!("/mnt/{}", info.name), 0o755, fs)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Figures out which filesystem is on `device`, if any, and opens it.
+pub fn probe(device: Arc<dyn BlockDevice>) -> Option<Arc<dyn FileSystem>> {
+    if let Ok(fs) = Ext2Fs::new(device.clone()) {
+        return Some(Arc::new(fs));
+    }
+    if let Ok(fs) = FatFs::new(device) {
+        return Some(Arc::new(fs));
+    }
+    None
+}
+
+/// Mounts `fs` at `path`, creating the directory first if needed.
+fn mount_on_new_dir(path: &str, mode: u32, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    match vfs::create_dir(path, mode) {
+        Ok(()) | Err(FsError::AlreadyExists) => vfs::mount(path, fs),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,282 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use spin::Once;
+
+use super::tar::{EntryKind, TarArchive, TarError};
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+
+/// The filesystem unpacked from the ramdisk the bootloader loaded, if there was one.
+pub static RAMDISK: Once<Ramdisk> = Once::new();
+
+/// Index of a node in a `Ramdisk`.
+pub type NodeIndex = usize;
+
+pub const ROOT: NodeIndex = 0;
+
+pub enum NodeKind {
+    Directory(BTreeMap<String, NodeIndex>),
+    /// File contents point straight into the ramdisk image, nothing is copied.
+    File(&'static [u8]),
+    Symlink(String),
+}
+
+pub struct Node {
+    pub kind: NodeKind,
+    pub mode: u32,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: u64,
+}
+
+/// A read-only filesystem built from a tar archive.
+pub struct Ramdisk {
+    nodes: Vec<Node>,
+}
+
+impl Ramdisk {
+    /// Unpacks a tar archive. Hard links are resolved to the file they point at, entries that
+    /// aren't files, directories or symlinks are skipped.
+    pub fn from_tar(image: &'static [u8]) -> Result<Ramdisk, TarError> {
+        let mut ramdisk = Ramdisk {
+            nodes: Vec::new(),
+        };
+        ramdisk.nodes.push(Node {
+            kind: NodeKind::Directory(BTreeMap::new()),
+            mode: 0o755,
+            mtime: 0,
+        });
+
+        for entry in TarArchive::new(image) {
+            let entry = entry?;
+            let mut components: Vec<&str> = entry.path_components().collect();
+            let name = match components.pop() {
+                Some(name) => name,
+                // the archive's root directory itself
+                None => continue,
+            };
+            let parent = ramdisk.create_parents(&components);
+
+            let kind = match entry.kind {
+                EntryKind::File => NodeKind::File(entry.data),
+                EntryKind::Directory => NodeKind::Directory(BTreeMap::new()),
+                EntryKind::Symlink => NodeKind::Symlink(entry.link_target.to_string()),
+                EntryKind::HardLink => {
+                    match ramdisk.lookup(entry.link_target) {
+                        Some(target) if ramdisk.is_file(target) => {
+                            ramdisk.link(parent, name, target);
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+                EntryKind::Other => continue,
+            };
+
+            // directories may already exist if one of their children came first
+            if let Some(existing) = ramdisk.child(parent, name)
+                && let NodeKind::Directory(_) = ramdisk.nodes[existing].kind
+                && let NodeKind::Directory(_) = kind
+            {
+                ramdisk.nodes[existing].mode = entry.mode;
+                ramdisk.nodes[existing].mtime = entry.mtime;
+                continue;
+            }
+
+            let index = ramdisk.push(Node {
+                kind,
+                mode: entry.mode,
+                mtime: entry.mtime,
+            });
+            ramdisk.link(parent, name, index);
+        }
+
+        Ok(ramdisk)
+    }
+
+    pub fn node(&self, index: NodeIndex) -> &Node {
+        &self.nodes[index]
+    }
+
+    /// Number of nodes, including the root directory.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Looks up `name` in the directory `parent`.
+    pub fn child(&self, parent: NodeIndex, name: &str) -> Option<NodeIndex> {
+        match &self.nodes[parent].kind {
+            NodeKind::Directory(children) => children.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    /// Resolves a path relative to the root. Symlinks are not followed.
+    pub fn lookup(&self, path: &str) -> Option<NodeIndex> {
+        path.split('/')
+            .filter(|component| !component.is_empty() && *component != ".")
+            .try_fold(ROOT, |node, component| self.child(node, component))
+    }
+
+    /// Copies file contents starting at `offset` into `buf`, returning how many bytes were read.
+    pub fn read(&self, index: NodeIndex, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        match self.nodes[index].kind {
+            NodeKind::File(data) => {
+                let data = data.get(offset..).unwrap_or(&[]);
+                let len = usize::min(data.len(), buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Some(len)
+            }
+            _ => None,
+        }
+    }
+
+    fn is_file(&self, index: NodeIndex) -> bool {
+        matches!(self.nodes[index].kind, NodeKind::File(_))
+    }
+
+    fn push(&mut self, node: Node) -> NodeIndex {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    fn link(&mut self, parent: NodeIndex, name: &str, index: NodeIndex) {
+        if let NodeKind::Directory(children) = &mut self.nodes[parent].kind {
+            children.insert(name.to_string(), index);
+        }
+    }
+
+    /// Walks `components` from the root, creating any directories that are missing.
+    fn create_parents(&mut self, components: &[&str]) -> NodeIndex {
+        let mut parent = ROOT;
+        for component in components {
+            parent = match self.child(parent, component) {
+                Some(child) => child,
+                None => {
+                    let child = self.push(Node {
+                        kind: NodeKind::Directory(BTreeMap::new()),
+                        mode: 0o755,
+                        mtime: 0,
+                    });
+                    self.link(parent, component, child);
+                    child
+                }
+            };
+        }
+        parent
+    }
+}
+
+/// Exposes a `Ramdisk` through the VFS.
+pub struct RamdiskFs {
+    ramdisk: &'static Ramdisk,
+}
+
+impl RamdiskFs {
+    pub fn new(ramdisk: &'static Ramdisk) -> RamdiskFs {
+        RamdiskFs { ramdisk }
+    }
+}
+
+impl FileSystem for RamdiskFs {
+    fn name(&self) -> &'static str {
+        "ramdisk"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(RamdiskInode {
+            ramdisk: self.ramdisk,
+            index: ROOT,
+        })
+    }
+}
+
+struct RamdiskInode {
+    ramdisk: &'static Ramdisk,
+    index: NodeIndex,
+}
+
+impl RamdiskInode {
+    fn node(&self) -> &'static Node {
+        self.ramdisk.node(self.index)
+    }
+}
+
+fn file_type(kind: &NodeKind) -> FileType {
+    match kind {
+        NodeKind::Directory(_) => FileType::Directory,
+        NodeKind::File(_) => FileType::File,
+        NodeKind::Symlink(_) => FileType::Symlink,
+    }
+}
+
+impl Inode for RamdiskInode {
+    fn metadata(&self) -> Metadata {
+        let node = self.node();
+        let size = match &node.kind {
+            NodeKind::Directory(children) => children.len() as u64,
+            NodeKind::File(data) => data.len() as u64,
+            NodeKind::Symlink(target) => target.len() as u64,
+        };
+
+        Metadata {
+            // inode numbers start at 1
+            inode: self.index as u64 + 1,
+            file_type: file_type(&node.kind),
+            size,
+            mode: node.mode,
+            links: 1,
+            atime: node.mtime,
+            mtime: node.mtime,
+            ctime: node.mtime,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let offset = usize::try_from(offset).map_err(|_| FsError::InvalidArgument)?;
+        self.ramdisk
+            .read(self.index, offset, buf)
+            .ok_or(FsError::InvalidArgument)
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        match &self.node().kind {
+            NodeKind::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let index = self.ramdisk.child(self.index, name).ok_or(FsError::NotFound)?;
+        Ok(Arc::new(RamdiskInode {
+            ramdisk: self.ramdisk,
+            index,
+        }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        match &self.node().kind {
+            NodeKind::Directory(children) => Ok(children
+                .iter()
+                .map(|(name, &index)| DirEntry {
+                    name: name.clone(),
+                    inode: index as u64 + 1,
+                    file_type: file_type(&self.ramdisk.node(index).kind),
+                })
+                .collect()),
+            _ => Err(FsError::NotDirectory),
+        }
+    }
+}
+
+/// Unpacks the ramdisk image loaded by the bootloader into `RAMDISK`.
+pub fn init(image: &'static [u8]) -> Result<&'static Ramdisk, TarError> {
+    let ramdisk = Ramdisk::from_tar(image)?;
+    Ok(RAMDISK.call_once(|| ramdisk))
+}
@@ -0,0 +1,221 @@
+use core::str;
+
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarError {
+    /// The archive ends in the middle of a header or file.
+    Truncated,
+    /// A header's checksum doesn't match its contents.
+    BadChecksum,
+    /// A header field couldn't be parsed.
+    BadHeader,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    HardLink,
+    Symlink,
+    Directory,
+    /// Device nodes, FIFOs and anything else we don't know what to do with.
+    Other,
+}
+
+/// A single member of a tar archive, borrowing from the archive's memory.
+pub struct Entry<'a> {
+    // ustar splits long paths into a prefix and a name, which are joined with a slash
+    prefix: &'a str,
+    name: &'a str,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub mtime: u64,
+    /// Target of links, empty for other kinds.
+    pub link_target: &'a str,
+    pub data: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    /// The non-empty components of the entry's path, with `.` components removed.
+    pub fn path_components(&self) -> impl Iterator<Item = &'a str> {
+        self.prefix
+            .split('/')
+            .chain(self.name.split('/'))
+            .filter(|component| !component.is_empty() && *component != ".")
+    }
+}
+
+/// Iterates over the members of a ustar/GNU tar archive.
+///
+/// GNU long names (`L` entries) and the `path` key of pax extended headers are applied to the
+/// entry that follows them, all other metadata entries are skipped.
+pub struct TarArchive<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> TarArchive<'a> {
+    pub fn new(data: &'a [u8]) -> TarArchive<'a> {
+        TarArchive { data, offset: 0 }
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>, TarError> {
+        let mut long_name: Option<&'a str> = None;
+        let mut long_link: Option<&'a str> = None;
+
+        loop {
+            if self.offset + BLOCK_SIZE > self.data.len() {
+                // an archive without the two terminating zero blocks is still fine as long as it
+                // ends on a header boundary
+                return match self.offset == self.data.len() {
+                    true => Ok(None),
+                    false => Err(TarError::Truncated),
+                };
+            }
+
+            let header = &self.data[self.offset..self.offset + BLOCK_SIZE];
+            if header.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            verify_checksum(header)?;
+
+            let size = parse_octal(&header[124..136])? as usize;
+            let data_start = self.offset + BLOCK_SIZE;
+            let data_end = data_start.checked_add(size).ok_or(TarError::BadHeader)?;
+            if data_end > self.data.len() {
+                return Err(TarError::Truncated);
+            }
+            let data = &self.data[data_start..data_end];
+            self.offset = data_start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+
+            let typeflag = header[156];
+            match typeflag {
+                b'L' => {
+                    long_name = Some(parse_str(data)?);
+                    continue;
+                }
+                b'K' => {
+                    long_link = Some(parse_str(data)?);
+                    continue;
+                }
+                b'x' => {
+                    long_name = pax_path(data)?.or(long_name);
+                    continue;
+                }
+                // global pax headers don't apply to a single file
+                b'g' => continue,
+                _ => {}
+            }
+
+            let (prefix, name) = match long_name {
+                Some(name) => ("", name),
+                None => header_path(header)?,
+            };
+            let link_target = match long_link {
+                Some(name) => name,
+                None => parse_str(&header[157..257])?,
+            };
+            let kind = match typeflag {
+                b'0' | b'\0' | b'7' => EntryKind::File,
+                b'1' => EntryKind::HardLink,
+                b'2' => EntryKind::Symlink,
+                b'5' => EntryKind::Directory,
+                _ => EntryKind::Other,
+            };
+
+            return Ok(Some(Entry {
+                prefix,
+                name,
+                kind,
+                mode: parse_octal(&header[100..108])? as u32,
+                mtime: parse_octal(&header[136..148])?,
+                link_target,
+                data,
+            }));
+        }
+    }
+}
+
+impl<'a> Iterator for TarArchive<'a> {
+    type Item = Result<Entry<'a>, TarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_entry() {
+            Ok(entry) => entry.map(Ok),
+            Err(err) => {
+                // stop iterating after the first error, the offsets can't be trusted anymore
+                self.offset = self.data.len();
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+fn verify_checksum(header: &[u8]) -> Result<(), TarError> {
+    let expected = parse_octal(&header[148..156])?;
+    // the checksum field itself is counted as if it were filled with spaces
+    let actual: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(idx, &b)| if (148..156).contains(&idx) { b' ' as u64 } else { b as u64 })
+        .sum();
+
+    match expected == actual {
+        true => Ok(()),
+        false => Err(TarError::BadChecksum),
+    }
+}
+
+/// Splits the path of a header into the ustar prefix and the name field.
+fn header_path(header: &[u8]) -> Result<(&str, &str), TarError> {
+    let name = parse_str(&header[0..100])?;
+    // only POSIX ustar has a prefix field, GNU uses the space for other things
+    if &header[257..263] == b"ustar\0" {
+        return Ok((parse_str(&header[345..500])?, name));
+    }
+    Ok(("", name))
+}
+
+/// Looks for the `path` record in a pax extended header.
+fn pax_path(data: &[u8]) -> Result<Option<&str>, TarError> {
+    let mut rest = data;
+    while !rest.is_empty() {
+        // each record is "<length> <key>=<value>\n", with length counting the whole record
+        let space = rest.iter().position(|&b| b == b' ').ok_or(TarError::BadHeader)?;
+        let len: usize = parse_str(&rest[..space])?
+            .parse()
+            .map_err(|_| TarError::BadHeader)?;
+        if len <= space + 1 || len > rest.len() {
+            return Err(TarError::BadHeader);
+        }
+
+        let record = parse_str(&rest[space + 1..len - 1])?;
+        if let Some(path) = record.strip_prefix("path=") {
+            return Ok(Some(path));
+        }
+        rest = &rest[len..];
+    }
+    Ok(None)
+}
+
+fn parse_str(field: &[u8]) -> Result<&str, TarError> {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..end]).map_err(|_| TarError::BadHeader)
+}
+
+fn parse_octal(field: &[u8]) -> Result<u64, TarError> {
+    // GNU tar stores numbers too large for the octal field as big endian binary with the
+    // high bit of the first byte set
+    if let Some(&first) = field.first() && first & 0x80 != 0 {
+        let value = field[1..]
+            .iter()
+            .fold((first & 0x7f) as u64, |acc, &b| (acc << 8) | b as u64);
+        return Ok(value);
+    }
+
+    let text = parse_str(field)?.trim_matches(|c| c == ' ' || c == '\0');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| TarError::BadHeader)
+}
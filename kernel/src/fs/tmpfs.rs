@@ -0,0 +1,350 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::time;
+
+/// A filesystem that keeps everything in kernel heap memory.
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+    pub fn new() -> TmpFs {
+        let next_inode = Arc::new(AtomicU64::new(1));
+        TmpFs {
+            root: TmpInode::new(&next_inode, TmpKind::Directory(BTreeMap::new()), 0o755),
+        }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+enum TmpKind {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<TmpInode>>),
+    Symlink(String),
+}
+
+struct TmpData {
+    kind: TmpKind,
+    mode: u32,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+}
+
+impl TmpData {
+    fn children(&mut self) -> Result<&mut BTreeMap<String, Arc<TmpInode>>, FsError> {
+        match &mut self.kind {
+            TmpKind::Directory(children) => Ok(children),
+            _ => Err(FsError::NotDirectory),
+        }
+    }
+
+    /// Marks the contents as modified.
+    fn touch(&mut self) {
+        let now = time::now();
+        self.mtime = now;
+        self.ctime = now;
+    }
+}
+
+struct TmpInode {
+    inode: u64,
+    // shared by every inode of the filesystem to hand out inode numbers
+    next_inode: Arc<AtomicU64>,
+    data: Mutex<TmpData>,
+}
+
+impl TmpInode {
+    fn new(next_inode: &Arc<AtomicU64>, kind: TmpKind, mode: u32) -> Arc<TmpInode> {
+        let now = time::now();
+        Arc::new(TmpInode {
+            inode: next_inode.fetch_add(1, Ordering::Relaxed),
+            next_inode: next_inode.clone(),
+            data: Mutex::new(TmpData {
+                kind,
+                mode,
+                atime: now,
+                mtime: now,
+                ctime: now,
+            }),
+        })
+    }
+
+    fn file_type(&self) -> FileType {
+        Self::kind_type(&self.data.lock().kind)
+    }
+
+    fn kind_type(kind: &TmpKind) -> FileType {
+        match kind {
+            TmpKind::File(_) => FileType::File,
+            TmpKind::Directory(_) => FileType::Directory,
+            TmpKind::Symlink(_) => FileType::Symlink,
+        }
+    }
+
+    fn is_empty_directory(&self) -> bool {
+        matches!(&self.data.lock().kind, TmpKind::Directory(children) if children.is_empty())
+    }
+
+    /// Adds a new child called `name`, failing if the name is taken.
+    fn insert(&self, name: &str, kind: TmpKind, mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        let mut data = self.data.lock();
+        let children = data.children()?;
+        if children.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let child = TmpInode::new(&self.next_inode, kind, mode);
+        children.insert(name.to_string(), child.clone());
+        data.touch();
+        Ok(child)
+    }
+
+    /// Removes the child `name` after checking it with `check`.
+    fn remove(&self, name: &str, check: impl FnOnce(&TmpInode) -> Result<(), FsError>) -> Result<(), FsError> {
+        let mut data = self.data.lock();
+        let children = data.children()?;
+        let child = children.get(name).ok_or(FsError::NotFound)?;
+        check(child)?;
+
+        children.remove(name);
+        data.touch();
+        Ok(())
+    }
+}
+
+/// Checks whether `source` may replace `target` in a rename.
+fn check_replace(source: &TmpInode, target: &TmpInode) -> Result<(), FsError> {
+    match (source.file_type(), target.file_type()) {
+        (FileType::Directory, FileType::Directory) if !target.is_empty_directory() => Err(FsError::NotEmpty),
+        (FileType::Directory, FileType::Directory) => Ok(()),
+        (FileType::Directory, _) => Err(FsError::NotDirectory),
+        (_, FileType::Directory) => Err(FsError::IsDirectory),
+        _ => Ok(()),
+    }
+}
+
+impl Inode for TmpInode {
+    fn metadata(&self) -> Metadata {
+        let data = self.data.lock();
+        let (size, links) = match &data.kind {
+            TmpKind::File(contents) => (contents.len() as u64, 1),
+            TmpKind::Symlink(target) => (target.len() as u64, 1),
+            TmpKind::Directory(children) => {
+                // one link from the parent, one from "." and one from each child's ".."
+                let subdirs = children
+                    .values()
+                    .filter(|child| child.file_type() == FileType::Directory)
+                    .count();
+                (children.len() as u64, 2 + subdirs as u32)
+            }
+        };
+
+        Metadata {
+            inode: self.inode,
+            file_type: Self::kind_type(&data.kind),
+            size,
+            mode: data.mode,
+            links,
+            atime: data.atime,
+            mtime: data.mtime,
+            ctime: data.ctime,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut data = self.data.lock();
+        let contents = match &data.kind {
+            TmpKind::File(contents) => contents,
+            TmpKind::Directory(_) => return Err(FsError::IsDirectory),
+            TmpKind::Symlink(_) => return Err(FsError::InvalidArgument),
+        };
+
+        let start = usize::min(offset.try_into().unwrap_or(usize::MAX), contents.len());
+        let len = usize::min(contents.len() - start, buf.len());
+        buf[..len].copy_from_slice(&contents[start..start + len]);
+        data.atime = time::now();
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut data = self.data.lock();
+        let contents = match &mut data.kind {
+            TmpKind::File(contents) => contents,
+            TmpKind::Directory(_) => return Err(FsError::IsDirectory),
+            TmpKind::Symlink(_) => return Err(FsError::InvalidArgument),
+        };
+
+        let start = usize::try_from(offset).map_err(|_| FsError::NoSpace)?;
+        let end = start.checked_add(buf.len()).ok_or(FsError::NoSpace)?;
+        if end > contents.len() {
+            // running out of heap shouldn't take the kernel down with it
+            contents
+                .try_reserve(end - contents.len())
+                .map_err(|_| FsError::NoSpace)?;
+            // writing past the end leaves a hole of zeros
+            contents.resize(end, 0);
+        }
+        contents[start..end].copy_from_slice(buf);
+        data.touch();
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut data = self.data.lock();
+        let contents = match &mut data.kind {
+            TmpKind::File(contents) => contents,
+            TmpKind::Directory(_) => return Err(FsError::IsDirectory),
+            TmpKind::Symlink(_) => return Err(FsError::InvalidArgument),
+        };
+
+        let size = usize::try_from(size).map_err(|_| FsError::NoSpace)?;
+        if size > contents.len() {
+            contents
+                .try_reserve(size - contents.len())
+                .map_err(|_| FsError::NoSpace)?;
+        }
+        contents.resize(size, 0);
+        contents.shrink_to_fit();
+        data.touch();
+        Ok(())
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        match &self.data.lock().kind {
+            TmpKind::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let mut data = self.data.lock();
+        let child = data.children()?.get(name).ok_or(FsError::NotFound)?;
+        Ok(child.clone())
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut data = self.data.lock();
+        let entries = data
+            .children()?
+            .iter()
+            .map(|(name, child)| DirEntry {
+                name: name.clone(),
+                inode: child.inode,
+                file_type: child.file_type(),
+            })
+            .collect();
+        data.atime = time::now();
+        Ok(entries)
+    }
+
+    fn create(&self, name: &str, file_type: FileType, mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        let kind = match file_type {
+            FileType::File => TmpKind::File(Vec::new()),
+            FileType::Directory => TmpKind::Directory(BTreeMap::new()),
+            _ => return Err(FsError::Unsupported),
+        };
+        self.insert(name, kind, mode)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.insert(name, TmpKind::Symlink(target.to_string()), 0o777)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.remove(name, |child| match child.file_type() {
+            FileType::Directory => Err(FsError::IsDirectory),
+            _ => Ok(()),
+        })
+    }
+
+    fn rmdir(&self, name: &str) -> Result<(), FsError> {
+        self.remove(name, |child| match child.file_type() {
+            FileType::Directory if child.is_empty_directory() => Ok(()),
+            FileType::Directory => Err(FsError::NotEmpty),
+            _ => Err(FsError::NotDirectory),
+        })
+    }
+
+    fn rename(&self, old_name: &str, new_parent: &Arc<dyn Inode>, new_name: &str) -> Result<(), FsError> {
+        let new_parent = new_parent
+            .as_any()
+            .downcast_ref::<TmpInode>()
+            .ok_or(FsError::CrossDevice)?;
+
+        if new_parent.inode == self.inode {
+            let mut data = self.data.lock();
+            let children = data.children()?;
+            let source = children.get(old_name).ok_or(FsError::NotFound)?.clone();
+            if old_name == new_name {
+                return Ok(());
+            }
+            if let Some(target) = children.get(new_name) {
+                check_replace(&source, target)?;
+            }
+
+            children.remove(old_name);
+            children.insert(new_name.to_string(), source);
+            data.touch();
+            return Ok(());
+        }
+
+        // always lock the lower inode number first so two renames in opposite directions
+        // can't deadlock
+        let (mut old_data, mut new_data) = match self.inode < new_parent.inode {
+            true => {
+                let old_data = self.data.lock();
+                (old_data, new_parent.data.lock())
+            }
+            false => {
+                let new_data = new_parent.data.lock();
+                (self.data.lock(), new_data)
+            }
+        };
+
+        // both parents are locked from here on, so neither may be looked at through its own lock
+        let source = old_data.children()?.get(old_name).ok_or(FsError::NotFound)?.clone();
+        if source.inode == new_parent.inode {
+            // a directory can't move into itself
+            return Err(FsError::InvalidArgument);
+        }
+        let new_children = new_data.children()?;
+        if let Some(target) = new_children.get(new_name) {
+            if target.inode == self.inode {
+                // the old parent holds the source, so it's never empty
+                return Err(match source.file_type() {
+                    FileType::Directory => FsError::NotEmpty,
+                    _ => FsError::IsDirectory,
+                });
+            }
+            check_replace(&source, target)?;
+        }
+
+        new_children.insert(new_name.to_string(), source);
+        old_data.children()?.remove(old_name);
+        old_data.touch();
+        new_data.touch();
+        Ok(())
+    }
+}
@@ -0,0 +1,424 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use super::file::{File, OpenFlags};
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+
+/// How many symlinks may be followed while resolving a single path.
+const MAX_SYMLINKS: usize = 40;
+
+/// The root directory, before following whatever is mounted on top of it.
+static ROOT: Mutex<Option<Arc<Dentry>>> = Mutex::new(None);
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+static NEXT_MOUNT_ID: AtomicUsize = AtomicUsize::new(0);
+
+struct Mount {
+    id: usize,
+    fs: Arc<dyn FileSystem>,
+    root: Arc<Dentry>,
+    // the dentry this filesystem is mounted on top of, none for the first root filesystem
+    mountpoint: Option<Arc<Dentry>>,
+}
+
+/// Describes one entry of the mount table.
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    pub path: String,
+    pub fs_name: &'static str,
+}
+
+/// A name in the directory tree, caching the inode it resolves to.
+///
+/// The root of a mounted filesystem takes over the name and parent of its mount point, so
+/// walking up with `..` leaves the filesystem the same way it was entered.
+pub struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+    parent: Option<Arc<Dentry>>,
+    // id of the mount this dentry belongs to
+    mount_id: usize,
+    children: Mutex<BTreeMap<String, Arc<Dentry>>>,
+    // root of the filesystem mounted on top of this dentry
+    mounted: Mutex<Option<Arc<Dentry>>>,
+}
+
+impl Dentry {
+    fn new(name: String, inode: Arc<dyn Inode>, parent: Option<Arc<Dentry>>, mount_id: usize) -> Arc<Dentry> {
+        Arc::new(Dentry {
+            name,
+            inode,
+            parent,
+            mount_id,
+            children: Mutex::new(BTreeMap::new()),
+            mounted: Mutex::new(None),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.inode.metadata()
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.inode.metadata().file_type
+    }
+
+    /// The absolute path of this dentry.
+    pub fn path(&self) -> String {
+        let mut components = Vec::new();
+        let mut current = self;
+        while let Some(parent) = &current.parent {
+            components.push(current.name.as_str());
+            current = parent;
+        }
+
+        if components.is_empty() {
+            return String::from("/");
+        }
+        components
+            .iter()
+            .rev()
+            .fold(String::new(), |path, component| path + "/" + component)
+    }
+
+    /// Looks up `name` in this directory without following mounts on the result.
+    fn lookup_child(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>, FsError> {
+        if self.file_type() != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        if let Some(child) = self.children.lock().get(name) {
+            return Ok(child.clone());
+        }
+
+        let inode = self.inode.lookup(name)?;
+        let child = Dentry::new(name.to_string(), inode, Some(self.clone()), self.mount_id);
+        self.children.lock().insert(name.to_string(), child.clone());
+        Ok(child)
+    }
+
+    /// Drops `name` from the cache after it was removed or renamed.
+    fn forget_child(&self, name: &str) {
+        self.children.lock().remove(name);
+    }
+
+    fn is_mountpoint(&self) -> bool {
+        self.mounted.lock().is_some()
+    }
+}
+
+/// Returns the root of whatever is mounted on top of `dentry`, or `dentry` itself.
+fn follow_mounts(mut dentry: Arc<Dentry>) -> Arc<Dentry> {
+    loop {
+        let mounted = dentry.mounted.lock().clone();
+        match mounted {
+            Some(root) => dentry = root,
+            None => return dentry,
+        }
+    }
+}
+
+/// The root directory.
+pub fn root() -> Result<Arc<Dentry>, FsError> {
+    let root = ROOT.lock().clone().ok_or(FsError::NotFound)?;
+    Ok(follow_mounts(root))
+}
+
+/// Splits a path into its components, dropping empty and `.` components.
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+}
+
+/// Resolves `path` relative to `base`, or the root if it's absolute. Symlinks in the middle of
+/// the path are always followed, a symlink at the end only if `follow_last` is set.
+pub fn lookup_at(base: &Arc<Dentry>, path: &str, follow_last: bool) -> Result<Arc<Dentry>, FsError> {
+    let mut current = match path.starts_with('/') {
+        true => root()?,
+        false => base.clone(),
+    };
+    let mut pending: VecDeque<String> = components(path).map(String::from).collect();
+    let mut links = 0;
+
+    while let Some(component) = pending.pop_front() {
+        if current.file_type() != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+
+        let next = match component.as_str() {
+            ".." => current.parent.clone().unwrap_or_else(|| current.clone()),
+            name => current.lookup_child(name)?,
+        };
+        let next = follow_mounts(next);
+
+        if next.file_type() == FileType::Symlink && (!pending.is_empty() || follow_last) {
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(FsError::TooManyLinks);
+            }
+
+            let target = next.inode.read_link()?;
+            if target.starts_with('/') {
+                current = root()?;
+            }
+            for component in components(&target).rev() {
+                pending.push_front(String::from(component));
+            }
+            continue;
+        }
+
+        current = next;
+    }
+
+    Ok(current)
+}
+
+/// Resolves an absolute path, following a symlink at the end.
+pub fn lookup(path: &str) -> Result<Arc<Dentry>, FsError> {
+    lookup_at(&root()?, path, true)
+}
+
+/// Resolves everything but the last component of `path`, returning the parent directory and
+/// the last component's name.
+fn lookup_parent(path: &str) -> Result<(Arc<Dentry>, String), FsError> {
+    let (parent_path, name) = match path.trim_end_matches('/').rsplit_once('/') {
+        Some((parent_path, name)) => (parent_path, name),
+        None => (".", path.trim_end_matches('/')),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidArgument);
+    }
+
+    let parent_path = match parent_path.is_empty() {
+        true => "/",
+        false => parent_path,
+    };
+    let parent = lookup_at(&root()?, parent_path, true)?;
+    if parent.file_type() != FileType::Directory {
+        return Err(FsError::NotDirectory);
+    }
+    Ok((parent, name.to_string()))
+}
+
+/// Mounts `fs` at `path`. The first filesystem mounted at `/` becomes the root, anything mounted
+/// afterwards goes on top of an existing directory, including `/`.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let id = NEXT_MOUNT_ID.fetch_add(1, Ordering::Relaxed);
+
+    let mut root_lock = ROOT.lock();
+    if root_lock.is_none() {
+        if components(path).next().is_some() {
+            return Err(FsError::NotFound);
+        }
+        let root = Dentry::new(String::from("/"), fs.root(), None, id);
+        *root_lock = Some(root.clone());
+        drop(root_lock);
+        MOUNTS.lock().push(Mount {
+            id,
+            fs,
+            root,
+            mountpoint: None,
+        });
+        return Ok(());
+    }
+    drop(root_lock);
+
+    let mountpoint = lookup(path)?;
+    if mountpoint.file_type() != FileType::Directory {
+        return Err(FsError::NotDirectory);
+    }
+
+    let root = Dentry::new(mountpoint.name.clone(), fs.root(), mountpoint.parent.clone(), id);
+    *mountpoint.mounted.lock() = Some(root.clone());
+    MOUNTS.lock().push(Mount {
+        id,
+        fs,
+        root,
+        mountpoint: Some(mountpoint),
+    });
+    Ok(())
+}
+
+/// Unmounts the filesystem mounted at `path` after syncing it.
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let root = lookup(path)?;
+
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| Arc::ptr_eq(&mount.root, &root))
+        .ok_or(FsError::InvalidArgument)?;
+    let id = mounts[index].id;
+    let nested = mounts.iter().any(|mount| match &mount.mountpoint {
+        Some(mountpoint) => mountpoint.mount_id == id,
+        None => false,
+    });
+    // the root filesystem stays, and so does anything with other filesystems on top of it
+    if mounts[index].mountpoint.is_none() || nested {
+        return Err(FsError::Busy);
+    }
+
+    let mount = mounts.remove(index);
+    drop(mounts);
+    mount.fs.sync()?;
+    if let Some(mountpoint) = mount.mountpoint {
+        *mountpoint.mounted.lock() = None;
+    }
+    Ok(())
+}
+
+/// Lists the mount table in the order filesystems were mounted.
+pub fn mounts() -> Vec<MountInfo> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| MountInfo {
+            path: mount.root.path(),
+            fs_name: mount.fs.name(),
+        })
+        .collect()
+}
+
+/// Writes back every mounted filesystem.
+pub fn sync() -> Result<(), FsError> {
+    let filesystems: Vec<Arc<dyn FileSystem>> =
+        MOUNTS.lock().iter().map(|mount| mount.fs.clone()).collect();
+    filesystems.iter().try_for_each(|fs| fs.sync())
+}
+
+/// Opens the file at `path`. With `OpenFlags::CREATE` a regular file with permissions `mode`
+/// is created if nothing exists there yet.
+pub fn open(path: &str, flags: OpenFlags, mode: u32) -> Result<Arc<File>, FsError> {
+    let follow = !flags.contains(OpenFlags::NO_FOLLOW);
+    let dentry = match lookup_at(&root()?, path, follow) {
+        Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+            return Err(FsError::AlreadyExists);
+        }
+        Ok(dentry) => dentry,
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (parent, name) = lookup_parent(path)?;
+            parent.inode.create(&name, FileType::File, mode)?;
+            parent.lookup_child(&name)?
+        }
+        Err(err) => return Err(err),
+    };
+
+    match dentry.file_type() {
+        FileType::Directory if flags.intersects(OpenFlags::WRITE | OpenFlags::TRUNCATE) => {
+            return Err(FsError::IsDirectory);
+        }
+        FileType::Directory => {}
+        _ if flags.contains(OpenFlags::DIRECTORY) => return Err(FsError::NotDirectory),
+        FileType::File if flags.contains(OpenFlags::TRUNCATE | OpenFlags::WRITE) => {
+            dentry.inode.truncate(0)?;
+        }
+        _ => {}
+    }
+
+    Ok(Arc::new(File::new(dentry, flags)))
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, FsError> {
+    Ok(lookup(path)?.metadata())
+}
+
+/// Like `metadata`, but describes a symlink at the end of the path rather than its target.
+pub fn symlink_metadata(path: &str) -> Result<Metadata, FsError> {
+    Ok(lookup_at(&root()?, path, false)?.metadata())
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let dentry = lookup(path)?;
+    if dentry.file_type() != FileType::Directory {
+        return Err(FsError::NotDirectory);
+    }
+    dentry.inode.read_dir()
+}
+
+pub fn read_link(path: &str) -> Result<String, FsError> {
+    lookup_at(&root()?, path, false)?.inode.read_link()
+}
+
+pub fn create_dir(path: &str, mode: u32) -> Result<(), FsError> {
+    let (parent, name) = lookup_parent(path)?;
+    parent.inode.create(&name, FileType::Directory, mode)?;
+    Ok(())
+}
+
+/// Creates a symlink at `path` pointing to `target`.
+pub fn symlink(target: &str, path: &str) -> Result<(), FsError> {
+    let (parent, name) = lookup_parent(path)?;
+    parent.inode.symlink(&name, target)?;
+    Ok(())
+}
+
+/// Removes the empty directory at `path`.
+pub fn remove_dir(path: &str) -> Result<(), FsError> {
+    let (parent, name) = lookup_parent(path)?;
+    let child = parent.lookup_child(&name)?;
+    if child.is_mountpoint() {
+        return Err(FsError::Busy);
+    }
+    if child.file_type() != FileType::Directory {
+        return Err(FsError::NotDirectory);
+    }
+
+    parent.inode.rmdir(&name)?;
+    parent.forget_child(&name);
+    Ok(())
+}
+
+/// Removes the file, symlink or device node at `path`.
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    let (parent, name) = lookup_parent(path)?;
+    let child = parent.lookup_child(&name)?;
+    if child.file_type() == FileType::Directory {
+        return Err(FsError::IsDirectory);
+    }
+
+    parent.inode.unlink(&name)?;
+    parent.forget_child(&name);
+    Ok(())
+}
+
+/// Moves `old_path` to `new_path`, replacing whatever was there if the types are compatible.
+/// Both paths have to be on the same filesystem.
+pub fn rename(old_path: &str, new_path: &str) -> Result<(), FsError> {
+    let (old_parent, old_name) = lookup_parent(old_path)?;
+    let (new_parent, new_name) = lookup_parent(new_path)?;
+    if old_parent.mount_id != new_parent.mount_id {
+        return Err(FsError::CrossDevice);
+    }
+
+    let source = old_parent.lookup_child(&old_name)?;
+    if source.is_mountpoint() {
+        return Err(FsError::Busy);
+    }
+    if let Ok(target) = new_parent.lookup_child(&new_name) && target.is_mountpoint() {
+        return Err(FsError::Busy);
+    }
+    // a directory can't be moved into itself
+    let mut ancestor = Some(new_parent.clone());
+    while let Some(dentry) = ancestor {
+        if Arc::ptr_eq(&dentry, &source) {
+            return Err(FsError::InvalidArgument);
+        }
+        ancestor = dentry.parent.clone();
+    }
+
+    old_parent.inode.rename(&old_name, &new_parent.inode, &new_name)?;
+    old_parent.forget_child(&old_name);
+    new_parent.forget_child(&new_name);
+    Ok(())
+}
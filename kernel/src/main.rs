@@ -5,26 +5,44 @@
 #![feature(once_cell)]
 #![feature(panic_info_message)]
 
+extern crate alloc;
+
+mod acpi;
+mod ahci;
 mod arch;
+mod block;
+mod device;
 mod display;
+mod fs;
+mod memory;
+mod nvme;
+mod pci;
 mod serial;
+mod syscall;
+mod time;
+mod virtio;
 
+use alloc::format;
+use alloc::string::String;
 use core::fmt::Write;
 use core::panic::PanicInfo;
 
 use uart_16550::SerialPort;
+use bootloader_api::config::{BootloaderConfig, Mapping};
 use bootloader_api::{entry_point, info::Optional, BootInfo};
 
 use serial::DEBUG_SERIAL;
-use display::TEXT_DISPLAY;
-use display::{Color, TextDisplay};
+use display::{CONSOLES, LOG_CONSOLE};
+use display::{Color, VirtualConsoles};
 
 /// This function is called on panic.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    if let Some(mut lock) = TEXT_DISPLAY.try_lock() {
-        if let Some(text_display) = lock.get_mut() {
+    if let Some(mut lock) = CONSOLES.try_lock() {
+        if let Some(consoles) = lock.get_mut() {
             if let Some(args) = info.message() {
+                let text_display = consoles.active_console();
+                text_display.show();
                 text_display.set_clear_color(Color(0, 0, 0));
                 text_display.set_text_color(Color(255, 0, 0));
                 write!(text_display, "{}", args).unwrap();
@@ -43,16 +61,34 @@ fn panic(info: &PanicInfo) -> ! {
     loop {}
 }
 
-entry_point!(kernel_main);
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    // the memory manager needs to be able to reach any physical frame
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    // keep everything the bootloader maps in the upper half, below the kernel heap, so that the
+    // lower half is free for user address spaces
+    config.mappings.dynamic_range_start = Some(0xffff_8000_0000_0000);
+    config.mappings.dynamic_range_end = Some(memory::heap::HEAP_START - 1);
+    config
+};
+
+/// The steps of `kernel_main` that run on every boot once the architecture is up: what's logged
+/// before each, and the step, which gives what's logged once it's done.
+const PCI_STEPS: &[(&str, fn() -> String)] = &[
+    ("Enumerating PCI devices", enumerate_pci),
+    ("Binding PCI drivers", bind_pci_drivers),
+];
+
+/// How often the filesystems are synced once booted, so that writes make it to disk.
+const SYNC_INTERVAL_MS: u64 = 5000;
+
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     let fb = match &mut boot_info.framebuffer {
         Optional::Some(fb) => fb,
         Optional::None => panic!(),
     };
 
-    TEXT_DISPLAY
-        .lock()
-        .get_or_init(|| TextDisplay::new(fb, Color(0, 0, 0), Color(255, 255, 0)));
     DEBUG_SERIAL
         .lock()
         .get_or_init(|| {
@@ -61,22 +97,130 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
             serial
         });
 
-    clearscrn!();
     log("Booting into BeeOS");
 
+    // the console keeps its text on the heap, so until memory is up logs only go to serial
+    log("Initializing memory");
+    let physical_memory_offset = match boot_info.physical_memory_offset {
+        Optional::Some(offset) => offset,
+        Optional::None => panic!("Bootloader did not map physical memory"),
+    };
+    memory::init(physical_memory_offset, &boot_info.memory_regions);
+    log("Memory initialized");
+    #[cfg(feature = "vm-check")]
+    {
+        memory::check_protect_none().expect("Failed to check mprotect(PROT_NONE)");
+        log("mprotect(PROT_NONE) checked");
+    }
+
+    // the log console starts out on the screen, so booting can be watched
+    CONSOLES
+        .lock()
+        .get_or_init(|| VirtualConsoles::new(fb, Color(0, 0, 0), Color(255, 255, 0)));
+    log("Consoles initialized");
+    let buffered = CONSOLES
+        .lock()
+        .get_mut()
+        .map(|consoles| consoles.console(LOG_CONSOLE).set_double_buffered(true));
+    if let Some(Err(err)) = buffered {
+        log(&format!("Console drawing straight to the framebuffer, no memory for a back buffer: {:?}", err));
+    }
+    // the splash counts on every `step` still to come, some only happen if the bootloader found
+    // what they need
+    let found = [
+        matches!(boot_info.rsdp_addr, Optional::Some(_)),
+        matches!(boot_info.ramdisk_addr, Optional::Some(_)),
+    ];
+    let steps = found.iter().filter(|&&found| found).count() + arch::INIT_STEP_COUNT + PCI_STEPS.len();
+    if let Err(err) = display::splash::show(steps) {
+        log(&format!("Failed to show the boot splash: {:?}", err));
+    }
+
+    if let Optional::Some(rsdp_addr) = boot_info.rsdp_addr {
+        log("Reading ACPI tables");
+        match acpi::init(rsdp_addr) {
+            Ok(count) => step(&format!("ACPI tables read, {} tables", count)),
+            Err(err) => step(&format!("Failed to read ACPI tables: {:?}", err)),
+        }
+    }
+
+    if let Optional::Some(ramdisk_addr) = boot_info.ramdisk_addr {
+        log("Loading ramdisk");
+        let image = unsafe {
+            core::slice::from_raw_parts(ramdisk_addr as *const u8, boot_info.ramdisk_len as usize)
+        };
+        match fs::ramdisk::init(image) {
+            Ok(ramdisk) => step(&format!("Ramdisk loaded, {} nodes", ramdisk.node_count())),
+            Err(err) => step(&format!("Failed to load ramdisk: {:?}", err)),
+        }
+    }
+
     arch::init();
     log("x86_64 initialized");
 
-    loop {}
+    #[cfg(feature = "console-bench")]
+    {
+        use x86_64::instructions::interrupts;
+
+        // the console has to be on screen to be worth timing
+        display::splash::finish();
+        let switch = |index| interrupts::without_interrupts(|| {
+            CONSOLES.lock().get_mut().map(|consoles| consoles.switch(index))
+        });
+        log("Benchmarking console");
+        switch(display::PRINT_CONSOLE);
+        let rate = display::benchmark();
+        let fills = display::benchmark_fill();
+        switch(LOG_CONSOLE);
+        log(&format!("Console benchmarked, {} characters and {} full screen fills per second", rate, fills));
+    }
+
+    for (before, init) in PCI_STEPS {
+        log(before);
+        step(&init());
+    }
+
+    log("Mounting filesystems");
+    match fs::init() {
+        Ok(()) => log("Filesystems mounted"),
+        Err(err) => log(&format!("Failed to mount filesystems: {:?}", err)),
+    }
+    display::splash::finish();
+
+    // nothing else runs once booted, so writing back what the disk caches hold is up to this
+    let mut synced = time::uptime_ms();
+    loop {
+        x86_64::instructions::hlt();
+        if time::uptime_ms() - synced >= SYNC_INTERVAL_MS {
+            if let Err(err) = fs::vfs::sync() {
+                log(&format!("Failed to sync filesystems: {:?}", err));
+            }
+            synced = time::uptime_ms();
+        }
+    }
+}
+
+fn enumerate_pci() -> String {
+    let count = pci::init();
+    let access = if pci::config::is_ecam() { "ECAM" } else { "port I/O" };
+    format!("PCI enumerated through {}, {} functions", access, count)
+}
+
+fn bind_pci_drivers() -> String {
+    pci::register_driver(&ahci::DRIVER);
+    pci::register_driver(&nvme::DRIVER);
+    pci::register_driver(&virtio::bus::PCI_DRIVER);
+    virtio::bus::register_driver(&virtio::block::DRIVER);
+    String::from("PCI drivers bound")
 }
 
 fn log(message: &str) {
     use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
-        if let Some(mut lock) = TEXT_DISPLAY.try_lock() {
-            if let Some(text_display) = lock.get_mut() {
-                write!(text_display, "{}\n", message).unwrap();
+        if let Some(mut lock) = CONSOLES.try_lock() {
+            if let Some(consoles) = lock.get_mut() {
+                write!(consoles.console(LOG_CONSOLE), "{}\n", message).unwrap();
             }
         }
     });
@@ -85,4 +229,10 @@ fn log(message: &str) {
             let _ = debug_serial.write_fmt(format_args!("{}\n", message));
         }
     }
-}
\ No newline at end of file
+}
+
+/// Logs that a step of booting finished, moving the boot splash's progress bar along.
+fn step(message: &str) {
+    log(message);
+    display::splash::advance();
+}
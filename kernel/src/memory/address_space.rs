@@ -0,0 +1,408 @@
+use alloc::collections::BTreeMap;
+
+use bitflags::bitflags;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, UnmapError};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+use super::frame::checked_align_up;
+use super::PAGE_SIZE;
+
+/// Lowest address a VMA may start at, the first 4 MiB are left unmapped to catch null pointers.
+pub const USER_START: u64 = 0x0000_0000_0040_0000;
+/// One past the highest address a VMA may end at, the end of the lower half.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+/// Where searching for a free range starts when `map_anonymous` isn't given a hint.
+pub const MMAP_BASE: u64 = 0x0000_1000_0000_0000;
+
+bitflags! {
+    /// Access rights of a virtual memory area.
+    pub struct Protection: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+    }
+}
+
+/// What backs the pages of a virtual memory area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    /// Zero filled memory that gets a frame on first access.
+    Anonymous,
+}
+
+/// A virtual memory area, a page aligned range of an address space with uniform
+/// protection and backing.
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub protection: Protection,
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        self.start <= address && address < self.end
+    }
+
+    /// The page table flags used for pages of this area once they are faulted in.
+    ///
+    /// Pages of an area without any access keep their frames but aren't present, so that every
+    /// access faults and the contents are still there once access is allowed again.
+    fn page_flags(&self) -> PageTableFlags {
+        if self.protection.is_empty() {
+            return PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+        }
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if self.protection.contains(Protection::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.protection.contains(Protection::EXEC) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    /// The range is empty, unaligned or outside of user space.
+    InvalidRange,
+    /// There is no free range large enough for the request.
+    NoSpace,
+    /// No physical frame could be allocated.
+    OutOfMemory,
+    /// The address isn't covered by any VMA.
+    NotMapped,
+    /// The VMA covering the address doesn't permit the attempted access.
+    AccessViolation,
+    /// The fault happened while the memory manager was itself in use, so it can't be handled.
+    Reentrant,
+}
+
+/// A set of page tables together with the VMAs describing which parts of it may be
+/// populated on demand.
+pub struct AddressSpace {
+    page_table: OffsetPageTable<'static>,
+    root: PhysFrame,
+    // keyed by the start address of each VMA, VMAs never overlap
+    vmas: BTreeMap<u64, Vma>,
+    physical_memory_offset: VirtAddr,
+}
+
+impl AddressSpace {
+    /// Wraps the page tables currently loaded in CR3.
+    ///
+    /// This function is unsafe because the caller must guarantee that all physical memory is
+    /// mapped at `physical_memory_offset` and that it's only called once for the active tables.
+    pub unsafe fn active(physical_memory_offset: VirtAddr) -> AddressSpace {
+        let (root, _) = Cr3::read();
+        AddressSpace::from_root(root, physical_memory_offset)
+    }
+
+    /// Creates an empty address space sharing the kernel half of `kernel`.
+    pub fn new(
+        kernel: &mut AddressSpace,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<AddressSpace, VmError> {
+        let root = frame_allocator
+            .allocate_frame()
+            .ok_or(VmError::OutOfMemory)?;
+        let physical_memory_offset = kernel.physical_memory_offset;
+        let table_ptr = (physical_memory_offset + root.start_address().as_u64())
+            .as_mut_ptr::<PageTable>();
+
+        unsafe {
+            table_ptr.write(PageTable::new());
+            let table = &mut *table_ptr;
+            let kernel_table = kernel.page_table.level_4_table();
+            for index in 256..512 {
+                table[index] = kernel_table[index].clone();
+            }
+            Ok(AddressSpace::from_root(root, physical_memory_offset))
+        }
+    }
+
+    unsafe fn from_root(root: PhysFrame, physical_memory_offset: VirtAddr) -> AddressSpace {
+        let table_ptr = (physical_memory_offset + root.start_address().as_u64())
+            .as_mut_ptr::<PageTable>();
+
+        AddressSpace {
+            page_table: OffsetPageTable::new(&mut *table_ptr, physical_memory_offset),
+            root,
+            vmas: BTreeMap::new(),
+            physical_memory_offset,
+        }
+    }
+
+    /// Loads this address space's page tables into CR3.
+    ///
+    /// This function is unsafe because the kernel half of the address space must be
+    /// mapped exactly like in the address space currently active.
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.root, Cr3Flags::empty());
+    }
+
+    pub fn mapper(&mut self) -> &mut OffsetPageTable<'static> {
+        &mut self.page_table
+    }
+
+    pub fn vmas(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.values()
+    }
+
+    /// Returns the VMA containing `address`, if any.
+    pub fn find_vma(&self, address: VirtAddr) -> Option<&Vma> {
+        self.vmas
+            .range(..=address.as_u64())
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(address))
+    }
+
+    /// Reserves a range of `len` bytes of zero filled memory. No frames are allocated until the
+    /// memory is touched.
+    ///
+    /// With `fixed` set the range starts exactly at `hint`, replacing whatever was mapped there
+    /// before. Otherwise `hint` is only a suggestion and the first free range at or above it
+    /// is used.
+    pub fn map_anonymous(
+        &mut self,
+        hint: Option<VirtAddr>,
+        len: u64,
+        protection: Protection,
+        fixed: bool,
+        frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) -> Result<VirtAddr, VmError> {
+        if len == 0 {
+            return Err(VmError::InvalidRange);
+        }
+        let len = checked_align_up(len, PAGE_SIZE).ok_or(VmError::InvalidRange)?;
+
+        let start = if fixed {
+            let start = hint.ok_or(VmError::InvalidRange)?;
+            Self::check_range(start, len)?;
+            self.unmap(start, len, frame_allocator)?;
+            start
+        } else {
+            let hint = hint
+                .and_then(|hint| checked_align_up(hint.as_u64(), PAGE_SIZE))
+                .and_then(|hint| VirtAddr::try_new(hint).ok())
+                .filter(|hint| Self::check_range(*hint, len).is_ok())
+                .unwrap_or(VirtAddr::new(MMAP_BASE));
+            self.find_free_range(hint, len)?
+        };
+
+        self.vmas.insert(
+            start.as_u64(),
+            Vma {
+                start,
+                end: start + len,
+                protection,
+                kind: VmaKind::Anonymous,
+            },
+        );
+
+        Ok(start)
+    }
+
+    /// Removes every VMA in the given range, splitting the ones that only partially overlap it,
+    /// and frees the frames that were faulted in.
+    pub fn unmap(
+        &mut self,
+        start: VirtAddr,
+        len: u64,
+        frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) -> Result<(), VmError> {
+        let len = checked_align_up(len, PAGE_SIZE).ok_or(VmError::InvalidRange)?;
+        Self::check_range(start, len)?;
+        let end = start + len;
+
+        self.split_at(start);
+        self.split_at(end);
+        let removed: alloc::vec::Vec<u64> = self
+            .vmas
+            .range(start.as_u64()..end.as_u64())
+            .map(|(&key, _)| key)
+            .collect();
+
+        for key in removed {
+            let vma = self.vmas.remove(&key).unwrap();
+            for page in Self::pages(vma.start, vma.end) {
+                // a page that isn't present counts as unmapped, its frame would be leaked
+                if vma.protection.is_empty() {
+                    let _ = unsafe { self.page_table.update_flags(page, PageTableFlags::PRESENT) };
+                }
+                match self.page_table.unmap(page) {
+                    Ok((frame, flush)) => {
+                        flush.flush();
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                    }
+                    Err(UnmapError::PageNotMapped) => {}
+                    Err(err) => panic!("Failed to unmap {:?}: {:?}", page, err),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Changes the protection of every page in the given range. The whole range must be
+    /// covered by VMAs.
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        len: u64,
+        protection: Protection,
+    ) -> Result<(), VmError> {
+        let len = checked_align_up(len, PAGE_SIZE).ok_or(VmError::InvalidRange)?;
+        Self::check_range(start, len)?;
+        let end = start + len;
+
+        // make sure there are no holes before modifying anything
+        let mut covered = start;
+        while covered < end {
+            covered = self.find_vma(covered).ok_or(VmError::NotMapped)?.end;
+        }
+
+        self.split_at(start);
+        self.split_at(end);
+        for (_, vma) in self.vmas.range_mut(start.as_u64()..end.as_u64()) {
+            vma.protection = protection;
+            let flags = vma.page_flags();
+            for page in Self::pages(vma.start, vma.end) {
+                match unsafe { self.page_table.update_flags(page, flags) } {
+                    Ok(flush) => flush.flush(),
+                    Err(FlagUpdateError::PageNotMapped) => {}
+                    Err(err) => panic!("Failed to update flags of {:?}: {:?}", page, err),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolves a page fault at `address` by giving the page a zeroed frame, if a VMA covers it
+    /// and permits the access.
+    pub fn handle_page_fault(
+        &mut self,
+        address: VirtAddr,
+        error_code: PageFaultErrorCode,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<(), VmError> {
+        let vma = *self.find_vma(address).ok_or(VmError::NotMapped)?;
+
+        // the page is present, so this is a genuine protection violation and not a lazy mapping
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            return Err(VmError::AccessViolation);
+        }
+        if vma.protection.is_empty()
+            || (error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+                && !vma.protection.contains(Protection::WRITE))
+            || (error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+                && !vma.protection.contains(Protection::EXEC))
+        {
+            return Err(VmError::AccessViolation);
+        }
+
+        let page = Page::<Size4KiB>::containing_address(address);
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(VmError::OutOfMemory)?;
+        unsafe {
+            let frame_ptr = (self.physical_memory_offset + frame.start_address().as_u64())
+                .as_mut_ptr::<u8>();
+            core::ptr::write_bytes(frame_ptr, 0, PAGE_SIZE as usize);
+        }
+
+        let table_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE;
+        let result = unsafe {
+            self.page_table.map_to_with_table_flags(
+                page,
+                frame,
+                vma.page_flags(),
+                table_flags,
+                frame_allocator,
+            )
+        };
+        match result {
+            Ok(flush) => flush.flush(),
+            // someone beat us to it, the access can simply be retried
+            Err(MapToError::PageAlreadyMapped(_)) => unsafe {
+                frame_allocator.deallocate_frame(frame)
+            },
+            Err(MapToError::FrameAllocationFailed) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return Err(VmError::OutOfMemory);
+            }
+            Err(err) => panic!("Failed to map {:?}: {:?}", page, err),
+        }
+
+        Ok(())
+    }
+
+    fn check_range(start: VirtAddr, len: u64) -> Result<(), VmError> {
+        if len == 0 || !start.is_aligned(PAGE_SIZE) || len % PAGE_SIZE != 0 {
+            return Err(VmError::InvalidRange);
+        }
+        match start.as_u64().checked_add(len) {
+            Some(end) if start.as_u64() >= USER_START && end <= USER_END => Ok(()),
+            _ => Err(VmError::InvalidRange),
+        }
+    }
+
+    /// Finds the lowest free range of `len` bytes at or above `hint`.
+    fn find_free_range(&self, hint: VirtAddr, len: u64) -> Result<VirtAddr, VmError> {
+        let mut candidate = hint.as_u64();
+        // the VMA right before the hint may still reach past it
+        if let Some(vma) = self.find_vma(hint) {
+            candidate = vma.end.as_u64();
+        }
+
+        // the length comes from user space, so the end can be past the end of the address space
+        for vma in self.vmas.range(candidate..).map(|(_, vma)| vma) {
+            let end = candidate.checked_add(len).ok_or(VmError::NoSpace)?;
+            if vma.start.as_u64() >= end {
+                break;
+            }
+            candidate = vma.end.as_u64();
+        }
+
+        match candidate.checked_add(len) {
+            Some(end) if end <= USER_END => Ok(VirtAddr::new(candidate)),
+            _ => Err(VmError::NoSpace),
+        }
+    }
+
+    /// Splits the VMA containing `address` in two so that one ends and the other starts there.
+    fn split_at(&mut self, address: VirtAddr) {
+        let vma = match self.find_vma(address) {
+            Some(vma) if vma.start != address => *vma,
+            _ => return,
+        };
+
+        self.vmas.get_mut(&vma.start.as_u64()).unwrap().end = address;
+        self.vmas.insert(
+            address.as_u64(),
+            Vma {
+                start: address,
+                ..vma
+            },
+        );
+    }
+
+    fn pages(start: VirtAddr, end: VirtAddr) -> impl Iterator<Item = Page<Size4KiB>> {
+        Page::range(
+            Page::containing_address(start),
+            Page::containing_address(end),
+        )
+    }
+}
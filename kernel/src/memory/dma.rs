@@ -0,0 +1,271 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::structures::paging::mapper::Translate;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{phys_to_virt, ADDRESS_SPACE, FRAME_ALLOCATOR, PAGE_SIZE};
+
+/// Everything a device is handed to access, by id, so leaks can be found.
+static MAPPINGS: Mutex<BTreeMap<u64, Mapping>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    OutOfMemory,
+    /// The constraints contradict each other or the request, like a buffer bigger than the
+    /// boundary it mustn't cross.
+    BadConstraints,
+    /// The memory doesn't start at an address the device can use.
+    Misaligned,
+    /// The memory is above the highest address the device can reach.
+    Unreachable,
+    /// Part of a buffer isn't backed by memory.
+    NotMapped,
+}
+
+/// What a device can access. Memory is cache coherent with devices on x86, so normal write-back
+/// memory works and there's nothing to flush.
+#[derive(Debug, Clone, Copy)]
+pub struct DmaConstraints {
+    /// Alignment of the start of the memory, a power of two.
+    pub alignment: u64,
+    /// A power of two the memory mustn't cross a multiple of, 0 if there's none.
+    pub boundary: u64,
+    /// Highest physical address the device can reach, `u32::MAX` for 32 bit devices.
+    pub max_address: u64,
+    /// Largest piece a scatter gather list can have.
+    pub max_segment: u64,
+}
+
+impl Default for DmaConstraints {
+    fn default() -> DmaConstraints {
+        DmaConstraints {
+            alignment: 1,
+            boundary: 0,
+            max_address: u64::MAX,
+            max_segment: u64::MAX,
+        }
+    }
+}
+
+impl DmaConstraints {
+    fn check(&self) -> Result<(), DmaError> {
+        let boundary_ok = self.boundary == 0 || self.boundary.is_power_of_two();
+        if !self.alignment.is_power_of_two() || !boundary_ok || self.max_segment == 0 {
+            return Err(DmaError::BadConstraints);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingKind {
+    Buffer,
+    ScatterGather { segments: usize },
+}
+
+/// Memory a device has access to.
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    /// Start of the memory, or of the first segment.
+    pub address: PhysAddr,
+    pub length: usize,
+    pub kind: MappingKind,
+}
+
+fn track(mapping: Mapping) -> u64 {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    MAPPINGS.lock().insert(id, mapping);
+    id
+}
+
+fn untrack(id: u64) {
+    MAPPINGS.lock().remove(&id);
+}
+
+/// The memory devices currently have access to.
+pub fn mappings() -> Vec<Mapping> {
+    MAPPINGS.lock().values().copied().collect()
+}
+
+/// Zeroed, physically contiguous memory for a device, freed when dropped. The kernel reaches it
+/// through the physical memory mapping.
+pub struct DmaBuffer {
+    address: PhysAddr,
+    length: usize,
+    pages: usize,
+    id: u64,
+}
+
+// the memory belongs to the buffer alone, whoever has it can use it
+unsafe impl Send for DmaBuffer {}
+unsafe impl Sync for DmaBuffer {}
+
+impl DmaBuffer {
+    /// Allocates at least `length` bytes. The start is always page aligned, as is the length
+    /// actually allocated.
+    pub fn allocate(length: usize, constraints: &DmaConstraints) -> Result<DmaBuffer, DmaError> {
+        constraints.check()?;
+        if length == 0 || (constraints.boundary != 0 && length as u64 > constraints.boundary) {
+            return Err(DmaError::BadConstraints);
+        }
+
+        let pages = (length + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+        let mut align = usize::max((constraints.alignment / PAGE_SIZE) as usize, 1);
+        // memory aligned to its own size rounded up to a power of two can't cross any bigger
+        // boundary
+        if constraints.boundary > PAGE_SIZE {
+            align = usize::max(align, pages.next_power_of_two());
+        }
+
+        let frame = FRAME_ALLOCATOR
+            .lock()
+            .get_mut()
+            .and_then(|allocator| allocator.allocate_contiguous(pages, align))
+            .ok_or(DmaError::OutOfMemory)?;
+        let address = frame.start_address();
+        if address.as_u64() + (pages as u64 * PAGE_SIZE - 1) > constraints.max_address {
+            free_frames(address, pages);
+            return Err(DmaError::Unreachable);
+        }
+        unsafe { core::ptr::write_bytes(phys_to_virt(address).as_mut_ptr::<u8>(), 0, pages * PAGE_SIZE as usize) };
+
+        let id = track(Mapping { address, length, kind: MappingKind::Buffer });
+        Ok(DmaBuffer { address, length, pages, id })
+    }
+
+    /// Allocates whole pages.
+    pub fn allocate_pages(pages: usize) -> Result<DmaBuffer, DmaError> {
+        DmaBuffer::allocate(pages * PAGE_SIZE as usize, &DmaConstraints::default())
+    }
+
+    pub fn physical(&self) -> PhysAddr {
+        self.address
+    }
+
+    pub fn virtual_address(&self) -> VirtAddr {
+        phys_to_virt(self.address)
+    }
+
+    /// Length that was asked for.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virtual_address().as_ptr(), self.length) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virtual_address().as_mut_ptr(), self.length) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        untrack(self.id);
+        free_frames(self.address, self.pages);
+    }
+}
+
+fn free_frames(address: PhysAddr, pages: usize) {
+    if let Some(allocator) = FRAME_ALLOCATOR.lock().get_mut() {
+        unsafe { allocator.deallocate_contiguous(PhysFrame::containing_address(address), pages) };
+    }
+}
+
+/// A physically contiguous piece of a scatter gather list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub address: PhysAddr,
+    pub length: usize,
+}
+
+/// The physical memory behind a buffer that's already in the kernel's address space, split
+/// into segments a device can use. The buffer stays borrowed while the device has it.
+pub struct ScatterList<'a> {
+    segments: Vec<Segment>,
+    id: u64,
+    buffer: PhantomData<&'a [u8]>,
+}
+
+impl<'a> ScatterList<'a> {
+    /// Maps a buffer the device reads from.
+    pub fn new(buffer: &'a [u8], constraints: &DmaConstraints) -> Result<ScatterList<'a>, DmaError> {
+        ScatterList::build(VirtAddr::from_ptr(buffer.as_ptr()), buffer.len(), constraints)
+    }
+
+    /// Maps a buffer the device writes to.
+    pub fn new_mut(buffer: &'a mut [u8], constraints: &DmaConstraints) -> Result<ScatterList<'a>, DmaError> {
+        ScatterList::build(VirtAddr::from_ptr(buffer.as_ptr()), buffer.len(), constraints)
+    }
+
+    fn build(start: VirtAddr, length: usize, constraints: &DmaConstraints) -> Result<ScatterList<'a>, DmaError> {
+        constraints.check()?;
+        let segments = segments(start, length, constraints)?;
+        let address = segments.first().map_or(PhysAddr::zero(), |segment| segment.address);
+        let kind = MappingKind::ScatterGather { segments: segments.len() };
+        let id = track(Mapping { address, length, kind });
+        Ok(ScatterList { segments, id, buffer: PhantomData })
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+}
+
+impl Drop for ScatterList<'_> {
+    fn drop(&mut self) {
+        untrack(self.id);
+    }
+}
+
+/// Translates the buffer page by page, merging physically consecutive pages and splitting
+/// wherever the constraints need it.
+fn segments(start: VirtAddr, length: usize, constraints: &DmaConstraints) -> Result<Vec<Segment>, DmaError> {
+    let mut pieces: Vec<Segment> = Vec::new();
+    {
+        let mut space_lock = ADDRESS_SPACE.lock();
+        let mapper = space_lock.get_mut().ok_or(DmaError::NotMapped)?.mapper();
+
+        let mut offset = 0;
+        while offset < length {
+            let virt = start + offset;
+            let in_page = usize::min(length - offset, (PAGE_SIZE - virt.as_u64() % PAGE_SIZE) as usize);
+            let phys = mapper.translate_addr(virt).ok_or(DmaError::NotMapped)?;
+            match pieces.last_mut() {
+                Some(last) if last.address + last.length as u64 == phys => last.length += in_page,
+                _ => pieces.push(Segment { address: phys, length: in_page }),
+            }
+            offset += in_page;
+        }
+    }
+
+    if pieces.first().map_or(false, |first| first.address.as_u64() % constraints.alignment != 0) {
+        return Err(DmaError::Misaligned);
+    }
+
+    let mut segments = Vec::new();
+    for piece in pieces {
+        if piece.address.as_u64() + (piece.length as u64 - 1) > constraints.max_address {
+            return Err(DmaError::Unreachable);
+        }
+        let mut address = piece.address;
+        let end = piece.address + piece.length as u64;
+        while address < end {
+            let mut length = u64::min(end - address, constraints.max_segment);
+            if constraints.boundary != 0 {
+                let next_boundary = (address.as_u64() / constraints.boundary + 1) * constraints.boundary;
+                length = u64::min(length, next_boundary - address.as_u64());
+            }
+            segments.push(Segment { address, length: length as usize });
+            address += length;
+        }
+    }
+    Ok(segments)
+}
@@ -0,0 +1,186 @@
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::PAGE_SIZE;
+
+/// A frame allocator that keeps one bit per physical frame, set if the frame is in use.
+///
+/// The bitmap itself lives in the first usable region big enough to hold it and is accessed
+/// through the bootloader's physical memory mapping.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    // total number of frames the bitmap covers
+    frame_count: usize,
+    // number of frames currently not in use
+    free_frames: usize,
+    // index of the frame to start searching from, everything before it is known to be used
+    next_free: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Builds the allocator from the memory map handed over by the bootloader.
+    ///
+    /// This function is unsafe because the caller must guarantee that the memory map is valid,
+    /// that the regions marked usable are really unused, and that all physical memory is
+    /// mapped at `physical_memory_offset`.
+    pub unsafe fn new(
+        memory_regions: &MemoryRegions,
+        physical_memory_offset: VirtAddr,
+    ) -> BitmapFrameAllocator {
+        let max_address = memory_regions
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .map(|region| region.end)
+            .max()
+            .expect("No usable memory regions");
+        let frame_count = (max_address / PAGE_SIZE) as usize;
+        let bitmap_words = (frame_count + 63) / 64;
+        let bitmap_bytes = (bitmap_words * 8) as u64;
+        let bitmap_frames = (bitmap_bytes + PAGE_SIZE - 1) / PAGE_SIZE;
+
+        // find somewhere to put the bitmap, skipping the first frame so that a null physical
+        // address never looks like a valid allocation
+        let bitmap_start = memory_regions
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .map(|region| (u64::max(region.start, PAGE_SIZE), region.end))
+            .map(|(start, end)| (align_up(start, PAGE_SIZE), end))
+            .find(|&(start, end)| end > start && end - start >= bitmap_frames * PAGE_SIZE)
+            .map(|(start, _)| start)
+            .expect("No usable memory region is large enough for the frame bitmap");
+
+        let bitmap_ptr = (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, bitmap_words);
+        // start with everything marked as used, then free what the bootloader says is usable
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            frame_count,
+            free_frames: 0,
+            next_free: 0,
+        };
+
+        for region in memory_regions.iter() {
+            if region.kind != MemoryRegionKind::Usable {
+                continue;
+            }
+            let first = (align_up(region.start, PAGE_SIZE) / PAGE_SIZE) as usize;
+            let last = (region.end / PAGE_SIZE) as usize;
+            for index in first..last {
+                allocator.mark_free(index);
+            }
+        }
+
+        // the null frame and the bitmap itself are never handed out
+        allocator.mark_used(0);
+        let bitmap_first = (bitmap_start / PAGE_SIZE) as usize;
+        for index in bitmap_first..bitmap_first + bitmap_frames as usize {
+            allocator.mark_used(index);
+        }
+        allocator.next_free = 0;
+
+        allocator
+    }
+
+    /// Allocates `count` physically consecutive frames, the first at a multiple of `align`
+    /// frames, for devices that access memory by physical address. Returns the first frame.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        if count == 0 || align == 0 {
+            return None;
+        }
+
+        let mut first = (self.next_free + align - 1) / align * align;
+        while first + count <= self.frame_count {
+            match (first..first + count).rev().find(|&index| self.is_used(index)) {
+                // nothing can start before the frame after the used one
+                Some(used) => first = (used + 1 + align - 1) / align * align,
+                None => {
+                    for index in first..first + count {
+                        self.mark_used(index);
+                    }
+                    if first == self.next_free {
+                        self.next_free = first + count;
+                    }
+                    return Some(Self::frame_at(first));
+                }
+            }
+        }
+        None
+    }
+
+    /// Frees frames allocated with `allocate_contiguous`.
+    ///
+    /// This function is unsafe because the frames must not be in use anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, first: PhysFrame, count: usize) {
+        let first_index = (first.start_address().as_u64() / PAGE_SIZE) as usize;
+        for index in first_index..first_index + count {
+            self.deallocate_frame(Self::frame_at(index));
+        }
+    }
+
+    fn frame_at(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * PAGE_SIZE))
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn mark_used(&mut self, index: usize) {
+        if !self.is_used(index) {
+            self.bitmap[index / 64] |= 1 << (index % 64);
+            self.free_frames -= 1;
+        }
+    }
+
+    fn mark_free(&mut self, index: usize) {
+        if self.is_used(index) {
+            self.bitmap[index / 64] &= !(1 << (index % 64));
+            self.free_frames += 1;
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let first_word = self.next_free / 64;
+        for word_idx in first_word..self.bitmap.len() {
+            let word = self.bitmap[word_idx];
+            if word == u64::MAX {
+                continue;
+            }
+
+            let index = word_idx * 64 + (!word).trailing_zeros() as usize;
+            if index >= self.frame_count {
+                return None;
+            }
+            self.mark_used(index);
+            self.next_free = index + 1;
+            return Some(Self::frame_at(index));
+        }
+
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = (frame.start_address().as_u64() / PAGE_SIZE) as usize;
+        if !self.is_used(index) {
+            panic!("Double free of physical frame {:#x}", frame.start_address().as_u64());
+        }
+        self.mark_free(index);
+        self.next_free = usize::min(self.next_free, index);
+    }
+}
+
+pub(super) const fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) / align * align
+}
+
+/// `align_up` for values that come from user space, None if the result doesn't fit.
+pub(super) fn checked_align_up(value: u64, align: u64) -> Option<u64> {
+    Some(value.checked_add(align - 1)? / align * align)
+}
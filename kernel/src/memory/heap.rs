@@ -0,0 +1,42 @@
+use linked_list_allocator::LockedHeap;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+#[global_allocator]
+static ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+/// Start of the kernel heap, right at the beginning of the kernel's half of the address space
+/// that the bootloader is told to stay out of.
+pub const HEAP_START: u64 = 0xffff_c000_0000_0000;
+pub const HEAP_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Maps the kernel heap and hands it to the global allocator.
+///
+/// The heap is mapped eagerly rather than through demand paging so that the page fault handler
+/// can never end up waiting on a lock held by the allocator.
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let heap_start = VirtAddr::new(HEAP_START);
+    let heap_end = heap_start + HEAP_SIZE - 1u64;
+    let pages = Page::range_inclusive(
+        Page::<Size4KiB>::containing_address(heap_start),
+        Page::<Size4KiB>::containing_address(heap_end),
+    );
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for page in pages {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE as usize);
+    }
+
+    Ok(())
+}
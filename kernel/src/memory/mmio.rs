@@ -0,0 +1,57 @@
+use spin::Mutex;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::address_space::VmError;
+use super::frame::align_up;
+use super::heap::HEAP_START;
+use super::PAGE_SIZE;
+
+/// Start of the range device memory gets mapped into. It shares its top level page table entry
+/// with the heap, so mappings made after a user address space was created still show up in it.
+pub const MMIO_START: u64 = HEAP_START + 0x40_0000_0000;
+pub const MMIO_SIZE: u64 = 0x40_0000_0000;
+
+/// Where the next mapping goes, MMIO mappings are never taken down.
+static NEXT_MMIO: Mutex<u64> = Mutex::new(MMIO_START);
+
+/// Maps `size` bytes of device memory starting at `address` as uncached and returns where
+/// they can be accessed.
+pub fn map(
+    address: PhysAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, VmError> {
+    let first = address.align_down(PAGE_SIZE);
+    let within = address - first;
+    let length = align_up(within + size, PAGE_SIZE);
+
+    let start = {
+        let mut next = NEXT_MMIO.lock();
+        if *next + length > MMIO_START + MMIO_SIZE {
+            return Err(VmError::NoSpace);
+        }
+        let start = *next;
+        *next += length;
+        start
+    };
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    for offset in (0..length).step_by(PAGE_SIZE as usize) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(start + offset));
+        let frame = PhysFrame::containing_address(first + offset);
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::FrameAllocationFailed) => return Err(VmError::OutOfMemory),
+            Err(_) => return Err(VmError::InvalidRange),
+        }
+    }
+
+    Ok(VirtAddr::new(start + within))
+}
@@ -0,0 +1,110 @@
+pub mod address_space;
+pub mod dma;
+pub mod frame;
+pub mod heap;
+pub mod mmio;
+
+use core::cell::OnceCell;
+
+use bootloader_api::info::MemoryRegions;
+use spin::{Mutex, Once};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::{PhysAddr, VirtAddr};
+
+use address_space::{AddressSpace, VmError};
+use frame::BitmapFrameAllocator;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+pub static FRAME_ALLOCATOR: Mutex<OnceCell<BitmapFrameAllocator>> = Mutex::new(OnceCell::new());
+
+/// The address space currently loaded in CR3.
+///
+/// When locking both, always lock this before `FRAME_ALLOCATOR`.
+pub static ADDRESS_SPACE: Mutex<OnceCell<AddressSpace>> = Mutex::new(OnceCell::new());
+
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+/// Sets up the frame allocator, takes over the bootloader's page tables and maps the kernel heap.
+pub fn init(physical_memory_offset: u64, memory_regions: &MemoryRegions) {
+    let physical_memory_offset = VirtAddr::new(physical_memory_offset);
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
+
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::new(memory_regions, physical_memory_offset) };
+    let mut address_space = unsafe { AddressSpace::active(physical_memory_offset) };
+    heap::init_heap(address_space.mapper(), &mut frame_allocator)
+        .expect("Failed to map the kernel heap");
+
+    FRAME_ALLOCATOR.lock().get_or_init(|| frame_allocator);
+    ADDRESS_SPACE.lock().get_or_init(|| address_space);
+}
+
+/// Returns the virtual address through which the given physical address can be accessed.
+pub fn phys_to_virt(address: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("Memory management is not initialized");
+    *offset + address.as_u64()
+}
+
+/// Called by the page fault handler to populate lazily mapped memory in the active address space.
+///
+/// A fault while the address space or the frame allocator is locked came from the memory
+/// manager itself, which would spin forever waiting for the lock, so it's left unhandled.
+pub fn handle_page_fault(address: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), VmError> {
+    let mut space_lock = ADDRESS_SPACE.try_lock().ok_or(VmError::Reentrant)?;
+    let address_space = space_lock.get_mut().ok_or(VmError::NotMapped)?;
+    let mut allocator_lock = FRAME_ALLOCATOR.try_lock().ok_or(VmError::Reentrant)?;
+    let frame_allocator = allocator_lock.get_mut().ok_or(VmError::OutOfMemory)?;
+
+    address_space.handle_page_fault(address, error_code, frame_allocator)
+}
+
+/// Maps `size` bytes of device registers at physical `address` into the kernel's half of the
+/// address space, uncached.
+pub fn map_mmio(address: PhysAddr, size: u64) -> Result<VirtAddr, VmError> {
+    let mut space_lock = ADDRESS_SPACE.lock();
+    let address_space = space_lock.get_mut().ok_or(VmError::NotMapped)?;
+    let mut allocator_lock = FRAME_ALLOCATOR.lock();
+    let frame_allocator = allocator_lock.get_mut().ok_or(VmError::OutOfMemory)?;
+
+    mmio::map(address, size, address_space.mapper(), frame_allocator)
+}
+
+/// Checks that taking all access away from a page that was already faulted in makes every
+/// access to it fault, and that it comes back with its contents once reading is allowed again.
+#[cfg(feature = "vm-check")]
+pub fn check_protect_none() -> Result<(), VmError> {
+    use x86_64::structures::paging::{Mapper, Page, Size4KiB};
+
+    use address_space::Protection;
+
+    const VALUE: u64 = 0xB0BA_CAFE;
+
+    let mut space_lock = ADDRESS_SPACE.lock();
+    let address_space = space_lock.get_mut().ok_or(VmError::NotMapped)?;
+    let mut allocator_lock = FRAME_ALLOCATOR.lock();
+    let frame_allocator = allocator_lock.get_mut().ok_or(VmError::OutOfMemory)?;
+
+    let protection = Protection::READ | Protection::WRITE;
+    let start = address_space.map_anonymous(None, PAGE_SIZE, protection, false, frame_allocator)?;
+    // fault the page in like a write to it would, the lock is held so a real fault couldn't be
+    address_space.handle_page_fault(start, PageFaultErrorCode::CAUSED_BY_WRITE, frame_allocator)?;
+    unsafe { start.as_mut_ptr::<u64>().write_volatile(VALUE) };
+
+    address_space.protect(start, PAGE_SIZE, Protection::empty())?;
+    // a page that isn't present makes the CPU fault on any read, which must not be resolved
+    let page = Page::<Size4KiB>::containing_address(start);
+    let present = address_space.mapper().translate_page(page).is_ok();
+    let read = address_space.handle_page_fault(start, PageFaultErrorCode::USER_MODE, frame_allocator);
+
+    address_space.protect(start, PAGE_SIZE, Protection::READ)?;
+    let value = unsafe { start.as_ptr::<u64>().read_volatile() };
+    address_space.unmap(start, PAGE_SIZE, frame_allocator)?;
+
+    assert!(!present, "Page still present after mprotect(PROT_NONE)");
+    assert_eq!(read, Err(VmError::AccessViolation), "Read of a PROT_NONE page was let through");
+    assert_eq!(value, VALUE, "Page lost its contents while it was PROT_NONE");
+    Ok(())
+}
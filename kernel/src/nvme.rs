@@ -0,0 +1,569 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::block::{self, BlockDevice, BlockError};
+use crate::memory::dma::DmaBuffer;
+use crate::memory::{phys_to_virt, PAGE_SIZE};
+use crate::pci::{PciDevice, PciDriver, PciError, PciMatch};
+
+// controller registers
+const REG_CAP: usize = 0x00;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1C;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const DOORBELLS: usize = 0x1000;
+
+const CC_ENABLE: u32 = 1 << 0;
+/// Submission entries are 2^6 bytes and completion entries 2^4.
+const CC_ENTRY_SIZES: u32 = (6 << 16) | (4 << 20);
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+
+const SUBMISSION_ENTRY_SIZE: usize = 64;
+const COMPLETION_ENTRY_SIZE: usize = 16;
+const ADMIN_QUEUE_SIZE: u16 = 32;
+const IO_QUEUE_SIZE: u16 = 64;
+const IO_QUEUE_ID: u16 = 1;
+
+// admin commands
+const ADMIN_CREATE_SUBMISSION_QUEUE: u8 = 0x01;
+const ADMIN_CREATE_COMPLETION_QUEUE: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+
+// what identify returns
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_NAMESPACE_LIST: u32 = 0x02;
+
+// I/O commands
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+/// Transfers go through a bounce buffer of this many pages, since heap memory isn't
+/// physically contiguous.
+const BOUNCE_PAGES: usize = 16;
+const TIMEOUT_MS: u64 = 5000;
+/// How often the controller is polled before giving up when interrupts are off and the clock
+/// doesn't advance.
+const POLL_LIMIT: usize = 100_000_000;
+
+pub static DRIVER: NvmeDriver = NvmeDriver;
+
+/// Numbers the controllers "nvme0", "nvme1" and so on, their namespaces are "nvme0n1" and up.
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeError {
+    /// The controller doesn't work the way we need it to.
+    Unsupported,
+    OutOfMemory,
+    /// A command completed with this status code.
+    Command(u16),
+    /// The controller didn't respond in time or reported a fatal error.
+    Timeout,
+}
+
+impl From<NvmeError> for PciError {
+    fn from(err: NvmeError) -> PciError {
+        match err {
+            NvmeError::Unsupported => PciError::Unsupported,
+            NvmeError::OutOfMemory => PciError::OutOfMemory,
+            _ => PciError::DeviceError,
+        }
+    }
+}
+
+pub struct NvmeDriver;
+
+impl PciDriver for NvmeDriver {
+    fn name(&self) -> &'static str {
+        "nvme"
+    }
+
+    fn matches(&self) -> &'static [PciMatch] {
+        &[PciMatch::Class { class: 0x01, subclass: 0x08, prog_if: Some(0x02) }]
+    }
+
+    fn probe(&self, device: &Arc<PciDevice>) -> Result<(), PciError> {
+        device.enable();
+        let registers = device.map_bar(0)?;
+
+        // both queues share one vector, the handler only has to wake up whoever waits
+        let interrupts = device.enable_msi(vec![Box::new(|_| {})]).is_ok();
+        if !interrupts {
+            device.set_legacy_interrupt(false);
+        }
+
+        let controller = match Controller::new(registers, interrupts) {
+            Ok(controller) => Arc::new(controller),
+            Err(err) => {
+                device.disable_msi();
+                return Err(err.into());
+            }
+        };
+        let name = format!("nvme{}", NEXT_INDEX.fetch_add(1, Ordering::Relaxed));
+        crate::log(&format!("{}: {} on {}, {}", name, self.name(), device.address, controller.model));
+
+        let mut found = 0;
+        for id in controller.namespaces()? {
+            let namespace = match Namespace::new(&controller, id) {
+                Ok(Some(namespace)) => namespace,
+                _ => continue,
+            };
+            let name = format!("{}n{}", name, id);
+            crate::log(&format!(
+                "{}: {} blocks of {} bytes",
+                name, namespace.block_count, namespace.block_size
+            ));
+            if block::register(&name, Arc::new(namespace)).is_ok() {
+                found += 1;
+            }
+        }
+        match found {
+            0 => Err(PciError::Unsupported),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Polls until `done` returns true.
+fn poll(mut done: impl FnMut() -> bool) -> Result<(), NvmeError> {
+    for _ in 0..POLL_LIMIT {
+        if done() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(NvmeError::Timeout)
+}
+
+/// A submission queue and the completion queue its commands complete on, with the same id.
+/// Only one command is in flight at a time, so the queues never fill up.
+struct Queue {
+    id: u16,
+    size: u16,
+    submission: DmaBuffer,
+    completion: DmaBuffer,
+    submission_doorbell: VirtAddr,
+    completion_doorbell: VirtAddr,
+    submission_tail: u16,
+    completion_head: u16,
+    // the phase bit completion entries have until the queue wraps around
+    phase: bool,
+    next_command_id: u16,
+    // whether the completion queue interrupts
+    interrupts: bool,
+}
+
+impl Queue {
+    fn new(
+        id: u16,
+        size: u16,
+        registers: VirtAddr,
+        doorbell_stride: usize,
+        interrupts: bool,
+    ) -> Result<Queue, NvmeError> {
+        let submission = DmaBuffer::allocate_pages(Queue::pages(size, SUBMISSION_ENTRY_SIZE))
+            .map_err(|_| NvmeError::OutOfMemory)?;
+        let completion = DmaBuffer::allocate_pages(Queue::pages(size, COMPLETION_ENTRY_SIZE))
+            .map_err(|_| NvmeError::OutOfMemory)?;
+        let doorbell = registers + DOORBELLS + 2 * id as usize * doorbell_stride;
+        Ok(Queue {
+            id,
+            size,
+            submission,
+            completion,
+            submission_doorbell: doorbell,
+            completion_doorbell: doorbell + doorbell_stride,
+            submission_tail: 0,
+            completion_head: 0,
+            phase: true,
+            next_command_id: 0,
+            interrupts,
+        })
+    }
+
+    fn pages(size: u16, entry_size: usize) -> usize {
+        (size as usize * entry_size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize
+    }
+
+    /// Runs one command and waits for it to complete, returning the command specific result.
+    fn run(&mut self, mut command: [u32; 16]) -> Result<u32, NvmeError> {
+        let id = self.next_command_id;
+        self.next_command_id = self.next_command_id.wrapping_add(1);
+        command[0] |= (id as u32) << 16;
+
+        let entry = self.submission.virtual_address() + self.submission_tail as usize * SUBMISSION_ENTRY_SIZE;
+        unsafe { entry.as_mut_ptr::<[u32; 16]>().write_volatile(command) };
+        self.submission_tail = (self.submission_tail + 1) % self.size;
+        // the entry has to be visible before the doorbell tells the controller about it
+        fence(Ordering::SeqCst);
+        unsafe { self.submission_doorbell.as_mut_ptr::<u32>().write_volatile(self.submission_tail as u32) };
+
+        loop {
+            let (completed, status, result) = self.wait()?;
+            // completions of commands that timed out earlier can still show up
+            if completed == id {
+                return match status {
+                    0 => Ok(result),
+                    _ => Err(NvmeError::Command(status)),
+                };
+            }
+        }
+    }
+
+    /// Takes the next completion if there's one, returning the command id, status and result.
+    fn complete(&mut self) -> Option<(u16, u16, u32)> {
+        let entry = self.completion.virtual_address() + self.completion_head as usize * COMPLETION_ENTRY_SIZE;
+        let entry = unsafe { entry.as_ptr::<[u32; 4]>().read_volatile() };
+        if (entry[3] & (1 << 16) != 0) != self.phase {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+
+        self.completion_head += 1;
+        if self.completion_head == self.size {
+            self.completion_head = 0;
+            self.phase = !self.phase;
+        }
+        unsafe { self.completion_doorbell.as_mut_ptr::<u32>().write_volatile(self.completion_head as u32) };
+        Some((entry[3] as u16, ((entry[3] >> 17) & 0x7FFF) as u16, entry[0]))
+    }
+
+    /// Waits for a completion. Sleeps until an interrupt if the queue has them and interrupts are
+    /// enabled, syscalls run with them disabled so there we poll.
+    fn wait(&mut self) -> Result<(u16, u16, u32), NvmeError> {
+        if !self.interrupts || !interrupts::are_enabled() {
+            for _ in 0..POLL_LIMIT {
+                if let Some(completion) = self.complete() {
+                    return Ok(completion);
+                }
+                core::hint::spin_loop();
+            }
+            return Err(NvmeError::Timeout);
+        }
+
+        let start = crate::time::uptime_ms();
+        loop {
+            // checking and halting have to be atomic, or the interrupt could arrive in between
+            // and leave us asleep until the next one
+            interrupts::disable();
+            if let Some(completion) = self.complete() {
+                interrupts::enable();
+                return Ok(completion);
+            }
+            interrupts::enable_and_hlt();
+
+            if crate::time::uptime_ms() - start > TIMEOUT_MS {
+                return Err(NvmeError::Timeout);
+            }
+        }
+    }
+}
+
+/// Builds a command with the given data pointers and command specific dwords 10 to 15.
+fn command(opcode: u8, namespace: u32, data: (u64, u64), arguments: [u32; 6]) -> [u32; 16] {
+    let mut command = [0; 16];
+    command[0] = opcode as u32;
+    command[1] = namespace;
+    command[6] = data.0 as u32;
+    command[7] = (data.0 >> 32) as u32;
+    command[8] = data.1 as u32;
+    command[9] = (data.1 >> 32) as u32;
+    command[10..].copy_from_slice(&arguments);
+    command
+}
+
+struct IoState {
+    queue: Queue,
+    // one page for the PRP list, then the bounce buffer
+    memory: DmaBuffer,
+}
+
+impl IoState {
+    fn bounce_buffer(&self) -> PhysAddr {
+        self.memory.physical() + PAGE_SIZE
+    }
+
+    /// Data pointers for the first `length` bytes of the bounce buffer. The first points at the
+    /// first page, the second at the second page or at a list of the pages after the first.
+    fn data_pointers(&self, length: usize) -> (u64, u64) {
+        let first = self.bounce_buffer().as_u64();
+        let pages = (length + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+        match pages {
+            0 | 1 => (first, 0),
+            2 => (first, first + PAGE_SIZE),
+            _ => {
+                let list = self.memory.virtual_address().as_mut_ptr::<u64>();
+                for page in 1..pages {
+                    unsafe { list.add(page - 1).write_volatile(first + page as u64 * PAGE_SIZE) };
+                }
+                (first, self.memory.physical().as_u64())
+            }
+        }
+    }
+}
+
+/// An NVMe controller with one I/O queue pair, shared by all its namespaces.
+pub struct Controller {
+    registers: VirtAddr,
+    admin: Mutex<Queue>,
+    io: Mutex<IoState>,
+    // largest transfer a command can do, in bytes
+    max_transfer: usize,
+    namespace_count: u32,
+    pub model: String,
+}
+
+impl Controller {
+    fn new(registers: VirtAddr, interrupts: bool) -> Result<Controller, NvmeError> {
+        let capabilities = unsafe { registers.as_ptr::<u64>().read_volatile() };
+        let doorbell_stride = 4 << ((capabilities >> 32) & 0xF);
+        let max_queue_size = (capabilities & 0xFFFF) as u16 + 1;
+        // the NVM command set and 4 KiB pages have to be supported
+        let min_page_size = 4096 << ((capabilities >> 48) & 0xF);
+        if capabilities & (1 << 37) == 0 || min_page_size > PAGE_SIZE {
+            return Err(NvmeError::Unsupported);
+        }
+
+        let admin_size = u16::min(ADMIN_QUEUE_SIZE, max_queue_size);
+        let admin = Queue::new(0, admin_size, registers, doorbell_stride, interrupts)?;
+        let io_size = u16::min(IO_QUEUE_SIZE, max_queue_size);
+        let io = Queue::new(IO_QUEUE_ID, io_size, registers, doorbell_stride, interrupts)?;
+        let memory = DmaBuffer::allocate_pages(BOUNCE_PAGES + 1).map_err(|_| NvmeError::OutOfMemory)?;
+
+        let mut controller = Controller {
+            registers,
+            admin: Mutex::new(admin),
+            io: Mutex::new(IoState { queue: io, memory }),
+            max_transfer: BOUNCE_PAGES * PAGE_SIZE as usize,
+            namespace_count: 0,
+            model: String::new(),
+        };
+        if let Err(err) = controller.set_up() {
+            // a controller that won't stop might still write to the queues, better to leak them
+            if controller.disable().is_err() {
+                core::mem::forget(controller);
+            }
+            return Err(err);
+        }
+        Ok(controller)
+    }
+
+    fn read<T>(&self, register: usize) -> T {
+        unsafe { core::ptr::read_volatile((self.registers + register).as_ptr::<T>()) }
+    }
+
+    fn write<T>(&self, register: usize, value: T) {
+        unsafe { core::ptr::write_volatile((self.registers + register).as_mut_ptr::<T>(), value) }
+    }
+
+    fn disable(&self) -> Result<(), NvmeError> {
+        self.write::<u32>(REG_CC, self.read::<u32>(REG_CC) & !CC_ENABLE);
+        poll(|| self.read::<u32>(REG_CSTS) & CSTS_READY == 0)
+    }
+
+    /// Resets the controller, hands it the admin queue, enables it, identifies it and creates the
+    /// I/O queue.
+    fn set_up(&mut self) -> Result<(), NvmeError> {
+        self.disable()?;
+
+        {
+            let admin = self.admin.lock();
+            self.write::<u32>(REG_AQA, (admin.size as u32 - 1) << 16 | (admin.size as u32 - 1));
+            self.write::<u64>(REG_ASQ, admin.submission.physical().as_u64());
+            self.write::<u64>(REG_ACQ, admin.completion.physical().as_u64());
+        }
+        self.write::<u32>(REG_CC, CC_ENTRY_SIZES | CC_ENABLE);
+        poll(|| self.read::<u32>(REG_CSTS) & (CSTS_READY | CSTS_FATAL) != 0)?;
+        if self.read::<u32>(REG_CSTS) & CSTS_FATAL != 0 {
+            return Err(NvmeError::Timeout);
+        }
+
+        let identify = self.identify(IDENTIFY_CONTROLLER, 0)?;
+        self.model = String::from_utf8_lossy(&identify[24..64]).trim().into();
+        self.namespace_count = u32::from_le_bytes(identify[516..520].try_into().unwrap());
+        // a maximum transfer of 0 means there's no limit, otherwise it's in minimum size pages,
+        // and a limit too big for a usize is as good as none
+        let min_page_size = 4096usize << ((self.read::<u64>(REG_CAP) >> 48) & 0xF);
+        let max_pages_shift = identify[77] as u32;
+        if max_pages_shift != 0 && max_pages_shift < min_page_size.leading_zeros() {
+            self.max_transfer = usize::min(self.max_transfer, min_page_size << max_pages_shift);
+        }
+
+        // the completion queue has to exist before the submission queue that uses it
+        let (id, size, submission, completion, interrupts) = {
+            let io = self.io.lock();
+            let queue = &io.queue;
+            (
+                queue.id as u32,
+                queue.size as u32,
+                queue.submission.physical(),
+                queue.completion.physical(),
+                queue.interrupts,
+            )
+        };
+        let mut admin = self.admin.lock();
+        // physically contiguous, and interrupting through vector 0 if at all
+        let flags = 1 | if interrupts { 1 << 1 } else { 0 };
+        admin.run(command(
+            ADMIN_CREATE_COMPLETION_QUEUE,
+            0,
+            (completion.as_u64(), 0),
+            [(size - 1) << 16 | id, flags, 0, 0, 0, 0],
+        ))?;
+        admin.run(command(
+            ADMIN_CREATE_SUBMISSION_QUEUE,
+            0,
+            (submission.as_u64(), 0),
+            [(size - 1) << 16 | id, id << 16 | 1, 0, 0, 0, 0],
+        ))?;
+        Ok(())
+    }
+
+    /// Runs identify with the given CNS value, the data goes through the I/O bounce buffer.
+    fn identify(&self, kind: u32, namespace: u32) -> Result<Vec<u8>, NvmeError> {
+        let io = self.io.lock();
+        let buffer = io.bounce_buffer();
+        self.admin
+            .lock()
+            .run(command(ADMIN_IDENTIFY, namespace, (buffer.as_u64(), 0), [kind, 0, 0, 0, 0, 0]))?;
+
+        let mut data = vec![0; PAGE_SIZE as usize];
+        let source = phys_to_virt(buffer).as_ptr::<u8>();
+        unsafe { core::ptr::copy_nonoverlapping(source, data.as_mut_ptr(), data.len()) };
+        Ok(data)
+    }
+
+    /// Ids of the active namespaces. Controllers too old for the list get every id up to the
+    /// namespace count, inactive ones turn out empty when identified.
+    fn namespaces(&self) -> Result<Vec<u32>, NvmeError> {
+        match self.identify(IDENTIFY_NAMESPACE_LIST, 0) {
+            Ok(list) => Ok(list
+                .chunks(4)
+                .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+                .take_while(|&id| id != 0)
+                .collect()),
+            Err(NvmeError::Command(_)) => Ok((1..=self.namespace_count).collect()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Drop for Controller {
+    fn drop(&mut self) {
+        // stop the controller before its queues are freed
+        let _ = self.disable();
+    }
+}
+
+/// One namespace of a controller, a separate disk.
+pub struct Namespace {
+    controller: Arc<Controller>,
+    id: u32,
+    block_size: usize,
+    block_count: u64,
+}
+
+impl Namespace {
+    /// Identifies namespace `id`, returning none if it's inactive or has metadata interleaved
+    /// with the data.
+    fn new(controller: &Arc<Controller>, id: u32) -> Result<Option<Namespace>, NvmeError> {
+        let identify = controller.identify(IDENTIFY_NAMESPACE, id)?;
+        let block_count = u64::from_le_bytes(identify[0..8].try_into().unwrap());
+        let format = (identify[26] & 0xF) as usize;
+        let format = u32::from_le_bytes(identify[128 + 4 * format..132 + 4 * format].try_into().unwrap());
+        let metadata_size = format & 0xFFFF;
+        // the block size is given as a power of two, from 512 bytes up to a page is supported
+        let block_shift = (format >> 16) & 0xFF;
+
+        if block_count == 0 || metadata_size != 0 || block_shift < 9 || block_shift > PAGE_SIZE.trailing_zeros() {
+            return Ok(None);
+        }
+        let block_size = 1usize << block_shift;
+        Ok(Some(Namespace {
+            controller: controller.clone(),
+            id,
+            block_size,
+            block_count,
+        }))
+    }
+
+    fn check_range(&self, sector: u64, len: usize) -> Result<(), BlockError> {
+        if len % self.block_size != 0 {
+            return Err(BlockError::BadBufferSize);
+        }
+        match sector.checked_add((len / self.block_size) as u64) {
+            Some(end) if end <= self.block_count => Ok(()),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+
+    /// Reads or writes `length` bytes of the bounce buffer starting at block `first`.
+    fn transfer(&self, io: &mut IoState, opcode: u8, first: u64, length: usize) -> Result<(), BlockError> {
+        let blocks = (length / self.block_size) as u32;
+        let data = io.data_pointers(length);
+        io.queue
+            .run(command(opcode, self.id, data, [first as u32, (first >> 32) as u32, blocks - 1, 0, 0, 0]))
+            .map(|_| ())
+            .map_err(|_| BlockError::Io)
+    }
+}
+
+impl BlockDevice for Namespace {
+    fn sector_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(sector, buf.len())?;
+        let mut io = self.controller.io.lock();
+        let bounce = phys_to_virt(io.bounce_buffer()).as_ptr::<u8>();
+
+        let chunk_size = self.controller.max_transfer;
+        for (i, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let first = sector + (i * chunk_size / self.block_size) as u64;
+            self.transfer(&mut io, IO_READ, first, chunk.len())?;
+            unsafe { core::ptr::copy_nonoverlapping(bounce, chunk.as_mut_ptr(), chunk.len()) };
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_range(sector, buf.len())?;
+        let mut io = self.controller.io.lock();
+        let bounce = phys_to_virt(io.bounce_buffer()).as_mut_ptr::<u8>();
+
+        let chunk_size = self.controller.max_transfer;
+        for (i, chunk) in buf.chunks(chunk_size).enumerate() {
+            let first = sector + (i * chunk_size / self.block_size) as u64;
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), bounce, chunk.len()) };
+            self.transfer(&mut io, IO_WRITE, first, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let mut io = self.controller.io.lock();
+        io.queue
+            .run(command(IO_FLUSH, self.id, (0, 0), [0; 6]))
+            .map(|_| ())
+            .map_err(|_| BlockError::Io)
+    }
+}
@@ -0,0 +1,101 @@
+use alloc::vec::Vec;
+
+use super::{config, PciAddress};
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR: u8 = 0x09;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+const STATUS_CAPABILITY_LIST: u16 = 1 << 4;
+/// More capabilities than fit in the 192 bytes after the header means the list loops.
+const MAX_CAPABILITIES: usize = 48;
+
+/// An entry of a function's capability list.
+#[derive(Debug, Clone, Copy)]
+pub enum Capability {
+    Msi(MsiCapability),
+    MsiX(MsixCapability),
+    /// Any capability we don't decode, with its ID and where it is in configuration space.
+    Other { id: u8, offset: u16 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MsiCapability {
+    pub offset: u16,
+    /// Whether the message address register is 64 bits.
+    pub is_64bit: bool,
+    pub per_vector_masking: bool,
+    /// Log2 of how many vectors the function can use.
+    pub max_vectors_log2: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MsixCapability {
+    pub offset: u16,
+    pub table_size: u16,
+    /// BAR index and offset into it of the vector table.
+    pub table_bar: u8,
+    pub table_offset: u32,
+    /// BAR index and offset into it of the pending bit array.
+    pub pba_bar: u8,
+    pub pba_offset: u32,
+}
+
+impl Capability {
+    pub fn id(&self) -> u8 {
+        match self {
+            Capability::Msi(_) => CAPABILITY_MSI,
+            Capability::MsiX(_) => CAPABILITY_MSIX,
+            Capability::Other { id, .. } => *id,
+        }
+    }
+
+    pub fn offset(&self) -> u16 {
+        match self {
+            Capability::Msi(msi) => msi.offset,
+            Capability::MsiX(msix) => msix.offset,
+            Capability::Other { offset, .. } => *offset,
+        }
+    }
+}
+
+/// Walks the capability list of the function at `address`.
+pub fn parse(address: PciAddress) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if config::read_u16(address, 0x06) & STATUS_CAPABILITY_LIST == 0 {
+        return capabilities;
+    }
+
+    let mut offset = (config::read_u8(address, 0x34) & 0xFC) as u16;
+    while offset != 0 && capabilities.len() < MAX_CAPABILITIES {
+        let id = config::read_u8(address, offset);
+        let next = (config::read_u8(address, offset + 1) & 0xFC) as u16;
+        let control = config::read_u16(address, offset + 2);
+
+        capabilities.push(match id {
+            CAPABILITY_MSI => Capability::Msi(MsiCapability {
+                offset,
+                is_64bit: control & (1 << 7) != 0,
+                per_vector_masking: control & (1 << 8) != 0,
+                max_vectors_log2: ((control >> 1) & 0b111) as u8,
+            }),
+            CAPABILITY_MSIX => {
+                // the low 3 bits of the offsets select the BAR
+                let table = config::read_u32(address, offset + 4);
+                let pba = config::read_u32(address, offset + 8);
+                Capability::MsiX(MsixCapability {
+                    offset,
+                    table_size: (control & 0x7FF) + 1,
+                    table_bar: (table & 0b111) as u8,
+                    table_offset: table & !0b111,
+                    pba_bar: (pba & 0b111) as u8,
+                    pba_offset: pba & !0b111,
+                })
+            }
+            _ => Capability::Other { id, offset },
+        });
+        offset = next;
+    }
+    capabilities
+}
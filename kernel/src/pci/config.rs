@@ -0,0 +1,187 @@
+use alloc::vec::Vec;
+
+use spin::{Mutex, Once};
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::PciAddress;
+use crate::acpi::{self, SDT_HEADER_SIZE};
+use crate::memory;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// How configuration space is reached, decided once from the ACPI tables.
+static ACCESS: Once<Access> = Once::new();
+/// The address and data ports have to be used as a pair.
+static PORT_LOCK: Mutex<()> = Mutex::new(());
+
+enum Access {
+    /// The legacy mechanism, limited to segment 0 and the first 256 bytes of each function.
+    Ports,
+    /// PCIe memory mapped configuration space, 4 KiB per function.
+    Ecam(Vec<EcamRegion>),
+}
+
+/// One entry of the MCFG table, a segment's range of buses mapped into memory.
+struct EcamRegion {
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    base: VirtAddr,
+}
+
+/// Uses ECAM if the firmware describes it in an MCFG table, port I/O otherwise. Returns the
+/// segments and bus ranges that can be enumerated.
+pub fn init() -> Vec<(u16, u8, u8)> {
+    let access = ACCESS.call_once(|| match acpi::find_table(b"MCFG").and_then(parse_mcfg) {
+        Some(regions) if !regions.is_empty() => Access::Ecam(regions),
+        _ => Access::Ports,
+    });
+
+    match access {
+        Access::Ports => alloc::vec![(0, 0, 255)],
+        Access::Ecam(regions) => regions
+            .iter()
+            .map(|region| (region.segment, region.start_bus, region.end_bus))
+            .collect(),
+    }
+}
+
+pub fn is_ecam() -> bool {
+    matches!(ACCESS.get(), Some(Access::Ecam(_)))
+}
+
+fn parse_mcfg(table: &[u8]) -> Option<Vec<EcamRegion>> {
+    // the header is followed by 8 reserved bytes, then 16 byte allocation entries
+    let mut regions = Vec::new();
+    for entry in table.get(SDT_HEADER_SIZE + 8..)?.chunks_exact(16) {
+        let base = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+        let segment = u16::from_le_bytes(entry[8..10].try_into().unwrap());
+        let start_bus = entry[10];
+        let end_bus = entry[11];
+        if end_bus < start_bus {
+            continue;
+        }
+
+        // each bus takes 32 devices * 8 functions * 4 KiB
+        let first = base + ((start_bus as u64) << 20);
+        let size = (end_bus as u64 - start_bus as u64 + 1) << 20;
+        let base = memory::map_mmio(PhysAddr::new(first), size).ok()?;
+        regions.push(EcamRegion {
+            segment,
+            start_bus,
+            end_bus,
+            base,
+        });
+    }
+    Some(regions)
+}
+
+/// Returns a pointer to `offset` in the configuration space of `address`, or none if that
+/// isn't mapped.
+fn ecam_pointer(regions: &[EcamRegion], address: PciAddress, offset: u16) -> Option<*mut u8> {
+    let region = regions.iter().find(|region| {
+        region.segment == address.segment && (region.start_bus..=region.end_bus).contains(&address.bus)
+    })?;
+    let function = ((address.bus - region.start_bus) as u64) << 20
+        | (address.device as u64) << 15
+        | (address.function as u64) << 12;
+    Some((region.base + function + offset as u64).as_mut_ptr())
+}
+
+fn port_address(address: PciAddress, offset: u16) -> u32 {
+    1 << 31
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset as u32 & 0xFC)
+}
+
+/// Reads a value of type `T` at `offset`, which must be aligned to its size. Reads from
+/// functions or offsets that can't be reached return all ones, like a missing device.
+fn read<T: Copy + PortValue>(address: PciAddress, offset: u16) -> T {
+    match ACCESS.get() {
+        Some(Access::Ecam(regions)) => match ecam_pointer(regions, address, offset) {
+            Some(pointer) => unsafe { core::ptr::read_volatile(pointer as *const T) },
+            None => T::ALL_ONES,
+        },
+        Some(Access::Ports) if address.segment == 0 && offset < 256 => {
+            let _lock = PORT_LOCK.lock();
+            unsafe {
+                Port::<u32>::new(CONFIG_ADDRESS).write(port_address(address, offset));
+                T::read_port(CONFIG_DATA + (offset & 3))
+            }
+        }
+        _ => T::ALL_ONES,
+    }
+}
+
+fn write<T: Copy + PortValue>(address: PciAddress, offset: u16, value: T) {
+    match ACCESS.get() {
+        Some(Access::Ecam(regions)) => {
+            if let Some(pointer) = ecam_pointer(regions, address, offset) {
+                unsafe { core::ptr::write_volatile(pointer as *mut T, value) };
+            }
+        }
+        Some(Access::Ports) if address.segment == 0 && offset < 256 => {
+            let _lock = PORT_LOCK.lock();
+            unsafe {
+                Port::<u32>::new(CONFIG_ADDRESS).write(port_address(address, offset));
+                T::write_port(CONFIG_DATA + (offset & 3), value);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The widths configuration space can be accessed in.
+trait PortValue: Sized {
+    const ALL_ONES: Self;
+    unsafe fn read_port(port: u16) -> Self;
+    unsafe fn write_port(port: u16, value: Self);
+}
+
+macro_rules! port_value {
+    ($type:ty) => {
+        impl PortValue for $type {
+            const ALL_ONES: $type = <$type>::MAX;
+
+            unsafe fn read_port(port: u16) -> $type {
+                Port::<$type>::new(port).read()
+            }
+
+            unsafe fn write_port(port: u16, value: $type) {
+                Port::<$type>::new(port).write(value)
+            }
+        }
+    };
+}
+
+port_value!(u8);
+port_value!(u16);
+port_value!(u32);
+
+pub fn read_u8(address: PciAddress, offset: u16) -> u8 {
+    read(address, offset)
+}
+
+pub fn read_u16(address: PciAddress, offset: u16) -> u16 {
+    read(address, offset & !1)
+}
+
+pub fn read_u32(address: PciAddress, offset: u16) -> u32 {
+    read(address, offset & !3)
+}
+
+pub fn write_u8(address: PciAddress, offset: u16, value: u8) {
+    write(address, offset, value)
+}
+
+pub fn write_u16(address: PciAddress, offset: u16, value: u16) {
+    write(address, offset & !1, value)
+}
+
+pub fn write_u32(address: PciAddress, offset: u16, value: u32) {
+    write(address, offset & !3, value)
+}
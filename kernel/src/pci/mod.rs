@@ -0,0 +1,383 @@
+pub mod capability;
+pub mod config;
+mod msi;
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt;
+
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::device::{self, Bus, Device, DeviceError, Driver};
+use capability::Capability;
+
+static DEVICES: Mutex<Vec<Arc<PciDevice>>> = Mutex::new(Vec::new());
+
+// configuration space header registers
+const REG_VENDOR_ID: u16 = 0x00;
+const REG_DEVICE_ID: u16 = 0x02;
+const REG_COMMAND: u16 = 0x04;
+const REG_REVISION: u16 = 0x08;
+const REG_PROG_IF: u16 = 0x09;
+const REG_SUBCLASS: u16 = 0x0A;
+const REG_CLASS: u16 = 0x0B;
+const REG_HEADER_TYPE: u16 = 0x0E;
+const REG_BAR0: u16 = 0x10;
+const REG_INTERRUPT_LINE: u16 = 0x3C;
+const REG_INTERRUPT_PIN: u16 = 0x3D;
+
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciError {
+    /// A BAR the driver needs is missing or not the kind it expects.
+    BadBar,
+    /// The function lacks a capability the driver needs.
+    MissingCapability,
+    /// Mapping registers or allocating memory for the device failed.
+    OutOfMemory,
+    /// The device reported an error or didn't respond.
+    DeviceError,
+    /// The driver doesn't support this particular device after all.
+    Unsupported,
+}
+
+/// Where a function sits in the PCI hierarchy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+/// A base address register, a window of memory or I/O ports the function decodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: PhysAddr,
+        size: u64,
+        prefetchable: bool,
+        is_64bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+/// A function found while enumerating the bus.
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    /// Indexed by BAR number, the upper half of a 64 bit BAR is none.
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    /// Legacy interrupt pin, 1 for INTA# through 4 for INTD#, 0 if there is none.
+    pub interrupt_pin: u8,
+    /// What the firmware routed the legacy interrupt pin to, if anything.
+    pub interrupt_line: u8,
+    msi: Mutex<Option<msi::MsiState>>,
+}
+
+impl PciDevice {
+    /// Reads the header of the function at `address`, returning none if there isn't one.
+    fn probe(address: PciAddress) -> Option<PciDevice> {
+        let vendor_id = config::read_u16(address, REG_VENDOR_ID);
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+
+        let header_type = config::read_u8(address, REG_HEADER_TYPE);
+        let mut device = PciDevice {
+            address,
+            vendor_id,
+            device_id: config::read_u16(address, REG_DEVICE_ID),
+            class: config::read_u8(address, REG_CLASS),
+            subclass: config::read_u8(address, REG_SUBCLASS),
+            prog_if: config::read_u8(address, REG_PROG_IF),
+            revision: config::read_u8(address, REG_REVISION),
+            header_type,
+            bars: [None; 6],
+            capabilities: capability::parse(address),
+            interrupt_pin: config::read_u8(address, REG_INTERRUPT_PIN),
+            interrupt_line: config::read_u8(address, REG_INTERRUPT_LINE),
+            msi: Mutex::new(None),
+        };
+
+        // bridges only have two BARs, card bus bridges none we care about
+        let bar_count = match header_type & !HEADER_TYPE_MULTI_FUNCTION {
+            0 => 6,
+            1 => 2,
+            _ => 0,
+        };
+        device.read_bars(bar_count);
+        Some(device)
+    }
+
+    /// Decodes the BARs, sizing each by writing all ones and seeing which bits stick. Decoding
+    /// is turned off meanwhile so the half written addresses don't claim anything.
+    fn read_bars(&mut self, count: usize) {
+        let command = self.read_u16(REG_COMMAND);
+        self.write_u16(REG_COMMAND, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+        let mut index = 0;
+        while index < count {
+            let offset = REG_BAR0 + 4 * index as u16;
+            let raw = self.read_u32(offset);
+            self.write_u32(offset, u32::MAX);
+            let mask = self.read_u32(offset);
+            self.write_u32(offset, raw);
+
+            if raw & 1 == 1 {
+                // only the low 16 bits of I/O BARs are implemented on x86
+                let mask = mask & 0xFFFC;
+                if mask != 0 {
+                    self.bars[index] = Some(Bar::Io {
+                        port: (raw & 0xFFFC) as u16,
+                        size: (!mask & 0xFFFF) + 1,
+                    });
+                }
+                index += 1;
+                continue;
+            }
+
+            let is_64bit = (raw >> 1) & 0b11 == 0b10 && index + 1 < count;
+            let mut address = (raw & !0xF) as u64;
+            let mut mask = (mask & !0xF) as u64 | 0xFFFF_FFFF_0000_0000;
+            if is_64bit {
+                let high_offset = offset + 4;
+                let high = self.read_u32(high_offset);
+                self.write_u32(high_offset, u32::MAX);
+                let high_mask = self.read_u32(high_offset);
+                self.write_u32(high_offset, high);
+                address |= (high as u64) << 32;
+                mask = (mask & 0xFFFF_FFFF) | (high_mask as u64) << 32;
+            }
+
+            if mask & 0xFFFF_FFF0 != 0 || is_64bit && mask >> 32 != 0 {
+                self.bars[index] = Some(Bar::Memory {
+                    address: PhysAddr::new(address),
+                    size: !mask + 1,
+                    prefetchable: raw & (1 << 3) != 0,
+                    is_64bit,
+                });
+            }
+            index += if is_64bit { 2 } else { 1 };
+        }
+
+        self.write_u16(REG_COMMAND, command);
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        config::read_u8(self.address, offset)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        config::read_u16(self.address, offset)
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        config::read_u32(self.address, offset)
+    }
+
+    pub fn write_u8(&self, offset: u16, value: u8) {
+        config::write_u8(self.address, offset, value)
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        config::write_u16(self.address, offset, value)
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        config::write_u32(self.address, offset, value)
+    }
+
+    fn set_command_bits(&self, bits: u16, enable: bool) {
+        let command = self.read_u16(REG_COMMAND);
+        let command = if enable { command | bits } else { command & !bits };
+        self.write_u16(REG_COMMAND, command);
+    }
+
+    /// Lets the function respond to its memory and I/O BARs and do DMA.
+    pub fn enable(&self) {
+        self.set_command_bits(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER, true);
+    }
+
+    /// Stops the function from raising its legacy interrupt, for when it uses MSI instead.
+    pub fn set_legacy_interrupt(&self, enable: bool) {
+        self.set_command_bits(COMMAND_INTERRUPT_DISABLE, !enable);
+    }
+
+    pub fn capability(&self, id: u8) -> Option<&Capability> {
+        self.capabilities.iter().find(|capability| capability.id() == id)
+    }
+
+    /// Maps memory BAR `index` and returns where its registers can be accessed.
+    pub fn map_bar(&self, index: usize) -> Result<VirtAddr, PciError> {
+        match self.bars.get(index).copied().flatten() {
+            Some(Bar::Memory { address, size, .. }) => {
+                crate::memory::map_mmio(address, size).map_err(|_| PciError::OutOfMemory)
+            }
+            _ => Err(PciError::BadBar),
+        }
+    }
+}
+
+impl Device for PciDevice {
+    fn name(&self) -> String {
+        self.address.to_string()
+    }
+
+    fn bus(&self) -> Bus {
+        Bus::Pci
+    }
+
+    fn as_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+/// Which functions a driver can take, checked against each function's configuration header.
+#[derive(Debug, Clone, Copy)]
+pub enum PciMatch {
+    Id { vendor: u16, device: u16 },
+    /// Any function of a vendor.
+    Vendor { vendor: u16 },
+    /// A class and subclass, and if given a programming interface.
+    Class { class: u8, subclass: u8, prog_if: Option<u8> },
+}
+
+impl PciMatch {
+    fn matches(&self, device: &PciDevice) -> bool {
+        match *self {
+            PciMatch::Id { vendor, device: id } => device.vendor_id == vendor && device.device_id == id,
+            PciMatch::Vendor { vendor } => device.vendor_id == vendor,
+            PciMatch::Class { class, subclass, prog_if } => {
+                device.class == class
+                    && device.subclass == subclass
+                    && prog_if.map_or(true, |prog_if| device.prog_if == prog_if)
+            }
+        }
+    }
+}
+
+pub trait PciDriver: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The functions this driver is able to take.
+    fn matches(&self) -> &'static [PciMatch];
+
+    /// Takes over `device`. Called once for every matching function that no other driver has
+    /// taken yet.
+    fn probe(&self, device: &Arc<PciDevice>) -> Result<(), PciError>;
+
+    /// Lets go of a function it took, by default drivers keep them.
+    fn remove(&self, _device: &Arc<PciDevice>) -> Result<(), DeviceError> {
+        Err(DeviceError::Busy)
+    }
+}
+
+/// Makes a PCI driver a driver of the device model.
+struct Adapter(&'static dyn PciDriver);
+
+impl Driver for Adapter {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    fn bus(&self) -> Bus {
+        Bus::Pci
+    }
+
+    fn matches(&self, device: &Arc<dyn Device>) -> bool {
+        match device::downcast::<PciDevice>(device) {
+            Some(device) => self.0.matches().iter().any(|id| id.matches(&device)),
+            None => false,
+        }
+    }
+
+    fn probe(&self, device: &Arc<dyn Device>) -> Result<(), DeviceError> {
+        let device = device::downcast::<PciDevice>(device).ok_or(DeviceError::Unsupported)?;
+        Ok(self.0.probe(&device)?)
+    }
+
+    fn remove(&self, device: &Arc<dyn Device>) -> Result<(), DeviceError> {
+        let device = device::downcast::<PciDevice>(device).ok_or(DeviceError::Unsupported)?;
+        self.0.remove(&device)
+    }
+}
+
+/// Adds `driver` to the drivers tried on new functions and offers it every function found so
+/// far that's still unclaimed.
+pub fn register_driver(driver: &'static dyn PciDriver) {
+    // drivers are never unregistered, so the adapter lives as long as the kernel
+    device::register_driver(Box::leak(Box::new(Adapter(driver))));
+}
+
+/// Every function found on the bus, in order of address.
+pub fn devices() -> Vec<Arc<PciDevice>> {
+    DEVICES.lock().clone()
+}
+
+/// Picks how to reach configuration space, enumerates every bus and adds the functions to the
+/// device registry, which binds the drivers registered so far. Returns how many functions were found.
+pub fn init() -> usize {
+    let mut found = Vec::new();
+    for (segment, start_bus, end_bus) in config::init() {
+        for bus in start_bus..=end_bus {
+            for device in 0..32 {
+                let address = PciAddress {
+                    segment,
+                    bus,
+                    device,
+                    function: 0,
+                };
+                let first = match PciDevice::probe(address) {
+                    Some(first) => first,
+                    None => continue,
+                };
+
+                let functions = match first.header_type & HEADER_TYPE_MULTI_FUNCTION {
+                    0 => 1,
+                    _ => 8,
+                };
+                found.push(Arc::new(first));
+                for function in 1..functions {
+                    let address = PciAddress { function, ..address };
+                    if let Some(device) = PciDevice::probe(address) {
+                        found.push(Arc::new(device));
+                    }
+                }
+            }
+        }
+    }
+
+    let count = found.len();
+    *DEVICES.lock() = found;
+    for function in devices() {
+        device::register_device(function, None);
+    }
+    count
+}
@@ -0,0 +1,157 @@
+use alloc::vec::Vec;
+
+use x86_64::VirtAddr;
+
+use super::capability::{Capability, MsiCapability, MsixCapability, CAPABILITY_MSI, CAPABILITY_MSIX};
+use super::{Bar, PciDevice, PciError, COMMAND_BUS_MASTER};
+use crate::arch::{self, InterruptHandler};
+use crate::memory;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+/// The most vectors multi-message MSI can give a function.
+const MSI_MAX_VECTORS: usize = 32;
+
+/// The message signalled interrupts a function was set up with.
+pub(super) enum MsiState {
+    /// A block of `count` vectors, which may be more than there are handlers.
+    Msi { first: u8, count: usize },
+    MsiX { vectors: Vec<u8> },
+}
+
+impl PciDevice {
+    /// Gives the function a vector of its own for each of `handlers` and turns its legacy
+    /// interrupt off. MSI-X is preferred, the handlers then go to table entries 0, 1 and so on,
+    /// with plain MSI they go to the function's message numbers. Returns the vectors in the
+    /// order of the handlers.
+    pub fn enable_msi(&self, handlers: Vec<InterruptHandler>) -> Result<Vec<u8>, PciError> {
+        if handlers.is_empty() || self.msi.lock().is_some() {
+            return Err(PciError::Unsupported);
+        }
+
+        let msix = self.capability(CAPABILITY_MSIX).copied();
+        let msi = self.capability(CAPABILITY_MSI).copied();
+        let (state, vectors) = match (msix, msi) {
+            (Some(Capability::MsiX(msix)), _) => self.enable_msix(&msix, handlers.len())?,
+            (_, Some(Capability::Msi(msi))) => self.enable_msi_block(&msi, handlers.len())?,
+            _ => return Err(PciError::MissingCapability),
+        };
+
+        for (&vector, handler) in vectors.iter().zip(handlers) {
+            arch::set_handler(vector, handler);
+        }
+        *self.msi.lock() = Some(state);
+
+        // the messages are memory writes, so the function has to be allowed to master the bus
+        self.set_command_bits(COMMAND_BUS_MASTER, true);
+        self.set_legacy_interrupt(false);
+        Ok(vectors)
+    }
+
+    /// Undoes `enable_msi`, releasing the vectors and turning the legacy interrupt back on.
+    pub fn disable_msi(&self) {
+        match self.msi.lock().take() {
+            Some(MsiState::Msi { first, count }) => {
+                if let Some(Capability::Msi(msi)) = self.capability(CAPABILITY_MSI) {
+                    let control = self.read_u16(msi.offset + 2);
+                    self.write_u16(msi.offset + 2, control & !MSI_CONTROL_ENABLE);
+                }
+                arch::free_vectors(first, count);
+            }
+            Some(MsiState::MsiX { vectors }) => {
+                if let Some(Capability::MsiX(msix)) = self.capability(CAPABILITY_MSIX) {
+                    let control = self.read_u16(msix.offset + 2);
+                    self.write_u16(msix.offset + 2, control & !MSIX_CONTROL_ENABLE);
+                }
+                for vector in vectors {
+                    arch::free_vectors(vector, 1);
+                }
+            }
+            None => return,
+        }
+        self.set_legacy_interrupt(true);
+    }
+
+    fn enable_msix(&self, msix: &MsixCapability, count: usize) -> Result<(MsiState, Vec<u8>), PciError> {
+        if count > msix.table_size as usize {
+            return Err(PciError::Unsupported);
+        }
+        let table = match self.bars.get(msix.table_bar as usize).copied().flatten() {
+            Some(Bar::Memory { address, .. }) => memory::map_mmio(
+                address + msix.table_offset as u64,
+                msix.table_size as u64 * MSIX_ENTRY_SIZE,
+            )
+            .map_err(|_| PciError::OutOfMemory)?,
+            _ => return Err(PciError::BadBar),
+        };
+
+        let mut vectors = Vec::new();
+        for _ in 0..count {
+            match arch::allocate_vectors(1) {
+                Some(vector) => vectors.push(vector),
+                None => {
+                    for vector in vectors {
+                        arch::free_vectors(vector, 1);
+                    }
+                    return Err(PciError::OutOfMemory);
+                }
+            }
+        }
+
+        // keep the whole function masked while the table is filled in
+        let control = self.read_u16(msix.offset + 2);
+        self.write_u16(msix.offset + 2, control | MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK);
+        for entry in 0..msix.table_size as usize {
+            let base = table + entry as u64 * MSIX_ENTRY_SIZE;
+            match vectors.get(entry) {
+                Some(&vector) => {
+                    let (address, data) = arch::msi_message(vector);
+                    write_register(base, 0, address as u32);
+                    write_register(base, 4, (address >> 32) as u32);
+                    write_register(base, 8, data);
+                    write_register(base, 12, 0);
+                }
+                None => write_register(base, 12, MSIX_VECTOR_MASKED),
+            }
+        }
+        self.write_u16(msix.offset + 2, (control | MSIX_CONTROL_ENABLE) & !MSIX_CONTROL_FUNCTION_MASK);
+
+        Ok((MsiState::MsiX { vectors: vectors.clone() }, vectors))
+    }
+
+    fn enable_msi_block(&self, msi: &MsiCapability, count: usize) -> Result<(MsiState, Vec<u8>), PciError> {
+        // the function gets a power of two of vectors and raises the others by changing the
+        // low bits of the message data
+        let block = count.next_power_of_two();
+        if block > MSI_MAX_VECTORS || block > 1 << msi.max_vectors_log2 {
+            return Err(PciError::Unsupported);
+        }
+        let first = arch::allocate_vectors(block).ok_or(PciError::OutOfMemory)?;
+
+        let (address, data) = arch::msi_message(first);
+        let data_offset = if msi.is_64bit { 12 } else { 8 };
+        self.write_u32(msi.offset + 4, address as u32);
+        if msi.is_64bit {
+            self.write_u32(msi.offset + 8, (address >> 32) as u32);
+        }
+        self.write_u16(msi.offset + data_offset, data as u16);
+        if msi.per_vector_masking {
+            self.write_u32(msi.offset + data_offset + 4, 0);
+        }
+
+        // multiple message enable takes log2 of the block size
+        let control = self.read_u16(msi.offset + 2) & !(0b111 << 4);
+        let enabled = (block.trailing_zeros() as u16) << 4;
+        self.write_u16(msi.offset + 2, control | enabled | MSI_CONTROL_ENABLE);
+
+        let vectors = (first..first + count as u8).collect();
+        Ok((MsiState::Msi { first, count: block }, vectors))
+    }
+}
+
+fn write_register(base: VirtAddr, offset: u64, value: u32) {
+    unsafe { core::ptr::write_volatile((base + offset).as_mut_ptr::<u32>(), value) }
+}
@@ -1,10 +1,44 @@
+use alloc::sync::Arc;
 use core::cell::OnceCell;
 
 use spin::Mutex;
 use uart_16550::SerialPort;
 
+use crate::device::platform::{PlatformDevice, PlatformDriver};
+use crate::device::DeviceError;
+
 pub static DEBUG_SERIAL: Mutex<OnceCell<SerialPort>> = Mutex::new(OnceCell::new());
 
+pub static DRIVER: SerialDriver = SerialDriver;
+
+/// Drives the first serial port as `DEBUG_SERIAL`, which is set up before anything else to
+/// log the boot, so probing adopts it rather than resetting it.
+pub struct SerialDriver;
+
+impl PlatformDriver for SerialDriver {
+    fn name(&self) -> &'static str {
+        "uart16550"
+    }
+
+    fn kind(&self) -> &'static str {
+        "serial"
+    }
+
+    fn probe(&self, device: &Arc<PlatformDevice>) -> Result<(), DeviceError> {
+        // only the debug port has somewhere to go
+        if device.index != 0 {
+            return Err(DeviceError::Unsupported);
+        }
+        let base = device.io_ports(0)?;
+        DEBUG_SERIAL.lock().get_or_init(|| {
+            let mut serial = unsafe { SerialPort::new(base) };
+            serial.init();
+            serial
+        });
+        Ok(())
+    }
+}
+
 #[doc(hidden)]
 pub fn _serial_print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
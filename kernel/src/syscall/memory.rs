@@ -0,0 +1,81 @@
+use x86_64::VirtAddr;
+
+use crate::memory::address_space::Protection;
+use crate::memory::{ADDRESS_SPACE, FRAME_ALLOCATOR};
+
+use super::{Errno, SyscallResult};
+
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const PROT_EXEC: u64 = 0x4;
+
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+fn protection_from_bits(prot: u64) -> Result<Protection, Errno> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Errno::InvalidArgument);
+    }
+
+    let mut protection = Protection::empty();
+    protection.set(Protection::READ, prot & PROT_READ != 0);
+    protection.set(Protection::WRITE, prot & PROT_WRITE != 0);
+    protection.set(Protection::EXEC, prot & PROT_EXEC != 0);
+    Ok(protection)
+}
+
+fn user_address(address: u64) -> Result<VirtAddr, Errno> {
+    VirtAddr::try_new(address).map_err(|_| Errno::InvalidArgument)
+}
+
+/// Maps `len` bytes of anonymous memory. Only anonymous mappings are supported, so `fd` and
+/// `offset` are ignored.
+pub fn mmap(addr: u64, len: u64, prot: u64, flags: u64, _fd: u64, _offset: u64) -> SyscallResult {
+    let protection = protection_from_bits(prot)?;
+    // exactly one of shared and private has to be given, without fork they behave the same
+    if (flags & MAP_SHARED != 0) == (flags & MAP_PRIVATE != 0) {
+        return Err(Errno::InvalidArgument);
+    }
+    if flags & MAP_ANONYMOUS == 0 {
+        return Err(Errno::NoDevice);
+    }
+
+    let fixed = flags & MAP_FIXED != 0;
+    let hint = match addr {
+        0 if !fixed => None,
+        addr => Some(user_address(addr)?),
+    };
+
+    let mut space_lock = ADDRESS_SPACE.lock();
+    let address_space = space_lock.get_mut().ok_or(Errno::OutOfMemory)?;
+    let mut allocator_lock = FRAME_ALLOCATOR.lock();
+    let frame_allocator = allocator_lock.get_mut().ok_or(Errno::OutOfMemory)?;
+
+    let start = address_space.map_anonymous(hint, len, protection, fixed, frame_allocator)?;
+    Ok(start.as_u64())
+}
+
+pub fn munmap(addr: u64, len: u64) -> SyscallResult {
+    let start = user_address(addr)?;
+
+    let mut space_lock = ADDRESS_SPACE.lock();
+    let address_space = space_lock.get_mut().ok_or(Errno::OutOfMemory)?;
+    let mut allocator_lock = FRAME_ALLOCATOR.lock();
+    let frame_allocator = allocator_lock.get_mut().ok_or(Errno::OutOfMemory)?;
+
+    address_space.unmap(start, len, frame_allocator)?;
+    Ok(0)
+}
+
+pub fn mprotect(addr: u64, len: u64, prot: u64) -> SyscallResult {
+    let start = user_address(addr)?;
+    let protection = protection_from_bits(prot)?;
+
+    let mut space_lock = ADDRESS_SPACE.lock();
+    let address_space = space_lock.get_mut().ok_or(Errno::OutOfMemory)?;
+
+    address_space.protect(start, len, protection)?;
+    Ok(0)
+}
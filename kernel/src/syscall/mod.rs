@@ -0,0 +1,65 @@
+mod memory;
+
+use crate::memory::address_space::VmError;
+
+/// System call numbers, these follow the Linux x86_64 numbering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallNumber {
+    Mmap = 9,
+    Mprotect = 10,
+    Munmap = 11,
+}
+
+impl TryFrom<u64> for SyscallNumber {
+    type Error = Errno;
+
+    fn try_from(number: u64) -> Result<Self, Self::Error> {
+        match number {
+            9 => Ok(SyscallNumber::Mmap),
+            10 => Ok(SyscallNumber::Mprotect),
+            11 => Ok(SyscallNumber::Munmap),
+            _ => Err(Errno::NotImplemented),
+        }
+    }
+}
+
+/// Error codes returned to the caller negated, with the same values as Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    OutOfMemory = 12,
+    PermissionDenied = 13,
+    NoDevice = 19,
+    InvalidArgument = 22,
+    NotImplemented = 38,
+}
+
+impl From<VmError> for Errno {
+    fn from(err: VmError) -> Self {
+        match err {
+            VmError::InvalidRange => Errno::InvalidArgument,
+            VmError::NoSpace | VmError::OutOfMemory | VmError::NotMapped => Errno::OutOfMemory,
+            // system calls never run with the memory manager locked
+            VmError::Reentrant => Errno::OutOfMemory,
+            VmError::AccessViolation => Errno::PermissionDenied,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+/// Runs the system call `number` with the given arguments. The return value is what ends up
+/// in the caller's rax: the result on success, or the negated error code.
+pub fn dispatch(number: u64, args: [u64; 6]) -> i64 {
+    let result = SyscallNumber::try_from(number).and_then(|number| match number {
+        SyscallNumber::Mmap => memory::mmap(args[0], args[1], args[2], args[3], args[4], args[5]),
+        SyscallNumber::Mprotect => memory::mprotect(args[0], args[1], args[2]),
+        SyscallNumber::Munmap => memory::munmap(args[0], args[1]),
+    });
+
+    match result {
+        Ok(value) => value as i64,
+        Err(errno) => -(errno as i64),
+    }
+}
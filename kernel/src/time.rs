@@ -0,0 +1,89 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// How many times per second the timer interrupt fires.
+pub const TICK_HZ: u64 = 100;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// seconds since the Unix epoch at the time the clock was initialized
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Records the wall clock time at boot, read from the real time clock.
+pub fn init(unix_time: u64) {
+    BOOT_TIME.store(unix_time, Ordering::Relaxed);
+}
+
+/// Called from the timer interrupt handler.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of timer ticks since interrupts were enabled.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since interrupts were enabled.
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TICK_HZ
+}
+
+/// The current wall clock time in seconds since the Unix epoch.
+pub fn now() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + ticks() / TICK_HZ
+}
+
+/// A UTC calendar date and time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u64,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Converts to seconds since the Unix epoch.
+    pub fn to_unix(&self) -> u64 {
+        // days from the civil calendar, counting years from March so the leap day comes last
+        let (year, month) = match self.month <= 2 {
+            true => (self.year - 1, self.month as u64 + 9),
+            false => (self.year, self.month as u64 - 3),
+        };
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        days * 86_400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    /// Converts from seconds since the Unix epoch, the inverse of `to_unix`.
+    pub fn from_unix(unix_time: u64) -> DateTime {
+        let days = unix_time / 86_400 + 719_468;
+        let seconds = unix_time % 86_400;
+
+        let era = days / 146_097;
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let (year, month) = match month < 10 {
+            true => (year_of_era + era * 400, month + 3),
+            false => (year_of_era + era * 400 + 1, month - 9),
+        };
+
+        DateTime {
+            year,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
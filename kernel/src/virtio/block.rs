@@ -0,0 +1,240 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use super::bus::{VirtioDevice, VirtioDriver};
+use super::queue::{Buffer, VirtQueue};
+use super::{transport, Transport, VirtioError, DEVICE_BLOCK};
+use crate::block::{self, BlockDevice, BlockError};
+use crate::memory::dma::DmaBuffer;
+use crate::memory::PAGE_SIZE;
+use crate::pci::capability::CAPABILITY_MSIX;
+use crate::pci::PciDevice;
+
+// features
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+const STATUS_OK: u8 = 0;
+
+/// Capacity is always counted in 512 byte sectors, whatever the device's block size.
+const SECTOR_SIZE: usize = 512;
+/// Most queue entries we ask for, a request only ever needs three.
+const MAX_QUEUE_SIZE: u16 = 16;
+/// Requests go through a bounce buffer of this many pages, since heap memory isn't
+/// physically contiguous.
+const BOUNCE_PAGES: usize = 16;
+
+pub static DRIVER: VirtioBlkDriver = VirtioBlkDriver;
+
+/// Numbers the disks "virtio0", "virtio1" and so on.
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+pub struct VirtioBlkDriver;
+
+impl VirtioDriver for VirtioBlkDriver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn device_types(&self) -> &'static [u16] {
+        &[DEVICE_BLOCK]
+    }
+
+    fn probe(&self, virtio: &Arc<VirtioDevice>) -> Result<(), VirtioError> {
+        let device = &virtio.pci;
+        device.enable();
+        let disk = VirtioBlk::new(device)?;
+        let name = format!("virtio{}", NEXT_INDEX.fetch_add(1, Ordering::Relaxed));
+        crate::log(&format!(
+            "{}: {} on {}, {} sectors{}",
+            name,
+            self.name(),
+            device.address,
+            disk.capacity,
+            if disk.read_only { ", read only" } else { "" }
+        ));
+        block::register(&name, Arc::new(disk)).map_err(|_| VirtioError::DeviceError)?;
+        Ok(())
+    }
+}
+
+/// A virtio block device, with one request in flight at a time.
+pub struct VirtioBlk {
+    transport: Box<dyn Transport>,
+    capacity: u64,
+    read_only: bool,
+    flush: bool,
+    state: Mutex<State>,
+}
+
+struct State {
+    queue: VirtQueue,
+    // one page for the request header and status, then the bounce buffer
+    memory: DmaBuffer,
+    // a request that timed out, the device may still use the memory until it's done with it
+    stale: Option<u16>,
+}
+
+impl VirtioBlk {
+    fn new(device: &Arc<PciDevice>) -> Result<VirtioBlk, VirtioError> {
+        let transport = transport::open(device)?;
+        let (features, state) = match VirtioBlk::set_up(device, &*transport) {
+            Ok(set_up) => set_up,
+            Err(err) => {
+                super::fail(&*transport);
+                device.disable_msi();
+                return Err(err);
+            }
+        };
+        super::start(&*transport);
+
+        let mut capacity = [0; 8];
+        transport.read_config(0, &mut capacity);
+        Ok(VirtioBlk {
+            transport,
+            capacity: u64::from_le_bytes(capacity),
+            read_only: features & FEATURE_READ_ONLY != 0,
+            flush: features & FEATURE_FLUSH != 0,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Negotiates features and sets up the request queue and memory.
+    fn set_up(device: &Arc<PciDevice>, transport: &dyn Transport) -> Result<(u64, State), VirtioError> {
+        let features = super::negotiate(transport, FEATURE_READ_ONLY | FEATURE_FLUSH)?;
+
+        // with MSI-X the queue interrupt wakes us up while we wait, there's nothing else to do
+        // in the handler
+        let msix = device.capability(CAPABILITY_MSIX).is_some()
+            && device.enable_msi(vec![Box::new(|_| {})]).is_ok();
+
+        let max_size = transport.max_queue_size(0);
+        let size = match transport.fixed_queue_size() {
+            true => max_size,
+            false => u16::min(max_size, MAX_QUEUE_SIZE),
+        };
+        let mut queue = VirtQueue::new(0, size)?;
+        if msix && transport.enable_queue(&queue, Some(0)).is_ok() {
+            queue.set_interrupts(true);
+        } else {
+            transport.enable_queue(&queue, None)?;
+        }
+
+        let memory = DmaBuffer::allocate_pages(BOUNCE_PAGES + 1).map_err(|_| VirtioError::OutOfMemory)?;
+        Ok((features, State { queue, memory, stale: None }))
+    }
+
+    fn check_range(&self, sector: u64, len: usize) -> Result<(), BlockError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(BlockError::BadBufferSize);
+        }
+        match sector.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.capacity => Ok(()),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+
+    /// Sends one request and waits for it. `data` is the number of bytes of the bounce buffer
+    /// that go along with it, in the direction the request type implies.
+    fn request(&self, state: &mut State, kind: u32, sector: u64, data: usize) -> Result<(), BlockError> {
+        if let Some(head) = state.stale {
+            state.queue.wait(head).map_err(|_| BlockError::Io)?;
+            state.stale = None;
+        }
+
+        let header = state.memory.virtual_address().as_mut_ptr::<u8>();
+        unsafe {
+            header.cast::<u32>().write_volatile(kind);
+            header.add(4).cast::<u32>().write_volatile(0);
+            header.add(8).cast::<u64>().write_volatile(sector);
+            header.add(16).write_volatile(0xFF);
+        }
+
+        let header_buffer = Buffer {
+            address: state.memory.physical(),
+            length: 16,
+            device_writable: false,
+        };
+        let data_buffer = Buffer {
+            address: state.memory.physical() + PAGE_SIZE,
+            length: data as u32,
+            device_writable: kind == REQUEST_IN,
+        };
+        let status_buffer = Buffer {
+            address: state.memory.physical() + 16u64,
+            length: 1,
+            device_writable: true,
+        };
+        let result = match data {
+            0 => state.queue.add(&[header_buffer, status_buffer]),
+            _ => state.queue.add(&[header_buffer, data_buffer, status_buffer]),
+        };
+        let head = result.map_err(|_| BlockError::Io)?;
+        self.transport.notify(state.queue.index());
+        if state.queue.wait(head).is_err() {
+            state.stale = Some(head);
+            return Err(BlockError::Io);
+        }
+
+        match unsafe { header.add(16).read_volatile() } {
+            STATUS_OK => Ok(()),
+            _ => Err(BlockError::Io),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(sector, buf.len())?;
+        let mut state = self.state.lock();
+        let bounce = (state.memory.virtual_address() + PAGE_SIZE).as_ptr::<u8>();
+
+        for (i, chunk) in buf.chunks_mut(BOUNCE_PAGES * PAGE_SIZE as usize).enumerate() {
+            let first = sector + (i * BOUNCE_PAGES * PAGE_SIZE as usize / SECTOR_SIZE) as u64;
+            self.request(&mut state, REQUEST_IN, first, chunk.len())?;
+            unsafe { core::ptr::copy_nonoverlapping(bounce, chunk.as_mut_ptr(), chunk.len()) };
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        self.check_range(sector, buf.len())?;
+        let mut state = self.state.lock();
+        let bounce = (state.memory.virtual_address() + PAGE_SIZE).as_mut_ptr::<u8>();
+
+        for (i, chunk) in buf.chunks(BOUNCE_PAGES * PAGE_SIZE as usize).enumerate() {
+            let first = sector + (i * BOUNCE_PAGES * PAGE_SIZE as usize / SECTOR_SIZE) as u64;
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), bounce, chunk.len()) };
+            self.request(&mut state, REQUEST_OUT, first, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        // without the feature the device writes through
+        if !self.flush {
+            return Ok(());
+        }
+        let mut state = self.state.lock();
+        self.request(&mut state, REQUEST_FLUSH, 0, 0)
+    }
+}
@@ -0,0 +1,124 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::any::Any;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{VirtioError, VENDOR_ID};
+use crate::device::{self, Bus, Device, DeviceError, Driver};
+use crate::pci::{PciDevice, PciDriver, PciError, PciMatch};
+
+/// Legacy devices only tell what they are in the subsystem id.
+const REG_SUBSYSTEM_ID: u16 = 0x2E;
+
+pub static PCI_DRIVER: VirtioPciDriver = VirtioPciDriver;
+
+/// Numbers the devices "virtio0", "virtio1" and so on.
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// A virtio device, found on a PCI function.
+pub struct VirtioDevice {
+    index: usize,
+    /// What kind of device it is, like `DEVICE_BLOCK`.
+    pub device_type: u16,
+    /// The function the device is reached through.
+    pub pci: Arc<PciDevice>,
+}
+
+impl Device for VirtioDevice {
+    fn name(&self) -> String {
+        format!("virtio{}", self.index)
+    }
+
+    fn bus(&self) -> Bus {
+        Bus::Virtio
+    }
+
+    fn as_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+pub trait VirtioDriver: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The kinds of devices this driver takes.
+    fn device_types(&self) -> &'static [u16];
+
+    fn probe(&self, device: &Arc<VirtioDevice>) -> Result<(), VirtioError>;
+
+    fn remove(&self, _device: &Arc<VirtioDevice>) -> Result<(), DeviceError> {
+        Err(DeviceError::Busy)
+    }
+}
+
+/// Makes a virtio driver a driver of the device model.
+struct Adapter(&'static dyn VirtioDriver);
+
+impl Driver for Adapter {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    fn bus(&self) -> Bus {
+        Bus::Virtio
+    }
+
+    fn matches(&self, device: &Arc<dyn Device>) -> bool {
+        match device::downcast::<VirtioDevice>(device) {
+            Some(device) => self.0.device_types().contains(&device.device_type),
+            None => false,
+        }
+    }
+
+    fn probe(&self, device: &Arc<dyn Device>) -> Result<(), DeviceError> {
+        let device = device::downcast::<VirtioDevice>(device).ok_or(DeviceError::Unsupported)?;
+        Ok(self.0.probe(&device)?)
+    }
+
+    fn remove(&self, device: &Arc<dyn Device>) -> Result<(), DeviceError> {
+        let device = device::downcast::<VirtioDevice>(device).ok_or(DeviceError::Unsupported)?;
+        self.0.remove(&device)
+    }
+}
+
+pub fn register_driver(driver: &'static dyn VirtioDriver) {
+    // drivers are never unregistered, so the adapter lives as long as the kernel
+    device::register_driver(Box::leak(Box::new(Adapter(driver))));
+}
+
+/// Takes the virtio PCI functions and puts the devices behind them on the virtio bus.
+pub struct VirtioPciDriver;
+
+impl PciDriver for VirtioPciDriver {
+    fn name(&self) -> &'static str {
+        "virtio-pci"
+    }
+
+    fn matches(&self) -> &'static [PciMatch] {
+        &[PciMatch::Vendor { vendor: VENDOR_ID }]
+    }
+
+    fn probe(&self, device: &Arc<PciDevice>) -> Result<(), PciError> {
+        // transitional devices have ids from 0x1000, modern ones 0x1040 plus the type
+        let device_type = match device.device_id {
+            0x1000..=0x103F => device.read_u16(REG_SUBSYSTEM_ID),
+            0x1040..=0x107F => device.device_id - 0x1040,
+            _ => return Err(PciError::Unsupported),
+        };
+
+        let virtio = Arc::new(VirtioDevice {
+            index: NEXT_INDEX.fetch_add(1, Ordering::Relaxed),
+            device_type,
+            pci: device.clone(),
+        });
+        device::register_device(virtio, Some(device.name()));
+        Ok(())
+    }
+
+    fn remove(&self, _device: &Arc<PciDevice>) -> Result<(), DeviceError> {
+        // the virtio device on the function is removed before it
+        Ok(())
+    }
+}
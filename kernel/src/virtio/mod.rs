@@ -0,0 +1,110 @@
+pub mod block;
+pub mod bus;
+pub mod queue;
+pub mod transport;
+
+use crate::device::DeviceError;
+use queue::VirtQueue;
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+// device types
+pub const DEVICE_BLOCK: u16 = 2;
+
+// device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 0x80;
+
+/// Set by devices that follow virtio 1.0 and later, which the modern transport requires.
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// Every descriptor of the queue is in use.
+    QueueFull,
+    /// The queue doesn't exist or can't be set up.
+    BadQueue,
+    /// The device didn't accept the features we asked for.
+    FeaturesRejected,
+    /// The PCI function doesn't have the registers a transport needs.
+    BadTransport,
+    OutOfMemory,
+    /// The device failed a request or didn't complete it in time.
+    DeviceError,
+}
+
+impl From<VirtioError> for DeviceError {
+    fn from(err: VirtioError) -> DeviceError {
+        match err {
+            VirtioError::OutOfMemory => DeviceError::OutOfMemory,
+            VirtioError::BadTransport => DeviceError::MissingResource,
+            _ => DeviceError::Failed,
+        }
+    }
+}
+
+/// How a driver reaches the registers of a virtio device, independent of the bus and of
+/// which version of the interface the device implements.
+pub trait Transport: Send + Sync {
+    fn device_features(&self) -> u64;
+
+    fn set_driver_features(&self, features: u64);
+
+    fn status(&self) -> u8;
+
+    /// Writing 0 resets the device.
+    fn set_status(&self, status: u8);
+
+    /// Largest size queue `index` can have, 0 if there's no such queue.
+    fn max_queue_size(&self, index: u16) -> u16;
+
+    /// Whether queues have to be exactly `max_queue_size` big, like with legacy devices.
+    fn fixed_queue_size(&self) -> bool;
+
+    /// Hands the rings of `queue` to the device. With `msix_entry` the device signals used
+    /// buffers through that MSI-X table entry, otherwise it doesn't interrupt for them.
+    fn enable_queue(&self, queue: &VirtQueue, msix_entry: Option<u16>) -> Result<(), VirtioError>;
+
+    /// Tells the device there are new buffers in queue `index`.
+    fn notify(&self, index: u16);
+
+    /// Reads the device specific configuration starting at `offset`.
+    fn read_config(&self, offset: usize, buf: &mut [u8]);
+}
+
+/// Resets the device and agrees on the features both it and the driver know, out of
+/// `supported`. Returns the features in use. Queues are set up after this, then the device is
+/// started with `start`.
+pub fn negotiate(transport: &dyn Transport, supported: u64) -> Result<u64, VirtioError> {
+    transport.set_status(0);
+    transport.set_status(STATUS_ACKNOWLEDGE);
+    transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+    let features = transport.device_features() & (supported | FEATURE_VERSION_1);
+    transport.set_driver_features(features);
+
+    // legacy devices don't know about FEATURES_OK and take whatever they get
+    if features & FEATURE_VERSION_1 != 0 {
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+        if transport.status() & STATUS_FEATURES_OK == 0 {
+            transport.set_status(STATUS_FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+    }
+    Ok(features)
+}
+
+/// Lets the device start processing its queues.
+pub fn start(transport: &dyn Transport) {
+    let status = transport.status();
+    transport.set_status(status | STATUS_DRIVER_OK);
+}
+
+/// Gives up on the device after something went wrong setting it up.
+pub fn fail(transport: &dyn Transport) {
+    let status = transport.status();
+    transport.set_status(status | STATUS_FAILED);
+}
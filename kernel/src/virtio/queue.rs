@@ -0,0 +1,259 @@
+use core::sync::atomic::{fence, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::PhysAddr;
+
+use super::VirtioError;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::PAGE_SIZE;
+
+const DESCRIPTOR_SIZE: usize = 16;
+const DESCRIPTOR_NEXT: u16 = 1;
+const DESCRIPTOR_WRITE: u16 = 2;
+
+const TIMEOUT_MS: u64 = 5000;
+/// How often the used ring is checked before giving up when interrupts are off and the clock
+/// doesn't advance.
+const POLL_LIMIT: usize = 100_000_000;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// One piece of a request, a physically contiguous buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub length: u32,
+    /// Whether the device writes to the buffer rather than reading it.
+    pub device_writable: bool,
+}
+
+/// A split virtqueue: a table of descriptors, the ring of chains made available to the
+/// device and the ring of chains it's done with.
+///
+/// The three parts are laid out the way legacy devices expect, back to back with the used
+/// ring starting on a new page, which modern devices are fine with too.
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    available_offset: usize,
+    used_offset: usize,
+    // head of the list of free descriptors, chained through `next`
+    free_head: u16,
+    free_count: u16,
+    // how far we've read the used ring
+    last_used: u16,
+    // whether the device interrupts when it uses buffers
+    interrupts: bool,
+}
+
+impl VirtQueue {
+    /// Allocates queue `index` with room for `size` descriptors.
+    pub fn new(index: u16, size: u16) -> Result<VirtQueue, VirtioError> {
+        if size == 0 || !size.is_power_of_two() {
+            return Err(VirtioError::BadQueue);
+        }
+
+        let count = size as usize;
+        let page = PAGE_SIZE as usize;
+        let available_offset = count * DESCRIPTOR_SIZE;
+        // flags, index, ring and the used event
+        let available_size = 6 + 2 * count;
+        let used_offset = (available_offset + available_size + page - 1) / page * page;
+        let used_size = 6 + 8 * count;
+        let pages = (used_offset + used_size + page - 1) / page;
+
+        let memory = DmaBuffer::allocate_pages(pages).map_err(|_| VirtioError::OutOfMemory)?;
+        let queue = VirtQueue {
+            index,
+            size,
+            memory,
+            available_offset,
+            used_offset,
+            free_head: 0,
+            free_count: size,
+            last_used: 0,
+            interrupts: false,
+        };
+        for i in 0..size {
+            queue.write_descriptor(i, Descriptor {
+                address: 0,
+                length: 0,
+                flags: 0,
+                next: (i + 1) % size,
+            });
+        }
+        Ok(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn descriptor_address(&self) -> PhysAddr {
+        self.memory.physical()
+    }
+
+    pub fn available_address(&self) -> PhysAddr {
+        self.memory.physical() + self.available_offset
+    }
+
+    pub fn used_address(&self) -> PhysAddr {
+        self.memory.physical() + self.used_offset
+    }
+
+    /// Has `wait` sleep until an interrupt instead of spinning, for queues the device
+    /// interrupts for.
+    pub fn set_interrupts(&mut self, interrupts: bool) {
+        self.interrupts = interrupts;
+    }
+
+    fn pointer<T>(&self, offset: usize) -> *mut T {
+        (self.memory.virtual_address() + offset).as_mut_ptr()
+    }
+
+    fn write_descriptor(&self, index: u16, descriptor: Descriptor) {
+        let pointer = self.pointer::<Descriptor>(index as usize * DESCRIPTOR_SIZE);
+        unsafe { pointer.write_volatile(descriptor) };
+    }
+
+    fn read_descriptor(&self, index: u16) -> Descriptor {
+        let pointer = self.pointer::<Descriptor>(index as usize * DESCRIPTOR_SIZE);
+        unsafe { pointer.read_volatile() }
+    }
+
+    /// Chains `buffers` together and makes them available to the device, returning the
+    /// index of the first descriptor, which identifies the chain once it's used. The device
+    /// still has to be notified.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Result<u16, VirtioError> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return Err(VirtioError::QueueFull);
+        }
+
+        let head = self.free_head;
+        let mut current = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let next = self.read_descriptor(current).next;
+            let mut flags = if buffer.device_writable { DESCRIPTOR_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESCRIPTOR_NEXT;
+            }
+            self.write_descriptor(current, Descriptor {
+                address: buffer.address.as_u64(),
+                length: buffer.length,
+                flags,
+                next,
+            });
+            if i + 1 < buffers.len() {
+                current = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        // the ring entry has to be visible before the index that publishes it
+        let available_index = unsafe { self.pointer::<u16>(self.available_offset + 2).read_volatile() };
+        let slot = self.available_offset + 4 + 2 * (available_index % self.size) as usize;
+        unsafe { self.pointer::<u16>(slot).write_volatile(head) };
+        fence(Ordering::SeqCst);
+        unsafe {
+            self.pointer::<u16>(self.available_offset + 2)
+                .write_volatile(available_index.wrapping_add(1))
+        };
+        fence(Ordering::SeqCst);
+
+        Ok(head)
+    }
+
+    /// Whether the device has used chains we haven't looked at yet.
+    pub fn has_used(&self) -> bool {
+        fence(Ordering::SeqCst);
+        let used_index = unsafe { self.pointer::<u16>(self.used_offset + 2).read_volatile() };
+        used_index != self.last_used
+    }
+
+    /// Takes the next chain the device is done with, returning its first descriptor and how
+    /// many bytes the device wrote. Its descriptors are free again afterwards.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+
+        let slot = self.used_offset + 4 + 8 * (self.last_used % self.size) as usize;
+        let head = unsafe { self.pointer::<u32>(slot).read_volatile() } as u16;
+        let length = unsafe { self.pointer::<u32>(slot + 4).read_volatile() };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // put the chain back on the free list
+        let mut last = head;
+        let mut count = 1;
+        loop {
+            let descriptor = self.read_descriptor(last);
+            if descriptor.flags & DESCRIPTOR_NEXT == 0 {
+                break;
+            }
+            last = descriptor.next;
+            count += 1;
+        }
+        let mut descriptor = self.read_descriptor(last);
+        descriptor.next = self.free_head;
+        self.write_descriptor(last, descriptor);
+        self.free_head = head;
+        self.free_count += count;
+
+        Some((head, length))
+    }
+
+    /// Waits for the device to use the chain starting at `head` and takes it, returning how many
+    /// bytes the device wrote. Chains used before it are taken too and dropped, they're late
+    /// completions of requests that already timed out.
+    ///
+    /// Sleeps until an interrupt if the queue has them and interrupts are enabled, syscalls run
+    /// with them disabled so there we poll.
+    pub fn wait(&mut self, head: u16) -> Result<u32, VirtioError> {
+        if !self.interrupts || !interrupts::are_enabled() {
+            for _ in 0..POLL_LIMIT {
+                match self.pop_used() {
+                    Some((used, length)) if used == head => return Ok(length),
+                    Some(_) => continue,
+                    None => core::hint::spin_loop(),
+                }
+            }
+            return Err(VirtioError::DeviceError);
+        }
+
+        let start = crate::time::uptime_ms();
+        loop {
+            // checking and halting have to be atomic, or the interrupt could arrive in between
+            // and leave us asleep until the next one
+            interrupts::disable();
+            match self.pop_used() {
+                Some((used, length)) if used == head => {
+                    interrupts::enable();
+                    return Ok(length);
+                }
+                Some(_) => {
+                    interrupts::enable();
+                    continue;
+                }
+                None => interrupts::enable_and_hlt(),
+            }
+
+            if crate::time::uptime_ms() - start > TIMEOUT_MS {
+                return Err(VirtioError::DeviceError);
+            }
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+
+use super::queue::VirtQueue;
+use super::{Transport, VirtioError};
+use crate::memory;
+use crate::pci::capability::{Capability, CAPABILITY_MSIX, CAPABILITY_VENDOR};
+use crate::pci::{Bar, PciDevice};
+
+/// Written as an MSI-X entry to leave an event without an interrupt.
+const NO_VECTOR: u16 = 0xFFFF;
+
+// legacy registers, offsets into the I/O BAR
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_CONFIG_VECTOR: u16 = 0x14;
+const LEGACY_QUEUE_VECTOR: u16 = 0x16;
+/// Where the device configuration starts, it moves back when MSI-X is on.
+const LEGACY_CONFIG: u16 = 0x14;
+const LEGACY_CONFIG_MSIX: u16 = 0x18;
+
+// types of the vendor capabilities modern devices describe their registers with
+const CAP_COMMON_CONFIG: u8 = 1;
+const CAP_NOTIFY_CONFIG: u8 = 2;
+const CAP_DEVICE_CONFIG: u8 = 4;
+
+// modern common configuration registers
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_CONFIG_MSIX_VECTOR: usize = 0x10;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_CONFIG_GENERATION: usize = 0x15;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1A;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFFSET: usize = 0x1E;
+const COMMON_QUEUE_DESCRIPTORS: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+/// Picks the transport for a virtio PCI function: the modern one if the function describes
+/// its registers with capabilities, the legacy I/O port interface otherwise.
+pub fn open(device: &Arc<PciDevice>) -> Result<Box<dyn Transport>, VirtioError> {
+    match ModernTransport::new(device) {
+        Some(transport) => Ok(Box::new(transport)),
+        None => Ok(Box::new(LegacyTransport::new(device)?)),
+    }
+}
+
+/// The interface of devices from before virtio 1.0, all registers in I/O BAR 0.
+pub struct LegacyTransport {
+    device: Arc<PciDevice>,
+    port: u16,
+}
+
+impl LegacyTransport {
+    fn new(device: &Arc<PciDevice>) -> Result<LegacyTransport, VirtioError> {
+        match device.bars[0] {
+            Some(Bar::Io { port, .. }) => Ok(LegacyTransport {
+                device: device.clone(),
+                port,
+            }),
+            _ => Err(VirtioError::BadTransport),
+        }
+    }
+
+    fn msix_enabled(&self) -> bool {
+        match self.device.capability(CAPABILITY_MSIX) {
+            Some(capability) => self.device.read_u16(capability.offset() + 2) & (1 << 15) != 0,
+            None => false,
+        }
+    }
+
+    fn read8(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.port + register).read() }
+    }
+
+    fn read16(&self, register: u16) -> u16 {
+        unsafe { Port::new(self.port + register).read() }
+    }
+
+    fn read32(&self, register: u16) -> u32 {
+        unsafe { Port::new(self.port + register).read() }
+    }
+
+    fn write8(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.port + register).write(value) }
+    }
+
+    fn write16(&self, register: u16, value: u16) {
+        unsafe { Port::new(self.port + register).write(value) }
+    }
+
+    fn write32(&self, register: u16, value: u32) {
+        unsafe { Port::new(self.port + register).write(value) }
+    }
+}
+
+impl Transport for LegacyTransport {
+    fn device_features(&self) -> u64 {
+        self.read32(LEGACY_DEVICE_FEATURES) as u64
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.write32(LEGACY_DRIVER_FEATURES, features as u32);
+    }
+
+    fn status(&self) -> u8 {
+        self.read8(LEGACY_DEVICE_STATUS)
+    }
+
+    fn set_status(&self, status: u8) {
+        self.write8(LEGACY_DEVICE_STATUS, status);
+    }
+
+    fn max_queue_size(&self, index: u16) -> u16 {
+        self.write16(LEGACY_QUEUE_SELECT, index);
+        self.read16(LEGACY_QUEUE_SIZE)
+    }
+
+    fn fixed_queue_size(&self) -> bool {
+        true
+    }
+
+    fn enable_queue(&self, queue: &VirtQueue, msix_entry: Option<u16>) -> Result<(), VirtioError> {
+        if queue.size() != self.max_queue_size(queue.index()) {
+            return Err(VirtioError::BadQueue);
+        }
+
+        if self.msix_enabled() {
+            self.write16(LEGACY_CONFIG_VECTOR, NO_VECTOR);
+            let entry = msix_entry.unwrap_or(NO_VECTOR);
+            self.write16(LEGACY_QUEUE_VECTOR, entry);
+            if self.read16(LEGACY_QUEUE_VECTOR) != entry {
+                return Err(VirtioError::BadQueue);
+            }
+        }
+        // legacy devices take the page number of the whole queue
+        let page = queue.descriptor_address().as_u64() / memory::PAGE_SIZE;
+        self.write32(LEGACY_QUEUE_ADDRESS, page as u32);
+        Ok(())
+    }
+
+    fn notify(&self, index: u16) {
+        self.write16(LEGACY_QUEUE_NOTIFY, index);
+    }
+
+    fn read_config(&self, offset: usize, buf: &mut [u8]) {
+        let base = if self.msix_enabled() { LEGACY_CONFIG_MSIX } else { LEGACY_CONFIG };
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.read8(base + (offset + i) as u16);
+        }
+    }
+}
+
+/// The interface of virtio 1.0 and later devices, memory mapped registers found through
+/// vendor specific capabilities.
+pub struct ModernTransport {
+    common: VirtAddr,
+    notify: VirtAddr,
+    notify_multiplier: u32,
+    device_config: VirtAddr,
+}
+
+impl ModernTransport {
+    fn new(device: &Arc<PciDevice>) -> Option<ModernTransport> {
+        let mut common = None;
+        let mut notify = None;
+        let mut notify_multiplier = 0;
+        let mut device_config = None;
+
+        for capability in &device.capabilities {
+            let offset = match capability {
+                Capability::Other { id: CAPABILITY_VENDOR, offset } => *offset,
+                _ => continue,
+            };
+            let config_type = device.read_u8(offset + 3);
+            let region = match config_type {
+                CAP_COMMON_CONFIG | CAP_NOTIFY_CONFIG | CAP_DEVICE_CONFIG => {
+                    map_region(device, offset)?
+                }
+                _ => continue,
+            };
+            // the first capability of each type is the preferred one
+            match config_type {
+                CAP_COMMON_CONFIG => common = common.or(Some(region)),
+                CAP_NOTIFY_CONFIG if notify.is_none() => {
+                    notify = Some(region);
+                    notify_multiplier = device.read_u32(offset + 16);
+                }
+                CAP_DEVICE_CONFIG => device_config = device_config.or(Some(region)),
+                _ => {}
+            }
+        }
+
+        Some(ModernTransport {
+            common: common?,
+            notify: notify?,
+            notify_multiplier,
+            // devices without configuration don't need to have it
+            device_config: device_config.unwrap_or(VirtAddr::zero()),
+        })
+    }
+
+    fn read<T>(&self, register: usize) -> T {
+        unsafe { core::ptr::read_volatile((self.common + register).as_ptr::<T>()) }
+    }
+
+    fn write<T>(&self, register: usize, value: T) {
+        unsafe { core::ptr::write_volatile((self.common + register).as_mut_ptr::<T>(), value) }
+    }
+}
+
+/// Maps the part of a BAR the capability at `offset` points to.
+fn map_region(device: &PciDevice, offset: u16) -> Option<VirtAddr> {
+    let bar = device.read_u8(offset + 4) as usize;
+    let start = device.read_u32(offset + 8) as u64;
+    let length = device.read_u32(offset + 12) as u64;
+    match device.bars.get(bar).copied().flatten() {
+        Some(Bar::Memory { address, .. }) => memory::map_mmio(address + start, length).ok(),
+        _ => None,
+    }
+}
+
+impl Transport for ModernTransport {
+    fn device_features(&self) -> u64 {
+        self.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, 0);
+        let low = self.read::<u32>(COMMON_DEVICE_FEATURE) as u64;
+        self.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, 1);
+        let high = self.read::<u32>(COMMON_DEVICE_FEATURE) as u64;
+        high << 32 | low
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        self.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, 0);
+        self.write::<u32>(COMMON_DRIVER_FEATURE, features as u32);
+        self.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, 1);
+        self.write::<u32>(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+    }
+
+    fn status(&self) -> u8 {
+        self.read(COMMON_DEVICE_STATUS)
+    }
+
+    fn set_status(&self, status: u8) {
+        self.write(COMMON_DEVICE_STATUS, status);
+    }
+
+    fn max_queue_size(&self, index: u16) -> u16 {
+        self.write(COMMON_QUEUE_SELECT, index);
+        self.read(COMMON_QUEUE_SIZE)
+    }
+
+    fn fixed_queue_size(&self) -> bool {
+        false
+    }
+
+    fn enable_queue(&self, queue: &VirtQueue, msix_entry: Option<u16>) -> Result<(), VirtioError> {
+        self.write::<u16>(COMMON_CONFIG_MSIX_VECTOR, NO_VECTOR);
+        self.write(COMMON_QUEUE_SELECT, queue.index());
+        self.write(COMMON_QUEUE_SIZE, queue.size());
+
+        let entry = msix_entry.unwrap_or(NO_VECTOR);
+        self.write(COMMON_QUEUE_MSIX_VECTOR, entry);
+        if self.read::<u16>(COMMON_QUEUE_MSIX_VECTOR) != entry {
+            return Err(VirtioError::BadQueue);
+        }
+
+        self.write(COMMON_QUEUE_DESCRIPTORS, queue.descriptor_address().as_u64());
+        self.write(COMMON_QUEUE_DRIVER, queue.available_address().as_u64());
+        self.write(COMMON_QUEUE_DEVICE, queue.used_address().as_u64());
+        self.write::<u16>(COMMON_QUEUE_ENABLE, 1);
+        Ok(())
+    }
+
+    fn notify(&self, index: u16) {
+        self.write(COMMON_QUEUE_SELECT, index);
+        let offset = self.read::<u16>(COMMON_QUEUE_NOTIFY_OFFSET) as u64 * self.notify_multiplier as u64;
+        unsafe { core::ptr::write_volatile((self.notify + offset).as_mut_ptr::<u16>(), index) };
+    }
+
+    fn read_config(&self, offset: usize, buf: &mut [u8]) {
+        // the device bumps the generation when the configuration changes, read it again if
+        // that happened in the middle of reading
+        loop {
+            let generation = self.read::<u8>(COMMON_CONFIG_GENERATION);
+            for (i, byte) in buf.iter_mut().enumerate() {
+                let pointer = (self.device_config + offset + i).as_ptr::<u8>();
+                *byte = unsafe { core::ptr::read_volatile(pointer) };
+            }
+            if self.read::<u8>(COMMON_CONFIG_GENERATION) == generation {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use super::Color;
+
+/// Where a channel is in a pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Channel {
+    shift: u32,
+    bits: u32,
+}
+
+impl Channel {
+    /// The largest value of the channel.
+    fn max(&self) -> u64 {
+        (1 << self.bits) - 1
+    }
+
+    /// `value` scaled from 8 bits to the channel and put in its place.
+    fn encode(&self, value: u8) -> u32 {
+        ((value as u64 * self.max() / 255) as u32) << self.shift
+    }
+
+    /// The channel of `pixel`, scaled to 8 bits.
+    fn decode(&self, pixel: u32) -> u8 {
+        let value = (pixel >> self.shift) as u64 & self.max();
+        // rounded, so that a color encoded into a channel wider than 8 bits decodes the same
+        ((value * 255 + self.max() / 2) / self.max()) as u8
+    }
+}
+
+/// The layout of pixels the bootloader only knows the bit positions of the channels of, stored
+/// as little endian numbers of up to 4 bytes.
+///
+/// Each channel is taken to reach up to the next one. The last one can't tell its own width from
+/// unused bits above it, so it's taken to be as wide as the widest of the others, or to reach to
+/// the end of the pixel if that's sooner. That's right for 8:8:8 with or without padding, 5:6:5,
+/// 5:5:5 and 10:10:10, which covers the GOP modes out there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bitmask {
+    red: Channel,
+    green: Channel,
+    blue: Channel,
+}
+
+impl Bitmask {
+    /// The layout with red, green and blue starting at the bits `positions` of pixels
+    /// `bytes_per_pixel` bytes wide. None if they don't fit in a pixel or two of them start at
+    /// the same bit.
+    pub fn new(positions: [u8; 3], bytes_per_pixel: usize) -> Option<Bitmask> {
+        if bytes_per_pixel == 0 || bytes_per_pixel > 4 {
+            return None;
+        }
+        let pixel_bits = bytes_per_pixel as u32 * 8;
+        let positions = positions.map(|position| position as u32);
+        if positions.iter().any(|&position| position >= pixel_bits) {
+            return None;
+        }
+
+        let [red, green, blue] = positions;
+        if red == green || green == blue || red == blue {
+            return None;
+        }
+
+        // every channel but the last reaches up to the next
+        let next = |shift: u32| positions.iter().copied().filter(|&position| position > shift).min();
+        let widest = positions
+            .iter()
+            .filter_map(|&shift| next(shift).map(|end| end - shift))
+            .max()
+            .unwrap_or(8);
+        let channel = |shift: u32| {
+            let bits = match next(shift) {
+                Some(end) => end - shift,
+                None => u32::min(pixel_bits - shift, widest),
+            };
+            Channel { shift, bits }
+        };
+        Some(Bitmask {
+            red: channel(red),
+            green: channel(green),
+            blue: channel(blue),
+        })
+    }
+
+    /// Writes `color` into `pixel`, which is as many bytes as a pixel.
+    pub fn encode(&self, color: Color, pixel: &mut [u8]) {
+        let value = self.red.encode(color.0) | self.green.encode(color.1) | self.blue.encode(color.2);
+        let len = pixel.len();
+        pixel.copy_from_slice(&value.to_le_bytes()[..len]);
+    }
+
+    pub fn decode(&self, pixel: &[u8]) -> Color {
+        let mut bytes = [0; 4];
+        bytes[..pixel.len()].copy_from_slice(pixel);
+        let value = u32::from_le_bytes(bytes);
+        Color(self.red.decode(value), self.green.decode(value), self.blue.decode(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The pixel `bitmask` encodes `color` as, as a number.
+    fn encoded(bitmask: &Bitmask, color: Color, bytes_per_pixel: usize) -> u32 {
+        let mut pixel = [0; 4];
+        bitmask.encode(color, &mut pixel[..bytes_per_pixel]);
+        u32::from_le_bytes(pixel)
+    }
+
+    fn widths(bitmask: &Bitmask) -> [u32; 3] {
+        [bitmask.red.bits, bitmask.green.bits, bitmask.blue.bits]
+    }
+
+    #[test]
+    fn rgb888_padded() {
+        let bitmask = Bitmask::new([16, 8, 0], 4).unwrap();
+        assert_eq!(widths(&bitmask), [8, 8, 8]);
+        assert_eq!(encoded(&bitmask, Color(0x12, 0x34, 0x56), 4), 0x00123456);
+        assert!(bitmask.decode(&0xFF123456u32.to_le_bytes()) == Color(0x12, 0x34, 0x56));
+    }
+
+    #[test]
+    fn bgr888_padded_at_the_bottom() {
+        let bitmask = Bitmask::new([8, 16, 24], 4).unwrap();
+        assert_eq!(widths(&bitmask), [8, 8, 8]);
+        assert_eq!(encoded(&bitmask, Color(0x12, 0x34, 0x56), 4), 0x56341200);
+    }
+
+    #[test]
+    fn rgb888() {
+        let bitmask = Bitmask::new([16, 8, 0], 3).unwrap();
+        assert_eq!(widths(&bitmask), [8, 8, 8]);
+        assert_eq!(encoded(&bitmask, Color(0x12, 0x34, 0x56), 3), 0x123456);
+        assert!(bitmask.decode(&[0x56, 0x34, 0x12]) == Color(0x12, 0x34, 0x56));
+    }
+
+    #[test]
+    fn rgb565() {
+        let bitmask = Bitmask::new([11, 5, 0], 2).unwrap();
+        assert_eq!(widths(&bitmask), [5, 6, 5]);
+        assert_eq!(encoded(&bitmask, Color(255, 255, 255), 2), 0xFFFF);
+        assert_eq!(encoded(&bitmask, Color(255, 0, 0), 2), 0xF800);
+        assert_eq!(encoded(&bitmask, Color(0, 255, 0), 2), 0x07E0);
+        assert!(bitmask.decode(&0xF800u16.to_le_bytes()) == Color(255, 0, 0));
+        assert!(bitmask.decode(&0x07E0u16.to_le_bytes()) == Color(0, 255, 0));
+    }
+
+    #[test]
+    fn rgb555() {
+        let bitmask = Bitmask::new([10, 5, 0], 2).unwrap();
+        assert_eq!(widths(&bitmask), [5, 5, 5]);
+        // the top bit is padding
+        assert_eq!(encoded(&bitmask, Color(255, 255, 255), 2), 0x7FFF);
+        assert!(bitmask.decode(&0xFFFFu16.to_le_bytes()) == Color(255, 255, 255));
+        assert!(bitmask.decode(&0x001Fu16.to_le_bytes()) == Color(0, 0, 255));
+    }
+
+    #[test]
+    fn rgb101010() {
+        let bitmask = Bitmask::new([20, 10, 0], 4).unwrap();
+        assert_eq!(widths(&bitmask), [10, 10, 10]);
+        assert_eq!(encoded(&bitmask, Color(255, 0, 255), 4), 0x3FF003FF);
+        assert!(bitmask.decode(&0xC00FFC00u32.to_le_bytes()) == Color(0, 255, 0));
+    }
+
+    #[test]
+    fn round_trips() {
+        for (positions, bytes_per_pixel) in [([16, 8, 0], 4), ([0, 8, 16], 3), ([24, 16, 8], 4), ([20, 10, 0], 4)] {
+            let bitmask = Bitmask::new(positions, bytes_per_pixel).unwrap();
+            for value in 0..=255 {
+                let color = Color(value, 255 - value, value / 2);
+                let mut pixel = [0; 4];
+                bitmask.encode(color, &mut pixel[..bytes_per_pixel]);
+                assert!(bitmask.decode(&pixel[..bytes_per_pixel]) == color);
+            }
+        }
+    }
+
+    #[test]
+    fn overlapping_channels() {
+        assert_eq!(Bitmask::new([0, 0, 8], 4), None);
+        assert_eq!(Bitmask::new([16, 8, 16], 4), None);
+    }
+
+    #[test]
+    fn channels_outside_the_pixel() {
+        assert_eq!(Bitmask::new([32, 8, 0], 4), None);
+        assert_eq!(Bitmask::new([16, 8, 0], 2), None);
+    }
+
+    #[test]
+    fn empty_or_oversized_pixels() {
+        assert_eq!(Bitmask::new([16, 8, 0], 0), None);
+        assert_eq!(Bitmask::new([16, 8, 0], 5), None);
+    }
+}
@@ -0,0 +1,11 @@
+//! Colors and the pixel layouts the framebuffer can have, kept apart from the kernel so that
+//! they can be tested on the host with `cargo test -p pixel`.
+#![cfg_attr(not(test), no_std)]
+
+mod bitmask;
+
+pub use bitmask::Bitmask;
+
+/// (R, G, B) color
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Color(pub u8, pub u8, pub u8);
@@ -3,11 +3,15 @@ use std::io::Result;
 fn main() -> Result<()> {
     // read env variables that were set in build script
     let uefi_path = env!("UEFI_PATH");
+    // shared with the guest as a FAT disk
+    let host_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/host");
 
     let mut cmd = std::process::Command::new("qemu-system-x86_64");
     cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
     cmd.arg("-drive")
         .arg(format!("format=raw,file={uefi_path}"));
+    cmd.arg("-drive")
+        .arg(format!("format=raw,file=fat:rw:{host_dir}"));
     cmd.arg("-device")
         .arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
     cmd.arg("-serial")